@@ -0,0 +1,56 @@
+/* The C interface to rlox, from building the crate with the `capi` feature.
+ * Ownership is described in src/capi.rs. */
+
+#ifndef RLOX_H
+#define RLOX_H
+
+#include <stdbool.h>
+#include <stddef.h>
+
+#ifdef __cplusplus
+extern "C" {
+#endif
+
+#define RLOX_OK 0
+#define RLOX_COMPILE_ERROR 65
+#define RLOX_RUNTIME_ERROR 70
+
+#define RLOX_NIL 0
+#define RLOX_BOOL 1
+#define RLOX_NUMBER 2
+#define RLOX_STRING 3
+#define RLOX_OBJECT 4
+#define RLOX_ERROR 5
+
+typedef struct RloxVm RloxVm;
+typedef struct RloxValue RloxValue;
+
+typedef RloxValue *(*RloxNative)(void *user_data, size_t argc, const RloxValue *const *argv);
+
+RloxVm *rlox_new(void);
+void rlox_free(RloxVm *vm);
+void rlox_set_max_call_depth(RloxVm *vm, size_t depth);
+int rlox_eval(RloxVm *vm, const char *source);
+const char *rlox_last_error(const RloxVm *vm);
+void rlox_register_native(RloxVm *vm, const char *name, int arity, RloxNative native,
+                          void *user_data);
+RloxValue *rlox_get_global(const RloxVm *vm, const char *name);
+
+RloxValue *rlox_nil(void);
+RloxValue *rlox_bool(bool b);
+RloxValue *rlox_number(double n);
+RloxValue *rlox_string(const char *s);
+RloxValue *rlox_error(const char *message);
+
+int rlox_value_type(const RloxValue *value);
+double rlox_value_as_number(const RloxValue *value);
+bool rlox_value_as_bool(const RloxValue *value);
+char *rlox_value_to_string(const RloxValue *value);
+void rlox_value_free(RloxValue *value);
+void rlox_string_free(char *s);
+
+#ifdef __cplusplus
+}
+#endif
+
+#endif
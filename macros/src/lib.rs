@@ -0,0 +1,208 @@
+//! The `#[lox_native]` attribute, which rlox re-exports as `rlox::lox_native`.
+
+use proc_macro::TokenStream;
+use quote::{format_ident, quote};
+use syn::{
+    parse_macro_input, spanned::Spanned, AttributeArgs, Error, FnArg, ItemFn, Lit, Meta,
+    NestedMeta, ReturnType, Type,
+};
+
+// Turns an ordinary Rust function into a Lox native. The function is kept as it is, and
+// alongside it goes `<name>_native()`, which gives a `NativeFn` to register with
+// `Interpreter::register_native_fn`. The native checks and converts its arguments, and
+// converts what the function returns.
+//
+// Parameters can be a `LoxValue`, or any type that can be converted from one with
+// `TryFrom<&LoxValue>`. An `Option` of one is optional, and a `&[LoxValue]` last takes
+// the rest of the arguments, however many there are. The function can return nothing, a
+// type that converts into a `LoxValue`, or a `Result` of one with an error that converts
+// into a `RuntimeError`.
+// It's called the function's name in Lox, unless it's given another with
+// `#[lox_native(name = "...")]`.
+#[proc_macro_attribute]
+pub fn lox_native(args: TokenStream, item: TokenStream) -> TokenStream {
+    let args = parse_macro_input!(args as AttributeArgs);
+    let function = parse_macro_input!(item as ItemFn);
+    match expand(args, function) {
+        Ok(tokens) => tokens.into(),
+        Err(e) => e.to_compile_error().into(),
+    }
+}
+
+fn expand(args: AttributeArgs, function: ItemFn) -> Result<proc_macro2::TokenStream, Error> {
+    let ident = &function.sig.ident;
+    let mut name = ident.to_string();
+    for arg in &args {
+        match arg {
+            NestedMeta::Meta(Meta::NameValue(nv)) if nv.path.is_ident("name") => match &nv.lit {
+                Lit::Str(s) => name = s.value(),
+                lit => return Err(Error::new(lit.span(), "Expected a string")),
+            },
+            arg => return Err(Error::new(arg.span(), "Expected `name = \"...\"`")),
+        }
+    }
+    let sig = &function.sig;
+    if !sig.generics.params.is_empty() || sig.asyncness.is_some() || sig.variadic.is_some() {
+        return Err(Error::new(
+            sig.span(),
+            "Natives have to be plain functions, without generics",
+        ));
+    }
+
+    // Optional parameters, which are `Option`s, come after the others, and a `&[LoxValue]`
+    // at the end takes the rest of the arguments
+    let mut params = Vec::new();
+    let (mut required, mut optional, mut rest) = (0usize, 0usize, false);
+    for (i, input) in sig.inputs.iter().enumerate() {
+        let ty = match input {
+            FnArg::Typed(pat) => &pat.ty,
+            FnArg::Receiver(r) => return Err(Error::new(r.span(), "Natives can't take self")),
+        };
+        if rest {
+            return Err(Error::new(
+                ty.span(),
+                "The rest of the arguments have to go last",
+            ));
+        }
+        let arg = format_ident!("arg{}", i);
+        if is_rest(ty) {
+            rest = true;
+            // Empty rather than out of range when optional arguments before it are left out
+            params.push(quote! { let #arg = args.get(#i..).unwrap_or(&[]); });
+        } else if let Some(inner) = option_of(ty) {
+            optional += 1;
+            let convert = convert(&name, i, inner, quote! { arg });
+            params.push(quote! {
+                let #arg = match args.get(#i) {
+                    Some(arg) => Some({ #convert }),
+                    None => None,
+                };
+            });
+        } else if optional > 0 {
+            return Err(Error::new(
+                ty.span(),
+                "Optional parameters have to go after the others",
+            ));
+        } else {
+            required += 1;
+            let convert = convert(&name, i, ty, quote! { &args[#i] });
+            params.push(quote! { let #arg = { #convert }; });
+        }
+    }
+    let args: Vec<_> = (0..sig.inputs.len())
+        .map(|i| format_ident!("arg{}", i))
+        .collect();
+    let arity = if rest {
+        quote! { ::rlox::loxvalue::Arity::AtLeast(#required) }
+    } else if optional > 0 {
+        let max = required + optional;
+        quote! { ::rlox::loxvalue::Arity::Between(#required, #max) }
+    } else {
+        quote! { ::rlox::loxvalue::Arity::Exactly(#required) }
+    };
+
+    let call = quote! { #ident(#(#args),*) };
+    let result = match &sig.output {
+        ReturnType::Default => quote! {
+            #call;
+            Ok(::rlox::loxvalue::LoxValue::Nil)
+        },
+        ReturnType::Type(_, ty) if is_result(ty) => quote! {
+            let value = #call.map_err(
+                ::std::convert::Into::<::rlox::interpreter::RuntimeError>::into,
+            )?;
+            Ok(::rlox::loxvalue::LoxValue::from(value))
+        },
+        ReturnType::Type(..) => quote! {
+            Ok(::rlox::loxvalue::LoxValue::from(#call))
+        },
+    };
+
+    let vis = &function.vis;
+    let native = format_ident!("{}_native", ident);
+    let doc = format!("`{}` as a Lox native called `{}`", ident, name);
+    Ok(quote! {
+        #function
+
+        #[doc = #doc]
+        #vis fn #native() -> ::rlox::loxvalue::NativeFn {
+            ::rlox::loxvalue::NativeFn {
+                name: #name.to_string(),
+                arity: #arity,
+                code: ::std::sync::Arc::new(
+                    |_: &mut ::rlox::interpreter::Interpreter<'_>,
+                     _: ::std::option::Option<&::rlox::tokens::Token>,
+                     args: &[::rlox::loxvalue::LoxValue]| -> ::std::result::Result<
+                        ::rlox::loxvalue::LoxValue,
+                        ::rlox::interpreter::RuntimeError,
+                    > {
+                        #(#params)*
+                        #result
+                    },
+                ),
+            }
+        }
+    })
+}
+
+// Converts the argument at `i`, a `&LoxValue`, into `ty`, returning from the native if
+// it can't be
+fn convert(
+    name: &str,
+    i: usize,
+    ty: &Type,
+    value: proc_macro2::TokenStream,
+) -> proc_macro2::TokenStream {
+    let position = i + 1;
+    if is_value(ty) {
+        return quote! { ::std::clone::Clone::clone(#value) };
+    }
+    quote! {
+        match <#ty as ::std::convert::TryFrom<&::rlox::loxvalue::LoxValue>>::try_from(#value) {
+            Ok(arg) => arg,
+            Err(e) => {
+                let e: ::rlox::loxvalue::ConversionError = e;
+                return Err(::rlox::interpreter::RuntimeError::NativeArgument(format!(
+                    "{}() expects argument {} to be {}, but got {}",
+                    #name, #position, e.expected, e.found
+                )));
+            }
+        }
+    }
+}
+
+// LoxValues are passed as they are, rather than converted
+fn is_value(ty: &Type) -> bool {
+    matches!(last_segment(ty), Some(segment) if segment.ident == "LoxValue")
+}
+
+// A `&[LoxValue]`, which takes the rest of the arguments
+fn is_rest(ty: &Type) -> bool {
+    match ty {
+        Type::Reference(r) => matches!(&*r.elem, Type::Slice(slice) if is_value(&slice.elem)),
+        _ => false,
+    }
+}
+
+// The `T` of an `Option<T>`
+fn option_of(ty: &Type) -> Option<&Type> {
+    let segment = last_segment(ty).filter(|segment| segment.ident == "Option")?;
+    match &segment.arguments {
+        syn::PathArguments::AngleBracketed(args) => match args.args.first()? {
+            syn::GenericArgument::Type(ty) => Some(ty),
+            _ => None,
+        },
+        _ => None,
+    }
+}
+
+fn is_result(ty: &Type) -> bool {
+    matches!(last_segment(ty), Some(segment) if segment.ident == "Result")
+}
+
+fn last_segment(ty: &Type) -> Option<&syn::PathSegment> {
+    match ty {
+        Type::Path(path) if path.qself.is_none() => path.path.segments.last(),
+        _ => None,
+    }
+}
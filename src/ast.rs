@@ -1,148 +1,429 @@
-use crate::tokens::{Token, TokenLiteral};
+use std::{fmt::Write, mem::size_of, ops::Index, sync::Arc};
 
+use serde::{Serialize, Serializer};
+
+use crate::tokens::{Span, Token, TokenLiteral, TokenType};
+
+// Expressions and statements live in a flat arena, and refer to their children by index.
+// An expression's id is its index, which also gives the resolver a stable key to record
+// where each variable lives.
+#[derive(Clone, Copy, Debug, Eq, Hash, PartialEq, Serialize)]
+pub struct ExprId(usize);
+
+#[derive(Clone, Copy, Debug, Eq, Hash, PartialEq, Serialize)]
+pub struct StmtId(usize);
+
+// Nodes are added, and ids stay valid for as long as the arena is around, unless the
+// arena's truncated back to a mark. The REPL keeps adding every line to the same arena,
+// which lets functions from earlier lines keep running.
+#[derive(Clone, Debug, Default, Serialize)]
+pub struct Ast {
+    exprs: Nodes<Expr>,
+    stmts: Nodes<Stmt>,
+    // The line each statement starts on
+    stmt_lines: Nodes<usize>,
+    // Where in the source each node is, from its first token to its last. Nodes that
+    // stand in for something left out, such as a missing initializer, are empty spans
+    // where it would have been.
+    expr_spans: Nodes<Span>,
+    stmt_spans: Nodes<Span>,
+}
+
+// How big the arena was, to truncate it back to once code added since is finished with
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct AstMark {
+    exprs: usize,
+    stmts: usize,
+}
+
+const CHUNK_SIZE: usize = 1024;
+
+// Nodes are kept in chunks that copies of the arena share. Adding to an arena that's
+// shared, as eval() does while the program is running, copies the last chunk rather than
+// every node.
 #[derive(Clone, Debug)]
+struct Nodes<T> {
+    chunks: Vec<Arc<Vec<T>>>,
+    len: usize,
+}
+
+impl<T: Clone> Nodes<T> {
+    fn push(&mut self, node: T) -> usize {
+        if self.len.is_multiple_of(CHUNK_SIZE) {
+            self.chunks.push(Arc::new(Vec::with_capacity(CHUNK_SIZE)));
+        }
+        Arc::make_mut(self.chunks.last_mut().unwrap()).push(node);
+        self.len += 1;
+        self.len - 1
+    }
+
+    fn get(&self, i: usize) -> &T {
+        &self.chunks[i / CHUNK_SIZE][i % CHUNK_SIZE]
+    }
+
+    fn set(&mut self, i: usize, node: T) {
+        Arc::make_mut(&mut self.chunks[i / CHUNK_SIZE])[i % CHUNK_SIZE] = node;
+    }
+
+    fn truncate(&mut self, len: usize) {
+        self.chunks.truncate(len.div_ceil(CHUNK_SIZE));
+        if !len.is_multiple_of(CHUNK_SIZE) {
+            Arc::make_mut(self.chunks.last_mut().unwrap()).truncate(len % CHUNK_SIZE);
+        }
+        self.len = len;
+    }
+}
+
+impl<T> Default for Nodes<T> {
+    fn default() -> Self {
+        Nodes {
+            chunks: Vec::new(),
+            len: 0,
+        }
+    }
+}
+
+// As a single list, the way the arena's dumped
+impl<T: Serialize> Serialize for Nodes<T> {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_seq(self.chunks.iter().flat_map(|chunk| chunk.iter()))
+    }
+}
+
+impl Ast {
+    pub fn new() -> Ast {
+        Ast::default()
+    }
+
+    pub fn add_expr(&mut self, expr: Expr, span: Span) -> ExprId {
+        self.expr_spans.push(span);
+        ExprId(self.exprs.push(expr))
+    }
+
+    pub fn add_stmt(&mut self, stmt: Stmt, line: usize, span: Span) -> StmtId {
+        self.stmt_lines.push(line);
+        self.stmt_spans.push(span);
+        StmtId(self.stmts.push(stmt))
+    }
+
+    // Used by the parser, which only finds out an expression is an assignment target
+    // after it's been parsed, and by the optimizer, whose folded expressions keep the
+    // span of what they replace
+    pub fn replace_expr(&mut self, id: ExprId, expr: Expr) {
+        self.exprs.set(id.0, expr);
+    }
+
+    // Used by the parser for an assignment, which takes over its target's id
+    pub fn extend_expr_span(&mut self, id: ExprId, end: usize) {
+        let span = Span {
+            end,
+            ..self.expr_span(id)
+        };
+        self.expr_spans.set(id.0, span);
+    }
+
+    // Used by the optimizer to rewrite statements once they've been resolved
+    pub fn replace_stmt(&mut self, id: StmtId, stmt: Stmt) {
+        self.stmts.set(id.0, stmt);
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.exprs.len == 0 && self.stmts.len == 0
+    }
+
+    pub fn stmt_ids(&self) -> impl Iterator<Item = StmtId> {
+        (0..self.stmts.len).map(StmtId)
+    }
+
+    pub fn mark(&self) -> AstMark {
+        AstMark {
+            exprs: self.exprs.len,
+            stmts: self.stmts.len,
+        }
+    }
+
+    pub fn expr_ids_since(&self, mark: AstMark) -> impl Iterator<Item = ExprId> {
+        (mark.exprs..self.exprs.len).map(ExprId)
+    }
+
+    // Whether statements added since the mark declare functions or classes. Closures
+    // made from them keep using their bodies, so those nodes have to stay.
+    pub fn declares_functions_since(&self, mark: AstMark) -> bool {
+        (mark.stmts..self.stmts.len)
+            .any(|i| matches!(self.stmts.get(i), Stmt::Function(_) | Stmt::Class(_)))
+    }
+
+    // Roughly how much memory the nodes added since the mark take up, with the
+    // declarations functions and classes share with their closures
+    pub fn size_since(&self, mark: AstMark) -> usize {
+        let stmts: usize = (mark.stmts..self.stmts.len)
+            .map(|i| {
+                let shared = match self.stmts.get(i) {
+                    Stmt::Function(_) => size_of::<FunctionStmt>(),
+                    Stmt::Class(class) => {
+                        size_of::<ClassStmt>() + class.methods.len() * size_of::<FunctionStmt>()
+                    }
+                    _ => 0,
+                };
+                size_of::<Stmt>() + size_of::<usize>() + size_of::<Span>() + shared
+            })
+            .sum();
+        (self.exprs.len - mark.exprs) * (size_of::<Expr>() + size_of::<Span>()) + stmts
+    }
+
+    // Drops the nodes added since the mark, whose ids mustn't be used again
+    pub fn truncate(&mut self, mark: AstMark) {
+        self.exprs.truncate(mark.exprs);
+        self.stmts.truncate(mark.stmts);
+        self.stmt_lines.truncate(mark.stmts);
+        self.expr_spans.truncate(mark.exprs);
+        self.stmt_spans.truncate(mark.stmts);
+    }
+
+    // The whole arena, with `program` listing the top-level statements. Nodes refer to
+    // each other by their index in `exprs` or `stmts`.
+    pub fn to_json(&self, program: &[StmtId]) -> String {
+        #[derive(Serialize)]
+        struct Dump<'a> {
+            program: &'a [StmtId],
+            #[serde(flatten)]
+            ast: &'a Ast,
+        }
+        serde_json::to_string(&Dump { program, ast: self }).unwrap()
+    }
+
+    pub fn line(&self, id: StmtId) -> usize {
+        *self.stmt_lines.get(id.0)
+    }
+
+    pub fn expr_span(&self, id: ExprId) -> Span {
+        *self.expr_spans.get(id.0)
+    }
+
+    pub fn stmt_span(&self, id: StmtId) -> Span {
+        *self.stmt_spans.get(id.0)
+    }
+
+    // A token to report errors in an expression against, if it has one
+    pub fn token(&self, mut id: ExprId) -> Option<&Token> {
+        while let Expr::Grouping(e) = &self[id] {
+            id = *e;
+        }
+        match &self[id] {
+            Expr::Grouping(_) | Expr::Literal(_) => None,
+            Expr::Assign(e) => Some(&e.name),
+            Expr::Binary(e) => Some(&e.operator),
+            Expr::Call(e) => Some(&e.paren),
+            Expr::Get(e) => Some(&e.name),
+            Expr::Logical(e) => Some(&e.operator),
+            Expr::Set(e) => Some(&e.name),
+            Expr::Super(e) => Some(&e.keyword),
+            Expr::This(e) => Some(&e.keyword),
+            Expr::Unary(e) => Some(&e.operator),
+            Expr::Variable(e) => Some(&e.name),
+        }
+    }
+}
+
+impl Index<ExprId> for Ast {
+    type Output = Expr;
+
+    fn index(&self, id: ExprId) -> &Expr {
+        self.exprs.get(id.0)
+    }
+}
+
+impl Index<StmtId> for Ast {
+    type Output = Stmt;
+
+    fn index(&self, id: StmtId) -> &Stmt {
+        self.stmts.get(id.0)
+    }
+}
+
+#[derive(Clone, Debug, Serialize)]
 pub enum Stmt {
-    Block(Vec<Stmt>),
-    Break,
+    Block(Vec<StmtId>),
+    // The keyword, for reporting a break outside of a loop
+    Break(Token),
     Class(Box<ClassStmt>),
-    Expression(Expr),
-    Function(FunctionStmt),
+    Debugger,
+    Expression(ExprId),
+    Function(Arc<FunctionStmt>),
     If(IfStmt),
-    Print(Expr),
+    Print(ExprId),
     Return(ReturnStmt),
     While(WhileStmt),
     Var(VarStmt),
+    Yield(YieldStmt),
 }
 
-#[derive(Clone, Debug)]
+#[derive(Clone, Debug, Serialize)]
 pub enum Expr {
     Assign(AssignExpr),
     Binary(BinaryExpr),
     Call(CallExpr),
     Get(GetExpr),
-    Grouping(Box<Expr>),
+    Grouping(ExprId),
     Literal(TokenLiteral),
     Logical(LogicalExpr),
     Set(SetExpr),
     Super(SuperExpr),
-    This(Token),
+    This(ThisExpr),
     Unary(UnaryExpr),
-    Variable(Token),
+    Variable(VariableExpr),
 }
 
-#[derive(Clone, Debug)]
+#[derive(Clone, Debug, Serialize)]
 pub struct ClassStmt {
     pub name: Token,
+    // From `///` comments before the class, when the source was scanned with trivia
+    pub doc: Option<String>,
 
     // Superclass will only ever be parsed as an Expr::Variable
-    pub superclass: Option<Expr>,
-    pub methods: Vec<FunctionStmt>,
+    pub superclass: Option<ExprId>,
+    pub methods: Vec<Arc<FunctionStmt>>,
 }
 
-#[derive(Clone, Debug)]
+// Function declarations are shared with the closures created from them, so those can
+// outlive the statements that were parsed alongside them.
+#[derive(Clone, Debug, Serialize)]
 pub struct FunctionStmt {
     pub name: Token,
     pub params: Vec<Token>,
-    pub body: Vec<Stmt>,
+    // Type annotations are only read by the type checker, and are None where left out
+    pub param_types: Vec<Option<Token>>,
+    pub return_type: Option<Token>,
+    pub body: Vec<StmtId>,
+    // From `///` comments before the function or method, when the source was scanned
+    // with trivia
+    pub doc: Option<String>,
+    // Whether the body yields, making calls return a generator instead of running it
+    pub is_generator: bool,
 }
 
-#[derive(Clone, Debug)]
+#[derive(Clone, Debug, Serialize)]
 pub struct IfStmt {
-    pub condition: Box<Expr>,
-    pub then_branch: Box<Stmt>,
-    pub else_branch: Option<Box<Stmt>>,
+    pub condition: ExprId,
+    pub then_branch: StmtId,
+    pub else_branch: Option<StmtId>,
 }
 
-#[derive(Clone, Debug)]
+#[derive(Clone, Debug, Serialize)]
 pub struct ReturnStmt {
     pub keyword: Token,
-    pub value: Box<Expr>,
+    pub value: ExprId,
 }
 
-#[derive(Clone, Debug)]
+#[derive(Clone, Debug, Serialize)]
 pub struct WhileStmt {
-    pub condition: Box<Expr>,
-    pub body: Box<Stmt>,
+    pub condition: ExprId,
+    pub body: StmtId,
 }
 
-#[derive(Clone, Debug)]
+#[derive(Clone, Debug, Serialize)]
 pub struct VarStmt {
     pub name: Token,
-    pub initializer: Box<Expr>,
+    pub type_annotation: Option<Token>,
+    pub initializer: ExprId,
 }
 
-#[derive(Clone, Debug)]
+#[derive(Clone, Debug, Serialize)]
+pub struct YieldStmt {
+    pub keyword: Token,
+    pub value: ExprId,
+}
+
+#[derive(Clone, Debug, Serialize)]
 pub struct AssignExpr {
     pub name: Token,
-    pub value: Box<Expr>,
+    pub value: ExprId,
 }
 
-#[derive(Clone, Debug)]
+#[derive(Clone, Debug, Serialize)]
 pub struct BinaryExpr {
-    pub left: Box<Expr>,
+    pub left: ExprId,
     pub operator: Token,
-    pub right: Box<Expr>,
+    pub right: ExprId,
 }
 
-#[derive(Clone, Debug)]
+#[derive(Clone, Debug, Serialize)]
 pub struct CallExpr {
-    pub callee: Box<Expr>,
+    pub callee: ExprId,
     pub paren: Token, // Closing paren (So we have it's location for errors)
-    pub arguments: Vec<Expr>,
+    pub arguments: Vec<ExprId>,
 }
 
-#[derive(Clone, Debug)]
+#[derive(Clone, Debug, Serialize)]
 pub struct GetExpr {
     pub name: Token,
-    pub object: Box<Expr>,
+    pub object: ExprId,
 }
 
-#[derive(Clone, Debug)]
+#[derive(Clone, Debug, Serialize)]
 pub struct LogicalExpr {
-    pub left: Box<Expr>,
+    pub left: ExprId,
     pub operator: Token,
-    pub right: Box<Expr>,
+    pub right: ExprId,
 }
 
-#[derive(Clone, Debug)]
+#[derive(Clone, Debug, Serialize)]
 pub struct SetExpr {
-    pub object: Box<Expr>,
+    pub object: ExprId,
     pub name: Token,
-    pub value: Box<Expr>,
+    pub value: ExprId,
 }
 
-#[derive(Clone, Debug)]
+#[derive(Clone, Debug, Serialize)]
 pub struct SuperExpr {
     pub keyword: Token,
     pub method: Token,
 }
 
-#[derive(Clone, Debug)]
+#[derive(Clone, Debug, Serialize)]
+pub struct ThisExpr {
+    pub keyword: Token,
+}
+
+#[derive(Clone, Debug, Serialize)]
 pub struct UnaryExpr {
     pub operator: Token,
-    pub right: Box<Expr>,
+    pub right: ExprId,
 }
 
-pub struct PrettyPrinter {}
+#[derive(Clone, Debug, Serialize)]
+pub struct VariableExpr {
+    pub name: Token,
+}
 
-impl PrettyPrinter {
-    pub fn print_stmt(&self, stmt: &Stmt) -> String {
-        match stmt {
+pub struct PrettyPrinter<'a> {
+    ast: &'a Ast,
+}
+
+impl<'a> PrettyPrinter<'a> {
+    pub fn new(ast: &'a Ast) -> Self {
+        PrettyPrinter { ast }
+    }
+
+    pub fn print_stmt(&self, stmt: StmtId) -> String {
+        match &self.ast[stmt] {
             Stmt::Block(vec) => {
                 let mut s = String::new();
                 for statement in vec {
-                    s.push_str(&self.print_stmt(statement));
+                    s.push_str(&self.print_stmt(*statement));
                 }
                 s
             }
-            Stmt::Break => "break;".to_string(),
+            Stmt::Break(_) => "break;".to_string(),
             Stmt::Class(class) => {
                 let mut s = "class ".to_string();
                 s.push_str(&class.name.lexeme);
-                if let Some(Expr::Variable(token)) = &class.superclass {
-                    s.push_str(" < ");
-                    s.push_str(&token.lexeme);
-                    s.push_str(" ");
+                if let Some(sc) = class.superclass {
+                    if let Expr::Variable(sc) = &self.ast[sc] {
+                        s.push_str(" < ");
+                        s.push_str(&sc.name.lexeme);
+                        s.push(' ');
+                    }
                 }
                 s.push_str(" { ");
                 for m in &class.methods {
@@ -151,156 +432,989 @@ impl PrettyPrinter {
                 s.push_str(" } ");
                 s
             }
-            Stmt::Expression(e) => self.print_expr(e),
+            Stmt::Debugger => "debugger;".to_string(),
+            Stmt::Expression(e) => self.print_expr(*e),
             Stmt::Function(stmt) => self.print_function_stmt(stmt),
             Stmt::If(e) => {
                 let mut s = "if (".to_string();
-                s.push_str(&self.print_expr(&e.condition));
+                s.push_str(&self.print_expr(e.condition));
                 s.push_str(") ");
-                s.push_str(&self.print_stmt(&e.then_branch));
-                if let Some(else_stmt) = &e.else_branch {
+                s.push_str(&self.print_stmt(e.then_branch));
+                if let Some(else_stmt) = e.else_branch {
                     s.push_str(&self.print_stmt(else_stmt));
                 }
-                s.push_str(";");
+                s.push(';');
                 s
             }
             Stmt::Print(e) => {
                 let mut s = "print ".to_string();
-                s.push_str(&self.print_expr(e));
-                s.push_str(";");
+                s.push_str(&self.print_expr(*e));
+                s.push(';');
                 s
             }
             Stmt::Return(ReturnStmt { keyword: _, value }) => {
                 let mut s = "return ".to_string();
-                s.push_str(&self.print_expr(value));
-                s.push_str(";");
+                s.push_str(&self.print_expr(*value));
+                s.push(';');
                 s
             }
             Stmt::While(WhileStmt { condition, body }) => {
                 let mut s = "while (".to_string();
-                s.push_str(&self.print_expr(&condition));
+                s.push_str(&self.print_expr(*condition));
                 s.push_str(") ");
-                s.push_str(&self.print_stmt(&body));
+                s.push_str(&self.print_stmt(*body));
                 s
             }
             Stmt::Var(vs) => {
                 let mut s = "var ".to_string();
                 s.push_str(&vs.name.lexeme);
-                s.push_str(&self.print_expr(vs.initializer.as_ref()));
-                s.push_str(";");
+                s.push_str(" = ");
+                s.push_str(&self.print_expr(vs.initializer));
+                s.push(';');
+                s
+            }
+            Stmt::Yield(YieldStmt { keyword: _, value }) => {
+                let mut s = "yield ".to_string();
+                s.push_str(&self.print_expr(*value));
+                s.push(';');
                 s
             }
         }
     }
 
-    pub fn print_expr(&self, e: &Expr) -> String {
-        match e {
+    pub fn print_expr(&self, e: ExprId) -> String {
+        match &self.ast[e] {
             Expr::Assign(e) => {
                 let mut s = e.name.lexeme.clone();
                 s.push_str(" = ");
-                s.push_str(&self.print_expr(&e.value));
-                s.push_str(";");
+                s.push_str(&self.print_expr(e.value));
+                s.push(';');
                 s
             }
-            Expr::Binary(e) => self.parenthesize(&e.operator.lexeme, &[&e.left, &e.right]),
+            Expr::Binary(e) => self.parenthesize(&e.operator.lexeme, &[e.left, e.right]),
             Expr::Call(CallExpr {
                 callee,
                 paren: _,
                 arguments,
             }) => {
-                let mut s = self.print_expr(&callee);
-                s.push_str("(");
-                for arg in arguments {
-                    s.push_str(&self.print_expr(&arg));
+                let mut s = self.print_expr(*callee);
+                s.push('(');
+                for (i, arg) in arguments.iter().enumerate() {
+                    if i > 0 {
+                        s.push_str(", ");
+                    }
+                    s.push_str(&self.print_expr(*arg));
                 }
-                s.push_str(")");
+                s.push(')');
                 s
             }
             Expr::Get(GetExpr { name, object }) => {
-                let mut s = self.print_expr(&object);
-                s.push_str(".");
+                let mut s = self.print_expr(*object);
+                s.push('.');
                 s.push_str(&name.lexeme);
                 s
             }
-            Expr::Grouping(b) => {
-                let e = b.as_ref();
-                self.parenthesize("group", &[e])
-            }
+            Expr::Grouping(e) => self.parenthesize("group", &[*e]),
             Expr::Literal(token_literal) => match token_literal {
                 TokenLiteral::None => "nil".to_string(),
                 TokenLiteral::True => "true".to_string(),
                 TokenLiteral::False => "false".to_string(),
                 TokenLiteral::Nil => "nil".to_string(),
-                TokenLiteral::String(s) => s.clone(),
+                TokenLiteral::String(s) => format!("\"{}\"", s),
                 TokenLiteral::Number(n) => n.to_string(),
             },
-            Expr::Logical(e) => self.parenthesize(&e.operator.lexeme, &[&e.left, &e.right]),
+            Expr::Logical(e) => self.parenthesize(&e.operator.lexeme, &[e.left, e.right]),
             Expr::Set(e) => {
-                let mut s = self.print_expr(&e.object);
-                s.push_str(".");
+                let mut s = self.print_expr(e.object);
+                s.push('.');
                 s.push_str(&e.name.lexeme);
                 s.push_str(" = ");
-                s.push_str(&self.print_expr(&e.value));
+                s.push_str(&self.print_expr(e.value));
                 s
             }
             Expr::Super(e) => {
                 let mut s = e.keyword.lexeme.clone();
-                s.push_str(".");
+                s.push('.');
                 s.push_str(&e.method.lexeme);
                 s
             }
             Expr::This(_) => "this".to_string(),
-            Expr::Unary(e) => self.parenthesize(&e.operator.lexeme, &[&e.right]),
-            Expr::Variable(token) => token.lexeme.clone(),
+            Expr::Unary(e) => self.parenthesize(&e.operator.lexeme, &[e.right]),
+            Expr::Variable(v) => v.name.lexeme.clone(),
         }
     }
 
-    fn print_function_stmt(&self, FunctionStmt { name, params, body }: &FunctionStmt) -> String {
+    fn print_function_stmt(
+        &self,
+        FunctionStmt {
+            name, params, body, ..
+        }: &FunctionStmt,
+    ) -> String {
         let mut s = "fun ".to_string();
         s.push_str(&name.lexeme);
-        for p in params {
+        s.push('(');
+        for (i, p) in params.iter().enumerate() {
+            if i > 0 {
+                s.push_str(", ");
+            }
             s.push_str(&p.lexeme)
         }
-        s.push('{');
+        s.push_str(") {");
         for stmt in body {
-            s.push_str(&self.print_stmt(stmt));
+            s.push_str(&self.print_stmt(*stmt));
         }
         s.push('}');
         s
     }
 
-    fn parenthesize(&self, name: &str, exprs: &[&Expr]) -> String {
+    fn parenthesize(&self, name: &str, exprs: &[ExprId]) -> String {
         let mut s = "(".to_string();
         s.push_str(name);
         for e in exprs {
             s.push(' ');
-            s.push_str(&self.print_expr(e));
+            s.push_str(&self.print_expr(*e));
         }
-        s.push_str(")");
+        s.push(')');
         s
     }
 }
 
+// Prints the tree back out as Lox source that parses to the same tree, apart from
+// positions. `for` loops come out as the `while` loops they were turned into. Parens are
+// added wherever the tree needs them to parse the same way, which for trees straight
+// from the parser is nowhere but its groupings.
+pub struct SourceEmitter<'a> {
+    ast: &'a Ast,
+    out: String,
+    indent: usize,
+}
+
+// How tightly each kind of expression binds, loosest first
+#[derive(Clone, Copy, Debug, PartialEq, PartialOrd)]
+enum Precedence {
+    Comma,
+    Ternary,
+    Assignment,
+    Or,
+    And,
+    Equality,
+    Comparison,
+    Term,
+    Factor,
+    Unary,
+    Call,
+}
+
+impl<'a> SourceEmitter<'a> {
+    pub fn new(ast: &'a Ast) -> Self {
+        SourceEmitter {
+            ast,
+            out: String::new(),
+            indent: 0,
+        }
+    }
+
+    pub fn emit(mut self, program: &[StmtId]) -> String {
+        for stmt in program {
+            self.line(*stmt);
+        }
+        self.out
+    }
+
+    pub fn emit_expr(mut self, expr: ExprId) -> String {
+        self.expr(expr, Precedence::Comma);
+        self.out
+    }
+
+    // A function or method, declared as a function
+    pub fn emit_function(mut self, function: &FunctionStmt) -> String {
+        self.out.push_str("fun ");
+        self.function(function);
+        self.out.push('\n');
+        self.out
+    }
+
+    fn line(&mut self, stmt: StmtId) {
+        for _ in 0..self.indent {
+            self.out.push_str("    ");
+        }
+        self.stmt(stmt);
+        self.out.push('\n');
+    }
+
+    fn stmt(&mut self, id: StmtId) {
+        match &self.ast[id] {
+            Stmt::Block(stmts) => self.block(stmts),
+            Stmt::Break(_) => self.out.push_str("break;"),
+            Stmt::Class(class) => {
+                self.out.push_str("class ");
+                self.out.push_str(&class.name.lexeme);
+                if let Some(superclass) = class.superclass {
+                    self.out.push_str(" < ");
+                    self.expr(superclass, Precedence::Call);
+                }
+                if class.methods.is_empty() {
+                    self.out.push_str(" {}");
+                    return;
+                }
+                self.out.push_str(" {\n");
+                self.indent += 1;
+                for method in &class.methods {
+                    for _ in 0..self.indent {
+                        self.out.push_str("    ");
+                    }
+                    self.function(method);
+                    self.out.push('\n');
+                }
+                self.indent -= 1;
+                self.close_brace();
+            }
+            Stmt::Debugger => self.out.push_str("debugger;"),
+            Stmt::Expression(e) => {
+                self.expr(*e, Precedence::Comma);
+                self.out.push(';');
+            }
+            Stmt::Function(function) => {
+                self.out.push_str("fun ");
+                self.function(function);
+            }
+            Stmt::If(e) => {
+                self.out.push_str("if (");
+                self.expr(e.condition, Precedence::Comma);
+                self.out.push_str(") ");
+                match e.else_branch {
+                    // Otherwise the else would go with the inner if
+                    Some(_) if self.ends_in_open_if(e.then_branch) => self.block(&[e.then_branch]),
+                    _ => self.stmt(e.then_branch),
+                }
+                if let Some(else_branch) = e.else_branch {
+                    self.out.push_str(" else ");
+                    self.stmt(else_branch);
+                }
+            }
+            Stmt::Print(e) => self.keyword_stmt("print", *e),
+            Stmt::Return(r) => self.keyword_stmt("return", r.value),
+            Stmt::While(e) => {
+                self.out.push_str("while (");
+                self.expr(e.condition, Precedence::Comma);
+                self.out.push_str(") ");
+                self.stmt(e.body);
+            }
+            Stmt::Var(v) => {
+                self.out.push_str("var ");
+                self.out.push_str(&v.name.lexeme);
+                if let Some(type_name) = &v.type_annotation {
+                    self.out.push_str(": ");
+                    self.out.push_str(&type_name.lexeme);
+                }
+                self.out.push_str(" = ");
+                self.expr(v.initializer, Precedence::Assignment);
+                self.out.push(';');
+            }
+            Stmt::Yield(y) => self.keyword_stmt("yield", y.value),
+        }
+    }
+
+    fn keyword_stmt(&mut self, keyword: &str, expr: ExprId) {
+        self.out.push_str(keyword);
+        self.out.push(' ');
+        self.expr(expr, Precedence::Comma);
+        self.out.push(';');
+    }
+
+    fn block(&mut self, stmts: &[StmtId]) {
+        if stmts.is_empty() {
+            self.out.push_str("{}");
+            return;
+        }
+        self.out.push_str("{\n");
+        self.indent += 1;
+        for stmt in stmts {
+            self.line(*stmt);
+        }
+        self.indent -= 1;
+        self.close_brace();
+    }
+
+    fn close_brace(&mut self) {
+        for _ in 0..self.indent {
+            self.out.push_str("    ");
+        }
+        self.out.push('}');
+    }
+
+    // Whether an else written after the statement would be taken as part of it
+    fn ends_in_open_if(&self, stmt: StmtId) -> bool {
+        match &self.ast[stmt] {
+            Stmt::If(e) => e
+                .else_branch
+                .is_none_or(|else_branch| self.ends_in_open_if(else_branch)),
+            Stmt::While(e) => self.ends_in_open_if(e.body),
+            _ => false,
+        }
+    }
+
+    // Everything after `fun`
+    fn function(&mut self, function: &FunctionStmt) {
+        self.out.push_str(&function.name.lexeme);
+        self.out.push('(');
+        for (i, param) in function.params.iter().enumerate() {
+            if i > 0 {
+                self.out.push_str(", ");
+            }
+            self.out.push_str(&param.lexeme);
+            if let Some(Some(type_name)) = function.param_types.get(i) {
+                self.out.push_str(": ");
+                self.out.push_str(&type_name.lexeme);
+            }
+        }
+        self.out.push_str(") ");
+        if let Some(type_name) = &function.return_type {
+            self.out.push_str("-> ");
+            self.out.push_str(&type_name.lexeme);
+            self.out.push(' ');
+        }
+        self.block(&function.body);
+    }
+
+    fn precedence(&self, id: ExprId) -> Precedence {
+        match &self.ast[id] {
+            Expr::Assign(_) | Expr::Set(_) => Precedence::Assignment,
+            Expr::Binary(e) => binary_precedence(&e.operator.token_type),
+            Expr::Logical(e) if e.operator.token_type == TokenType::And => Precedence::And,
+            Expr::Logical(_) => Precedence::Or,
+            Expr::Unary(_) => Precedence::Unary,
+            // Negative numbers only come from the optimizer, and are written as negation
+            Expr::Literal(TokenLiteral::Number(n)) if n.is_sign_negative() => Precedence::Unary,
+            Expr::Call(_)
+            | Expr::Get(_)
+            | Expr::Grouping(_)
+            | Expr::Literal(_)
+            | Expr::Super(_)
+            | Expr::This(_)
+            | Expr::Variable(_) => Precedence::Call,
+        }
+    }
+
+    // Writes the expression, in parens if it binds more loosely than `min`
+    fn expr(&mut self, id: ExprId, min: Precedence) {
+        let parens = self.precedence(id) < min;
+        if parens {
+            self.out.push('(');
+        }
+        match &self.ast[id] {
+            Expr::Assign(e) => {
+                self.out.push_str(&e.name.lexeme);
+                self.out.push_str(" = ");
+                self.expr(e.value, Precedence::Assignment);
+            }
+            Expr::Binary(e) if e.operator.token_type == TokenType::QuestionMark => {
+                self.expr(e.left, Precedence::Ternary);
+                self.out.push_str(" ? ");
+                match &self.ast[e.right] {
+                    Expr::Binary(options) if options.operator.token_type == TokenType::Colon => {
+                        self.expr(options.left, Precedence::Assignment);
+                        self.out.push_str(" : ");
+                        self.expr(options.right, Precedence::Assignment);
+                    }
+                    _ => self.expr(e.right, Precedence::Assignment),
+                }
+            }
+            Expr::Binary(BinaryExpr {
+                left,
+                operator,
+                right,
+            })
+            | Expr::Logical(LogicalExpr {
+                left,
+                operator,
+                right,
+            }) => {
+                let precedence = self.precedence(id);
+                self.expr(*left, precedence);
+                if operator.token_type != TokenType::Comma {
+                    self.out.push(' ');
+                }
+                self.out.push_str(&operator.lexeme);
+                self.out.push(' ');
+                // Operators group to the left, so one on the right needs parens
+                self.expr(*right, next_precedence(precedence));
+            }
+            Expr::Call(e) => {
+                self.expr(e.callee, Precedence::Call);
+                self.out.push('(');
+                for (i, argument) in e.arguments.iter().enumerate() {
+                    if i > 0 {
+                        self.out.push_str(", ");
+                    }
+                    self.expr(*argument, Precedence::Assignment);
+                }
+                self.out.push(')');
+            }
+            Expr::Get(e) => {
+                self.expr(e.object, Precedence::Call);
+                self.out.push('.');
+                self.out.push_str(&e.name.lexeme);
+            }
+            Expr::Grouping(e) => {
+                self.out.push('(');
+                self.expr(*e, Precedence::Comma);
+                self.out.push(')');
+            }
+            Expr::Literal(literal) => match literal {
+                TokenLiteral::None | TokenLiteral::Nil => self.out.push_str("nil"),
+                TokenLiteral::True => self.out.push_str("true"),
+                TokenLiteral::False => self.out.push_str("false"),
+                TokenLiteral::String(s) => {
+                    let _ = write!(self.out, "\"{}\"", s);
+                }
+                TokenLiteral::Number(n) if n.is_sign_negative() => {
+                    let _ = write!(self.out, "-{}", -n);
+                }
+                TokenLiteral::Number(n) => {
+                    let _ = write!(self.out, "{}", n);
+                }
+            },
+            Expr::Set(e) => {
+                self.expr(e.object, Precedence::Call);
+                self.out.push('.');
+                self.out.push_str(&e.name.lexeme);
+                self.out.push_str(" = ");
+                self.expr(e.value, Precedence::Assignment);
+            }
+            Expr::Super(e) => {
+                self.out.push_str("super.");
+                self.out.push_str(&e.method.lexeme);
+            }
+            Expr::This(_) => self.out.push_str("this"),
+            Expr::Unary(e) => {
+                self.out.push_str(&e.operator.lexeme);
+                self.expr(e.right, Precedence::Unary);
+            }
+            Expr::Variable(v) => self.out.push_str(&v.name.lexeme),
+        }
+        if parens {
+            self.out.push(')');
+        }
+    }
+}
+
+fn binary_precedence(operator: &TokenType) -> Precedence {
+    match operator {
+        TokenType::Comma => Precedence::Comma,
+        TokenType::QuestionMark | TokenType::Colon => Precedence::Ternary,
+        TokenType::BangEqual | TokenType::EqualEqual => Precedence::Equality,
+        TokenType::Greater | TokenType::GreaterEqual | TokenType::Less | TokenType::LessEqual => {
+            Precedence::Comparison
+        }
+        TokenType::Minus | TokenType::Plus => Precedence::Term,
+        _ => Precedence::Factor,
+    }
+}
+
+fn next_precedence(precedence: Precedence) -> Precedence {
+    match precedence {
+        Precedence::Comma => Precedence::Ternary,
+        Precedence::Ternary => Precedence::Assignment,
+        Precedence::Assignment => Precedence::Or,
+        Precedence::Or => Precedence::And,
+        Precedence::And => Precedence::Equality,
+        Precedence::Equality => Precedence::Comparison,
+        Precedence::Comparison => Precedence::Term,
+        Precedence::Term => Precedence::Factor,
+        Precedence::Factor => Precedence::Unary,
+        Precedence::Unary | Precedence::Call => Precedence::Call,
+    }
+}
+
+// Draws the tree as a Graphviz graph, with a node for each statement and expression and
+// edges labelled with the part of their parent they are
+pub struct DotPrinter<'a> {
+    ast: &'a Ast,
+    out: String,
+    // Methods don't have statement ids, so they're numbered separately
+    functions: usize,
+}
+
+impl<'a> DotPrinter<'a> {
+    pub fn new(ast: &'a Ast) -> Self {
+        DotPrinter {
+            ast,
+            out: String::new(),
+            functions: 0,
+        }
+    }
+
+    pub fn print(mut self, program: &[StmtId]) -> String {
+        self.out.push_str("digraph ast {\n  node [shape=box];\n");
+        self.node("program", "program");
+        for (i, stmt) in program.iter().enumerate() {
+            let node = self.stmt(*stmt);
+            self.edge("program", &node, &i.to_string());
+        }
+        self.out.push_str("}\n");
+        self.out
+    }
+
+    fn stmt(&mut self, id: StmtId) -> String {
+        let node = format!("s{}", id.0);
+        match &self.ast[id] {
+            Stmt::Block(stmts) => {
+                self.node(&node, "block");
+                for (i, stmt) in stmts.iter().enumerate() {
+                    let child = self.stmt(*stmt);
+                    self.edge(&node, &child, &i.to_string());
+                }
+            }
+            Stmt::Break(_) => self.node(&node, "break"),
+            Stmt::Class(class) => {
+                self.node(&node, &format!("class {}", class.name.lexeme));
+                if let Some(superclass) = class.superclass {
+                    let child = self.expr(superclass);
+                    self.edge(&node, &child, "superclass");
+                }
+                for method in &class.methods {
+                    let child = self.function(None, method);
+                    self.edge(&node, &child, "method");
+                }
+            }
+            Stmt::Debugger => self.node(&node, "debugger"),
+            Stmt::Expression(e) => self.unary_stmt(&node, "expression", *e),
+            Stmt::Function(function) => {
+                self.function(Some(&node), function);
+            }
+            Stmt::If(e) => {
+                self.node(&node, "if");
+                let condition = self.expr(e.condition);
+                self.edge(&node, &condition, "condition");
+                let then_branch = self.stmt(e.then_branch);
+                self.edge(&node, &then_branch, "then");
+                if let Some(else_branch) = e.else_branch {
+                    let else_branch = self.stmt(else_branch);
+                    self.edge(&node, &else_branch, "else");
+                }
+            }
+            Stmt::Print(e) => self.unary_stmt(&node, "print", *e),
+            Stmt::Return(r) => self.unary_stmt(&node, "return", r.value),
+            Stmt::While(e) => {
+                self.node(&node, "while");
+                let condition = self.expr(e.condition);
+                self.edge(&node, &condition, "condition");
+                let body = self.stmt(e.body);
+                self.edge(&node, &body, "body");
+            }
+            Stmt::Var(v) => {
+                self.unary_stmt(&node, &format!("var {}", v.name.lexeme), v.initializer)
+            }
+            Stmt::Yield(y) => self.unary_stmt(&node, "yield", y.value),
+        }
+        node
+    }
+
+    fn unary_stmt(&mut self, node: &str, label: &str, expr: ExprId) {
+        self.node(node, label);
+        let child = self.expr(expr);
+        self.edge(node, &child, "");
+    }
+
+    fn function(&mut self, node: Option<&str>, function: &FunctionStmt) -> String {
+        let node = node.map(|n| n.to_string()).unwrap_or_else(|| {
+            self.functions += 1;
+            format!("f{}", self.functions)
+        });
+        let params: Vec<&str> = function.params.iter().map(|p| p.lexeme.as_str()).collect();
+        let label = format!("fun {}({})", function.name.lexeme, params.join(", "));
+        self.node(&node, &label);
+        for (i, stmt) in function.body.iter().enumerate() {
+            let child = self.stmt(*stmt);
+            self.edge(&node, &child, &i.to_string());
+        }
+        node
+    }
+
+    fn expr(&mut self, id: ExprId) -> String {
+        let node = format!("e{}", id.0);
+        let (label, children): (String, Vec<(&str, ExprId)>) = match &self.ast[id] {
+            Expr::Assign(e) => (format!("{} =", e.name.lexeme), vec![("value", e.value)]),
+            Expr::Binary(_) => return self.binary_chain(id),
+            Expr::Call(e) => {
+                let mut children = vec![("callee", e.callee)];
+                children.extend(e.arguments.iter().map(|a| ("argument", *a)));
+                ("call".to_string(), children)
+            }
+            Expr::Get(e) => (format!(".{}", e.name.lexeme), vec![("object", e.object)]),
+            Expr::Grouping(e) => ("group".to_string(), vec![("", *e)]),
+            Expr::Literal(_) => (PrettyPrinter::new(self.ast).print_expr(id), vec![]),
+            Expr::Logical(e) => (
+                e.operator.lexeme.clone(),
+                vec![("left", e.left), ("right", e.right)],
+            ),
+            Expr::Set(e) => (
+                format!(".{} =", e.name.lexeme),
+                vec![("object", e.object), ("value", e.value)],
+            ),
+            Expr::Super(e) => (format!("super.{}", e.method.lexeme), vec![]),
+            Expr::This(_) => ("this".to_string(), vec![]),
+            Expr::Unary(e) => (e.operator.lexeme.clone(), vec![("", e.right)]),
+            Expr::Variable(v) => (v.name.lexeme.clone(), vec![]),
+        };
+        self.node(&node, &label);
+        for (edge, child) in children {
+            let child_node = self.expr(child);
+            self.edge(&node, &child_node, edge);
+        }
+        node
+    }
+
+    // Chains nest down their left operands, as deep as they're long, so they're walked
+    // down with a stack of the operators passed, printed in the order recursing would
+    fn binary_chain(&mut self, id: ExprId) -> String {
+        let ast = self.ast;
+        let mut chain = Vec::new();
+        let mut left = id;
+        while let Expr::Binary(e) = &ast[left] {
+            let node = format!("e{}", left.0);
+            self.node(&node, &e.operator.lexeme);
+            chain.push((node, e.right));
+            left = e.left;
+        }
+        let mut left_node = self.expr(left);
+        while let Some((node, right)) = chain.pop() {
+            self.edge(&node, &left_node, "left");
+            let right_node = self.expr(right);
+            self.edge(&node, &right_node, "right");
+            left_node = node;
+        }
+        left_node
+    }
+
+    fn node(&mut self, node: &str, label: &str) {
+        let _ = writeln!(self.out, "  {} [label={}];", node, quote(label));
+    }
+
+    fn edge(&mut self, from: &str, to: &str, label: &str) {
+        if label.is_empty() {
+            let _ = writeln!(self.out, "  {} -> {};", from, to);
+        } else {
+            let _ = writeln!(self.out, "  {} -> {} [label={}];", from, to, quote(label));
+        }
+    }
+}
+
+fn quote(s: &str) -> String {
+    format!("\"{}\"", s.replace('\\', "\\\\").replace('"', "\\\""))
+}
+
 #[cfg(test)]
 mod test {
     use super::*;
-    use crate::tokens::{Token, TokenLiteral, TokenType};
+    use crate::{
+        errors::ErrorReporter,
+        parser::Parser,
+        scanner::Scanner,
+        symbol::Symbol,
+        tokens::{Span, Token, TokenLiteral, TokenType},
+    };
+    use proptest::prelude::*;
 
     #[test]
     pub fn can_pretty_print() {
+        let mut ast = Ast::new();
+        let left = ast.add_expr(Expr::Literal(TokenLiteral::Number(1.23)), Span::default());
+        let right = ast.add_expr(Expr::Literal(TokenLiteral::Number(4.5)), Span::default());
         let e = Expr::Binary(BinaryExpr {
-            left: Box::new(Expr::Literal(TokenLiteral::Number(1.23))),
+            left,
             operator: Token {
                 token_type: TokenType::Plus,
                 lexeme: "+".to_string(),
                 literal: TokenLiteral::None,
                 line: 1,
+                column: 6,
+                span: Span { start: 5, end: 6 },
+                symbol: Symbol::default(),
+                trivia: None,
             },
-            right: Box::new(Expr::Literal(TokenLiteral::Number(4.5))),
+            right,
         });
+        let e = ast.add_expr(e, Span::default());
 
-        let pp = PrettyPrinter {};
-        let s = pp.print_expr(&e);
+        let pp = PrettyPrinter::new(&ast);
+        let s = pp.print_expr(e);
         println!("AST: {}", s);
+        assert_eq!(s, "(+ 1.23 4.5)");
+
+        let stmt = ast.add_stmt(Stmt::Print(e), 1, Span::default());
+        let json: serde_json::Value = serde_json::from_str(&ast.to_json(&[stmt])).unwrap();
+        assert_eq!(json["program"], serde_json::json!([0]));
+        assert_eq!(json["stmts"][0]["Print"], 2);
+        assert_eq!(json["exprs"][2]["Binary"]["operator"]["lexeme"], "+");
+        assert_eq!(json["exprs"][2]["Binary"]["right"], 1);
+
+        assert_eq!(
+            DotPrinter::new(&ast).print(&[stmt]),
+            "digraph ast {
+  node [shape=box];
+  program [label=\"program\"];
+  s0 [label=\"print\"];
+  e2 [label=\"+\"];
+  e0 [label=\"1.23\"];
+  e2 -> e0 [label=\"left\"];
+  e1 [label=\"4.5\"];
+  e2 -> e1 [label=\"right\"];
+  s0 -> e2;
+  program -> s0 [label=\"0\"];
+}
+"
+        );
+    }
+
+    #[test]
+    pub fn dot_prints_long_chains_without_recursing() {
+        let (ast, stmts) = parse("print 1 + 2 - 3;");
+        assert_eq!(
+            DotPrinter::new(&ast).print(&stmts),
+            "digraph ast {
+  node [shape=box];
+  program [label=\"program\"];
+  s0 [label=\"print\"];
+  e4 [label=\"-\"];
+  e2 [label=\"+\"];
+  e0 [label=\"1\"];
+  e2 -> e0 [label=\"left\"];
+  e1 [label=\"2\"];
+  e2 -> e1 [label=\"right\"];
+  e4 -> e2 [label=\"left\"];
+  e3 [label=\"3\"];
+  e4 -> e3 [label=\"right\"];
+  s0 -> e4;
+  program -> s0 [label=\"0\"];
+}
+"
+        );
+
+        let (ast, stmts) = parse(&format!("print 0{};", " + 1".repeat(200_000)));
+        let dot = DotPrinter::new(&ast).print(&stmts);
+        assert_eq!(dot.matches("[label=\"left\"]").count(), 200_000);
+    }
+
+    fn parse(src: &str) -> (Ast, Vec<StmtId>) {
+        let reporter = ErrorReporter::new();
+        let mut ast = Ast::new();
+        let stmts = Parser::new(Scanner::new(src, &reporter), &mut ast, &reporter).parse_stmts();
+        assert!(!reporter.had_error(), "{} doesn't parse", src);
+        (ast, stmts)
+    }
+
+    #[test]
+    pub fn pretty_prints_separated_arguments_and_parameters() {
+        let (ast, stmts) = parse("fun add(a, b) { return a + b; } add(1, f(2, 3), \"x\");");
+        let pp = PrettyPrinter::new(&ast);
+        assert_eq!(pp.print_stmt(stmts[0]), "fun add(a, b) {return (+ a b);}");
+        assert_eq!(pp.print_stmt(stmts[1]), "add(1, f(2, 3), \"x\")");
+    }
+
+    // The tree as JSON, with children written out in place of their ids, so trees built
+    // in a different order compare equal, and without the positions of tokens
+    fn shape(ast: &Ast, program: &[StmtId]) -> serde_json::Value {
+        use serde_json::Value;
+
+        fn inline(ast: &Ast, value: &mut Value) {
+            let map = match value {
+                Value::Object(map) => map,
+                Value::Array(values) => return values.iter_mut().for_each(|v| inline(ast, v)),
+                _ => return,
+            };
+            for key in ["line", "column", "span"] {
+                map.remove(key);
+            }
+            for (key, child) in map.iter_mut() {
+                match key.as_str() {
+                    "Grouping" | "Expression" | "Print" | "condition" | "value" | "initializer"
+                    | "left" | "right" | "callee" | "arguments" | "object" | "superclass" => {
+                        node(ast, child, |id| serde_json::to_value(&ast[ExprId(id)]))
+                    }
+                    "Block" | "then_branch" | "else_branch" | "body" => {
+                        node(ast, child, |id| serde_json::to_value(&ast[StmtId(id)]))
+                    }
+                    _ => inline(ast, child),
+                }
+            }
+        }
+
+        // Replaces ids, alone or in an array, with the nodes they refer to
+        fn node(
+            ast: &Ast,
+            value: &mut Value,
+            lookup: impl Fn(usize) -> serde_json::Result<Value> + Copy,
+        ) {
+            match value {
+                Value::Number(id) => {
+                    *value = lookup(id.as_u64().unwrap() as usize).unwrap();
+                    inline(ast, value);
+                }
+                Value::Array(values) => values.iter_mut().for_each(|v| node(ast, v, lookup)),
+                _ => {}
+            }
+        }
+
+        let mut value = Value::Array(
+            program
+                .iter()
+                .map(|id| serde_json::to_value(&ast[*id]).unwrap())
+                .collect(),
+        );
+        inline(ast, &mut value);
+        value
+    }
+
+    // Emits the parsed source, checks it parses back to the same tree, and returns it
+    fn round_trip(src: &str) -> String {
+        let (ast, stmts) = parse(src);
+        let emitted = SourceEmitter::new(&ast).emit(&stmts);
+        let (reparsed, restmts) = parse(&emitted);
+        assert_eq!(
+            shape(&ast, &stmts),
+            shape(&reparsed, &restmts),
+            "{} was emitted as {}",
+            src,
+            emitted
+        );
+        // Emitting it again changes nothing
+        assert_eq!(SourceEmitter::new(&reparsed).emit(&restmts), emitted);
+        emitted
+    }
+
+    #[test]
+    pub fn emits_source_that_parses_to_the_same_tree() {
+        round_trip(include_str!("test.lox"));
+        assert_eq!(
+            round_trip("for (var i = 0; i < 3; i = i + 1) print -(i - 1) * 2;"),
+            "{
+    var i = 0;
+    while (i < 3) {
+        print -(i - 1) * 2;
+        i = i + 1;
+    }
+}
+"
+        );
+        assert_eq!(
+            round_trip("class A < B { init(x: Number) -> Nil { this.x = x; } m() {} }"),
+            "class A < B {
+    init(x: Number) -> Nil {
+        this.x = x;
+    }
+    m() {}
+}
+"
+        );
+        assert_eq!(
+            round_trip("if (a) { if (b) print 1; } else print 2; var c: Bool;"),
+            "if (a) {
+    if (b) print 1;
+} else print 2;
+var c: Bool = nil;
+"
+        );
+        round_trip("print a ? b : c ? d : e, f;");
+        round_trip("a.b = c = d.e(f)(g) or !h and i == j; return; yield 1; debugger;");
+    }
+
+    #[test]
+    pub fn adds_parens_the_tree_needs() {
+        // The tree for (1 - 2) - (3 - 4), as the optimizer might leave it without its
+        // groupings
+        let mut ast = Ast::new();
+        let numbers: Vec<ExprId> = (1..=4)
+            .map(|n| {
+                ast.add_expr(
+                    Expr::Literal(TokenLiteral::Number(n as f64)),
+                    Span::default(),
+                )
+            })
+            .collect();
+        let minus = Token::new(
+            TokenType::Minus,
+            "-".to_string(),
+            TokenLiteral::None,
+            1,
+            1,
+            Span { start: 0, end: 1 },
+        );
+        let subtract = |ast: &mut Ast, left, right| {
+            let e = Expr::Binary(BinaryExpr {
+                left,
+                operator: minus.clone(),
+                right,
+            });
+            ast.add_expr(e, Span::default())
+        };
+        let left = subtract(&mut ast, numbers[0], numbers[1]);
+        let right = subtract(&mut ast, numbers[2], numbers[3]);
+        let e = subtract(&mut ast, left, right);
+        let stmt = ast.add_stmt(Stmt::Print(e), 1, Span::default());
+        assert_eq!(
+            SourceEmitter::new(&ast).emit(&[stmt]),
+            "print 1 - 2 - (3 - 4);\n"
+        );
+    }
+
+    // Random expressions that parse, with assignments kept in parens where they'd
+    // otherwise be invalid targets. Ternaries are only allowed at the top of a statement.
+    fn expression() -> impl Strategy<Value = String> {
+        let leaf = prop_oneof![
+            (0..1000u32).prop_map(|n| n.to_string()),
+            (0..100u32).prop_map(|n| format!("{}.5", n)),
+            "[a-e]",
+            Just("\"s\"".to_string()),
+            Just("true".to_string()),
+            Just("nil".to_string()),
+            Just("this".to_string()),
+        ];
+        leaf.prop_recursive(6, 48, 4, |inner| {
+            let operator = prop_oneof![
+                Just("+"),
+                Just("-"),
+                Just("*"),
+                Just("/"),
+                Just("=="),
+                Just("!="),
+                Just("<"),
+                Just(">="),
+                Just("and"),
+                Just("or"),
+            ];
+            prop_oneof![
+                (inner.clone(), operator, inner.clone())
+                    .prop_map(|(l, op, r)| format!("{} {} {}", l, op, r)),
+                (prop_oneof![Just("-"), Just("!")], inner.clone())
+                    .prop_map(|(op, e)| format!("{}{}", op, e)),
+                inner.clone().prop_map(|e| format!("({})", e)),
+                ("[a-e]", prop::collection::vec(inner.clone(), 0..3))
+                    .prop_map(|(f, args)| format!("{}({})", f, args.join(", "))),
+                inner.clone().prop_map(|e| format!("({}).x", e)),
+                ("[a-e]", inner).prop_map(|(v, e)| format!("({} = {})", v, e)),
+            ]
+        })
+    }
+
+    fn statement() -> impl Strategy<Value = String> {
+        prop_oneof![
+            expression().prop_map(|e| format!("print {};", e)),
+            (expression(), expression(), expression())
+                .prop_map(|(c, t, f)| format!("print {} ? {} : {}, {};", c, t, f, c)),
+            ("[a-e]", expression()).prop_map(|(v, e)| format!("var {} = {};", v, e)),
+            ("[a-e]", expression()).prop_map(|(v, e)| format!("{}.y = {};", v, e)),
+            (expression(), expression(), expression())
+                .prop_map(|(c, t, f)| format!("if ({}) if ({}) print 1; else print {};", c, t, f)),
+            expression().prop_map(|e| format!("while ({}) {{ break; }}", e)),
+        ]
+    }
+
+    proptest! {
+        #[test]
+        fn random_programs_round_trip(stmts in prop::collection::vec(statement(), 1..4)) {
+            round_trip(&stmts.concat());
+        }
     }
 }
@@ -0,0 +1,152 @@
+use std::{
+    path::Path,
+    process::{Command, Stdio},
+    time::{Duration, Instant},
+};
+
+// The ways a script can be run, to compare. There's only the tree-walking interpreter
+// so far, which can run the program as parsed or after the optimizer has been over it.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Engine {
+    Interpreter,
+    Optimized,
+}
+
+impl Engine {
+    fn name(self) -> &'static str {
+        match self {
+            Engine::Interpreter => "interpreter",
+            Engine::Optimized => "optimized",
+        }
+    }
+
+    fn args(self) -> &'static [&'static str] {
+        match self {
+            Engine::Interpreter => &[],
+            Engine::Optimized => &["-O"],
+        }
+    }
+}
+
+#[derive(Debug, PartialEq)]
+pub struct Stats {
+    pub min: Duration,
+    pub mean: Duration,
+    pub stddev: Duration,
+}
+
+impl Stats {
+    // The standard deviation is of the sample, as the runs are a sample of all the
+    // times the script could take
+    pub fn of(times: &[Duration]) -> Stats {
+        let seconds: Vec<f64> = times.iter().map(Duration::as_secs_f64).collect();
+        let mean = seconds.iter().sum::<f64>() / seconds.len() as f64;
+        let variance = if seconds.len() > 1 {
+            let squares: f64 = seconds.iter().map(|s| (s - mean).powi(2)).sum();
+            squares / (seconds.len() - 1) as f64
+        } else {
+            0.0
+        };
+        Stats {
+            min: times.iter().min().copied().unwrap_or_default(),
+            mean: Duration::from_secs_f64(mean),
+            stddev: Duration::from_secs_f64(variance.sqrt()),
+        }
+    }
+}
+
+// Times runs of the script with each engine, after a run of each to warm up the file
+// cache. Scripts are run by this same rlox in processes of their own, with their output
+// thrown away, so the times include starting up. Returns false if a run failed.
+pub fn run_bench(file: &str, iterations: usize, engines: &[Engine]) -> bool {
+    let rlox = std::env::current_exe().expect("Could not find the rlox executable");
+    println!("Benchmarking {}, {} iterations", file, iterations);
+    println!(
+        "{:<12} {:>12} {:>12} {:>12}",
+        "engine", "min", "mean", "stddev"
+    );
+    let mut results = Vec::new();
+    for engine in engines {
+        let mut times = Vec::new();
+        for _ in 0..=iterations {
+            match time_run(&rlox, file, *engine) {
+                Ok(time) => times.push(time),
+                Err(e) => {
+                    eprintln!("{} failed with {}: {}", file, engine.name(), e);
+                    return false;
+                }
+            }
+        }
+        let stats = Stats::of(&times[1..]);
+        println!(
+            "{:<12} {:>12} {:>12} {:>12}",
+            engine.name(),
+            format_duration(stats.min),
+            format_duration(stats.mean),
+            format_duration(stats.stddev)
+        );
+        results.push((engine.name(), stats));
+    }
+    if let [(first, first_stats), (second, second_stats)] = &results[..] {
+        let (faster, slower, ratio) = if second_stats.mean < first_stats.mean {
+            (
+                second,
+                first,
+                first_stats.mean.as_secs_f64() / second_stats.mean.as_secs_f64(),
+            )
+        } else {
+            (
+                first,
+                second,
+                second_stats.mean.as_secs_f64() / first_stats.mean.as_secs_f64(),
+            )
+        };
+        println!("\n{} is {:.2}x faster than {}", faster, ratio, slower);
+    }
+    true
+}
+
+fn time_run(rlox: &Path, file: &str, engine: Engine) -> Result<Duration, String> {
+    let start = Instant::now();
+    let status = Command::new(rlox)
+        .args(engine.args())
+        .arg(file)
+        .stdin(Stdio::null())
+        .stdout(Stdio::null())
+        .stderr(Stdio::null())
+        .status()
+        .map_err(|e| e.to_string())?;
+    let time = start.elapsed();
+    match status.code() {
+        Some(0) => Ok(time),
+        Some(code) => Err(format!("exit code {}", code)),
+        None => Err("no exit code".to_string()),
+    }
+}
+
+fn format_duration(duration: Duration) -> String {
+    let seconds = duration.as_secs_f64();
+    if seconds >= 1.0 {
+        format!("{:.3}s", seconds)
+    } else {
+        format!("{:.2}ms", seconds * 1000.0)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    pub fn summarizes_times() {
+        let ms = Duration::from_millis;
+        let stats = Stats::of(&[ms(10), ms(14), ms(12), ms(12)]);
+        assert_eq!(stats.min, ms(10));
+        assert_eq!(stats.mean, ms(12));
+        // The sample variance is 8/3 ms²
+        assert_eq!(stats.stddev.as_micros(), 1632);
+        assert_eq!(Stats::of(&[ms(5)]).stddev, Duration::ZERO);
+        assert_eq!(format_duration(ms(1500)), "1.500s");
+        assert_eq!(format_duration(Duration::from_micros(12345)), "12.35ms");
+    }
+}
@@ -0,0 +1,371 @@
+//! A C interface for embedding the interpreter, built with the `capi` feature, as in
+//! `cargo rustc --lib --release --features capi --crate-type cdylib`. The declarations
+//! are in `include/rlox.h`.
+//!
+//! Every `RloxValue` handle a function returns is owned by the caller, and has to be
+//! freed with `rlox_value_free`, including those a native returns, which the interpreter
+//! takes and frees itself. Arguments handed to a native are only borrowed for the call.
+//! Strings returned to the caller are freed with `rlox_string_free`, apart from
+//! `rlox_last_error`'s, which the interpreter keeps until the next call.
+//!
+//! Lox calls grow the stack as they get deeper, on the heap, up to the depth set with
+//! `rlox_set_max_call_depth`. Parsing doesn't, so `rlox_eval` needs about 1MB of the
+//! calling thread's stack for code nested as deeply as the parser allows, in a release
+//! build. Debug builds need about 3MB.
+
+use std::{
+    ffi::{CStr, CString},
+    os::raw::{c_char, c_int, c_void},
+    ptr,
+};
+
+use crate::{
+    errors::ErrorReporter,
+    interpreter::{Interpreter, InterpreterOptions, RuntimeError},
+    loxvalue::{Arity, LoxValue},
+};
+
+// The same codes the rlox command exits with
+pub const RLOX_OK: c_int = 0;
+pub const RLOX_COMPILE_ERROR: c_int = 65;
+pub const RLOX_RUNTIME_ERROR: c_int = 70;
+
+pub const RLOX_NIL: c_int = 0;
+pub const RLOX_BOOL: c_int = 1;
+pub const RLOX_NUMBER: c_int = 2;
+pub const RLOX_STRING: c_int = 3;
+pub const RLOX_OBJECT: c_int = 4;
+pub const RLOX_ERROR: c_int = 5;
+
+pub struct RloxVm {
+    // Declared first so it's dropped before the reporter it borrows
+    interpreter: Interpreter<'static>,
+    _reporter: Box<ErrorReporter>,
+    last_error: Option<CString>,
+}
+
+// A value, or an error a native returns in place of one
+pub struct RloxValue(Result<LoxValue, String>);
+
+pub type RloxNative = extern "C" fn(
+    user_data: *mut c_void,
+    argc: usize,
+    argv: *const *const RloxValue,
+) -> *mut RloxValue;
+
+fn into_handle(value: Result<LoxValue, String>) -> *mut RloxValue {
+    Box::into_raw(Box::new(RloxValue(value)))
+}
+
+// Strings with a NUL in them can't be handed over whole, so they're cut short there
+fn into_c_string(s: &str) -> CString {
+    let end = s.find('\0').unwrap_or(s.len());
+    CString::new(&s[..end]).unwrap()
+}
+
+unsafe fn str_arg<'a>(s: *const c_char) -> Option<&'a str> {
+    if s.is_null() {
+        return None;
+    }
+    CStr::from_ptr(s).to_str().ok()
+}
+
+#[no_mangle]
+pub extern "C" fn rlox_new() -> *mut RloxVm {
+    let reporter = Box::new(ErrorReporter::new());
+    // The box keeps the reporter where it is for as long as the interpreter lives
+    let borrowed: &'static ErrorReporter = unsafe { &*(reporter.as_ref() as *const _) };
+    Box::into_raw(Box::new(RloxVm {
+        interpreter: Interpreter::new(borrowed, InterpreterOptions::default()),
+        _reporter: reporter,
+        last_error: None,
+    }))
+}
+
+/// # Safety
+/// `vm` has to come from `rlox_new`, and can't be used again afterwards.
+#[no_mangle]
+pub unsafe extern "C" fn rlox_free(vm: *mut RloxVm) {
+    if !vm.is_null() {
+        drop(Box::from_raw(vm));
+    }
+}
+
+/// Sets how many calls deep scripts can go before a stack overflow error, 10000 unless
+/// it's set.
+///
+/// # Safety
+/// `vm` has to come from `rlox_new`.
+#[no_mangle]
+pub unsafe extern "C" fn rlox_set_max_call_depth(vm: *mut RloxVm, depth: usize) {
+    (*vm).interpreter.set_max_call_depth(depth);
+}
+
+/// Runs `source`, returning `RLOX_OK` or the kind of error it had.
+///
+/// # Safety
+/// `vm` has to come from `rlox_new`, and `source` has to be a NUL-terminated string.
+#[no_mangle]
+pub unsafe extern "C" fn rlox_eval(vm: *mut RloxVm, source: *const c_char) -> c_int {
+    let vm = &mut *vm;
+    vm.last_error = None;
+    let source = match str_arg(source) {
+        Some(source) => source,
+        None => {
+            vm.last_error = Some(into_c_string("Source isn't valid UTF-8"));
+            return RLOX_COMPILE_ERROR;
+        }
+    };
+    let outcome = vm.interpreter.run(source);
+    if !outcome.diagnostics.is_empty() {
+        let errors: Vec<_> = outcome.diagnostics.iter().map(|d| d.to_string()).collect();
+        vm.last_error = Some(into_c_string(&errors.join("\n")));
+    }
+    if outcome.had_error {
+        RLOX_COMPILE_ERROR
+    } else if outcome.had_runtime_error {
+        RLOX_RUNTIME_ERROR
+    } else {
+        RLOX_OK
+    }
+}
+
+/// The errors and warnings from the last `rlox_eval`, or NULL if it had none.
+///
+/// # Safety
+/// `vm` has to come from `rlox_new`.
+#[no_mangle]
+pub unsafe extern "C" fn rlox_last_error(vm: *const RloxVm) -> *const c_char {
+    (*vm)
+        .last_error
+        .as_ref()
+        .map_or(ptr::null(), |e| e.as_ptr())
+}
+
+/// Defines a global native. An arity of -1 takes any number of arguments. The native
+/// returns a value, or an error from `rlox_error`, either of which the interpreter
+/// takes. Returning NULL is an error too.
+///
+/// # Safety
+/// `vm` has to come from `rlox_new`, and `name` has to be a NUL-terminated string.
+/// `user_data` is passed to every call of `native`, and has to live as long as `vm`.
+#[no_mangle]
+pub unsafe extern "C" fn rlox_register_native(
+    vm: *mut RloxVm,
+    name: *const c_char,
+    arity: c_int,
+    native: RloxNative,
+    user_data: *mut c_void,
+) {
+    let name = match str_arg(name) {
+        Some(name) => name.to_string(),
+        None => return,
+    };
+    let arity = if arity < 0 {
+        Arity::AtLeast(0)
+    } else {
+        Arity::Exactly(arity as usize)
+    };
+    let error_name = name.clone();
+    (*vm).interpreter.register_native(
+        &name,
+        arity,
+        move |args| -> Result<LoxValue, RuntimeError> {
+            let handles: Vec<RloxValue> = args.iter().map(|a| RloxValue(Ok(a.clone()))).collect();
+            let argv: Vec<*const RloxValue> = handles.iter().map(|h| h as *const _).collect();
+            let result = native(user_data, argv.len(), argv.as_ptr());
+            if result.is_null() {
+                return Err(format!("{}() failed", error_name).into());
+            }
+            Box::from_raw(result).0.map_err(RuntimeError::from)
+        },
+    );
+}
+
+/// The global's value, or NULL if there isn't one with the name.
+///
+/// # Safety
+/// `vm` has to come from `rlox_new`, and `name` has to be a NUL-terminated string.
+#[no_mangle]
+pub unsafe extern "C" fn rlox_get_global(vm: *const RloxVm, name: *const c_char) -> *mut RloxValue {
+    match str_arg(name).and_then(|name| (*vm).interpreter.global(name)) {
+        Some(value) => into_handle(Ok(value)),
+        None => ptr::null_mut(),
+    }
+}
+
+#[no_mangle]
+pub extern "C" fn rlox_nil() -> *mut RloxValue {
+    into_handle(Ok(LoxValue::Nil))
+}
+
+#[no_mangle]
+pub extern "C" fn rlox_bool(b: bool) -> *mut RloxValue {
+    into_handle(Ok(LoxValue::Boolean(b)))
+}
+
+#[no_mangle]
+pub extern "C" fn rlox_number(n: f64) -> *mut RloxValue {
+    into_handle(Ok(LoxValue::Number(n)))
+}
+
+/// A string value, or NULL if `s` isn't valid UTF-8.
+///
+/// # Safety
+/// `s` has to be a NUL-terminated string.
+#[no_mangle]
+pub unsafe extern "C" fn rlox_string(s: *const c_char) -> *mut RloxValue {
+    match str_arg(s) {
+        Some(s) => into_handle(Ok(s.into())),
+        None => ptr::null_mut(),
+    }
+}
+
+/// An error for a native to return, which becomes a runtime error in the script.
+///
+/// # Safety
+/// `message` has to be a NUL-terminated string.
+#[no_mangle]
+pub unsafe extern "C" fn rlox_error(message: *const c_char) -> *mut RloxValue {
+    let message = str_arg(message).unwrap_or("Native function failed");
+    into_handle(Err(message.to_string()))
+}
+
+/// # Safety
+/// `value` has to be a handle that hasn't been freed.
+#[no_mangle]
+pub unsafe extern "C" fn rlox_value_type(value: *const RloxValue) -> c_int {
+    match &(*value).0 {
+        Ok(LoxValue::Nil) => RLOX_NIL,
+        Ok(LoxValue::Boolean(_)) => RLOX_BOOL,
+        Ok(LoxValue::Number(_)) => RLOX_NUMBER,
+        Ok(LoxValue::String(_)) => RLOX_STRING,
+        Ok(LoxValue::Ref(_)) => RLOX_OBJECT,
+        Err(_) => RLOX_ERROR,
+    }
+}
+
+/// The number, or 0 if the value isn't one.
+///
+/// # Safety
+/// `value` has to be a handle that hasn't been freed.
+#[no_mangle]
+pub unsafe extern "C" fn rlox_value_as_number(value: *const RloxValue) -> f64 {
+    match &(*value).0 {
+        Ok(LoxValue::Number(n)) => *n,
+        _ => 0.0,
+    }
+}
+
+/// Whether the value is truthy, as Lox has it.
+///
+/// # Safety
+/// `value` has to be a handle that hasn't been freed.
+#[no_mangle]
+pub unsafe extern "C" fn rlox_value_as_bool(value: *const RloxValue) -> bool {
+    !matches!(
+        &(*value).0,
+        Ok(LoxValue::Nil) | Ok(LoxValue::Boolean(false)) | Err(_)
+    )
+}
+
+/// The value as `print` writes it, or an error's message, to be freed with
+/// `rlox_string_free`.
+///
+/// # Safety
+/// `value` has to be a handle that hasn't been freed.
+#[no_mangle]
+pub unsafe extern "C" fn rlox_value_to_string(value: *const RloxValue) -> *mut c_char {
+    let s = match &(*value).0 {
+        Ok(value) => value.to_string(),
+        Err(message) => message.clone(),
+    };
+    into_c_string(&s).into_raw()
+}
+
+/// # Safety
+/// `value` has to be a handle that hasn't been freed, or NULL.
+#[no_mangle]
+pub unsafe extern "C" fn rlox_value_free(value: *mut RloxValue) {
+    if !value.is_null() {
+        drop(Box::from_raw(value));
+    }
+}
+
+/// # Safety
+/// `s` has to come from `rlox_value_to_string`, or be NULL.
+#[no_mangle]
+pub unsafe extern "C" fn rlox_string_free(s: *mut c_char) {
+    if !s.is_null() {
+        drop(CString::from_raw(s));
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    extern "C" fn add(
+        _: *mut c_void,
+        argc: usize,
+        argv: *const *const RloxValue,
+    ) -> *mut RloxValue {
+        unsafe {
+            let args = std::slice::from_raw_parts(argv, argc);
+            if args.iter().any(|&a| rlox_value_type(a) != RLOX_NUMBER) {
+                return rlox_error(b"add() expects numbers\0".as_ptr() as *const c_char);
+            }
+            rlox_number(args.iter().map(|&a| rlox_value_as_number(a)).sum())
+        }
+    }
+
+    fn c(s: &str) -> CString {
+        CString::new(s).unwrap()
+    }
+
+    #[test]
+    pub fn embeds_through_the_c_interface() {
+        unsafe {
+            let vm = rlox_new();
+            rlox_register_native(vm, c("add").as_ptr(), -1, add, ptr::null_mut());
+            let code = rlox_eval(vm, c("var total = add(1, 2, 3);").as_ptr());
+            assert_eq!(code, RLOX_OK);
+            assert!(rlox_last_error(vm).is_null());
+
+            let total = rlox_get_global(vm, c("total").as_ptr());
+            assert_eq!(rlox_value_type(total), RLOX_NUMBER);
+            assert_eq!(rlox_value_as_number(total), 6.0);
+            let s = rlox_value_to_string(total);
+            assert_eq!(CStr::from_ptr(s).to_str().unwrap(), "6");
+            rlox_string_free(s);
+            rlox_value_free(total);
+            assert!(rlox_get_global(vm, c("missing").as_ptr()).is_null());
+
+            assert_eq!(
+                rlox_eval(vm, c("add(1, \"2\");").as_ptr()),
+                RLOX_RUNTIME_ERROR
+            );
+            let error = CStr::from_ptr(rlox_last_error(vm)).to_str().unwrap();
+            assert!(error.contains("add() expects numbers"));
+            assert_eq!(rlox_eval(vm, c("var;").as_ptr()), RLOX_COMPILE_ERROR);
+            rlox_free(vm);
+        }
+    }
+
+    #[test]
+    pub fn limits_call_depth() {
+        let recurse = c("fun f(n) { return f(n + 1); } f(0);");
+        unsafe {
+            let vm = rlox_new();
+            assert_eq!(rlox_eval(vm, recurse.as_ptr()), RLOX_RUNTIME_ERROR);
+            let error = CStr::from_ptr(rlox_last_error(vm)).to_str().unwrap();
+            assert!(error.contains("max call depth 10000 exceeded"));
+
+            rlox_set_max_call_depth(vm, 10);
+            assert_eq!(rlox_eval(vm, recurse.as_ptr()), RLOX_RUNTIME_ERROR);
+            let error = CStr::from_ptr(rlox_last_error(vm)).to_str().unwrap();
+            assert!(error.contains("max call depth 10 exceeded"));
+            rlox_free(vm);
+        }
+    }
+}
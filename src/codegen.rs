@@ -0,0 +1,149 @@
+use crate::{
+    ast::{Ast, StmtId},
+    errors::ErrorReporter,
+    resolver::Resolver,
+    symbol::Symbol,
+};
+
+mod rust;
+// The runtime is only copied into programs, but is built with the tests to check it
+#[cfg(test)]
+mod rust_runtime;
+
+// The languages scripts can be transpiled to
+#[derive(Clone, Copy, Debug, PartialEq, strum_macros::EnumString)]
+#[strum(serialize_all = "lowercase")]
+pub enum Target {
+    // A standalone program, with the runtime it needs copied into it
+    Rust,
+}
+
+// Gives the program as source in the target language, or None if it has errors. It's
+// resolved first, so programs the interpreter wouldn't run aren't transpiled either.
+pub fn transpile(
+    target: Target,
+    ast: &Ast,
+    program: &[StmtId],
+    reporter: &ErrorReporter,
+) -> Option<String> {
+    Resolver::new(ast, reporter).resolve_stmts(program);
+    if reporter.had_error() {
+        return None;
+    }
+    let code = match target {
+        Target::Rust => rust::RustEmitter::new(ast, reporter).emit(program),
+    };
+    if reporter.had_error() {
+        None
+    } else {
+        Some(code)
+    }
+}
+
+// Lines of code, indented as they're written
+#[derive(Default)]
+pub struct Writer {
+    out: String,
+    indent: usize,
+}
+
+impl Writer {
+    pub fn line(&mut self, text: &str) {
+        if !text.is_empty() {
+            self.out.push_str(&"    ".repeat(self.indent));
+            self.out.push_str(text);
+        }
+        self.out.push('\n');
+    }
+
+    // Writes code written elsewhere, such as by another Writer, at this indent
+    pub fn lines(&mut self, text: &str) {
+        for line in text.lines() {
+            self.line(line);
+        }
+    }
+
+    pub fn indent(&mut self) {
+        self.indent += 1;
+    }
+
+    pub fn dedent(&mut self) {
+        self.indent -= 1;
+    }
+
+    pub fn finish(self) -> String {
+        self.out
+    }
+}
+
+// The locals in scope as code is generated, for targets that turn them into variables of
+// their own. Variables that aren't found are globals. Targets where functions have to
+// say what they capture can find out from end_function.
+#[derive(Default)]
+pub struct Scopes {
+    // Each local's name and what it's called in the generated code
+    scopes: Vec<Vec<(Symbol, String)>>,
+    functions: Vec<FunctionScope>,
+}
+
+struct FunctionScope {
+    // The first scope inside the function
+    depth: usize,
+    captures: Vec<String>,
+}
+
+impl Scopes {
+    pub fn is_global(&self) -> bool {
+        self.scopes.is_empty()
+    }
+
+    pub fn begin(&mut self) {
+        self.scopes.push(Vec::new());
+    }
+
+    pub fn end(&mut self) {
+        self.scopes.pop();
+    }
+
+    pub fn declare(&mut self, name: Symbol, variable: String) {
+        if let Some(scope) = self.scopes.last_mut() {
+            scope.push((name, variable));
+        }
+    }
+
+    // Finds the innermost local with the name, noting it as captured by the functions
+    // it's used from inside
+    pub fn lookup(&mut self, name: &Symbol) -> Option<String> {
+        let (depth, variable) = self
+            .scopes
+            .iter()
+            .enumerate()
+            .rev()
+            .find_map(|(i, scope)| {
+                let (_, variable) = scope.iter().rev().find(|(n, _)| n == name)?;
+                Some((i, variable.clone()))
+            })?;
+        for function in self.functions.iter_mut().filter(|f| f.depth > depth) {
+            if !function.captures.contains(&variable) {
+                function.captures.push(variable.clone());
+            }
+        }
+        Some(variable)
+    }
+
+    // Starts a function, along with a scope for its parameters
+    pub fn begin_function(&mut self) {
+        self.functions.push(FunctionScope {
+            depth: self.scopes.len(),
+            captures: Vec::new(),
+        });
+        self.begin();
+    }
+
+    // Ends the function, giving the variables from outside it that it uses
+    pub fn end_function(&mut self) -> Vec<String> {
+        let function = self.functions.pop().expect("Not in a function");
+        self.scopes.truncate(function.depth);
+        function.captures
+    }
+}
@@ -0,0 +1,556 @@
+use crate::{
+    ast::{Ast, BinaryExpr, ClassStmt, Expr, ExprId, FunctionStmt, Stmt, StmtId},
+    codegen::{Scopes, Writer},
+    errors::ErrorReporter,
+    symbol,
+    tokens::{Token, TokenLiteral, TokenType},
+};
+
+const RUNTIME: &str = include_str!("rust_runtime.rs");
+
+#[derive(Clone, Copy, PartialEq)]
+enum FunctionKind {
+    Function,
+    Method,
+    Initializer,
+}
+
+// Turns a program into a Rust program that does the same, with the values and errors of
+// the interpreter. Locals become Rust variables, shared with the closures that capture
+// them, and globals are looked up by name when they're used, as they can be declared
+// after the functions that use them.
+pub struct RustEmitter<'a> {
+    ast: &'a Ast,
+    reporter: &'a ErrorReporter,
+    out: Writer,
+    scopes: Scopes,
+    functions: Vec<FunctionKind>,
+}
+
+impl<'a> RustEmitter<'a> {
+    pub fn new(ast: &'a Ast, reporter: &'a ErrorReporter) -> Self {
+        RustEmitter {
+            ast,
+            reporter,
+            out: Writer::default(),
+            scopes: Scopes::default(),
+            functions: Vec::new(),
+        }
+    }
+
+    pub fn emit(mut self, program: &[StmtId]) -> String {
+        self.out
+            .line("// Generated by rlox. Build it with `rustc -O`, it only needs std.");
+        self.out.line("#![allow(unused, unreachable_code)]");
+        self.out.line("");
+        self.out.line("mod lox {");
+        self.out.indent();
+        self.out.lines(RUNTIME);
+        self.out.dedent();
+        self.out.line("}");
+        self.out.line("");
+        self.out.line("use lox::*;");
+        self.out.line("use std::{collections::HashMap, rc::Rc};");
+        self.out.line("");
+        self.out.line("fn main() {");
+        self.out.line("    start(run);");
+        self.out.line("}");
+        self.out.line("");
+        self.out.line("fn run() -> Result<(), Error> {");
+        self.out.indent();
+        self.stmts(program);
+        self.out.line("Ok(())");
+        self.out.dedent();
+        self.out.line("}");
+        self.out.finish()
+    }
+
+    fn stmts(&mut self, stmts: &[StmtId]) {
+        for stmt in stmts {
+            self.stmt(*stmt);
+        }
+    }
+
+    // The branch of an if or body of a loop, in braces the caller has written. Blocks
+    // don't need braces of their own.
+    fn branch(&mut self, stmt: StmtId) {
+        self.out.indent();
+        self.scopes.begin();
+        match &self.ast[stmt] {
+            Stmt::Block(stmts) => self.stmts(stmts),
+            _ => self.stmt(stmt),
+        }
+        self.scopes.end();
+        self.out.dedent();
+    }
+
+    fn stmt(&mut self, id: StmtId) {
+        match &self.ast[id] {
+            Stmt::Block(stmts) => {
+                self.out.line("{");
+                self.out.indent();
+                self.scopes.begin();
+                self.stmts(stmts);
+                self.scopes.end();
+                self.out.dedent();
+                self.out.line("}");
+            }
+            Stmt::Break(_) => self.out.line("break;"),
+            Stmt::Class(class) => self.class(class),
+            // There's no debugger to stop in
+            Stmt::Debugger => {}
+            Stmt::Expression(expr) => {
+                let expr = self.expr(*expr);
+                self.out.line(&format!("let _ = {};", expr));
+            }
+            Stmt::Function(function) => {
+                let name = &function.name;
+                match self.declare(name) {
+                    Some(variable) => {
+                        self.out
+                            .line(&format!("let {} = Var::new(Value::Nil);", variable));
+                        let prefix = format!("{}.set(", variable);
+                        self.function(&prefix, function, FunctionKind::Function, ");");
+                    }
+                    None => {
+                        let prefix = format!("define_global({:?}, ", name.lexeme);
+                        self.function(&prefix, function, FunctionKind::Function, ");");
+                    }
+                }
+            }
+            Stmt::If(e) => {
+                let condition = self.expr(e.condition);
+                self.out.line(&format!("if truthy(&{}) {{", condition));
+                self.branch(e.then_branch);
+                if let Some(else_branch) = e.else_branch {
+                    self.out.line("} else {");
+                    self.branch(else_branch);
+                }
+                self.out.line("}");
+            }
+            Stmt::Print(expr) => {
+                let expr = self.expr(*expr);
+                self.out.line(&format!("println!(\"{{}}\", {});", expr));
+            }
+            Stmt::Return(r) => {
+                let value = self.expr(r.value);
+                if self.functions.last() == Some(&FunctionKind::Initializer) {
+                    self.out.line("return Ok(v_this.get());");
+                } else {
+                    self.out.line(&format!("return Ok({});", value));
+                }
+            }
+            Stmt::While(e) => {
+                let condition = self.expr(e.condition);
+                self.out.line(&format!("while truthy(&{}) {{", condition));
+                self.branch(e.body);
+                self.out.line("}");
+            }
+            Stmt::Var(v) => {
+                // Declared after the initializer, which can't refer to it
+                let value = self.expr(v.initializer);
+                match self.declare(&v.name) {
+                    Some(variable) => self
+                        .out
+                        .line(&format!("let {} = Var::new({});", variable, value)),
+                    None => self
+                        .out
+                        .line(&format!("define_global({:?}, {});", v.name.lexeme, value)),
+                }
+            }
+            Stmt::Yield(y) => self
+                .reporter
+                .token_error(y.keyword.clone(), "Generators can't be transpiled to Rust"),
+        }
+    }
+
+    // The Rust variable for a local, or None for a global
+    fn declare(&mut self, name: &Token) -> Option<String> {
+        if self.scopes.is_global() {
+            return None;
+        }
+        let variable = format!("v_{}", name.lexeme);
+        self.scopes.declare(name.symbol.clone(), variable.clone());
+        Some(variable)
+    }
+
+    // Methods are kept in a map, and the class is defined once it's made. Its name is
+    // declared first, as methods can refer to it.
+    fn class(&mut self, class: &ClassStmt) {
+        let variable = self.declare(&class.name);
+        if let Some(variable) = &variable {
+            self.out
+                .line(&format!("let {} = Var::new(Value::Nil);", variable));
+        }
+        self.out.line("{");
+        self.out.indent();
+        self.scopes.begin();
+        let superclass = match class.superclass {
+            Some(superclass) => {
+                let value = self.expr(superclass);
+                self.out.line(&format!(
+                    "let parent = superclass({}, {})?;",
+                    value,
+                    at(&class.name)
+                ));
+                self.out
+                    .line("let v_super = Var::new(Value::Class(parent.clone()));");
+                self.scopes
+                    .declare(symbol::SUPER.clone(), "v_super".to_string());
+                "Some(parent)"
+            }
+            None => "None",
+        };
+        self.out.line("let mut methods = HashMap::new();");
+        for method in &class.methods {
+            let kind = if method.name.symbol == *symbol::INIT {
+                FunctionKind::Initializer
+            } else {
+                FunctionKind::Method
+            };
+            let prefix = format!("methods.insert({:?}, ", method.name.lexeme);
+            self.function(&prefix, method, kind, ");");
+        }
+        let value = format!("class({:?}, {}, methods)", class.name.lexeme, superclass);
+        match variable {
+            Some(variable) => self.out.line(&format!("{}.set({});", variable, value)),
+            None => self.out.line(&format!(
+                "define_global({:?}, {});",
+                class.name.lexeme, value
+            )),
+        }
+        self.scopes.end();
+        self.out.dedent();
+        self.out.line("}");
+    }
+
+    // Writes the function as a closure, between the prefix and suffix. The body is
+    // written first, to find out what the closure has to capture, which it's given
+    // clones of.
+    fn function(
+        &mut self,
+        prefix: &str,
+        function: &FunctionStmt,
+        kind: FunctionKind,
+        suffix: &str,
+    ) {
+        let outer = std::mem::take(&mut self.out);
+        self.functions.push(kind);
+        self.scopes.begin_function();
+        if kind != FunctionKind::Function {
+            self.scopes
+                .declare(symbol::THIS.clone(), "v_this".to_string());
+            self.out.line("let v_this = Var::new(this);");
+        }
+        for (i, param) in function.params.iter().enumerate() {
+            let variable = self.declare(param).unwrap();
+            self.out.line(&format!(
+                "let {} = Var::new(args[{}].clone());",
+                variable, i
+            ));
+        }
+        self.stmts(&function.body);
+        let returns = matches!(
+            function.body.last().map(|stmt| &self.ast[*stmt]),
+            Some(Stmt::Return(_))
+        );
+        if !returns {
+            self.out.line(if kind == FunctionKind::Initializer {
+                "Ok(v_this.get())"
+            } else {
+                "Ok(Value::Nil)"
+            });
+        }
+        let captures = self.scopes.end_function();
+        self.functions.pop();
+        let body = std::mem::replace(&mut self.out, outer).finish();
+
+        let constructor = match kind {
+            FunctionKind::Function => "function",
+            FunctionKind::Method | FunctionKind::Initializer => "method",
+        };
+        let closure = format!(
+            "{}({:?}, {}, Rc::new(move |this, args| {{",
+            constructor,
+            function.name.lexeme,
+            function.params.len()
+        );
+        if captures.is_empty() {
+            self.out.line(&format!("{}{}", prefix, closure));
+        } else {
+            self.out.line(&format!("{}{{", prefix));
+            self.out.indent();
+            for variable in &captures {
+                self.out
+                    .line(&format!("let {} = {}.clone();", variable, variable));
+            }
+            self.out.line(&closure);
+        }
+        self.out.indent();
+        self.out.lines(&body);
+        self.out.dedent();
+        if captures.is_empty() {
+            self.out.line(&format!("}})){}", suffix));
+        } else {
+            self.out.line("}))");
+            self.out.dedent();
+            self.out.line(&format!("}}{}", suffix));
+        }
+    }
+
+    // A Rust expression giving the value, which returns from the enclosing function if
+    // there's an error
+    fn expr(&mut self, id: ExprId) -> String {
+        match &self.ast[id] {
+            Expr::Assign(e) => {
+                let value = self.expr(e.value);
+                match self.scopes.lookup(&e.name.symbol) {
+                    Some(variable) => format!("{}.set({})", variable, value),
+                    None => format!(
+                        "assign_global({:?}, {}, {})?",
+                        e.name.lexeme,
+                        value,
+                        at(&e.name)
+                    ),
+                }
+            }
+            Expr::Binary(_) => self.binary_chain(id),
+            Expr::Call(e) => {
+                let callee = self.expr(e.callee);
+                let args: Vec<String> = e.arguments.iter().map(|arg| self.expr(*arg)).collect();
+                format!(
+                    "call(&{}, vec![{}], {})?",
+                    callee,
+                    args.join(", "),
+                    at(&e.paren)
+                )
+            }
+            Expr::Get(e) => {
+                let object = self.expr(e.object);
+                format!("get({}, {:?}, {})?", object, e.name.lexeme, at(&e.name))
+            }
+            Expr::Grouping(e) => self.expr(*e),
+            Expr::Literal(literal) => match literal {
+                TokenLiteral::None | TokenLiteral::Nil => "Value::Nil".to_string(),
+                TokenLiteral::True => "Value::Boolean(true)".to_string(),
+                TokenLiteral::False => "Value::Boolean(false)".to_string(),
+                TokenLiteral::Number(n) => format!("Value::Number({:?})", n),
+                TokenLiteral::String(s) => format!("Value::string({:?})", s),
+            },
+            Expr::Logical(e) => {
+                let left = self.expr(e.left);
+                let right = self.expr(e.right);
+                let (if_true, if_false) = match e.operator.token_type {
+                    TokenType::Or => ("left".to_string(), right),
+                    _ => (right, "left".to_string()),
+                };
+                format!(
+                    "{{ let left = {}; if truthy(&left) {{ {} }} else {{ {} }} }}",
+                    left, if_true, if_false
+                )
+            }
+            Expr::Set(e) => {
+                let object = self.expr(e.object);
+                let value = self.expr(e.value);
+                format!(
+                    "set_field(instance({}, {})?, {:?}, {})",
+                    object,
+                    at(&e.name),
+                    e.name.lexeme,
+                    value
+                )
+            }
+            Expr::Super(e) => {
+                let superclass = self.scopes.lookup(&symbol::SUPER).unwrap_or_default();
+                let this = self.scopes.lookup(&symbol::THIS).unwrap_or_default();
+                format!(
+                    "super_method({}.get(), {}.get(), {:?}, {})?",
+                    superclass,
+                    this,
+                    e.method.lexeme,
+                    at(&e.method)
+                )
+            }
+            Expr::This(_) => {
+                let this = self.scopes.lookup(&symbol::THIS).unwrap_or_default();
+                format!("{}.get()", this)
+            }
+            Expr::Unary(e) => {
+                let right = self.expr(e.right);
+                match e.operator.token_type {
+                    TokenType::Bang => format!("not({})", right),
+                    _ => format!("negate({}, {})?", right, at(&e.operator)),
+                }
+            }
+            Expr::Variable(e) => match self.scopes.lookup(&e.name.symbol) {
+                Some(variable) => format!("{}.get()", variable),
+                None => format!("get_global({:?}, {})?", e.name.lexeme, at(&e.name)),
+            },
+        }
+    }
+
+    // A chain of binary operators nests down its left operands, as deep as it's long, so
+    // it's walked down rather than recursed into. A chain of more than one becomes a
+    // block applying each operator in turn, which rustc doesn't have to recurse into
+    // either.
+    fn binary_chain(&mut self, id: ExprId) -> String {
+        let ast = self.ast;
+        let mut chain = Vec::new();
+        let mut left = id;
+        while let Expr::Binary(e) = &ast[left] {
+            chain.push(e);
+            left = e.left;
+        }
+        let left = self.expr(left);
+        if let [e] = chain[..] {
+            return self.binary(e, left);
+        }
+        let mut block = format!("{{ let value = {};", left);
+        for e in chain.into_iter().rev() {
+            block += &format!(" let value = {};", self.binary(e, "value".to_string()));
+        }
+        block + " value }"
+    }
+
+    fn binary(&mut self, e: &BinaryExpr, left: String) -> String {
+        let right = self.expr(e.right);
+        let function = match e.operator.token_type {
+            TokenType::EqualEqual => return format!("equal({}, {})", left, right),
+            TokenType::BangEqual => return format!("not_equal({}, {})", left, right),
+            TokenType::Plus => "add",
+            TokenType::Minus => "subtract",
+            TokenType::Star => "multiply",
+            TokenType::Slash => "divide",
+            TokenType::Greater => "greater",
+            TokenType::GreaterEqual => "greater_equal",
+            TokenType::Less => "less",
+            TokenType::LessEqual => "less_equal",
+            _ => {
+                self.unsupported(&e.operator);
+                return "Value::Nil".to_string();
+            }
+        };
+        format!("{}({}, {}, {})?", function, left, right, at(&e.operator))
+    }
+
+    // The interpreter reports these when they're run, which is too late here
+    fn unsupported(&self, operator: &Token) {
+        self.reporter
+            .token_error(operator.clone(), "Unsupported operation");
+    }
+}
+
+// Where an error in the token is reported, as a tuple of line and column
+fn at(token: &Token) -> String {
+    format!("({}, {})", token.line, token.column)
+}
+
+#[cfg(test)]
+mod test {
+    use std::process::Command;
+
+    use super::*;
+    use crate::{codegen::transpile, codegen::Target, parser::Parser, scanner::Scanner};
+
+    fn transpile_src(src: &str, reporter: &ErrorReporter) -> Option<String> {
+        let mut ast = Ast::new();
+        let stmts = Parser::new(Scanner::new(src, reporter), &mut ast, reporter).parse_stmts();
+        transpile(Target::Rust, &ast, &stmts, reporter)
+    }
+
+    // Builds the program with rustc and runs it, when there's a rustc to build it with
+    fn run(code: &str, name: &str) -> Option<(String, Option<i32>)> {
+        let dir = std::env::temp_dir().join(format!("rlox-{}-{}", name, std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let (source, binary) = (dir.join("main.rs"), dir.join("main"));
+        std::fs::write(&source, code).unwrap();
+        let built = Command::new("rustc")
+            .arg(&source)
+            .arg("-o")
+            .arg(&binary)
+            .output()
+            .ok()?;
+        assert!(
+            built.status.success(),
+            "{}",
+            String::from_utf8_lossy(&built.stderr)
+        );
+        let output = Command::new(&binary).output().unwrap();
+        let _ = std::fs::remove_dir_all(&dir);
+        Some((
+            String::from_utf8(output.stdout).unwrap(),
+            output.status.code(),
+        ))
+    }
+
+    #[test]
+    pub fn transpiled_programs_run_like_the_interpreter() {
+        let src = "fun counter() {
+  var i = 0;
+  fun count() { i = i + 1; return i; }
+  return count;
+}
+var c = counter();
+c();
+print c();
+class A {
+  init(n) { this.n = n; }
+  show() { return \"A\" + this.n; }
+}
+class B < A {
+  init(n) { super.init(n * 2); }
+  show() { return \"B/\" + super.show(); }
+}
+print B(3).show();
+print B;
+for (var i = 0; i < 5; i = i + 1) { if (i == 2) break; print i / 2; }
+print nil or \"x\";
+fun fail(a) { return a.x; }
+fail(1);
+";
+        let reporter = ErrorReporter::new();
+        let code = transpile_src(src, &reporter).unwrap();
+        assert!(code.contains("fn run() -> Result<(), Error> {"));
+        let (output, status) = match run(&code, "transpile") {
+            Some(run) => run,
+            None => return,
+        };
+        assert_eq!(
+            output,
+            "2\nB/A6\nB\n0\n0.5\nx\n\
+             [Line 21:24] Runtime Error: Only instances have fields\n    \
+             at fail() called from line 22:7\n"
+        );
+        assert_eq!(status, Some(70));
+    }
+
+    #[test]
+    pub fn transpiles_long_chains_without_recursing() {
+        let reporter = ErrorReporter::new();
+        let terms = (0..200_000).map(|n| n.to_string()).collect::<Vec<_>>();
+        let code = transpile_src(&format!("print {};", terms.join(" + ")), &reporter);
+        assert!(code.is_some_and(|code| code.contains("let value = add(value, ")));
+
+        let code = transpile_src("print 1 + 2 * 3 - 4 / 2 + (1 - 2 - 3) == 1;", &reporter).unwrap();
+        assert!(reporter.take_errors().is_empty());
+        if let Some((output, status)) = run(&code, "chains") {
+            assert_eq!((output.as_str(), status), ("true\n", Some(0)));
+        }
+    }
+
+    #[test]
+    pub fn reports_what_cant_be_transpiled() {
+        let reporter = ErrorReporter::new();
+        assert_eq!(
+            transpile_src("fun f() { yield 1; }\n1, 2;", &reporter),
+            None
+        );
+        assert_eq!(
+            reporter.take_errors(),
+            vec![
+                "[line 1:11] Error  at 'yield': Generators can't be transpiled to Rust",
+                "[line 2:2] Error  at ',': Unsupported operation",
+            ]
+        );
+    }
+}
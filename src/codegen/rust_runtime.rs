@@ -0,0 +1,477 @@
+// The runtime programs transpiled to Rust are built on. It's copied into each program as
+// it is, so it can only use std, and behaves like the interpreter: values print the same,
+// and runtime errors have the same messages and backtraces.
+#![allow(dead_code)]
+
+use std::{
+    cell::RefCell,
+    collections::HashMap,
+    fmt,
+    rc::Rc,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+const MAX_CALL_DEPTH: usize = 10_000;
+const MAX_BACKTRACE_FRAMES: usize = 16;
+const STACK_SIZE: usize = 256 * 1024 * 1024;
+
+// Line and column
+pub type At = (usize, usize);
+
+// What a function's body is compiled to. Methods are given the instance they're bound
+// to, and other functions nil.
+pub type Code = Rc<dyn Fn(Value, &[Value]) -> Result<Value, Error>>;
+
+#[derive(Clone)]
+pub enum Value {
+    Nil,
+    Boolean(bool),
+    Number(f64),
+    String(Rc<str>),
+    Function(Rc<Function>),
+    Class(Rc<Class>),
+    Instance(Rc<Instance>),
+}
+
+impl Value {
+    pub fn string(s: &str) -> Value {
+        Value::String(s.into())
+    }
+}
+
+impl fmt::Display for Value {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Value::Nil => f.write_str("Nil"),
+            Value::Boolean(b) => write!(f, "{}", b),
+            Value::Number(n) => write!(f, "{}", n),
+            Value::String(s) => f.write_str(s),
+            Value::Function(_) => f.write_str("(function)"),
+            Value::Class(c) => f.write_str(&c.name),
+            Value::Instance(i) => write!(f, "{} instance", i.class.name),
+        }
+    }
+}
+
+// Functions are never equal, even to themselves, and instances are equal when their
+// fields are
+impl PartialEq for Value {
+    fn eq(&self, other: &Value) -> bool {
+        match (self, other) {
+            (Value::Nil, Value::Nil) => true,
+            (Value::Boolean(a), Value::Boolean(b)) => a == b,
+            (Value::Number(a), Value::Number(b)) => a == b,
+            (Value::String(a), Value::String(b)) => a == b,
+            (Value::Class(a), Value::Class(b)) => Rc::ptr_eq(a, b),
+            (Value::Instance(a), Value::Instance(b)) => {
+                Rc::ptr_eq(&a.class, &b.class) && *a.fields.borrow() == *b.fields.borrow()
+            }
+            _ => false,
+        }
+    }
+}
+
+// A variable that closures can share
+#[derive(Clone)]
+pub struct Var(Rc<RefCell<Value>>);
+
+impl Var {
+    pub fn new(value: Value) -> Var {
+        Var(Rc::new(RefCell::new(value)))
+    }
+
+    pub fn get(&self) -> Value {
+        self.0.borrow().clone()
+    }
+
+    pub fn set(&self, value: Value) -> Value {
+        *self.0.borrow_mut() = value.clone();
+        value
+    }
+}
+
+#[derive(Clone)]
+pub struct Function {
+    name: String,
+    arity: usize,
+    initializer: bool,
+    this: Option<Value>,
+    code: Code,
+}
+
+pub fn function(name: &str, arity: usize, code: Code) -> Value {
+    Value::Function(Rc::new(Function {
+        name: name.to_string(),
+        arity,
+        initializer: false,
+        this: None,
+        code,
+    }))
+}
+
+pub fn method(name: &str, arity: usize, code: Code) -> Rc<Function> {
+    Rc::new(Function {
+        name: name.to_string(),
+        arity,
+        initializer: name == "init",
+        this: None,
+        code,
+    })
+}
+
+impl Function {
+    fn bind(&self, instance: Value) -> Rc<Function> {
+        Rc::new(Function {
+            this: Some(instance),
+            ..self.clone()
+        })
+    }
+}
+
+pub struct Class {
+    name: String,
+    superclass: Option<Rc<Class>>,
+    methods: HashMap<&'static str, Rc<Function>>,
+}
+
+pub fn class(
+    name: &str,
+    superclass: Option<Rc<Class>>,
+    methods: HashMap<&'static str, Rc<Function>>,
+) -> Value {
+    Value::Class(Rc::new(Class {
+        name: name.to_string(),
+        superclass,
+        methods,
+    }))
+}
+
+impl Class {
+    fn find_method(&self, name: &str) -> Option<Rc<Function>> {
+        match self.methods.get(name) {
+            Some(method) => Some(method.clone()),
+            None => self.superclass.as_ref()?.find_method(name),
+        }
+    }
+}
+
+pub struct Instance {
+    class: Rc<Class>,
+    fields: RefCell<HashMap<String, Value>>,
+}
+
+pub struct Error {
+    at: At,
+    message: String,
+    backtrace: Vec<String>,
+}
+
+impl Error {
+    pub fn new(at: At, message: &str) -> Error {
+        let backtrace = STACK.with(|stack| {
+            let stack = stack.borrow();
+            let mut backtrace: Vec<String> = stack
+                .iter()
+                .rev()
+                .take(MAX_BACKTRACE_FRAMES)
+                .map(|(name, at)| format!("at {}() called from line {}", name, position(*at)))
+                .collect();
+            if stack.len() > MAX_BACKTRACE_FRAMES {
+                backtrace.push(format!("... {} more", stack.len() - MAX_BACKTRACE_FRAMES));
+            }
+            backtrace
+        });
+        Error {
+            at,
+            message: message.to_string(),
+            backtrace,
+        }
+    }
+
+    // Natives don't know where they were called from, so their errors are reported
+    // against the call
+    fn native(message: &str) -> Error {
+        let at = STACK.with(|stack| stack.borrow().last().map_or((0, 0), |(_, at)| *at));
+        Error::new(at, message)
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "[Line {}] Runtime Error: {}",
+            position(self.at),
+            self.message
+        )?;
+        for line in &self.backtrace {
+            write!(f, "\n    {}", line)?;
+        }
+        Ok(())
+    }
+}
+
+fn position((line, column): At) -> String {
+    if column == 0 {
+        line.to_string()
+    } else {
+        format!("{}:{}", line, column)
+    }
+}
+
+thread_local! {
+    static GLOBALS: RefCell<HashMap<&'static str, Value>> = RefCell::new(natives());
+    // The function called and where from, for each call being run
+    static STACK: RefCell<Vec<(String, At)>> = const { RefCell::new(Vec::new()) };
+}
+
+// Runs the program on a thread with room for deep recursion, and exits as rlox would
+pub fn start(run: fn() -> Result<(), Error>) -> ! {
+    let thread = std::thread::Builder::new()
+        .stack_size(STACK_SIZE)
+        .spawn(move || match run() {
+            Ok(()) => 0,
+            Err(e) => {
+                println!("{}", e);
+                70
+            }
+        })
+        .expect("Could not start the program");
+    std::process::exit(thread.join().unwrap_or(70));
+}
+
+pub fn define_global(name: &'static str, value: Value) {
+    GLOBALS.with(|globals| globals.borrow_mut().insert(name, value));
+}
+
+pub fn get_global(name: &str, at: At) -> Result<Value, Error> {
+    GLOBALS
+        .with(|globals| globals.borrow().get(name).cloned())
+        .ok_or_else(|| Error::new(at, &format!("Undefined variable {}", name)))
+}
+
+pub fn assign_global(name: &str, value: Value, at: At) -> Result<Value, Error> {
+    GLOBALS.with(|globals| match globals.borrow_mut().get_mut(name) {
+        Some(global) => {
+            *global = value.clone();
+            Ok(value)
+        }
+        None => Err(Error::new(at, &format!("Undefined variable {}", name))),
+    })
+}
+
+pub fn truthy(value: &Value) -> bool {
+    !matches!(value, Value::Nil | Value::Boolean(false))
+}
+
+pub fn negate(value: Value, at: At) -> Result<Value, Error> {
+    match value {
+        Value::Number(n) => Ok(Value::Number(-n)),
+        _ => Err(Error::new(at, "Unsupported operation")),
+    }
+}
+
+pub fn not(value: Value) -> Value {
+    Value::Boolean(!truthy(&value))
+}
+
+pub fn add(left: Value, right: Value, at: At) -> Result<Value, Error> {
+    match (left, right) {
+        (Value::Number(l), Value::Number(r)) => Ok(Value::Number(l + r)),
+        (Value::String(l), r) => Ok(Value::String(format!("{}{}", l, r).into())),
+        _ => Err(Error::new(
+            at,
+            "Operands for '+' must be numbers, or first operand must be a string",
+        )),
+    }
+}
+
+fn numbers(left: Value, right: Value, at: At) -> Result<(f64, f64), Error> {
+    match (left, right) {
+        (Value::Number(l), Value::Number(r)) => Ok((l, r)),
+        _ => Err(Error::new(at, "Operands must be numbers")),
+    }
+}
+
+pub fn subtract(left: Value, right: Value, at: At) -> Result<Value, Error> {
+    numbers(left, right, at).map(|(l, r)| Value::Number(l - r))
+}
+
+pub fn multiply(left: Value, right: Value, at: At) -> Result<Value, Error> {
+    numbers(left, right, at).map(|(l, r)| Value::Number(l * r))
+}
+
+pub fn divide(left: Value, right: Value, at: At) -> Result<Value, Error> {
+    match numbers(left, right, at)? {
+        (_, 0.0) => Err(Error::new(at, "Attempted to divide by zero")),
+        (l, r) => Ok(Value::Number(l / r)),
+    }
+}
+
+pub fn greater(left: Value, right: Value, at: At) -> Result<Value, Error> {
+    numbers(left, right, at).map(|(l, r)| Value::Boolean(l > r))
+}
+
+pub fn greater_equal(left: Value, right: Value, at: At) -> Result<Value, Error> {
+    numbers(left, right, at).map(|(l, r)| Value::Boolean(l >= r))
+}
+
+pub fn less(left: Value, right: Value, at: At) -> Result<Value, Error> {
+    numbers(left, right, at).map(|(l, r)| Value::Boolean(l < r))
+}
+
+pub fn less_equal(left: Value, right: Value, at: At) -> Result<Value, Error> {
+    numbers(left, right, at).map(|(l, r)| Value::Boolean(l <= r))
+}
+
+pub fn equal(left: Value, right: Value) -> Value {
+    Value::Boolean(left == right)
+}
+
+pub fn not_equal(left: Value, right: Value) -> Value {
+    Value::Boolean(left != right)
+}
+
+pub fn call(callee: &Value, args: Vec<Value>, at: At) -> Result<Value, Error> {
+    let (function, this) = match callee {
+        Value::Function(function) => (Some(function.clone()), Value::Nil),
+        Value::Class(class) => {
+            let instance = Value::Instance(Rc::new(Instance {
+                class: class.clone(),
+                fields: RefCell::new(HashMap::new()),
+            }));
+            (class.find_method("init"), instance)
+        }
+        _ => return Err(Error::new(at, "Can only call functions and classes")),
+    };
+    let arity = function.as_ref().map_or(0, |function| function.arity);
+    if args.len() != arity {
+        let message = format!("Expected {} arguments but got {}", arity, args.len());
+        return Err(Error::new(at, &message));
+    }
+    if STACK.with(|stack| stack.borrow().len()) >= MAX_CALL_DEPTH {
+        let message = format!("Stack overflow: max call depth {} exceeded", MAX_CALL_DEPTH);
+        return Err(Error::new(at, &message));
+    }
+    let name = match callee {
+        Value::Class(class) => class.name.clone(),
+        _ => function
+            .as_ref()
+            .map_or_else(String::new, |f| f.name.clone()),
+    };
+    STACK.with(|stack| stack.borrow_mut().push((name, at)));
+    let result = match &function {
+        Some(function) => {
+            let this = function.this.clone().unwrap_or_else(|| this.clone());
+            (function.code)(this, &args)
+        }
+        None => Ok(Value::Nil),
+    };
+    STACK.with(|stack| stack.borrow_mut().pop());
+    match (callee, result) {
+        // Calling a class gives the instance, whatever its initializer returns
+        (Value::Class(_), Ok(_)) => Ok(this),
+        (_, result) => result,
+    }
+}
+
+pub fn get(object: Value, name: &str, at: At) -> Result<Value, Error> {
+    let instance = match &object {
+        Value::Instance(instance) => instance,
+        _ => return Err(Error::new(at, "Only instances have fields")),
+    };
+    if let Some(value) = instance.fields.borrow().get(name) {
+        return Ok(value.clone());
+    }
+    match instance.class.find_method(name) {
+        Some(method) => Ok(Value::Function(method.bind(object.clone()))),
+        None => Err(Error::new(at, &format!("Undefined property {}", name))),
+    }
+}
+
+// The object is checked before the value is worked out, so this is given the object
+// and then `set_field` the value
+pub fn instance(object: Value, at: At) -> Result<Rc<Instance>, Error> {
+    match object {
+        Value::Instance(instance) => Ok(instance),
+        _ => Err(Error::new(at, "Only instances have fields")),
+    }
+}
+
+pub fn set_field(instance: Rc<Instance>, name: &str, value: Value) -> Value {
+    instance
+        .fields
+        .borrow_mut()
+        .insert(name.to_string(), value.clone());
+    value
+}
+
+pub fn superclass(value: Value, at: At) -> Result<Rc<Class>, Error> {
+    match value {
+        Value::Class(class) => Ok(class),
+        _ => Err(Error::new(at, "Superclass must be a class")),
+    }
+}
+
+pub fn super_method(superclass: Value, this: Value, name: &str, at: At) -> Result<Value, Error> {
+    let method = match &superclass {
+        Value::Class(class) => class.find_method(name),
+        _ => None,
+    };
+    match method {
+        Some(method) => Ok(Value::Function(method.bind(this))),
+        None => Err(Error::new(at, &format!("Undefined property {}", name))),
+    }
+}
+
+fn natives() -> HashMap<&'static str, Value> {
+    let mut natives = HashMap::new();
+    let mut define = |name: &'static str, arity, code: fn(&[Value]) -> Result<Value, Error>| {
+        natives.insert(
+            name,
+            function(name, arity, Rc::new(move |_, args| code(args))),
+        );
+    };
+    define("clock", 0, |_| Ok(Value::Number(now().as_secs() as f64)));
+    define("chr", 1, |args| match &args[0] {
+        Value::Number(n) if n.fract() == 0.0 && *n >= 0.0 => char::from_u32(*n as u32)
+            .map(|c| Value::String(c.to_string().into()))
+            .ok_or_else(|| Error::native("chr() expects a valid Unicode code point")),
+        _ => Err(Error::native("chr() expects a valid Unicode code point")),
+    });
+    define("ord", 1, |args| {
+        if let Value::String(s) = &args[0] {
+            let mut chars = s.chars();
+            if let (Some(c), None) = (chars.next(), chars.next()) {
+                return Ok(Value::Number(c as u32 as f64));
+            }
+        }
+        Err(Error::native("ord() expects a single-character string"))
+    });
+    define("len", 1, |args| match &args[0] {
+        Value::String(s) => Ok(Value::Number(s.chars().count() as f64)),
+        _ => Err(Error::native("len() expects a string or a list")),
+    });
+    define("random", 0, |_| Ok(Value::Number(random())));
+    natives
+}
+
+fn now() -> std::time::Duration {
+    SystemTime::now().duration_since(UNIX_EPOCH).unwrap()
+}
+
+// A number in [0, 1), using splitmix64 seeded from the time
+fn random() -> f64 {
+    thread_local! {
+        static STATE: RefCell<u64> = RefCell::new(now().as_nanos() as u64);
+    }
+    STATE.with(|state| {
+        let mut state = state.borrow_mut();
+        *state = state.wrapping_add(0x9E37_79B9_7F4A_7C15);
+        let mut z = *state;
+        z = (z ^ (z >> 30)).wrapping_mul(0xBF58_476D_1CE4_E5B9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94D0_49BB_1331_11EB);
+        z ^= z >> 31;
+        (z >> 11) as f64 / (1u64 << 53) as f64
+    })
+}
@@ -0,0 +1,113 @@
+use std::{
+    collections::{BTreeMap, HashMap},
+    fmt::Write,
+};
+
+use crate::ast::{Ast, Stmt};
+
+// Counts how many times statements on each line ran. Blocks aren't counted, as the
+// statements in them are.
+#[derive(Default)]
+pub struct Coverage {
+    hits: HashMap<usize, usize>,
+}
+
+impl Coverage {
+    pub fn new() -> Self {
+        Coverage::default()
+    }
+
+    pub fn hit(&mut self, line: usize) {
+        *self.hits.entry(line).or_default() += 1;
+    }
+
+    // Every line with a statement on it, and how many times it ran. Statements the
+    // optimizer dropped are still in the AST, so they show up as never having run.
+    pub fn lines(&self, ast: &Ast) -> BTreeMap<usize, usize> {
+        ast.stmt_ids()
+            .filter(|id| !matches!(ast[*id], Stmt::Block(_)))
+            .map(|id| ast.line(id))
+            .map(|line| (line, self.hits.get(&line).copied().unwrap_or(0)))
+            .collect()
+    }
+
+    // The source with the hit count of each line beside it, and a summary at the end.
+    // Lines that never ran are marked with #####, as gcov does.
+    pub fn report(&self, ast: &Ast, source: &str) -> String {
+        let lines = self.lines(ast);
+        let mut s = String::new();
+        for (i, text) in source.lines().enumerate() {
+            let count = match lines.get(&(i + 1)) {
+                Some(0) => "#####".to_string(),
+                Some(hits) => hits.to_string(),
+                None => "-".to_string(),
+            };
+            let _ = writeln!(s, "{:>9}: {:>4}: {}", count, i + 1, text);
+        }
+        s.push_str(&summary(&lines));
+        s
+    }
+
+    // In the lcov tracefile format, which genhtml and most editors can read
+    pub fn lcov(&self, ast: &Ast, file: &str) -> String {
+        let lines = self.lines(ast);
+        let mut s = format!("SF:{}\n", file);
+        for (line, hits) in &lines {
+            let _ = writeln!(s, "DA:{},{}", line, hits);
+        }
+        let _ = writeln!(s, "LF:{}", lines.len());
+        let _ = writeln!(s, "LH:{}", lines.values().filter(|h| **h > 0).count());
+        s.push_str("end_of_record\n");
+        s
+    }
+}
+
+fn summary(lines: &BTreeMap<usize, usize>) -> String {
+    let covered = lines.values().filter(|h| **h > 0).count();
+    let percent = if lines.is_empty() {
+        100.0
+    } else {
+        covered as f64 * 100.0 / lines.len() as f64
+    };
+    format!(
+        "Lines covered: {:.1}% ({} of {})\n",
+        percent,
+        covered,
+        lines.len()
+    )
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::{errors::ErrorReporter, parser::Parser, scanner::Scanner};
+
+    #[test]
+    pub fn reports_lines_that_ran() {
+        let src = "var a = 1;\nif (a > 1) {\n  print a;\n}\n\nprint a;";
+        let reporter = ErrorReporter::new();
+        let mut ast = Ast::new();
+        let stmts = Parser::new(Scanner::new(src, &reporter), &mut ast, &reporter).parse_stmts();
+        let mut coverage = Coverage::new();
+        for stmt in &stmts {
+            coverage.hit(ast.line(*stmt));
+        }
+
+        assert_eq!(
+            coverage.report(&ast, src).lines().collect::<Vec<_>>(),
+            vec![
+                "        1:    1: var a = 1;",
+                "        1:    2: if (a > 1) {",
+                "    #####:    3:   print a;",
+                "        -:    4: }",
+                "        -:    5: ",
+                "        1:    6: print a;",
+                "Lines covered: 75.0% (3 of 4)",
+            ]
+        );
+        assert_eq!(
+            coverage.lcov(&ast, "test.lox"),
+            "SF:test.lox\nDA:1,1\nDA:2,1\nDA:3,0\nDA:6,1\nLF:4\nLH:3\nend_of_record\n"
+        );
+    }
+}
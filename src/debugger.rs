@@ -0,0 +1,346 @@
+use std::{
+    collections::BTreeSet,
+    io::{BufRead, Write},
+};
+
+use crate::{
+    ast::Ast, errors::ErrorReporter, interpreter::Interpreter, parser::Parser, scanner::Scanner,
+};
+
+/// Called by the interpreter before it runs each statement, and whenever it reaches a
+/// `debugger` statement.
+pub trait DebugHook {
+    fn before_stmt(&mut self, interpreter: &mut Interpreter<'_>, line: usize);
+
+    fn debugger_stmt(&mut self, interpreter: &mut Interpreter<'_>, line: usize);
+
+    // Whether before_stmt needs calling at all, so that a debugger that's only waiting
+    // for `debugger` statements doesn't slow everything else down
+    fn is_active(&self) -> bool;
+}
+
+const HELP: &str = "Commands:
+  break [FILE:]LINE   stop when a statement on LINE is about to run
+  delete [FILE:]LINE  remove a breakpoint
+  step                run the next statement, going into function calls
+  next                run the next statement, stepping over function calls
+  continue            run until the next breakpoint
+  backtrace           list the function calls on the stack
+  locals              list the variables in scope
+  print EXPR          evaluate an expression where execution is stopped
+  quit                stop the program
+Anything else is evaluated as an expression.";
+
+#[derive(Clone, Copy)]
+enum Mode {
+    Continue,
+    Step,
+    // Stop at the next statement that isn't in a function called from the given depth
+    Next(usize),
+}
+
+// Runs a script one command at a time. Under `rlox debug`, execution starts out stopped
+// before the first statement so that breakpoints can be set. Otherwise it only stops
+// once a `debugger` statement is reached.
+pub struct Debugger {
+    file: String,
+    source: Vec<String>,
+    breakpoints: BTreeSet<usize>,
+    mode: Mode,
+    input: Box<dyn BufRead>,
+    output: Box<dyn Write>,
+}
+
+impl Debugger {
+    pub fn new(
+        file: &str,
+        source: &str,
+        stop_at_start: bool,
+        input: Box<dyn BufRead>,
+        output: Box<dyn Write>,
+    ) -> Self {
+        Debugger {
+            file: file.to_string(),
+            source: source.lines().map(|l| l.to_string()).collect(),
+            breakpoints: BTreeSet::new(),
+            mode: if stop_at_start {
+                Mode::Step
+            } else {
+                Mode::Continue
+            },
+            input,
+            output,
+        }
+    }
+
+    fn should_stop(&self, depth: usize, line: usize) -> bool {
+        match self.mode {
+            Mode::Continue => self.breakpoints.contains(&line),
+            Mode::Step => true,
+            Mode::Next(from) => depth <= from,
+        }
+    }
+
+    // Accepts either a bare line number or one qualified by the file being debugged
+    fn parse_location(&self, location: &str) -> Result<usize, String> {
+        let line = match location.rsplit_once(':') {
+            Some((file, line)) if file == self.file => line,
+            Some((file, _)) => return Err(format!("Unknown file '{}'", file)),
+            None => location,
+        };
+        match line.parse() {
+            Ok(line) if line > 0 && line <= self.source.len() => Ok(line),
+            _ => Err(format!("Invalid line '{}'", line)),
+        }
+    }
+
+    // Returns whether execution should carry on
+    fn command(&mut self, interpreter: &mut Interpreter<'_>, command: &str) -> bool {
+        let (name, arg) = match command.split_once(' ') {
+            Some((name, arg)) => (name, arg.trim()),
+            None => (command, ""),
+        };
+        let depth = interpreter.call_stack().len();
+        match name {
+            "b" | "break" => match self.parse_location(arg) {
+                Ok(line) => {
+                    self.breakpoints.insert(line);
+                    self.write(&format!("Breakpoint at {}:{}", self.file, line));
+                }
+                Err(e) => self.write(&e),
+            },
+            "d" | "delete" => match self.parse_location(arg) {
+                Ok(line) if self.breakpoints.remove(&line) => {
+                    self.write(&format!("Deleted breakpoint at {}:{}", self.file, line))
+                }
+                Ok(line) => self.write(&format!("No breakpoint at {}:{}", self.file, line)),
+                Err(e) => self.write(&e),
+            },
+            "s" | "step" => {
+                self.mode = Mode::Step;
+                return true;
+            }
+            "n" | "next" => {
+                self.mode = Mode::Next(depth);
+                return true;
+            }
+            "c" | "continue" => {
+                self.mode = Mode::Continue;
+                return true;
+            }
+            "bt" | "backtrace" => {
+                let frames = interpreter.call_stack();
+                let mut lines: Vec<String> = frames
+                    .iter()
+                    .rev()
+                    .enumerate()
+                    .map(|(i, frame)| {
+                        format!(
+                            "#{} {}() called from line {}",
+                            i, frame.function, frame.line
+                        )
+                    })
+                    .collect();
+                lines.push(format!("#{} <script>", frames.len()));
+                self.write(&lines.join("\n"));
+            }
+            "l" | "locals" => {
+                let scopes = interpreter.scopes();
+                if scopes.iter().all(|scope| scope.is_empty()) {
+                    self.write("No local variables");
+                }
+                for (name, value) in scopes.into_iter().flatten() {
+                    self.write(&format!("{} = {}", name, value));
+                }
+            }
+            "p" | "print" => {
+                let result = self.evaluate(interpreter, arg);
+                self.write(&result.unwrap_or_else(|errors| errors.join("\n")));
+            }
+            "q" | "quit" => {
+                let _ = self.output.flush();
+                std::process::exit(0);
+            }
+            "h" | "help" => self.write(HELP),
+            "" => {}
+            _ => {
+                let result = self.evaluate(interpreter, command);
+                self.write(&result.unwrap_or_else(|errors| errors.join("\n")));
+            }
+        }
+        false
+    }
+
+    fn prompt(&mut self, interpreter: &mut Interpreter<'_>, line: usize) {
+        let source = self
+            .source
+            .get(line.wrapping_sub(1))
+            .map_or("", |s| s.trim());
+        if source.is_empty() {
+            self.write(&format!("{}:{}", self.file, line));
+        } else {
+            self.write(&format!("{}:{}: {}", self.file, line, source));
+        }
+        loop {
+            let _ = write!(self.output, "(debug) ");
+            let _ = self.output.flush();
+            let mut command = String::new();
+            match self.input.read_line(&mut command) {
+                // Once there are no more commands, the program runs to the end
+                Ok(0) | Err(_) => {
+                    self.breakpoints.clear();
+                    self.mode = Mode::Continue;
+                    return;
+                }
+                Ok(_) => {
+                    if self.command(interpreter, command.trim()) {
+                        return;
+                    }
+                }
+            }
+        }
+    }
+
+    fn evaluate(
+        &self,
+        interpreter: &mut Interpreter<'_>,
+        src: &str,
+    ) -> Result<String, Vec<String>> {
+        let reporter = ErrorReporter::new();
+        let mut ast = Ast::new();
+        let expr = Parser::new(Scanner::new(src, &reporter), &mut ast, &reporter).parse_expr();
+        let expr = match expr {
+            Ok(expr) if !reporter.had_error() => expr,
+            _ => return Err(reporter.take_errors()),
+        };
+        let (result, errors) = interpreter
+            .error_reporter()
+            .capture_runtime_errors(|| interpreter.evaluate_in_scope(&ast, expr));
+        result.map(|value| value.to_string()).map_err(|_| errors)
+    }
+
+    fn write(&mut self, text: &str) {
+        let _ = writeln!(self.output, "{}", text);
+    }
+}
+
+impl DebugHook for Debugger {
+    fn before_stmt(&mut self, interpreter: &mut Interpreter<'_>, line: usize) {
+        if self.should_stop(interpreter.call_stack().len(), line) {
+            self.prompt(interpreter, line);
+        }
+    }
+
+    fn debugger_stmt(&mut self, interpreter: &mut Interpreter<'_>, line: usize) {
+        self.prompt(interpreter, line);
+    }
+
+    fn is_active(&self) -> bool {
+        !matches!(self.mode, Mode::Continue) || !self.breakpoints.is_empty()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::{cell::RefCell, io::Cursor, rc::Rc};
+
+    use super::*;
+    use crate::{interpreter::InterpreterOptions, resolver::Resolver};
+
+    struct Output(Rc<RefCell<Vec<u8>>>);
+
+    impl Write for Output {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            self.0.borrow_mut().write(buf)
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    fn debug(src: &str, commands: &str) -> Vec<String> {
+        run(src, true, commands)
+    }
+
+    fn run(src: &str, stop_at_start: bool, commands: &str) -> Vec<String> {
+        let reporter = ErrorReporter::new();
+        let output = Rc::new(RefCell::new(Vec::new()));
+        let mut interpreter = Interpreter::new(&reporter, InterpreterOptions::default());
+        interpreter.set_debugger(Box::new(Debugger::new(
+            "test.lox",
+            src,
+            stop_at_start,
+            Box::new(Cursor::new(commands.to_string())),
+            Box::new(Output(output.clone())),
+        )));
+        let stmts = Parser::new(
+            Scanner::new(src, &reporter),
+            interpreter.ast_mut(),
+            &reporter,
+        )
+        .parse_stmts();
+        let resolved = Resolver::new(&interpreter.ast(), &reporter).resolve_stmts(&stmts);
+        interpreter.add_resolution(resolved);
+        interpreter.interpret(&stmts);
+        assert!(!reporter.had_runtime_error());
+
+        let output = String::from_utf8(output.borrow().clone()).unwrap();
+        output
+            .lines()
+            .map(|l| l.trim_start_matches("(debug) ").to_string())
+            .filter(|l| !l.is_empty())
+            .collect()
+    }
+
+    #[test]
+    pub fn stops_at_breakpoints() {
+        let src = "var a = 1;
+fun f(x) {
+  var y = x * 2;
+  return y;
+}
+print f(a);";
+        let commands =
+            "break test.lox:4\ncontinue\nlocals\nbacktrace\nprint y + a\nprint z\nnext\nnext\n";
+        assert_eq!(
+            debug(src, commands),
+            vec![
+                "test.lox:1: var a = 1;",
+                "Breakpoint at test.lox:4",
+                "test.lox:4: return y;",
+                "x = 1",
+                "y = 2",
+                "#0 f() called from line 6",
+                "#1 <script>",
+                "3",
+                "[Line 1:1] Runtime Error: Undefined variable z",
+                "    at f() called from line 6:10",
+            ]
+        );
+    }
+
+    #[test]
+    pub fn steps_over_calls() {
+        let src = "fun f() {\n  return 1;\n}\nvar a = f();\nprint a;";
+        assert_eq!(
+            debug(src, "next\nnext\nassign\nprint a = 5\nstep\n"),
+            vec![
+                "test.lox:1: fun f() {",
+                "test.lox:4: var a = f();",
+                "test.lox:5: print a;",
+                "[Line 1:1] Runtime Error: Undefined variable assign",
+                "5",
+            ]
+        );
+    }
+
+    #[test]
+    pub fn debugger_statements_open_a_prompt() {
+        let src = "fun f(x) {\n  var y = x + 1;\n  debugger;\n  print y;\n}\nf(1);\nprint 2;";
+        assert_eq!(
+            run(src, false, "y * 10\nx = 5\nlocals\ncontinue\n"),
+            vec!["test.lox:3: debugger;", "20", "5", "x = 5", "y = 2",]
+        );
+    }
+}
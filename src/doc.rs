@@ -0,0 +1,186 @@
+use crate::{
+    ast::{Ast, Expr, Stmt, StmtId},
+    highlight::escape,
+    index::signature,
+};
+
+#[derive(Clone, Copy, Debug, PartialEq, strum_macros::EnumString)]
+#[strum(serialize_all = "lowercase")]
+pub enum Format {
+    Markdown,
+    Html,
+}
+
+// A function, class or method, headed by how it's declared
+struct Item {
+    declaration: String,
+    doc: Option<String>,
+    methods: Vec<Item>,
+}
+
+// Lists what a file declares at its top level, its functions and its classes with their
+// methods, with the `///` comments before each. The program has to have been scanned
+// with trivia for there to be any comments.
+pub fn document(title: &str, ast: &Ast, program: &[StmtId], format: Format) -> String {
+    let mut functions = Vec::new();
+    let mut classes = Vec::new();
+    for stmt in program {
+        match &ast[*stmt] {
+            Stmt::Function(function) => functions.push(Item {
+                declaration: format!("fun {}", signature(function)),
+                doc: function.doc.clone(),
+                methods: Vec::new(),
+            }),
+            Stmt::Class(class) => {
+                let mut declaration = format!("class {}", class.name.lexeme);
+                if let Some(Expr::Variable(v)) = class.superclass.map(|id| &ast[id]) {
+                    declaration.push_str(&format!(" < {}", v.name.lexeme));
+                }
+                let methods = class
+                    .methods
+                    .iter()
+                    .map(|method| Item {
+                        declaration: format!("{}.{}", class.name.lexeme, signature(method)),
+                        doc: method.doc.clone(),
+                        methods: Vec::new(),
+                    })
+                    .collect();
+                classes.push(Item {
+                    declaration,
+                    doc: class.doc.clone(),
+                    methods,
+                });
+            }
+            _ => {}
+        }
+    }
+
+    let mut out = String::new();
+    heading(&mut out, format, 1, title, false);
+    for (section, items) in [("Functions", functions), ("Classes", classes)] {
+        if items.is_empty() {
+            continue;
+        }
+        heading(&mut out, format, 2, section, false);
+        for item in items {
+            self::item(&mut out, format, 3, &item);
+        }
+    }
+    out
+}
+
+fn item(out: &mut String, format: Format, level: usize, item: &Item) {
+    heading(out, format, level, &item.declaration, true);
+    if let Some(doc) = &item.doc {
+        match format {
+            Format::Markdown => out.push_str(&format!("{}\n\n", doc)),
+            Format::Html => {
+                for paragraph in doc.split("\n\n").filter(|p| !p.trim().is_empty()) {
+                    out.push_str(&format!("<p>{}</p>\n", escape(paragraph.trim())));
+                }
+            }
+        }
+    }
+    for method in &item.methods {
+        self::item(out, format, level + 1, method);
+    }
+}
+
+// Declarations are headed by their code
+fn heading(out: &mut String, format: Format, level: usize, text: &str, code: bool) {
+    match format {
+        Format::Markdown => {
+            let text = if code {
+                format!("`{}`", text)
+            } else {
+                text.to_string()
+            };
+            out.push_str(&format!("{} {}\n\n", "#".repeat(level), text));
+        }
+        Format::Html => {
+            let text = if code {
+                format!("<code>{}</code>", escape(text))
+            } else {
+                escape(text)
+            };
+            out.push_str(&format!("<h{0}>{1}</h{0}>\n", level, text));
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::{errors::ErrorReporter, parser::Parser, scanner::Scanner};
+
+    const SRC: &str = "/// Adds two numbers.
+///
+/// Both have to be numbers.
+fun add(a: Number, b) -> Number { return a + b; }
+
+// Not a doc comment
+fun helper() {}
+
+/// A point <on a plane>.
+class Point < Base {
+  /// Makes a point.
+  init(x, y) { this.x = x; }
+
+  norm() { return 0; }
+}
+";
+
+    fn document_src(format: Format) -> String {
+        let reporter = ErrorReporter::new();
+        let tokens = Scanner::new(SRC, &reporter).with_trivia().scan_tokens();
+        let mut ast = Ast::new();
+        let stmts = Parser::new(tokens, &mut ast, &reporter).parse_stmts();
+        assert!(!reporter.had_error());
+        document("point.lox", &ast, &stmts, format)
+    }
+
+    #[test]
+    pub fn lists_declarations_with_their_doc_comments() {
+        assert_eq!(
+            document_src(Format::Markdown),
+            "# point.lox
+
+## Functions
+
+### `fun add(a: Number, b) -> Number`
+
+Adds two numbers.
+
+Both have to be numbers.
+
+### `fun helper()`
+
+## Classes
+
+### `class Point < Base`
+
+A point <on a plane>.
+
+#### `Point.init(x, y)`
+
+Makes a point.
+
+#### `Point.norm()`
+
+"
+        );
+    }
+
+    #[test]
+    pub fn writes_html() {
+        let html = document_src(Format::Html);
+        assert!(html.starts_with("<h1>point.lox</h1>\n<h2>Functions</h2>\n"));
+        assert!(html.contains(
+            "<h3><code>fun add(a: Number, b) -&gt; Number</code></h3>\n\
+             <p>Adds two numbers.</p>\n<p>Both have to be numbers.</p>\n"
+        ));
+        assert!(html.contains(
+            "<h3><code>class Point &lt; Base</code></h3>\n<p>A point &lt;on a plane&gt;.</p>\n"
+        ));
+    }
+}
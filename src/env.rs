@@ -1,73 +1,193 @@
 use std::{cell::RefCell, collections::HashMap, rc::Rc};
 
-use crate::{interpreter::RuntimeError, loxvalue::LoxValue};
+use crate::{
+    errors::Suggestions,
+    gc::{addr, Trace},
+    interpreter::RuntimeError,
+    loxvalue::LoxValue,
+    symbol::Symbol,
+};
 
+// Locals are stored in the order they're declared, which is the same order the resolver
+// assigns their slots in, so they can be accessed by index. Globals can be referenced
+// before they're defined and so aren't resolved, and are still looked up by their
+// interned name.
 #[derive(Debug)]
-pub struct Environment<'a> {
-    enclosing: Option<Rc<RefCell<Environment<'a>>>>,
-    values: HashMap<String, LoxValue<'a>>,
+pub struct Environment {
+    enclosing: Option<Rc<RefCell<Environment>>>,
+    slots: Vec<LoxValue>,
+    // The names of the slots, for looking variables up when debugging
+    names: Vec<Symbol>,
+    globals: HashMap<Symbol, LoxValue>,
 }
 
-impl<'a> Environment<'a> {
-    pub fn new(enclosing: Option<Rc<RefCell<Environment<'a>>>>) -> Self {
+impl Environment {
+    pub fn new(enclosing: Option<Rc<RefCell<Environment>>>) -> Self {
         Environment {
             enclosing,
-            values: HashMap::new(),
+            slots: Vec::new(),
+            names: Vec::new(),
+            globals: HashMap::new(),
         }
     }
 
-    pub fn enclosing(&self) -> Option<Rc<RefCell<Environment<'a>>>> {
+    pub fn enclosing(&self) -> Option<Rc<RefCell<Environment>>> {
         self.enclosing.clone()
     }
 
-    pub fn define(&mut self, name: &str, value: LoxValue<'a>) {
-        self.values.insert(name.to_string(), value);
+    pub fn clear(&mut self) {
+        self.enclosing = None;
+        self.slots.clear();
+        self.names.clear();
+        self.globals.clear();
     }
 
-    pub fn get(&self, name: &str) -> Result<LoxValue<'a>, RuntimeError<'a>> {
-        if let Some(val) = self.values.get(&name.to_string()) {
+    pub fn is_global(&self) -> bool {
+        self.enclosing.is_none()
+    }
+
+    pub fn define(&mut self, name: Symbol, value: LoxValue) {
+        if self.is_global() {
+            self.globals.insert(name, value);
+        } else {
+            self.slots.push(value);
+            self.names.push(name);
+        }
+    }
+
+    pub fn get(&self, name: &Symbol) -> Result<LoxValue, RuntimeError> {
+        if let Some(val) = self.globals.get(name) {
             Ok(val.clone())
         } else if let Some(parent) = &self.enclosing {
             (*parent).borrow().get(name)
         } else {
-            Err(RuntimeError::UndefinedVar(name.to_string()))
+            Err(self.undefined(name.as_str()))
+        }
+    }
+
+    // For code that hasn't been resolved, finds a variable by name in the innermost scope
+    // that has one
+    pub fn get_named(&self, name: &Symbol) -> Option<LoxValue> {
+        if let Some(slot) = self.names.iter().rposition(|n| n == name) {
+            Some(self.slots[slot].clone())
+        } else if let Some(val) = self.globals.get(name) {
+            Some(val.clone())
+        } else {
+            self.enclosing
+                .as_ref()
+                .and_then(|parent| parent.borrow().get_named(name))
+        }
+    }
+
+    pub fn assign_named(&mut self, name: &Symbol, value: LoxValue) -> Result<(), RuntimeError> {
+        if let Some(slot) = self.names.iter().rposition(|n| n == name) {
+            self.slots[slot] = value;
+            Ok(())
+        } else if let Some(slot) = self.globals.get_mut(name) {
+            *slot = value;
+            Ok(())
+        } else if let Some(parent) = &self.enclosing {
+            parent.borrow_mut().assign_named(name, value)
+        } else {
+            Err(self.undefined(name.as_str()))
+        }
+    }
+
+    // The names of the variables in this scope and those enclosing it
+    pub fn names(&self) -> Vec<Symbol> {
+        let mut names = self.names.clone();
+        names.extend(self.globals.keys().cloned());
+        if let Some(parent) = &self.enclosing {
+            names.extend(parent.borrow().names());
+        }
+        names
+    }
+
+    // The error for a variable that isn't defined here, suggesting those that are with
+    // similar names
+    pub fn undefined(&self, name: &str) -> RuntimeError {
+        let names = self
+            .names()
+            .iter()
+            .map(Symbol::to_string)
+            .collect::<Vec<_>>();
+        RuntimeError::UndefinedVar(name.to_string(), Suggestions::new(name, names))
+    }
+
+    // The variables defined in this scope, not including enclosing ones
+    pub fn variables(&self) -> Vec<(Symbol, LoxValue)> {
+        if self.is_global() {
+            self.globals
+                .iter()
+                .map(|(n, v)| (n.clone(), v.clone()))
+                .collect()
+        } else {
+            self.names
+                .iter()
+                .cloned()
+                .zip(self.slots.iter().cloned())
+                .collect()
         }
     }
 
-    pub fn get_at(&self, distance: usize, name: &str) -> Result<LoxValue<'a>, RuntimeError<'a>> {
+    pub fn get_at(&self, distance: usize, slot: usize) -> LoxValue {
         if distance == 0 {
-            self.get(name)
+            self.slots
+                .get(slot)
+                .cloned()
+                .expect("Resolver calculated a slot that hasn't been defined")
         } else if let Some(env) = &self.enclosing {
-            env.borrow_mut().get_at(distance - 1, name)
+            env.borrow().get_at(distance - 1, slot)
         } else {
             panic!("Resolver calculated distance greater than stack size");
         }
     }
 
-    pub fn assign(&mut self, name: &str, value: LoxValue<'a>) -> Result<(), RuntimeError<'a>> {
-        let nm = name.to_string();
-        if self.values.contains_key(&nm) {
-            self.values.insert(nm, value);
+    pub fn assign(&mut self, name: &Symbol, value: LoxValue) -> Result<(), RuntimeError> {
+        if let Some(slot) = self.globals.get_mut(name) {
+            *slot = value;
             Ok(())
         } else if let Some(parent) = &self.enclosing {
             (**parent).borrow_mut().assign(name, value)
         } else {
-            Err(RuntimeError::UndefinedVar(nm))
+            Err(self.undefined(name.as_str()))
         }
     }
 
-    pub fn assign_at(
-        &mut self,
-        distance: usize,
-        name: &str,
-        value: LoxValue<'a>,
-    ) -> Result<(), RuntimeError<'a>> {
+    pub fn assign_at(&mut self, distance: usize, slot: usize, value: LoxValue) {
         if distance == 0 {
-            self.assign(name, value)
+            *self
+                .slots
+                .get_mut(slot)
+                .expect("Resolver calculated a slot that hasn't been defined") = value;
         } else if let Some(env) = &self.enclosing {
-            env.borrow_mut().assign_at(distance - 1, name, value)
+            env.borrow_mut().assign_at(distance - 1, slot, value)
         } else {
             panic!("Resolver calculated distance greater than stack size");
         }
     }
 }
+
+impl Trace for Environment {
+    fn trace(&self, visit: &mut dyn FnMut(usize)) {
+        if let Some(enclosing) = &self.enclosing {
+            visit(addr(enclosing));
+        }
+        self.slots.iter().for_each(|v| v.trace(visit));
+        self.globals.values().for_each(|v| v.trace(visit));
+    }
+
+    fn size(&self) -> usize {
+        std::mem::size_of::<RefCell<Environment>>()
+            + self
+                .slots
+                .iter()
+                .map(|v| std::mem::size_of::<Symbol>() + v.size())
+                .sum::<usize>()
+            + self
+                .globals
+                .values()
+                .map(|v| std::mem::size_of::<Symbol>() + v.size())
+                .sum::<usize>()
+    }
+}
@@ -0,0 +1,740 @@
+//! Where errors and warnings from every stage go. An [`ErrorReporter`] collects them,
+//! for the caller to print with the positions and wording rlox always uses, or to
+//! inspect as [`Diagnostic`]s. Hosts can instead have them sent to a
+//! [`DiagnosticSink`] of their own as they're found.
+
+use crate::{
+    lint::{Level, Rule},
+    render::{self, ColorChoice, ErrorFormat},
+    tokens::{Span, Token, TokenType},
+};
+use serde::Serialize;
+use std::{
+    cell::RefCell,
+    collections::HashMap,
+    fmt, io,
+    sync::{Arc, Mutex},
+};
+
+// Deep recursion can leave thousands of frames on the stack, only the innermost are shown
+const MAX_BACKTRACE_FRAMES: usize = 16;
+
+pub struct StackFrame {
+    pub function: String,
+    pub line: usize,
+    pub column: usize,
+}
+
+// Column 0 means the column isn't known
+pub(crate) fn position(line: usize, column: usize) -> String {
+    if column == 0 {
+        line.to_string()
+    } else {
+        format!("{}:{}", line, column)
+    }
+}
+
+// At most this many names are suggested for one that isn't defined
+const MAX_SUGGESTIONS: usize = 3;
+
+// Names like one that isn't defined, for its error to suggest in its place. Only those
+// close enough to be a likely typo are kept, closest first.
+//
+// Boxed, and None when empty, to keep runtime errors as small as they were, as every
+// expression evaluated returns a result that might be one.
+#[allow(clippy::box_collection)]
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Suggestions(Option<Box<Vec<String>>>);
+
+impl Suggestions {
+    pub fn new(name: &str, candidates: impl IntoIterator<Item = String>) -> Suggestions {
+        // Any one-letter name is a single edit from another, so those are never close
+        let length = name.chars().count();
+        let max_distance = (length / 3).max(1).min(length.saturating_sub(1));
+        let mut close: Vec<(usize, String)> = candidates
+            .into_iter()
+            .filter(|candidate| candidate != name)
+            .map(|candidate| (edit_distance(name, &candidate), candidate))
+            .filter(|(distance, _)| *distance <= max_distance)
+            .collect();
+        close.sort();
+        close.dedup();
+        let names: Vec<String> = close
+            .into_iter()
+            .take(MAX_SUGGESTIONS)
+            .map(|(_, candidate)| candidate)
+            .collect();
+        Suggestions(Some(Box::new(names)).filter(|names| !names.is_empty()))
+    }
+
+    pub fn names(&self) -> &[String] {
+        self.0.as_deref().map_or(&[], Vec::as_slice)
+    }
+}
+
+// Follows the name in an error's message, as in "Undefined variable cuont. Did you mean
+// 'count'?", and is empty when there's nothing to suggest
+impl fmt::Display for Suggestions {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let quoted: Vec<String> = self
+            .names()
+            .iter()
+            .map(|name| format!("'{}'", name))
+            .collect();
+        match quoted.split_last() {
+            None => Ok(()),
+            Some((last, [])) => write!(f, ". Did you mean {}?", last),
+            Some((last, rest)) => write!(f, ". Did you mean {} or {}?", rest.join(", "), last),
+        }
+    }
+}
+
+// The edits to turn one name into the other, counting swapping two neighbouring
+// characters as one, since that's a common typo
+fn edit_distance(a: &str, b: &str) -> usize {
+    let (a, b): (Vec<char>, Vec<char>) = (a.chars().collect(), b.chars().collect());
+    // Rows for the prefixes of `a` up to two characters back
+    let mut before: Vec<usize> = Vec::new();
+    let mut previous: Vec<usize> = (0..=b.len()).collect();
+    for i in 1..=a.len() {
+        let mut current = vec![i; b.len() + 1];
+        for j in 1..=b.len() {
+            let cost = usize::from(a[i - 1] != b[j - 1]);
+            current[j] = (previous[j] + 1)
+                .min(current[j - 1] + 1)
+                .min(previous[j - 1] + cost);
+            if i > 1 && j > 1 && a[i - 1] == b[j - 2] && a[i - 2] == b[j - 1] {
+                current[j] = current[j].min(before[j - 2] + 1);
+            }
+        }
+        before = std::mem::replace(&mut previous, current);
+    }
+    previous[b.len()]
+}
+
+// Each warning is for a lint rule, so `rlox lint` can say which and leave out the ones
+// turned off
+#[derive(Clone, Debug, PartialEq)]
+pub struct Warning {
+    pub rule: Rule,
+    pub line: usize,
+    pub column: usize,
+    pub message: String,
+}
+
+impl fmt::Display for Warning {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "[line {}] Warning: {}",
+            position(self.line, self.column),
+            self.message
+        )
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Severity {
+    Error,
+    Warning,
+}
+
+// An error or warning kept with where it was found, for tools that point at it. It
+// prints the way errors always have.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Diagnostic {
+    pub severity: Severity,
+    // What kind of problem it is, such as the lint rule for a warning, when it has one
+    pub code: Option<String>,
+    pub line: usize,
+    pub column: usize,
+    // The token the error is in, when there is one
+    pub span: Option<Span>,
+    // Such as " at 'x'", for errors found before running
+    pub location: String,
+    pub message: String,
+    pub runtime: bool,
+    // Lines naming the calls a runtime error happened in, innermost first
+    pub backtrace: Vec<String>,
+}
+
+impl fmt::Display for Diagnostic {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let position = position(self.line, self.column);
+        if self.severity == Severity::Warning {
+            write!(f, "[line {}] Warning: {}", position, self.message)?;
+        } else if self.runtime {
+            write!(f, "[Line {}] Runtime Error: {}", position, self.message)?;
+        } else {
+            write!(
+                f,
+                "[line {}] Error {}: {}",
+                position, self.location, self.message
+            )?;
+        }
+        for line in &self.backtrace {
+            write!(f, "\n    {}", line)?;
+        }
+        Ok(())
+    }
+}
+
+impl From<&Warning> for Diagnostic {
+    fn from(warning: &Warning) -> Self {
+        Diagnostic {
+            severity: Severity::Warning,
+            code: Some(warning.rule.to_string()),
+            line: warning.line,
+            column: warning.column,
+            span: None,
+            location: String::new(),
+            message: warning.message.clone(),
+            runtime: false,
+            backtrace: Vec::new(),
+        }
+    }
+}
+
+/// Receives errors and warnings as they're reported, in place of the reporter keeping
+/// them.
+pub trait DiagnosticSink {
+    fn report(&mut self, diagnostic: Diagnostic);
+}
+
+// Source that errors can be shown against, whose spans count from `offset`
+struct Source {
+    name: String,
+    text: String,
+    offset: usize,
+}
+
+impl Source {
+    // Up to and including where its Eof is
+    fn contains(&self, span: Span) -> bool {
+        (self.offset..=self.offset + self.text.len()).contains(&span.start)
+    }
+}
+
+#[derive(Default)]
+pub struct ErrorReporter {
+    errors_collected: Arc<Mutex<Vec<Diagnostic>>>,
+    // Kept apart from errors, as they don't stop anything running
+    warnings: RefCell<Vec<Warning>>,
+    // Rules whose warnings aren't simply reported
+    levels: RefCell<HashMap<Rule, Level>>,
+    // Where diagnostics go instead of being collected, if anywhere
+    sink: RefCell<Option<Box<dyn DiagnosticSink>>>,
+    had_error: RefCell<bool>,
+    had_runtime_error: RefCell<bool>,
+    // What's been run, for printed errors to show the lines they're on, if it's kept
+    sources: RefCell<Option<Vec<Source>>>,
+    color: RefCell<ColorChoice>,
+    format: RefCell<ErrorFormat>,
+    // Errors found before running are counted, so they can stop at a limit rather than
+    // go on to report every error that follows from the first
+    max_errors: RefCell<Option<usize>>,
+    errors_counted: RefCell<usize>,
+    // Whether saying that the limit was reached has been printed
+    aborted_printed: RefCell<bool>,
+}
+
+impl ErrorReporter {
+    pub fn new() -> ErrorReporter {
+        ErrorReporter::default()
+    }
+
+    // Sends diagnostics to the sink as they're reported. Whether there have been errors
+    // is still tracked, but there are none to take.
+    pub fn with_sink(sink: Box<dyn DiagnosticSink>) -> ErrorReporter {
+        ErrorReporter {
+            sink: RefCell::new(Some(sink)),
+            ..ErrorReporter::default()
+        }
+    }
+
+    pub fn error(&self, line: usize, column: usize, message: &str) {
+        self.report(line, column, "", message);
+    }
+
+    pub fn token_error(&self, t: Token, msg: &str) {
+        self.report_token_error(None, t, msg);
+    }
+
+    // For errors that have a code, which `rlox --explain` can say more about
+    pub fn token_error_with_code(&self, code: &str, t: Token, msg: &str) {
+        self.report_token_error(Some(code), t, msg);
+    }
+
+    // For errors found in checking code before it's run, such as by the resolver. They
+    // stop it running, as parse errors do.
+    pub fn static_error(&self, code: &str, t: &Token, msg: &str) {
+        self.report_token_error(Some(code), t.clone(), msg);
+    }
+
+    fn report_token_error(&self, code: Option<&str>, t: Token, msg: &str) {
+        let location = if let TokenType::Eof = t.token_type {
+            " at end".to_string()
+        } else {
+            format!(" at '{}'", t.lexeme)
+        };
+        self.report_diagnostic(Diagnostic {
+            code: code.map(str::to_string),
+            span: Some(t.span),
+            ..self.diagnostic(t.line, t.column, &location, msg)
+        });
+    }
+
+    pub fn runtime_error(&self, t: &Token, msg: &str) {
+        self.had_runtime_error.replace(true);
+        let diagnostic = Diagnostic {
+            span: Some(t.span),
+            runtime: true,
+            ..self.diagnostic(t.line, t.column, "", msg)
+        };
+        if self.count_error() {
+            self.send(diagnostic);
+        }
+    }
+
+    // Frames are given outermost first, and printed innermost first
+    pub fn runtime_error_with_backtrace(
+        &self,
+        code: &str,
+        line: usize,
+        column: usize,
+        span: Option<Span>,
+        msg: &str,
+        frames: &[StackFrame],
+    ) {
+        self.had_runtime_error.replace(true);
+        let mut backtrace: Vec<String> = frames
+            .iter()
+            .rev()
+            .take(MAX_BACKTRACE_FRAMES)
+            .map(|frame| {
+                format!(
+                    "at {}() called from line {}",
+                    frame.function,
+                    position(frame.line, frame.column)
+                )
+            })
+            .collect();
+        if frames.len() > MAX_BACKTRACE_FRAMES {
+            backtrace.push(format!("... {} more", frames.len() - MAX_BACKTRACE_FRAMES));
+        }
+        let diagnostic = Diagnostic {
+            code: Some(code.to_string()),
+            span,
+            runtime: true,
+            backtrace,
+            ..self.diagnostic(line, column, "", msg)
+        };
+        self.send(diagnostic);
+    }
+
+    pub fn report(&self, line: usize, column: usize, location: &str, msg: &str) {
+        self.report_diagnostic(self.diagnostic(line, column, location, msg));
+    }
+
+    fn report_diagnostic(&self, diagnostic: Diagnostic) {
+        self.had_error.replace(true);
+        if self.count_error() {
+            self.send(diagnostic);
+        }
+    }
+
+    // Returns false for errors past the limit, which are left out
+    fn count_error(&self) -> bool {
+        if self.too_many_errors() {
+            return false;
+        }
+        *self.errors_counted.borrow_mut() += 1;
+        true
+    }
+
+    // Errors found before running past the first `max` are left out, and the parser
+    // stops once it's reached. There's no limit by default.
+    pub fn set_max_errors(&self, max: Option<usize>) {
+        self.max_errors.replace(max);
+    }
+
+    pub fn too_many_errors(&self) -> bool {
+        self.max_errors
+            .borrow()
+            .is_some_and(|max| *self.errors_counted.borrow() >= max)
+    }
+
+    fn send(&self, diagnostic: Diagnostic) {
+        match &mut *self.sink.borrow_mut() {
+            Some(sink) => sink.report(diagnostic),
+            None => self.errors_collected.lock().unwrap().push(diagnostic),
+        }
+    }
+
+    fn diagnostic(&self, line: usize, column: usize, location: &str, msg: &str) -> Diagnostic {
+        Diagnostic {
+            severity: Severity::Error,
+            code: None,
+            line,
+            column,
+            span: None,
+            location: location.to_string(),
+            message: msg.to_string(),
+            runtime: false,
+            backtrace: Vec::new(),
+        }
+    }
+
+    pub fn warning(&self, rule: Rule, line: usize, column: usize, msg: &str) {
+        let warning = Warning {
+            rule,
+            line,
+            column,
+            message: msg.to_string(),
+        };
+        match self.level(rule) {
+            Level::Allow => {}
+            Level::Warn => match &mut *self.sink.borrow_mut() {
+                Some(sink) => sink.report(Diagnostic::from(&warning)),
+                None => self.warnings.borrow_mut().push(warning),
+            },
+            Level::Deny => self.report_diagnostic(Diagnostic {
+                severity: Severity::Error,
+                ..Diagnostic::from(&warning)
+            }),
+        }
+    }
+
+    // Rules are warned about unless they're given another level. Whether a rule is
+    // checked for at all is up to what checks for it.
+    pub fn set_level(&self, rule: Rule, level: Level) {
+        self.levels.borrow_mut().insert(rule, level);
+    }
+
+    pub fn level(&self, rule: Rule) -> Level {
+        self.levels
+            .borrow()
+            .get(&rule)
+            .copied()
+            .unwrap_or(Level::Warn)
+    }
+
+    pub fn take_warnings(&self) -> Vec<Warning> {
+        self.warnings.borrow_mut().drain(..).collect()
+    }
+
+    // Warnings go to stderr, so they don't get mixed up with the program's output
+    pub fn print_warnings(&self) {
+        let color = self.color.borrow().enabled(&io::stderr());
+        for warning in self.take_warnings() {
+            eprintln!("{}", self.render(&Diagnostic::from(&warning), color));
+        }
+    }
+
+    pub fn had_error(&self) -> bool {
+        *self.had_error.borrow()
+    }
+
+    pub fn had_runtime_error(&self) -> bool {
+        *self.had_runtime_error.borrow()
+    }
+
+    // Errors are only printed once, later calls print whatever was collected since. As
+    // JSON they go to stderr along with warnings, leaving stdout to the program.
+    pub fn print_collected_errors(&self) {
+        let color = self.color.borrow().enabled(&io::stdout());
+        for diagnostic in self.take_diagnostics() {
+            match *self.format.borrow() {
+                ErrorFormat::Human => println!("{}", self.render(&diagnostic, color)),
+                ErrorFormat::Json => eprintln!("{}", self.render(&diagnostic, color)),
+            }
+        }
+        if self.too_many_errors() && !self.aborted_printed.replace(true) {
+            let message = format!(
+                "Too many errors, aborting after the first {}",
+                self.errors_counted.borrow()
+            );
+            match *self.format.borrow() {
+                ErrorFormat::Human => println!("{}", message),
+                ErrorFormat::Json => eprintln!(
+                    "{}",
+                    serde_json::json!({ "severity": Severity::Error, "message": message })
+                ),
+            }
+        }
+    }
+
+    // Has errors that are printed show the line of the source they're on, for source
+    // added from now on
+    pub fn keep_sources(&self) {
+        self.sources.borrow_mut().get_or_insert_with(Vec::new);
+    }
+
+    // Keeps source that's about to be run, if sources are kept, giving the offset to scan
+    // it from. Errors in it are shown against it, and say it's called `name`. Each source
+    // gets offsets of its own, so it's kept apart from those before it, such as the
+    // REPL's earlier inputs that functions being called were defined in.
+    pub fn add_source(&self, name: &str, text: &str) -> Option<usize> {
+        let mut sources = self.sources.borrow_mut();
+        let sources = sources.as_mut()?;
+        // Past the Eof of the one before
+        let offset = sources
+            .last()
+            .map_or(0, |source| source.offset + source.text.len() + 1);
+        sources.push(Source {
+            name: name.to_string(),
+            text: text.to_string(),
+            offset,
+        });
+        Some(offset)
+    }
+
+    // For source whose code has been dropped, such as eval()'d code once it's run. It's
+    // kept if errors in it are yet to be printed.
+    pub fn remove_source(&self, offset: usize) {
+        let collected = self.errors_collected.lock().unwrap();
+        if let Some(sources) = self.sources.borrow_mut().as_mut() {
+            sources.retain(|source| {
+                source.offset != offset
+                    || collected
+                        .iter()
+                        .any(|diagnostic| diagnostic.span.is_some_and(|span| source.contains(span)))
+            });
+        }
+    }
+
+    // Whether printed errors and warnings are coloured, which they aren't by default
+    pub fn set_color(&self, color: ColorChoice) {
+        self.color.replace(color);
+    }
+
+    // How printed errors and warnings are written out, which is for people by default
+    pub fn set_error_format(&self, format: ErrorFormat) {
+        self.format.replace(format);
+    }
+
+    // Reads as it's printed, against the source it's in when that's kept
+    pub fn render(&self, diagnostic: &Diagnostic, color: bool) -> String {
+        let sources = self.sources.borrow();
+        let sources = sources.as_deref().unwrap_or_default();
+        // Without a span, it can only be told which source it's in when there's only one
+        let source = match diagnostic.span {
+            Some(span) => sources.iter().find(|source| source.contains(span)),
+            None => sources.first().filter(|_| sources.len() == 1),
+        };
+        // Spans are counted from the start of their source
+        let diagnostic = &Diagnostic {
+            span: diagnostic.span.map(|span| Span {
+                start: span.start - source.map_or(0, |source| source.offset),
+                end: span.end - source.map_or(0, |source| source.offset),
+            }),
+            ..diagnostic.clone()
+        };
+        let source = source.map(|source| (source.name.as_str(), source.text.as_str()));
+        match *self.format.borrow() {
+            ErrorFormat::Human => render::render(diagnostic, source, color),
+            ErrorFormat::Json => render::render_json(diagnostic, source.map(|(name, _)| name)),
+        }
+    }
+
+    pub fn take_errors(&self) -> Vec<String> {
+        self.take_diagnostics()
+            .iter()
+            .map(|diagnostic| diagnostic.to_string())
+            .collect()
+    }
+
+    pub fn take_diagnostics(&self) -> Vec<Diagnostic> {
+        self.errors_collected.lock().unwrap().drain(..).collect()
+    }
+
+    // Runs `f`, handing back any runtime errors it reports rather than recording them or
+    // sending them to the sink
+    pub fn capture_runtime_errors<T>(&self, f: impl FnOnce() -> T) -> (T, Vec<String>) {
+        let had_runtime_error = self.had_runtime_error();
+        let collected = self.errors_collected.lock().unwrap().len();
+        let sink = self.sink.borrow_mut().take();
+        let result = f();
+        self.sink.replace(sink);
+        self.had_runtime_error.replace(had_runtime_error);
+        let errors = self
+            .errors_collected
+            .lock()
+            .unwrap()
+            .drain(collected..)
+            .map(|diagnostic| diagnostic.to_string())
+            .collect();
+        (result, errors)
+    }
+
+    pub fn reset(&self) {
+        self.had_error.replace(false);
+        self.had_runtime_error.replace(false);
+        self.errors_counted.replace(0);
+        self.aborted_printed.replace(false);
+        self.errors_collected.lock().unwrap().clear();
+        self.warnings.borrow_mut().clear();
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::rc::Rc;
+
+    #[test]
+    pub fn runtime_errors_include_backtrace() {
+        let reporter = ErrorReporter::new();
+        let frames = [
+            StackFrame {
+                function: "outer".to_string(),
+                line: 10,
+                column: 3,
+            },
+            StackFrame {
+                function: "inner".to_string(),
+                line: 4,
+                column: 12,
+            },
+        ];
+        reporter.runtime_error_with_backtrace("E0211", 2, 7, None, "Oops", &frames);
+        assert!(reporter.had_runtime_error());
+        assert_eq!(
+            reporter.errors_collected.lock().unwrap()[0].to_string(),
+            "[Line 2:7] Runtime Error: Oops\n    at inner() called from line 4:12\n    at outer() called from line 10:3"
+        );
+        assert_eq!(
+            reporter.take_diagnostics()[0].code.as_deref(),
+            Some("E0211")
+        );
+    }
+
+    #[derive(Clone, Default)]
+    struct Recorder(Rc<RefCell<Vec<Diagnostic>>>);
+
+    impl DiagnosticSink for Recorder {
+        fn report(&mut self, diagnostic: Diagnostic) {
+            self.0.borrow_mut().push(diagnostic);
+        }
+    }
+
+    #[test]
+    pub fn diagnostics_can_go_to_a_sink() {
+        let recorder = Recorder::default();
+        let reporter = ErrorReporter::with_sink(Box::new(recorder.clone()));
+        reporter.error(3, 5, "Bad");
+        reporter.warning(Rule::EmptyBlock, 4, 1, "Empty block");
+        assert!(reporter.had_error());
+        assert!(reporter.take_diagnostics().is_empty());
+        assert!(reporter.take_warnings().is_empty());
+
+        let diagnostics = recorder.0.borrow();
+        assert_eq!(diagnostics.len(), 2);
+        assert_eq!(diagnostics[0].severity, Severity::Error);
+        assert_eq!((diagnostics[0].line, diagnostics[0].column), (3, 5));
+        assert_eq!(diagnostics[0].message, "Bad");
+        assert_eq!(diagnostics[1].severity, Severity::Warning);
+        assert_eq!(diagnostics[1].code.as_deref(), Some("empty-block"));
+        assert_eq!(
+            diagnostics[1].to_string(),
+            "[line 4:1] Warning: Empty block"
+        );
+    }
+
+    #[test]
+    pub fn keeps_sources_apart() {
+        let reporter = ErrorReporter::new();
+        assert_eq!(reporter.add_source("a.lox", "print 1;"), None);
+        reporter.keep_sources();
+        assert_eq!(reporter.add_source("a.lox", "print 1;"), Some(0));
+        assert_eq!(reporter.add_source("<eval>", "x"), Some(9));
+        assert_eq!(reporter.add_source("<eval>", "nil - 1"), Some(11));
+
+        let error = |start, end| Diagnostic {
+            span: Some(Span { start, end }),
+            runtime: true,
+            ..reporter.diagnostic(1, 1, "", "Oops")
+        };
+        reporter.send(error(11, 18));
+        // Sources are only dropped once errors in them have been printed
+        reporter.remove_source(9);
+        reporter.remove_source(11);
+        let names = |reporter: &ErrorReporter| {
+            let sources = reporter.sources.borrow();
+            let sources = sources.as_ref().unwrap();
+            sources
+                .iter()
+                .map(|source| source.name.clone())
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(names(&reporter), ["a.lox", "<eval>"]);
+        assert!(reporter
+            .render(&reporter.take_diagnostics()[0], false)
+            .contains("--> <eval>:1:1\n  |\n1 | nil - 1\n  | ^^^^^^^"));
+        assert!(reporter
+            .render(&error(6, 7), false)
+            .contains("--> a.lox:1:1\n  |\n1 | print 1;\n  |       ^"));
+    }
+
+    #[test]
+    pub fn suggests_similar_names() {
+        let names = |list: &[&str]| list.iter().map(|name| name.to_string()).collect::<Vec<_>>();
+        assert_eq!(edit_distance("cuont", "count"), 1);
+        assert_eq!(edit_distance("kitten", "sitting"), 3);
+        let suggestions = Suggestions::new("cuont", names(&["count", "counter", "amount", "x"]));
+        assert_eq!(suggestions.names(), ["count"]);
+        assert_eq!(suggestions.to_string(), ". Did you mean 'count'?");
+
+        let suggestions = Suggestions::new("nme", names(&["name", "nm", "me", "nme", "mean"]));
+        assert_eq!(
+            suggestions.to_string(),
+            ". Did you mean 'me', 'name' or 'nm'?"
+        );
+        assert_eq!(Suggestions::new("total", names(&["sum"])).to_string(), "");
+        assert_eq!(Suggestions::new("z", names(&["a", "f"])).to_string(), "");
+    }
+
+    #[test]
+    pub fn errors_stop_at_the_limit() {
+        let reporter = ErrorReporter::new();
+        reporter.set_max_errors(Some(2));
+        reporter.error(1, 1, "First");
+        assert!(!reporter.too_many_errors());
+        reporter.error(2, 1, "Second");
+        assert!(reporter.too_many_errors());
+        reporter.error(3, 1, "Third");
+        assert!(reporter.had_error());
+        assert_eq!(
+            reporter.take_errors(),
+            vec!["[line 1:1] Error : First", "[line 2:1] Error : Second"]
+        );
+        reporter.reset();
+        assert!(!reporter.too_many_errors());
+    }
+
+    #[test]
+    pub fn warnings_can_be_allowed_or_denied() {
+        let reporter = ErrorReporter::new();
+        reporter.set_level(Rule::EmptyBlock, Level::Allow);
+        reporter.set_level(Rule::Shadowing, Level::Deny);
+        reporter.warning(Rule::EmptyBlock, 1, 1, "Empty block");
+        reporter.warning(Rule::UnusedVariable, 2, 1, "Unused local variable 'a'");
+        assert!(!reporter.had_error());
+        reporter.warning(
+            Rule::Shadowing,
+            3,
+            1,
+            "'a' shadows the declaration on line 2",
+        );
+        assert!(reporter.had_error());
+
+        let warnings = reporter.take_warnings();
+        assert_eq!(warnings.len(), 1);
+        assert_eq!(warnings[0].rule, Rule::UnusedVariable);
+        let errors = reporter.take_diagnostics();
+        assert_eq!(errors.len(), 1);
+        assert_eq!(errors[0].severity, Severity::Error);
+        assert_eq!(errors[0].code.as_deref(), Some("shadowing"));
+    }
+}
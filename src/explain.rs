@@ -0,0 +1,574 @@
+//! Longer descriptions of the errors rlox reports, each with an example of code that
+//! has it. Every kind of [`ParseError`](crate::parser::ParseError),
+//! [`ResolveError`](crate::resolver::ResolveError) and
+//! [`RuntimeError`](crate::interpreter::RuntimeError) has a code, such as E0210, that
+//! stays the same from one version to the next, which `rlox --explain` looks up here.
+
+use std::fmt;
+
+pub struct Explanation {
+    pub code: &'static str,
+    // The variant of the error it's for
+    pub name: &'static str,
+    pub description: &'static str,
+    pub example: &'static str,
+}
+
+impl fmt::Display for Explanation {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "{} {}", self.code, self.name)?;
+        writeln!(f)?;
+        writeln!(f, "{}", self.description)?;
+        writeln!(f)?;
+        writeln!(f, "For example:")?;
+        writeln!(f)?;
+        for line in self.example.lines() {
+            writeln!(f, "    {}", line)?;
+        }
+        Ok(())
+    }
+}
+
+// By code, or by name, ignoring case either way
+pub fn lookup(code: &str) -> Option<&'static Explanation> {
+    EXPLANATIONS
+        .iter()
+        .find(|e| e.code.eq_ignore_ascii_case(code) || e.name.eq_ignore_ascii_case(code))
+}
+
+// Parse errors are E01xx, runtime errors E02xx, and errors the resolver finds E03xx
+pub const EXPLANATIONS: &[Explanation] = &[
+    Explanation {
+        code: "E0101",
+        name: "CallExpectPropertyName",
+        description: "A '.' has to be followed by the name of the property or method to get.",
+        example: "var point = Point(1, 2);\nprint point.;",
+    },
+    Explanation {
+        code: "E0102",
+        name: "CallRightParenExpected",
+        description: "The arguments to a call weren't followed by a ')'. Arguments are \
+                      separated by commas, and the last is followed by the closing paren.",
+        example: "print max(1, 2;",
+    },
+    Explanation {
+        code: "E0103",
+        name: "CallTooManyArgs",
+        description: "A call can have at most 255 arguments. Pass a list or an instance \
+                      holding the values instead.",
+        example: "f(a0, a1, a2, /* ... */ a255);",
+    },
+    Explanation {
+        code: "E0104",
+        name: "ClassExpectIdentifier",
+        description: "The 'class' keyword has to be followed by the name of the class.",
+        example: "class {\n  init() {}\n}",
+    },
+    Explanation {
+        code: "E0105",
+        name: "ClassExpectLeftBrace",
+        description: "A class's name, and its superclass if it has one, have to be followed \
+                      by a '{' opening the class body.",
+        example: "class Point\n  init(x, y) {}\n}",
+    },
+    Explanation {
+        code: "E0106",
+        name: "ClassExpectRightBrace",
+        description: "A class body wasn't closed with a '}' before the end of the file. The \
+                      error says which line the body was opened on.",
+        example: "class Point {\n  init(x, y) {}\n",
+    },
+    Explanation {
+        code: "E0107",
+        name: "ClassExpectSuperClass",
+        description: "A '<' after a class's name has to be followed by the name of the class \
+                      it inherits from.",
+        example: "class Circle < {}",
+    },
+    Explanation {
+        code: "E0108",
+        name: "ColonExpectedInTernary",
+        description: "The conditional operator needs both branches: a ':' separates the \
+                      value when the condition is true from the value when it's false.",
+        example: "var n = -2;\nn < 0 ? n = -n;",
+    },
+    Explanation {
+        code: "E0109",
+        name: "ExpressionExpected",
+        description: "A value was needed, such as a number, a variable or a call, but the \
+                      code there isn't one. Often there's an operator missing an operand.",
+        example: "var total = 1 + ;",
+    },
+    Explanation {
+        code: "E0110",
+        name: "ForStmtLeftParenExpected",
+        description: "The 'for' keyword has to be followed by a '(' before the initializer, \
+                      condition and increment.",
+        example: "for var i = 0; i < 3; i = i + 1) print i;",
+    },
+    Explanation {
+        code: "E0111",
+        name: "ForStmtRightParenExpected",
+        description: "The increment of a 'for' loop has to be followed by a ')'.",
+        example: "for (var i = 0; i < 3; i = i + 1 print i;",
+    },
+    Explanation {
+        code: "E0112",
+        name: "ForStmtSemiColonExpected",
+        description: "The condition of a 'for' loop has to be followed by a ';', even if \
+                      there's no increment.",
+        example: "for (var i = 0; i < 3) print i;",
+    },
+    Explanation {
+        code: "E0113",
+        name: "FunctionExpectBlockOpen",
+        description: "A function's parameters have to be followed by a '{' opening its \
+                      body, even when the body is a single statement.",
+        example: "fun double(n) return n * 2;",
+    },
+    Explanation {
+        code: "E0114",
+        name: "FunctionExpectIdentifier",
+        description: "The 'fun' keyword has to be followed by the name of the function. \
+                      There are no anonymous functions: declare a named one, and use its \
+                      name as the value.",
+        example: "fun (n) {\n  return n * 2;\n}",
+    },
+    Explanation {
+        code: "E0115",
+        name: "FunctionExpectLeftParen",
+        description: "A function's name has to be followed by its parameters in \
+                      parentheses, which are empty if it has none.",
+        example: "fun greet {\n  print \"Hello\";\n}",
+    },
+    Explanation {
+        code: "E0116",
+        name: "FunctionExpectParamName",
+        description: "Each of a function's parameters has to be a name, and a comma can't \
+                      come after the last one.",
+        example: "fun add(a, b,) {\n  return a + b;\n}",
+    },
+    Explanation {
+        code: "E0117",
+        name: "FunctionExpectRightParen",
+        description: "A function's parameters have to be followed by a ')'. Parameters are \
+                      separated by commas.",
+        example: "fun add(a b) {\n  return a + b;\n}",
+    },
+    Explanation {
+        code: "E0118",
+        name: "FunctionTooManyArgs",
+        description: "A function can have at most 255 parameters. Pass a list or an \
+                      instance holding the values instead.",
+        example: "fun f(a0, a1, a2, /* ... */ a255) {}",
+    },
+    Explanation {
+        code: "E0119",
+        name: "IfStmtLeftParenExpected",
+        description: "The condition of an 'if' has to be in parentheses.",
+        example: "if n > 0 print n;",
+    },
+    Explanation {
+        code: "E0120",
+        name: "IfStmtRightParenExpected",
+        description: "The condition of an 'if' has to be followed by a ')'.",
+        example: "if (n > 0 print n;",
+    },
+    Explanation {
+        code: "E0121",
+        name: "InvalidAssignmentTarget",
+        description: "Only variables and the fields of instances can be assigned to. The \
+                      left of an '=' can't be a value or the result of an operator, and \
+                      '==' is the operator for comparing.",
+        example: "var a = 1;\nif (a + 1 = 2) print a;",
+    },
+    Explanation {
+        code: "E0122",
+        name: "RightBraceExpected",
+        description: "A block wasn't closed with a '}' before the end of the file. The \
+                      error says which line the block was opened on, though the missing \
+                      brace is often that of a block inside it.",
+        example: "if (true) {\n  print \"yes\";",
+    },
+    Explanation {
+        code: "E0123",
+        name: "RightParenMissing",
+        description: "A '(' grouping an expression wasn't closed with a ')'.",
+        example: "print (1 + 2 * 3;",
+    },
+    Explanation {
+        code: "E0124",
+        name: "SemiColonExpected",
+        description: "Statements end with a ';'. When the statement started on an earlier \
+                      line than the error, the error says which, as the ';' is likely \
+                      missing from the end of that line.",
+        example: "var a = 1\nprint a;",
+    },
+    Explanation {
+        code: "E0125",
+        name: "SuperExpectDot",
+        description: "'super' can only be used to call or get a method of the superclass, \
+                      so it has to be followed by a '.' and the method's name.",
+        example: "class B < A {\n  init() {\n    super();\n  }\n}",
+    },
+    Explanation {
+        code: "E0126",
+        name: "SuperExpectMethodName",
+        description: "The '.' after 'super' has to be followed by the name of a method of \
+                      the superclass.",
+        example: "class B < A {\n  init() {\n    super.();\n  }\n}",
+    },
+    Explanation {
+        code: "E0127",
+        name: "TypeNameExpected",
+        description: "A ':' after a variable or parameter name has to be followed by the \
+                      name of a type, such as Number, String or a class.",
+        example: "var count: = 0;",
+    },
+    Explanation {
+        code: "E0128",
+        name: "TooDeeplyNested",
+        description: "Statements and expressions nested inside each other more deeply than \
+                      the parser allows. Move some of the inner code into functions, or \
+                      raise the limit with --max-parse-depth.",
+        example: "print ((((((((((/* ... */ 1 /* ... */))))))))));",
+    },
+    Explanation {
+        code: "E0129",
+        name: "VariableNameExpected",
+        description: "The 'var' keyword has to be followed by the name of the variable.",
+        example: "var = 1;",
+    },
+    Explanation {
+        code: "E0130",
+        name: "WhileStmtLeftParenExpected",
+        description: "The condition of a 'while' loop has to be in parentheses.",
+        example: "while n > 0 n = n - 1;",
+    },
+    Explanation {
+        code: "E0131",
+        name: "WhileStmtRightParenExpected",
+        description: "The condition of a 'while' loop has to be followed by a ')'.",
+        example: "while (n > 0 n = n - 1;",
+    },
+    Explanation {
+        code: "E0201",
+        name: "CallOnNonCallable",
+        description: "Only functions and classes can be called. The value before the '(' \
+                      is something else, often a variable that was given a new value.",
+        example: "var greeting = \"Hello\";\ngreeting();",
+    },
+    Explanation {
+        code: "E0202",
+        name: "CallWrongNumberOfArgs",
+        description: "A function or class was called with a different number of arguments \
+                      from the parameters it has. A class takes the arguments its init() \
+                      method does.",
+        example: "fun add(a, b) {\n  return a + b;\n}\nadd(1);",
+    },
+    Explanation {
+        code: "E0203",
+        name: "FieldAccessOnNonInstance",
+        description: "Only instances of classes have fields and methods to get or set with \
+                      '.'. Numbers, strings and the other built-in values don't.",
+        example: "var n = 1;\nprint n.value;",
+    },
+    Explanation {
+        code: "E0204",
+        name: "OperandsMustBeNumbers",
+        description: "Arithmetic and comparisons other than '==' and '!=' only work on \
+                      numbers.",
+        example: "print \"10\" < 9;",
+    },
+    Explanation {
+        code: "E0205",
+        name: "PlusOperandsWrong",
+        description: "'+' adds two numbers, or joins a string to any value. Anything else, \
+                      such as a number and a string the other way round, can't be added.",
+        example: "print 1 + \" apple\";",
+    },
+    Explanation {
+        code: "E0206",
+        name: "SuperclassMustBeAClass",
+        description: "A class can only inherit from a class. The name after '<' is a \
+                      variable holding something else.",
+        example: "var Base = \"base\";\nclass Derived < Base {}",
+    },
+    Explanation {
+        code: "E0207",
+        name: "UndefinedProperty",
+        description: "The instance has no field or method with that name. Fields only \
+                      exist once they're set, usually in init().",
+        example: "class Point {}\nvar p = Point();\nprint p.x;",
+    },
+    Explanation {
+        code: "E0208",
+        name: "UnsupportedOperation",
+        description: "The operator can't be used with the values it was given.",
+        example: "print -\"one\";",
+    },
+    Explanation {
+        code: "E0209",
+        name: "DivideByZero",
+        description: "A number was divided by zero, which has no result.",
+        example: "var count = 0;\nprint 10 / count;",
+    },
+    Explanation {
+        code: "E0210",
+        name: "UndefinedVar",
+        description: "There's no variable with that name where it's used. It may be \
+                      misspelt, declared later on, or declared in a block that has ended.",
+        example: "{\n  var inner = 1;\n}\nprint inner;",
+    },
+    Explanation {
+        code: "E0211",
+        name: "NativeArgument",
+        description: "A built-in function, or one the host running the script provides, \
+                      couldn't use an argument it was given. The error says what it \
+                      expected.",
+        example: "print chr(\"A\");",
+    },
+    Explanation {
+        code: "E0212",
+        name: "StackOverflow",
+        description: "More function calls were in progress at once than allowed, most \
+                      often from recursion that never stops. Check the recursion has a \
+                      case that returns without calling again, or raise the limit with \
+                      --max-call-depth.",
+        example: "fun forever(n) {\n  return forever(n + 1);\n}\nforever(0);",
+    },
+    Explanation {
+        code: "E0213",
+        name: "ExpressionTooDeep",
+        description: "An expression was nested more deeply than allowed while it ran. \
+                      Split it up using variables, or raise the limit with \
+                      --max-expr-depth.",
+        example: "print 1 + (1 + (1 + (1 + /* ... */ 1)));",
+    },
+    Explanation {
+        code: "E0214",
+        name: "BudgetExceeded",
+        description: "The script ran more statements and expressions than it was allowed \
+                      to with --max-steps, which is often an endless loop.",
+        example: "while (true) {}",
+    },
+    Explanation {
+        code: "E0215",
+        name: "Timeout",
+        description: "The script ran for longer than it was allowed to with --timeout, \
+                      which is often an endless loop.",
+        example: "while (true) {}",
+    },
+    Explanation {
+        code: "E0216",
+        name: "OutOfMemory",
+        description: "The script's values took up more memory than it was allowed with \
+                      --max-memory, even after collecting the ones no longer used.",
+        example: "var s = \"x\";\nwhile (true) s = s + s;",
+    },
+    Explanation {
+        code: "E0217",
+        name: "Interrupted",
+        description: "The script was stopped before it finished, by Ctrl-C or by the host \
+                      running it.",
+        example: "while (true) {}",
+    },
+    Explanation {
+        code: "E0218",
+        name: "GeneratorRunning",
+        description: "A generator was resumed from inside itself. A generator can't ask \
+                      for its own next value while it's working out the current one.",
+        example: "var g;\nfun count() {\n  yield g.next();\n}\ng = count();\ng.next();",
+    },
+    Explanation {
+        code: "E0219",
+        name: "Deadlock",
+        description: "A receive() was waiting on a channel that's empty, with no other task \
+                      left that could send to it, so it would have waited forever.",
+        example: "var c = channel();\nprint c.receive();",
+    },
+    Explanation {
+        code: "E0220",
+        name: "Output",
+        description: "What the script printed couldn't be written, such as when the \
+                      program it's piped to has exited.",
+        example: "print \"Hello\";",
+    },
+    Explanation {
+        code: "E0221",
+        name: "Breaking",
+        description: "How 'break' leaves a loop inside the interpreter, rather than an \
+                      error. It's only seen by hosts running statements the resolver \
+                      hasn't checked, where a 'break' is outside of a loop.",
+        example: "break;",
+    },
+    Explanation {
+        code: "E0222",
+        name: "Return",
+        description: "How 'return' leaves a function inside the interpreter, rather than an \
+                      error. It's only seen by hosts running statements the resolver \
+                      hasn't checked, where a 'return' is outside of a function.",
+        example: "return 1;",
+    },
+    Explanation {
+        code: "E0223",
+        name: "EvalSource",
+        description: "The source given to eval() has a syntax error, or a mistake the \
+                      resolver finds, such as a 'return' outside of a function. None of it \
+                      is run.",
+        example: "eval(\"print 1 +;\");",
+    },
+    Explanation {
+        code: "E0224",
+        name: "ProgramAfterOtherCode",
+        description: "A host gave an interpreter a compiled program to run after giving it \
+                      other code. A program can only be run by an interpreter that's new, \
+                      or that has only run that program. This is an example of the host's \
+                      code, in Rust.",
+        example: "let program = Program::compile(\"fun f() {}\", &reporter).unwrap();\n\
+                  interpreter.run(\"var a = 1;\");\n\
+                  interpreter.run_program(&program)?;",
+    },
+    Explanation {
+        code: "E0301",
+        name: "AlreadyDeclared",
+        description: "A block declares two variables with the same name. Give one of them \
+                      another name, or assign to the first rather than declaring it again.",
+        example: "{\n  var a = 1;\n  var a = 2;\n}",
+    },
+    Explanation {
+        code: "E0302",
+        name: "BreakOutsideLoop",
+        description: "A 'break' isn't inside a 'while' or 'for' loop, so there's nothing \
+                      for it to leave. A function declared inside a loop doesn't count as \
+                      being in it.",
+        example: "break;",
+    },
+    Explanation {
+        code: "E0303",
+        name: "ClassInheritsFromItself",
+        description: "A class names itself as its superclass.",
+        example: "class Oops < Oops {}",
+    },
+    Explanation {
+        code: "E0304",
+        name: "DuplicateParameter",
+        description: "A function has two parameters with the same name, so the second \
+                      would hide the first. The error says where the first one is.",
+        example: "fun add(a, a) {\n  return a + a;\n}",
+    },
+    Explanation {
+        code: "E0305",
+        name: "ReturnFromInitializer",
+        description: "An init() method returns a value. An initializer always gives back \
+                      the new instance, so it can only use a bare 'return;'.",
+        example: "class Point {\n  init(x) {\n    return x;\n  }\n}",
+    },
+    Explanation {
+        code: "E0306",
+        name: "ReturnFromTopLevel",
+        description: "A 'return' isn't inside a function, so there's nothing for it to \
+                      return from.",
+        example: "return 1;",
+    },
+    Explanation {
+        code: "E0307",
+        name: "SuperOutsideClass",
+        description: "'super' is used outside of a class. It only means something in the \
+                      methods of a class with a superclass.",
+        example: "fun f() {\n  return super.init();\n}",
+    },
+    Explanation {
+        code: "E0308",
+        name: "SuperWithoutSuperclass",
+        description: "'super' is used in a method of a class that doesn't inherit from \
+                      another, so there's no superclass to call methods on.",
+        example: "class Point {\n  init() {\n    super.init();\n  }\n}",
+    },
+    Explanation {
+        code: "E0309",
+        name: "ThisOutsideClass",
+        description: "'this' is used outside of a class. It only means something in \
+                      methods, where it's the instance they were called on.",
+        example: "fun name() {\n  return this.name;\n}",
+    },
+    Explanation {
+        code: "E0310",
+        name: "UndeclaredVariable",
+        description: "With --strict, a global is used that's never declared anywhere in \
+                      the script, which is usually a typo. Without it, this is only found \
+                      when the code runs.",
+        example: "fun greet() {\n  print nmae;\n}\nvar name = \"Ada\";",
+    },
+    Explanation {
+        code: "E0311",
+        name: "VariableReadInInitializer",
+        description: "A local variable is read in its own initializer, before it has a \
+                      value. Give it another name to use an outer variable of the same \
+                      name.",
+        example: "var a = 1;\n{\n  var a = a + 1;\n}",
+    },
+    Explanation {
+        code: "E0312",
+        name: "YieldFromInitializer",
+        description: "An init() method yields. Initializers can't be generators, as they \
+                      always give back the new instance.",
+        example: "class Counter {\n  init() {\n    yield 1;\n  }\n}",
+    },
+    Explanation {
+        code: "E0313",
+        name: "YieldFromTopLevel",
+        description: "A 'yield' isn't inside a function, so there's no generator for it to \
+                      give a value from.",
+        example: "yield 1;",
+    },
+];
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::{
+        errors::ErrorReporter,
+        interpreter::{Interpreter, InterpreterOptions},
+    };
+    use std::collections::HashSet;
+
+    #[test]
+    pub fn codes_are_unique() {
+        let codes: HashSet<_> = EXPLANATIONS.iter().map(|e| e.code).collect();
+        assert_eq!(codes.len(), EXPLANATIONS.len());
+        assert_eq!(lookup("e0210").unwrap().name, "UndefinedVar");
+        assert_eq!(lookup("undefinedvar").unwrap().code, "E0210");
+        assert!(lookup("E9999").is_none());
+    }
+
+    // Those that need a limit, --strict, a host, or more code than fits in an example are
+    // left out
+    #[test]
+    pub fn examples_have_the_errors_they_explain() {
+        let untested = [
+            "E0103", "E0118", "E0128", "E0213", "E0214", "E0215", "E0216", "E0217", "E0220",
+            "E0221", "E0222", "E0224", "E0310",
+        ];
+        for explanation in EXPLANATIONS {
+            if untested.contains(&explanation.code) {
+                continue;
+            }
+            let reporter = ErrorReporter::new();
+            // Deep enough for the stack overflow, without overflowing the test's own stack
+            let options = InterpreterOptions {
+                max_call_depth: 100,
+                ..InterpreterOptions::sandboxed()
+            };
+            let mut interpreter = Interpreter::new(&reporter, options);
+            let outcome = interpreter.run(explanation.example);
+            assert_eq!(
+                outcome.diagnostics.first().and_then(|d| d.code.as_deref()),
+                Some(explanation.code),
+                "{}: {:?}",
+                explanation.name,
+                outcome.diagnostics
+            );
+        }
+    }
+}
@@ -0,0 +1,338 @@
+use crate::{
+    ast::Ast,
+    errors::ErrorReporter,
+    parser::Parser,
+    scanner::Scanner,
+    tokens::{Token, TokenType, TriviaKind, TriviaPiece},
+};
+
+const INDENT: &str = "    ";
+
+// Formats source by respacing its tokens rather than printing the AST, as the parser
+// turns `for` loops into `while` loops and comments are only kept as trivia on the
+// tokens. Lines are broken
+// after `{`, `}` and `;`, and a single blank line is kept wherever the source had any.
+// Lines broken inside parens, by comments in a call's arguments or a function's
+// parameters, are indented one level further.
+// Source that doesn't parse isn't formatted, and None is returned with the errors
+// reported.
+pub fn format(src: &str, error_reporter: &ErrorReporter) -> Option<String> {
+    let tokens = Scanner::new(src, error_reporter)
+        .with_trivia()
+        .scan_tokens();
+    Parser::new(tokens.iter().cloned(), &mut Ast::new(), error_reporter).parse_stmts();
+    if error_reporter.had_error() {
+        return None;
+    }
+
+    let mut formatter = Formatter {
+        out: String::new(),
+        newlines: 0,
+        previous: None,
+        unary: false,
+        indent: 0,
+        parens: 0,
+        ternaries: 0,
+        line_break: false,
+        block_comment: false,
+    };
+    for token in &tokens {
+        formatter.token(token);
+    }
+    Some(formatter.out)
+}
+
+struct Formatter {
+    out: String,
+    // Newlines in the source since the last token or comment
+    newlines: usize,
+    previous: Option<TokenType>,
+    // Whether the last token was a unary operator, which is written against its operand
+    unary: bool,
+    indent: usize,
+    // Semicolons inside parens, as in a for loop's clauses, don't end lines
+    parens: usize,
+    // `?`s waiting for their `:`, to tell a ternary's colon from a type annotation's
+    ternaries: usize,
+    // Whether the next token goes on a new line
+    line_break: bool,
+    // Whether the last thing written was a block comment, after which the next token
+    // stays on the same line unless the source had it on a new one. Separators and
+    // closing parens stay on it either way.
+    block_comment: bool,
+}
+
+impl Formatter {
+    fn token(&mut self, token: &Token) {
+        let trivia = token.trivia.as_deref().cloned().unwrap_or_default();
+        self.trivia(&trivia.leading);
+        let newlines = std::mem::take(&mut self.newlines);
+        let token_type = &token.token_type;
+
+        if *token_type == TokenType::Eof {
+            if !self.out.is_empty() {
+                self.new_line();
+            }
+            return;
+        }
+
+        match token_type {
+            TokenType::RightBrace => self.indent = self.indent.saturating_sub(1),
+            TokenType::RightParen => self.parens = self.parens.saturating_sub(1),
+            _ => {}
+        }
+        // `{}` and `} else` stay together, unless there's a comment between them
+        let joined = match token_type {
+            TokenType::RightBrace => self.out.ends_with('{'),
+            TokenType::Else | TokenType::SemiColon | TokenType::Comma => self.out.ends_with('}'),
+            _ => false,
+        };
+        let after_block_comment = self.block_comment
+            && newlines > 0
+            && !matches!(
+                token_type,
+                TokenType::SemiColon | TokenType::Comma | TokenType::RightParen
+            );
+        let line_break = self.line_break || after_block_comment;
+        if line_break && !joined {
+            self.start_line(newlines);
+        } else if !self.at_line_start() && self.space_before(token_type) {
+            self.out.push(' ');
+        }
+        self.out.push_str(&token.lexeme);
+
+        self.line_break = false;
+        self.block_comment = false;
+        self.unary = match token_type {
+            TokenType::Bang => true,
+            TokenType::Minus => !self.ends_value(),
+            _ => false,
+        };
+        match token_type {
+            TokenType::LeftBrace => {
+                self.indent += 1;
+                self.line_break = true;
+            }
+            TokenType::RightBrace => self.line_break = true,
+            TokenType::SemiColon => self.line_break = self.parens == 0,
+            TokenType::LeftParen => self.parens += 1,
+            TokenType::QuestionMark => self.ternaries += 1,
+            TokenType::Colon => self.ternaries = self.ternaries.saturating_sub(1),
+            _ => {}
+        }
+        self.previous = Some(token_type.clone());
+        self.trivia(&trivia.trailing);
+    }
+
+    // Writes out the comments in trivia, counting newlines until the next thing written
+    fn trivia(&mut self, trivia: &[TriviaPiece]) {
+        for piece in trivia {
+            match piece.kind {
+                TriviaKind::Newline => self.newlines += 1,
+                TriviaKind::Whitespace | TriviaKind::Skipped => {}
+                TriviaKind::LineComment | TriviaKind::BlockComment | TriviaKind::Shebang => {
+                    // A comment after code on the same line stays there
+                    if self.newlines == 0 && !self.at_line_start() {
+                        self.out.push(' ');
+                    } else {
+                        self.start_line(self.newlines);
+                    }
+                    self.out.push_str(piece.text.trim_end());
+                    self.block_comment = piece.kind == TriviaKind::BlockComment;
+                    self.line_break |= !self.block_comment;
+                    self.newlines = 0;
+                }
+            }
+        }
+    }
+
+    // `newlines` is how many there were in the source, where more than one makes a blank
+    // line, except at the start of a block or the file
+    fn start_line(&mut self, newlines: usize) {
+        if !self.at_line_start() {
+            self.new_line();
+        }
+        let block_start = self.previous == Some(TokenType::LeftBrace) && self.out.ends_with("{\n");
+        if newlines > 1 && !self.out.is_empty() && !block_start && !self.out.ends_with("\n\n") {
+            self.out.push('\n');
+        }
+        for _ in 0..self.indent + self.parens.min(1) {
+            self.out.push_str(INDENT);
+        }
+    }
+
+    fn new_line(&mut self) {
+        let trimmed = self.out.trim_end_matches(' ').len();
+        self.out.truncate(trimmed);
+        self.out.push('\n');
+    }
+
+    fn at_line_start(&self) -> bool {
+        let line = self.out.rsplit('\n').next().unwrap_or("");
+        line.trim().is_empty()
+    }
+
+    // Whether the last token can end an operand, which makes a `-` after it subtraction
+    fn ends_value(&self) -> bool {
+        matches!(
+            self.previous,
+            Some(
+                TokenType::Identifier
+                    | TokenType::Number
+                    | TokenType::String
+                    | TokenType::RightParen
+                    | TokenType::True
+                    | TokenType::False
+                    | TokenType::Nil
+                    | TokenType::This
+            )
+        )
+    }
+
+    fn space_before(&self, token_type: &TokenType) -> bool {
+        let after_opener = matches!(
+            self.previous,
+            None | Some(TokenType::LeftParen | TokenType::Dot)
+        );
+        if after_opener || self.unary {
+            return false;
+        }
+        match token_type {
+            TokenType::RightBrace => self.previous != Some(TokenType::LeftBrace),
+            TokenType::SemiColon | TokenType::Comma | TokenType::Dot | TokenType::RightParen => {
+                false
+            }
+            // A call, or a function's parameters
+            TokenType::LeftParen => !matches!(
+                self.previous,
+                Some(TokenType::Identifier | TokenType::RightParen | TokenType::This)
+            ),
+            // In a type annotation, it goes against the name like Rust's
+            TokenType::Colon => self.ternaries > 0,
+            _ => true,
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn fmt(src: &str) -> String {
+        let reporter = ErrorReporter::new();
+        let formatted = format(src, &reporter).unwrap();
+        // Formatting again changes nothing
+        assert_eq!(format(&formatted, &reporter).unwrap(), formatted);
+        formatted
+    }
+
+    #[test]
+    pub fn formats_statements_and_expressions() {
+        assert_eq!(
+            fmt("var a=-1;print a*(2+ -a) ;var b:Number=a;print a?1:2;fun f(x:Number)->Number{return!x;}"),
+            "var a = -1;
+print a * (2 + -a);
+var b: Number = a;
+print a ? 1 : 2;
+fun f(x: Number) -> Number {
+    return !x;
+}
+"
+        );
+        assert_eq!(
+            fmt("class A<B{init(){super.init();this.x=f(1,2)-1;}\n\n\nm(){}}"),
+            "class A < B {
+    init() {
+        super.init();
+        this.x = f(1, 2) - 1;
+    }
+
+    m() {}
+}
+"
+        );
+        assert_eq!(
+            fmt("for(var i=0;i<3;i=i+1)if(i==1){break;}else print i;"),
+            "for (var i = 0; i < 3; i = i + 1) if (i == 1) {
+    break;
+} else print i;
+"
+        );
+    }
+
+    #[test]
+    pub fn keeps_comments_and_blank_lines() {
+        let src = "#!/usr/bin/env rlox
+// Leading comment
+var a = 1; // trailing
+   /* block
+      comment */
+
+
+{   // opens
+  print a;
+
+  // before the end
+}
+";
+        assert_eq!(
+            fmt(src),
+            "#!/usr/bin/env rlox
+// Leading comment
+var a = 1; // trailing
+/* block
+      comment */
+
+{ // opens
+    print a;
+
+    // before the end
+}
+"
+        );
+    }
+
+    #[test]
+    pub fn keeps_comments_in_parameters_and_arguments() {
+        let src = "fun f(a, // first
+      b /* second */, c) {
+  return g(a, // one
+           b,
+           // before c
+           c);
+}
+print f(1 /* x */, 2, 3);
+var x = g(
+  // leading
+  1,
+  2 // trailing
+);
+var y = g(1 /* one */
+  , 2);
+";
+        assert_eq!(
+            fmt(src),
+            "fun f(a, // first
+    b /* second */, c) {
+    return g(a, // one
+        b,
+        // before c
+        c);
+}
+print f(1 /* x */, 2, 3);
+var x = g(
+    // leading
+    1, 2 // trailing
+);
+var y = g(1 /* one */, 2);
+"
+        );
+    }
+
+    #[test]
+    pub fn leaves_code_with_errors_alone() {
+        let reporter = ErrorReporter::new();
+        assert_eq!(format("print (1;", &reporter), None);
+        assert!(reporter.had_error());
+    }
+}
@@ -0,0 +1,346 @@
+use std::{
+    cell::{Cell, RefCell},
+    collections::HashMap,
+    rc::{Rc, Weak},
+};
+
+use crate::{env::Environment, interpreter::RuntimeError, loxvalue::LoxRef};
+
+// Collection is triggered once this many objects and environments are being tracked,
+// and the threshold then grows with the size of the surviving heap.
+const INITIAL_GC_THRESHOLD: usize = 1024;
+
+/// Implemented by anything on the heap that holds references to other heap nodes.
+/// `visit` is called with the address of each directly referenced object or environment.
+pub trait Trace {
+    fn trace(&self, visit: &mut dyn FnMut(usize));
+
+    /// Roughly how many bytes this uses, not counting the other heap nodes it references.
+    fn size(&self) -> usize;
+}
+
+pub fn addr<T>(r: &Rc<T>) -> usize {
+    Rc::as_ptr(r) as *const () as usize
+}
+
+#[derive(Clone, Copy, Debug, Default)]
+pub struct HeapStats {
+    pub objects: usize,
+    pub environments: usize,
+    pub collections: usize,
+    pub freed: usize,
+    pub bytes: usize,
+    // Totals over the heap's lifetime, where objects and environments are the live counts
+    pub objects_allocated: usize,
+    pub environments_created: usize,
+}
+
+/// Keeps track of every object and environment the interpreter allocates so that
+/// reference cycles between them can be found and broken.
+///
+/// Values are still reference counted, so acyclic garbage is freed as soon as it's
+/// dropped. The collector only has to deal with cycles, and it does that without
+/// knowing the roots: any node with more strong references than the heap itself
+/// accounts for must be held from outside (a variable, the Rust stack, etc.), so it and
+/// everything reachable from it survives. Whatever is left can only be reached from
+/// other garbage, and is cleared out so the reference counts can drop to zero.
+pub struct Heap {
+    objects: RefCell<Vec<Weak<RefCell<LoxRef>>>>,
+    envs: RefCell<Vec<Weak<RefCell<Environment>>>>,
+    next_gc: Cell<usize>,
+    stats: Cell<HeapStats>,
+    // An estimate of the memory in use. It only grows between collections, which work
+    // out how much is actually still live.
+    bytes: Cell<usize>,
+    // What the interpreter keeps for as long as it lives, such as code, which collections
+    // don't free
+    kept_bytes: Cell<usize>,
+    max_bytes: Cell<Option<usize>>,
+}
+
+enum Node {
+    Object(Rc<RefCell<LoxRef>>),
+    Env(Rc<RefCell<Environment>>),
+}
+
+impl Node {
+    fn addr(&self) -> usize {
+        match self {
+            Node::Object(r) => addr(r),
+            Node::Env(e) => addr(e),
+        }
+    }
+
+    fn strong_count(&self) -> usize {
+        match self {
+            Node::Object(r) => Rc::strong_count(r),
+            Node::Env(e) => Rc::strong_count(e),
+        }
+    }
+
+    // Returns false if the node is currently borrowed and couldn't be inspected
+    fn trace(&self, visit: &mut dyn FnMut(usize)) -> bool {
+        match self {
+            Node::Object(r) => r.try_borrow().map(|r| r.trace(visit)).is_ok(),
+            Node::Env(e) => e.try_borrow().map(|e| e.trace(visit)).is_ok(),
+        }
+    }
+
+    fn size(&self) -> usize {
+        match self {
+            Node::Object(r) => r.try_borrow().map_or(0, |r| r.size()),
+            Node::Env(e) => e.try_borrow().map_or(0, |e| e.size()),
+        }
+    }
+
+    fn clear(&self) {
+        match self {
+            Node::Object(r) => *r.borrow_mut() = LoxRef::List(Vec::new()),
+            Node::Env(e) => e.borrow_mut().clear(),
+        }
+    }
+}
+
+impl Heap {
+    pub fn new() -> Heap {
+        Heap {
+            objects: RefCell::new(Vec::new()),
+            envs: RefCell::new(Vec::new()),
+            next_gc: Cell::new(INITIAL_GC_THRESHOLD),
+            stats: Cell::new(HeapStats::default()),
+            bytes: Cell::new(0),
+            kept_bytes: Cell::new(0),
+            max_bytes: Cell::new(None),
+        }
+    }
+
+    pub fn set_max_bytes(&self, max_bytes: Option<usize>) {
+        self.max_bytes.set(max_bytes);
+    }
+
+    pub fn alloc(&self, r: LoxRef) -> Rc<RefCell<LoxRef>> {
+        self.maybe_collect();
+        self.count(|stats| stats.objects_allocated += 1);
+        self.bytes.set(self.bytes.get() + r.size());
+        let r = Rc::new(RefCell::new(r));
+        self.objects.borrow_mut().push(Rc::downgrade(&r));
+        r
+    }
+
+    pub fn alloc_env(
+        &self,
+        enclosing: Option<Rc<RefCell<Environment>>>,
+    ) -> Rc<RefCell<Environment>> {
+        self.maybe_collect();
+        self.count(|stats| stats.environments_created += 1);
+        let env = Environment::new(enclosing);
+        self.bytes.set(self.bytes.get() + env.size());
+        let env = Rc::new(RefCell::new(env));
+        self.envs.borrow_mut().push(Rc::downgrade(&env));
+        env
+    }
+
+    fn count(&self, f: impl FnOnce(&mut HeapStats)) {
+        let mut stats = self.stats.get();
+        f(&mut stats);
+        self.stats.set(stats);
+    }
+
+    pub fn stats(&self) -> HeapStats {
+        let mut stats = self.stats.get();
+        stats.objects = live_count(&self.objects.borrow());
+        stats.environments = live_count(&self.envs.borrow());
+        stats.bytes = self.bytes.get();
+        stats
+    }
+
+    /// Accounts for memory used outside of heap nodes, like a new string, before it's
+    /// allocated.
+    pub fn reserve(&self, bytes: usize) -> Result<(), RuntimeError> {
+        self.enforce_limit(bytes)?;
+        self.bytes.set(self.bytes.get().saturating_add(bytes));
+        Ok(())
+    }
+
+    /// Accounts for memory that's kept for as long as the interpreter lives, such as the
+    /// functions eval() declares.
+    pub fn reserve_kept(&self, bytes: usize) -> Result<(), RuntimeError> {
+        self.enforce_limit(bytes)?;
+        self.kept_bytes
+            .set(self.kept_bytes.get().saturating_add(bytes));
+        Ok(())
+    }
+
+    /// Allocation itself never fails, so the interpreter checks in regularly instead.
+    pub fn check_limit(&self) -> Result<(), RuntimeError> {
+        self.enforce_limit(0)
+    }
+
+    // Only once the estimate goes over the limit is the heap collected, to see whether it
+    // really is
+    fn enforce_limit(&self, pending: usize) -> Result<(), RuntimeError> {
+        if let Some(max_bytes) = self.max_bytes.get() {
+            let used = || {
+                self.bytes
+                    .get()
+                    .saturating_add(self.kept_bytes.get())
+                    .saturating_add(pending)
+            };
+            if used() > max_bytes {
+                self.collect();
+                if used() > max_bytes {
+                    return Err(RuntimeError::OutOfMemory(max_bytes));
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn maybe_collect(&self) {
+        let tracked = self.objects.borrow().len() + self.envs.borrow().len();
+        if tracked >= self.next_gc.get() {
+            self.collect();
+        }
+    }
+
+    /// Frees all unreachable reference cycles, returning the number of objects freed.
+    pub fn collect(&self) -> usize {
+        let objects = self.objects.replace(Vec::new());
+        let envs = self.envs.replace(Vec::new());
+        let nodes: Vec<Node> = objects
+            .iter()
+            .filter_map(|w| w.upgrade().map(Node::Object))
+            .chain(envs.iter().filter_map(|w| w.upgrade().map(Node::Env)))
+            .collect();
+        drop(objects);
+        drop(envs);
+
+        let index: HashMap<usize, usize> = nodes
+            .iter()
+            .enumerate()
+            .map(|(i, n)| (n.addr(), i))
+            .collect();
+        let mut children: Vec<Vec<usize>> = vec![Vec::new(); nodes.len()];
+        let mut internal_refs = vec![0; nodes.len()];
+        let mut reachable = vec![false; nodes.len()];
+        let mut roots = Vec::new();
+
+        for (i, node) in nodes.iter().enumerate() {
+            let traced = node.trace(&mut |a| {
+                if let Some(&child) = index.get(&a) {
+                    children[i].push(child);
+                    internal_refs[child] += 1;
+                }
+            });
+            // Something further up the stack is using this node, so it's definitely live
+            if !traced {
+                roots.push(i);
+            }
+        }
+
+        for (i, node) in nodes.iter().enumerate() {
+            // One of the strong references is our own, from upgrading above
+            if node.strong_count() - 1 > internal_refs[i] {
+                roots.push(i);
+            }
+        }
+
+        while let Some(i) = roots.pop() {
+            if !reachable[i] {
+                reachable[i] = true;
+                roots.extend(children[i].iter().filter(|&&c| !reachable[c]));
+            }
+        }
+
+        let mut freed = 0;
+        let mut bytes = 0;
+        for (i, node) in nodes.iter().enumerate() {
+            if reachable[i] {
+                bytes += node.size();
+                match node {
+                    Node::Object(r) => self.objects.borrow_mut().push(Rc::downgrade(r)),
+                    Node::Env(e) => self.envs.borrow_mut().push(Rc::downgrade(e)),
+                }
+            } else {
+                if let Node::Object(_) = node {
+                    freed += 1;
+                }
+                node.clear();
+            }
+        }
+        drop(nodes);
+        self.bytes.set(bytes);
+
+        let live = self.objects.borrow().len() + self.envs.borrow().len();
+        self.next_gc.set(INITIAL_GC_THRESHOLD.max(live * 2));
+        self.count(|stats| {
+            stats.collections += 1;
+            stats.freed += freed;
+        });
+        freed
+    }
+}
+
+fn live_count<T>(nodes: &[Weak<T>]) -> usize {
+    nodes.iter().filter(|w| w.strong_count() > 0).count()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::{loxvalue::LoxValue, symbol::Symbol};
+
+    #[test]
+    pub fn collects_cycles() {
+        let heap = Heap::new();
+        let a = heap.alloc(LoxRef::List(Vec::new()));
+        let b = heap.alloc(LoxRef::List(vec![LoxValue::Ref(a.clone())]));
+        *a.borrow_mut() = LoxRef::List(vec![LoxValue::Ref(b.clone())]);
+        let weak_a = Rc::downgrade(&a);
+
+        // Still referenced from here, so nothing can be freed
+        assert_eq!(heap.collect(), 0);
+        assert_eq!(heap.stats().objects, 2);
+
+        drop(a);
+        drop(b);
+        assert_eq!(heap.stats().objects, 2);
+        assert_eq!(heap.collect(), 2);
+        assert!(weak_a.upgrade().is_none());
+        assert_eq!(heap.stats().objects, 0);
+    }
+
+    #[test]
+    pub fn keeps_objects_reachable_from_environments() {
+        let heap = Heap::new();
+        let env = heap.alloc_env(None);
+        let inner = heap.alloc_env(Some(env.clone()));
+        let obj = heap.alloc(LoxRef::List(Vec::new()));
+        inner
+            .borrow_mut()
+            .define(Symbol::intern("x"), LoxValue::Ref(obj.clone()));
+        drop(obj);
+        drop(env);
+
+        assert_eq!(heap.collect(), 0);
+        assert_eq!(heap.stats().objects, 1);
+        assert_eq!(heap.stats().environments, 2);
+    }
+
+    #[test]
+    pub fn enforces_memory_limit() {
+        let heap = Heap::new();
+        heap.set_max_bytes(Some(10_000));
+        let big = LoxValue::String("x".repeat(1_000).into());
+        let mut kept = Vec::new();
+        while heap.check_limit().is_ok() {
+            kept.push(heap.alloc(LoxRef::List(vec![big.clone(); 4])));
+        }
+        assert!(kept.len() > 1);
+
+        // Once the objects are dropped, collecting brings the estimate back under
+        drop(kept);
+        assert!(heap.check_limit().is_ok());
+        assert!(heap.reserve(20_000).is_err());
+    }
+}
@@ -0,0 +1,223 @@
+use std::collections::HashMap;
+
+use crate::{
+    ast::Ast,
+    errors::ErrorReporter,
+    index::{DeclarationKind, Index},
+    parser::Parser,
+    resolver::Resolver,
+    scanner::{Scanner, KEYWORDS},
+    tokens::{Token, TokenType, TriviaKind, TriviaPiece},
+};
+
+#[derive(Clone, Copy, Debug, PartialEq, strum_macros::EnumString)]
+#[strum(serialize_all = "lowercase")]
+pub enum Format {
+    // Escape codes for terminals
+    Ansi,
+    // A <pre> element, with a class on each span, as in `lox-keyword`
+    Html,
+}
+
+// What a piece of the source is, which picks its colour
+#[derive(Clone, Copy, Debug, PartialEq, strum_macros::Display)]
+#[strum(serialize_all = "lowercase")]
+enum Kind {
+    Keyword,
+    Number,
+    String,
+    Comment,
+    Variable,
+    Parameter,
+    Function,
+    Class,
+    Property,
+    Plain,
+}
+
+impl Kind {
+    fn ansi(self) -> &'static str {
+        match self {
+            Kind::Keyword => "\x1b[35m",
+            Kind::Number => "\x1b[33m",
+            Kind::String => "\x1b[32m",
+            Kind::Comment => "\x1b[90m",
+            Kind::Parameter => "\x1b[3m",
+            Kind::Function => "\x1b[34m",
+            Kind::Class => "\x1b[36m",
+            Kind::Variable | Kind::Property | Kind::Plain => "",
+        }
+    }
+}
+
+// Writes the source back out with each piece marked up by what it is. Identifiers are
+// told apart by what they were declared as, going by the resolver, and code that
+// doesn't parse is still highlighted as far as it can be.
+pub fn highlight(src: &str, format: Format) -> String {
+    let reporter = ErrorReporter::new();
+    let tokens = Scanner::new(src, &reporter).with_trivia().scan_tokens();
+    let mut ast = Ast::new();
+    let stmts = Parser::new(tokens.iter().cloned(), &mut ast, &reporter).parse_stmts();
+    let resolved = Resolver::new(&ast, &reporter)
+        .with_declarations()
+        .resolve_stmts(&stmts);
+    let index = Index::new(&ast, &stmts, &resolved);
+    let declared: HashMap<usize, DeclarationKind> = index
+        .references
+        .iter()
+        .map(|(span, declaration)| (span.start, index.declarations[*declaration].kind))
+        .collect();
+
+    let mut out = Highlighter {
+        format,
+        out: String::new(),
+    };
+    if format == Format::Html {
+        out.out.push_str("<pre class=\"lox\"><code>");
+    }
+    for (i, token) in tokens.iter().enumerate() {
+        let trivia = token.trivia.as_deref().cloned().unwrap_or_default();
+        out.trivia(&trivia.leading);
+        let previous = i.checked_sub(1).map(|i| &tokens[i].token_type);
+        let next = tokens.get(i + 1).map(|token| &token.token_type);
+        out.write(kind(token, &declared, previous, next), &token.lexeme);
+        out.trivia(&trivia.trailing);
+    }
+    if format == Format::Html {
+        out.out.push_str("</code></pre>\n");
+    }
+    out.out
+}
+
+fn kind(
+    token: &Token,
+    declared: &HashMap<usize, DeclarationKind>,
+    previous: Option<&TokenType>,
+    next: Option<&TokenType>,
+) -> Kind {
+    match token.token_type {
+        TokenType::Number => Kind::Number,
+        TokenType::String => Kind::String,
+        TokenType::Identifier => match declared.get(&token.span.start) {
+            Some(DeclarationKind::Variable) => Kind::Variable,
+            Some(DeclarationKind::Parameter) => Kind::Parameter,
+            Some(DeclarationKind::Function | DeclarationKind::Method) => Kind::Function,
+            Some(DeclarationKind::Class) => Kind::Class,
+            // Fields, and methods called on instances
+            None if previous == Some(&TokenType::Dot) => Kind::Property,
+            // Natives, which aren't declared anywhere in the source
+            None if next == Some(&TokenType::LeftParen) => Kind::Function,
+            None => Kind::Variable,
+        },
+        ref token_type if KEYWORDS.iter().any(|(_, keyword)| keyword == token_type) => {
+            Kind::Keyword
+        }
+        _ => Kind::Plain,
+    }
+}
+
+struct Highlighter {
+    format: Format,
+    out: String,
+}
+
+impl Highlighter {
+    fn trivia(&mut self, trivia: &[TriviaPiece]) {
+        for piece in trivia {
+            let kind = match piece.kind {
+                TriviaKind::LineComment | TriviaKind::BlockComment | TriviaKind::Shebang => {
+                    Kind::Comment
+                }
+                TriviaKind::Whitespace | TriviaKind::Newline | TriviaKind::Skipped => Kind::Plain,
+            };
+            self.write(kind, &piece.text);
+        }
+    }
+
+    fn write(&mut self, kind: Kind, text: &str) {
+        if text.is_empty() {
+            return;
+        }
+        match self.format {
+            Format::Ansi if kind.ansi().is_empty() => self.out.push_str(text),
+            Format::Ansi => {
+                // Each line is coloured on its own, so a block comment doesn't bleed into
+                // a pager's next screen
+                let lines: Vec<String> = text
+                    .split('\n')
+                    .map(|line| match line {
+                        "" => String::new(),
+                        _ => format!("{}{}\x1b[0m", kind.ansi(), line),
+                    })
+                    .collect();
+                self.out.push_str(&lines.join("\n"));
+            }
+            Format::Html if kind == Kind::Plain => self.out.push_str(&escape(text)),
+            Format::Html => {
+                let escaped = escape(text);
+                self.out
+                    .push_str(&format!("<span class=\"lox-{}\">{}</span>", kind, escaped));
+            }
+        }
+    }
+}
+
+pub fn escape(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    const SRC: &str = "// Count up
+class Counter < Base {
+  add(n) { this.total = this.total + n; return clock(); }
+}
+var c = Counter();
+print c.add(1) < 2 and \"<ok>\";
+";
+
+    #[test]
+    pub fn marks_up_html_by_what_identifiers_are() {
+        let html = highlight(SRC, Format::Html);
+        assert!(html.starts_with(
+            "<pre class=\"lox\"><code><span class=\"lox-comment\">// Count up</span>\n"
+        ));
+        for span in [
+            "<span class=\"lox-keyword\">class</span> <span class=\"lox-class\">Counter</span>",
+            "<span class=\"lox-function\">add</span>(<span class=\"lox-parameter\">n</span>)",
+            "<span class=\"lox-keyword\">this</span>.<span class=\"lox-property\">total</span>",
+            "<span class=\"lox-function\">clock</span>()",
+            "<span class=\"lox-keyword\">var</span> <span class=\"lox-variable\">c</span> = \
+             <span class=\"lox-class\">Counter</span>()",
+            " &lt; <span class=\"lox-number\">2</span>",
+            "<span class=\"lox-string\">&quot;&lt;ok&gt;&quot;</span>",
+        ] {
+            assert!(html.contains(span), "{} is missing from {}", span, html);
+        }
+    }
+
+    #[test]
+    pub fn keeps_the_source_text() {
+        let ansi = highlight(SRC, Format::Ansi);
+        let mut plain = String::new();
+        let mut rest = ansi.as_str();
+        while let Some(start) = rest.find('\x1b') {
+            plain.push_str(&rest[..start]);
+            rest = &rest[start + rest[start..].find('m').unwrap() + 1..];
+        }
+        plain.push_str(rest);
+        assert_eq!(plain, SRC);
+        assert!(ansi.contains("\x1b[35mclass\x1b[0m \x1b[36mCounter\x1b[0m"));
+
+        // Even when it doesn't parse
+        let broken = "print (1 +;\n";
+        assert!(highlight(broken, Format::Html).contains(
+            "<span class=\"lox-keyword\">print</span> (<span class=\"lox-number\">1</span> +;"
+        ));
+    }
+}
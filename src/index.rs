@@ -0,0 +1,250 @@
+use std::collections::HashMap;
+
+use crate::{
+    ast::{Ast, Expr, ExprId, FunctionStmt, Stmt, StmtId},
+    resolver::ResolvedProgram,
+    symbol::Symbol,
+    tokens::{Span, Token},
+};
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum DeclarationKind {
+    Variable,
+    Parameter,
+    Function,
+    Class,
+    Method,
+}
+
+pub struct Declaration {
+    pub name: Token,
+    pub kind: DeclarationKind,
+    // How it was declared, as in `fun add(a, b)`
+    pub detail: String,
+    // What's declared directly inside it, apart from parameters
+    pub children: Vec<usize>,
+}
+
+// The declarations in a program and the names that refer to them, for editor tooling.
+// Locals are found through the resolver, which has to have been asked to keep their
+// declarations, and anything else is taken to be the global declared at the top level.
+#[derive(Default)]
+pub struct Index {
+    pub declarations: Vec<Declaration>,
+    // Each name that refers to a declaration, including the names in declarations, with
+    // the index of the declaration
+    pub references: Vec<(Span, usize)>,
+    // The declarations that aren't inside a function or class
+    pub top_level: Vec<usize>,
+}
+
+impl Index {
+    pub fn new(ast: &Ast, program: &[StmtId], resolved: &ResolvedProgram) -> Index {
+        let mut indexer = Indexer {
+            ast,
+            resolved,
+            index: Index::default(),
+            by_start: HashMap::new(),
+            globals: HashMap::new(),
+            global_uses: Vec::new(),
+        };
+        let top_level = indexer.stmts(program, true);
+        let mut index = indexer.index;
+        for (span, symbol) in indexer.global_uses {
+            if let Some(declaration) = indexer.globals.get(&symbol) {
+                index.references.push((span, *declaration));
+            }
+        }
+        index.top_level = top_level;
+        index
+    }
+
+    // The declaration a name at the byte offset refers to, counting the offset just
+    // after a name as in it
+    pub fn declaration_at(&self, offset: usize) -> Option<&Declaration> {
+        self.references
+            .iter()
+            .find(|(span, _)| span.start <= offset && offset <= span.end)
+            .map(|(_, declaration)| &self.declarations[*declaration])
+    }
+}
+
+struct Indexer<'a> {
+    ast: &'a Ast,
+    resolved: &'a ResolvedProgram,
+    index: Index,
+    // Declarations by where their names start, to look up the resolver's results
+    by_start: HashMap<usize, usize>,
+    globals: HashMap<Symbol, usize>,
+    global_uses: Vec<(Span, Symbol)>,
+}
+
+impl<'a> Indexer<'a> {
+    fn stmts(&mut self, stmts: &[StmtId], top_level: bool) -> Vec<usize> {
+        let mut declared = Vec::new();
+        for stmt in stmts {
+            self.stmt(*stmt, top_level, &mut declared);
+        }
+        declared
+    }
+
+    // Blocks don't declare anything themselves, so what's declared in them goes in with
+    // the enclosing declarations
+    fn stmt(&mut self, id: StmtId, top_level: bool, declared: &mut Vec<usize>) {
+        match &self.ast[id] {
+            Stmt::Block(stmts) => declared.extend(self.stmts(stmts, false)),
+            Stmt::Break(_) | Stmt::Debugger => {}
+            Stmt::Class(class) => {
+                let mut detail = format!("class {}", class.name.lexeme);
+                if let Some(superclass) = class.superclass {
+                    if let Expr::Variable(v) = &self.ast[superclass] {
+                        detail.push_str(&format!(" < {}", v.name.lexeme));
+                    }
+                    self.expr(superclass);
+                }
+                let index = self.declare(&class.name, DeclarationKind::Class, detail, top_level);
+                for method in &class.methods {
+                    let detail = format!("{}.{}", class.name.lexeme, signature(method));
+                    let method = self.function(method, DeclarationKind::Method, detail, false);
+                    self.index.declarations[index].children.push(method);
+                }
+                declared.push(index);
+            }
+            Stmt::Expression(expr) | Stmt::Print(expr) => self.expr(*expr),
+            Stmt::Function(function) => {
+                let detail = format!("fun {}", signature(function));
+                declared.push(self.function(
+                    function,
+                    DeclarationKind::Function,
+                    detail,
+                    top_level,
+                ));
+            }
+            Stmt::If(e) => {
+                self.expr(e.condition);
+                self.stmt(e.then_branch, false, declared);
+                if let Some(else_branch) = e.else_branch {
+                    self.stmt(else_branch, false, declared);
+                }
+            }
+            Stmt::Return(r) => self.expr(r.value),
+            Stmt::While(e) => {
+                self.expr(e.condition);
+                self.stmt(e.body, false, declared);
+            }
+            Stmt::Var(v) => {
+                // The initializer can't refer to the variable, but can to a global of
+                // the same name
+                self.expr(v.initializer);
+                let mut detail = format!("var {}", v.name.lexeme);
+                if let Some(type_name) = &v.type_annotation {
+                    detail.push_str(&format!(": {}", type_name.lexeme));
+                }
+                declared.push(self.declare(&v.name, DeclarationKind::Variable, detail, top_level));
+            }
+            Stmt::Yield(y) => self.expr(y.value),
+        }
+    }
+
+    fn function(
+        &mut self,
+        function: &FunctionStmt,
+        kind: DeclarationKind,
+        detail: String,
+        top_level: bool,
+    ) -> usize {
+        let index = self.declare(&function.name, kind, detail, top_level);
+        for (i, param) in function.params.iter().enumerate() {
+            let mut detail = format!("parameter {}", param.lexeme);
+            if let Some(Some(type_name)) = function.param_types.get(i) {
+                detail.push_str(&format!(": {}", type_name.lexeme));
+            }
+            self.declare(param, DeclarationKind::Parameter, detail, false);
+        }
+        let children = self.stmts(&function.body, false);
+        self.index.declarations[index].children = children;
+        index
+    }
+
+    fn declare(
+        &mut self,
+        name: &Token,
+        kind: DeclarationKind,
+        detail: String,
+        global: bool,
+    ) -> usize {
+        let index = self.index.declarations.len();
+        self.index.declarations.push(Declaration {
+            name: name.clone(),
+            kind,
+            detail,
+            children: Vec::new(),
+        });
+        self.index.references.push((name.span, index));
+        self.by_start.insert(name.span.start, index);
+        // Methods are found through instances, so they can't be referred to by name
+        if global && kind != DeclarationKind::Method {
+            self.globals.entry(name.symbol.clone()).or_insert(index);
+        }
+        index
+    }
+
+    fn expr(&mut self, id: ExprId) {
+        match &self.ast[id] {
+            Expr::Assign(e) => {
+                self.reference(id, &e.name);
+                self.expr(e.value);
+            }
+            Expr::Binary(e) => {
+                self.expr(e.left);
+                self.expr(e.right);
+            }
+            Expr::Call(e) => {
+                self.expr(e.callee);
+                e.arguments.iter().for_each(|arg| self.expr(*arg));
+            }
+            Expr::Get(e) => self.expr(e.object),
+            Expr::Grouping(e) => self.expr(*e),
+            Expr::Logical(e) => {
+                self.expr(e.left);
+                self.expr(e.right);
+            }
+            Expr::Set(e) => {
+                self.expr(e.object);
+                self.expr(e.value);
+            }
+            Expr::Unary(e) => self.expr(e.right),
+            Expr::Variable(v) => self.reference(id, &v.name),
+            Expr::Literal(_) | Expr::Super(_) | Expr::This(_) => {}
+        }
+    }
+
+    fn reference(&mut self, id: ExprId, name: &Token) {
+        match self.resolved.declaration(id) {
+            Some(declared) => {
+                if let Some(declaration) = self.by_start.get(&declared.start) {
+                    self.index.references.push((name.span, *declaration));
+                }
+            }
+            None => self.global_uses.push((name.span, name.symbol.clone())),
+        }
+    }
+}
+
+// Everything after `fun`, without the body
+pub fn signature(function: &FunctionStmt) -> String {
+    let params: Vec<String> = function
+        .params
+        .iter()
+        .enumerate()
+        .map(|(i, param)| match function.param_types.get(i) {
+            Some(Some(type_name)) => format!("{}: {}", param.lexeme, type_name.lexeme),
+            _ => param.lexeme.clone(),
+        })
+        .collect();
+    let mut signature = format!("{}({})", function.name.lexeme, params.join(", "));
+    if let Some(type_name) = &function.return_type {
+        signature.push_str(&format!(" -> {}", type_name.lexeme));
+    }
+    signature
+}
@@ -1,25 +1,106 @@
+//! Runs resolved statements. An [`Interpreter`] keeps its globals and the code it's
+//! been given between calls, so code can be added to it a piece at a time, as the REPL
+//! does.
+
 use std::{
-    cell::RefCell, collections::HashMap, convert::TryFrom, rc::Rc, sync::Arc, time::SystemTime,
+    any::Any,
+    cell::{Cell, RefCell},
+    collections::{HashMap, HashSet},
+    convert::TryFrom,
+    future::{self, Future},
+    io::{self, Write},
+    rc::Rc,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+    task::Poll,
+    time::{Duration, Instant},
 };
 use thiserror::Error;
 
 use crate::{
-    ast::{CallExpr, Expr, GetExpr, ReturnStmt, Stmt, WhileStmt},
+    ast::{
+        Ast, AstMark, CallExpr, Expr, ExprId, GetExpr, ReturnStmt, SourceEmitter, Stmt, StmtId,
+        WhileStmt, YieldStmt,
+    },
+    coverage::Coverage,
+    debugger::DebugHook,
     env::Environment,
-    errors::ErrorReporter,
-    loxvalue::{Function, LoxCallable, LoxClass, LoxRef, LoxValue, NativeFn},
-    tokens::{Token, TokenType},
+    errors::{position, Diagnostic, ErrorReporter, StackFrame, Suggestions},
+    gc::Heap,
+    loxvalue::{
+        Arity, BuiltinMethod, Function, GeneratorFrame, GeneratorState, LoxCallable, LoxClass,
+        LoxRef, LoxValue, NativeFn, NumberFormat, UserData, UserDataType,
+    },
+    natives,
+    parser::Parser,
+    profiler::{FunctionKey, Profiler},
+    program::Program,
+    resolver::{Local, ResolvedProgram, Resolver},
+    scanner::Scanner,
+    snapshot::{self, Snapshot, SnapshotError},
+    stats::Stats,
+    symbol::{self, Symbol},
+    tasks::{self, PendingQueue, TaskQueue},
+    tokens::{Span, Token, TokenType},
+    trace::{StderrSink, TraceSink, Tracer},
 };
 
+impl RuntimeError {
+    // Whether the error comes from one of the limits set on a run
+    pub fn is_limit(&self) -> bool {
+        matches!(
+            self,
+            RuntimeError::StackOverflow(_)
+                | RuntimeError::ExpressionTooDeep(_)
+                | RuntimeError::BudgetExceeded(_)
+                | RuntimeError::Timeout(_)
+                | RuntimeError::OutOfMemory(_)
+                | RuntimeError::Interrupted
+        )
+    }
+
+    // As for parse errors, for `rlox --explain`
+    pub fn code(&self) -> &'static str {
+        match self {
+            RuntimeError::CallOnNonCallable => "E0201",
+            RuntimeError::CallWrongNumberOfArgs => "E0202",
+            RuntimeError::FieldAccessOnNonInstance => "E0203",
+            RuntimeError::OperandsMustBeNumbers => "E0204",
+            RuntimeError::PlusOperandsWrong => "E0205",
+            RuntimeError::SuperclassMustBeAClass => "E0206",
+            RuntimeError::UndefinedProperty(..) => "E0207",
+            RuntimeError::UnsupportedOperation => "E0208",
+            RuntimeError::DivideByZero => "E0209",
+            RuntimeError::UndefinedVar(..) => "E0210",
+            RuntimeError::NativeArgument(_) => "E0211",
+            RuntimeError::StackOverflow(_) => "E0212",
+            RuntimeError::ExpressionTooDeep(_) => "E0213",
+            RuntimeError::BudgetExceeded(_) => "E0214",
+            RuntimeError::Timeout(_) => "E0215",
+            RuntimeError::OutOfMemory(_) => "E0216",
+            RuntimeError::Interrupted => "E0217",
+            RuntimeError::GeneratorRunning => "E0218",
+            RuntimeError::Deadlock => "E0219",
+            RuntimeError::Output(_) => "E0220",
+            RuntimeError::Breaking => "E0221",
+            RuntimeError::Return(_) => "E0222",
+            RuntimeError::EvalSource(_) => "E0223",
+            RuntimeError::ProgramAfterOtherCode => "E0224",
+        }
+    }
+}
+
 #[derive(Debug, Error)]
-pub enum RuntimeError<'a> {
+pub enum RuntimeError {
     // This isn't really an error :-(
     #[error("Breaking out of a loop")]
     Breaking,
 
     // Nor this :-(
     #[error("Returning from function")]
-    Return(LoxValue<'a>),
+    Return(LoxValue),
 
     #[error("Can only call functions and classes")]
     CallOnNonCallable,
@@ -39,8 +120,8 @@ pub enum RuntimeError<'a> {
     #[error("Superclass must be a class")]
     SuperclassMustBeAClass,
 
-    #[error("Undefined property {0}")]
-    UndefinedProperty(String),
+    #[error("Undefined property {0}{1}")]
+    UndefinedProperty(String, Suggestions),
 
     #[error("Unsupported operation")]
     UnsupportedOperation,
@@ -48,78 +129,1007 @@ pub enum RuntimeError<'a> {
     #[error("Attempted to divide by zero")]
     DivideByZero,
 
-    #[error("Undefined variable {0}")]
-    UndefinedVar(String),
+    #[error("Undefined variable {0}{1}")]
+    UndefinedVar(String, Suggestions),
+
+    #[error("{0}")]
+    NativeArgument(String),
+
+    #[error("Stack overflow: max call depth {0} exceeded")]
+    StackOverflow(usize),
+
+    #[error("Expression too deeply nested: max depth {0} exceeded")]
+    ExpressionTooDeep(usize),
+
+    #[error("Execution budget exceeded: max steps {0} taken")]
+    BudgetExceeded(usize),
+
+    #[error("Execution timed out after {0:?}")]
+    Timeout(Duration),
+
+    #[error("Out of memory: heap limit of {0} bytes exceeded")]
+    OutOfMemory(usize),
+
+    #[error("Interrupted")]
+    Interrupted,
+
+    #[error("Generator is already running")]
+    GeneratorRunning,
+
+    #[error("Deadlock: receiving from an empty channel with no other tasks to send to it")]
+    Deadlock,
+
+    #[error("Couldn't write output: {0}")]
+    Output(io::Error),
+
+    #[error("Couldn't evaluate source: {0}")]
+    EvalSource(String),
+
+    #[error("Can only run a program in an interpreter that hasn't been given other code")]
+    ProgramAfterOtherCode,
+}
+
+// Lets natives give their errors as messages, with `?` or `.into()`
+impl From<String> for RuntimeError {
+    fn from(msg: String) -> Self {
+        RuntimeError::NativeArgument(msg)
+    }
+}
+
+impl From<&str> for RuntimeError {
+    fn from(msg: &str) -> Self {
+        RuntimeError::NativeArgument(msg.to_string())
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct InterpreterOptions {
+    pub max_call_depth: usize,
+    // Counted within each function call, so it doesn't limit recursion
+    pub max_expr_depth: usize,
+    // Statements and expressions evaluated per run before giving up, if limited
+    pub max_steps: Option<usize>,
+    // Wall-clock time allowed per run, if limited
+    pub timeout: Option<Duration>,
+    // Approximate limit on the memory used by the heap, in bytes
+    pub max_memory: Option<usize>,
+    // Swaps natives like clock() and random() for ones that give the same results every run
+    pub deterministic: bool,
+    // Seeds random(), which is otherwise seeded from the time unless running deterministically
+    pub seed: Option<u64>,
+    // Record how many times each function is called and how long it takes
+    pub profile: bool,
+    // Print each statement as it runs to stderr
+    pub trace: bool,
+    // Count how many times statements on each line run
+    pub coverage: bool,
+    // Count the nodes evaluated, calls made and so on
+    pub stats: bool,
+    // Carry on with the next top-level statement after one fails
+    pub keep_going: bool,
+    // How numbers are written by print, and when they're added to strings
+    pub number_format: NumberFormat,
+    // Which groups of natives scripts can use. Without System, the deterministic
+    // natives are used.
+    pub capabilities: HashSet<Capability>,
+}
+
+// Groups of natives that let a script see beyond its own values, which a host running
+// untrusted scripts can leave out
+#[derive(Clone, Copy, Debug, Eq, Hash, PartialEq)]
+pub enum Capability {
+    // The time, through clock() and the seed random() is given when there isn't one
+    System,
+    // The heap's size and collections, through heapObjects() and collectGarbage()
+    Heap,
+}
+
+impl InterpreterOptions {
+    // Options for scripts that can't be trusted: no capabilities, and limits on how long
+    // they run and how much they allocate. Hosts can loosen any of them. The stack calls
+    // grow into isn't counted in max_memory, so their depth is kept lower too.
+    pub fn sandboxed() -> Self {
+        InterpreterOptions {
+            max_call_depth: 1_000,
+            max_steps: Some(10_000_000),
+            timeout: Some(Duration::from_secs(1)),
+            max_memory: Some(64 * 1024 * 1024),
+            capabilities: HashSet::new(),
+            ..InterpreterOptions::default()
+        }
+    }
+}
+
+impl Default for InterpreterOptions {
+    fn default() -> Self {
+        InterpreterOptions {
+            max_call_depth: 10_000,
+            max_expr_depth: 1_000,
+            max_steps: None,
+            timeout: None,
+            max_memory: None,
+            deterministic: false,
+            seed: None,
+            profile: false,
+            trace: false,
+            coverage: false,
+            stats: false,
+            keep_going: false,
+            number_format: NumberFormat::default(),
+            capabilities: [Capability::System, Capability::Heap]
+                .iter()
+                .copied()
+                .collect(),
+        }
+    }
+}
+
+// What Interpreter::run gives back
+#[derive(Debug)]
+pub struct RunOutcome {
+    // The value of the last statement, if it's an expression and the run got that far
+    pub value: Option<LoxValue>,
+    // Errors from every stage, then warnings
+    pub diagnostics: Vec<Diagnostic>,
+    // Whether there were errors before running, which stop it starting
+    pub had_error: bool,
+    pub had_runtime_error: bool,
 }
 
-pub struct Interpreter<'a, 'b> {
-    env: Rc<RefCell<Environment<'b>>>,
-    globals: Rc<RefCell<Environment<'b>>>,
-    locals: HashMap<*const Expr, usize>,
+pub struct Interpreter<'a> {
+    ast: Arc<Ast>,
+    env: Rc<RefCell<Environment>>,
+    globals: Rc<RefCell<Environment>>,
+    resolved: ResolvedProgram,
+    heap: Rc<Heap>,
+    call_stack: Vec<StackFrame>,
+    expr_depth: usize,
+    // The line of the statement being run, for errors in expressions that have no token
+    // of their own, such as literals. 0 before any has run.
+    stmt_line: usize,
+    steps: usize,
+    deadline: Option<Instant>,
+    // Set from elsewhere, such as a Ctrl-C handler, to stop the current run
+    interrupt: Option<Arc<AtomicBool>>,
+    // Work stack for evaluating chains of binary operators, see evaluate_binary_chain
+    binary_chain: Vec<ExprId>,
+    profiler: Option<Profiler>,
+    tracer: Option<Tracer>,
+    coverage: Option<Coverage>,
+    stats: Option<Stats>,
+    // Where print statements write to, stdout unless the host says otherwise
+    output: Box<dyn Write>,
+    debugger: Option<Box<dyn DebugHook>>,
+    // Whether the debugger needs calling before every statement
+    debugger_active: bool,
+    tasks: TaskQueue,
+    pending: PendingQueue,
+    // Set while evaluating code from the debugger, which hasn't been resolved. Variables
+    // are then looked up by name in the current environment.
+    unresolved_scope: bool,
+    options: InterpreterOptions,
     error_reporter: &'a ErrorReporter,
+    // Runtime errors reported so far, which tells a native whether an error it gives has
+    // been reported already
+    errors_reported: Cell<usize>,
 }
 
-impl<'a, 'b> Interpreter<'a, 'b> {
-    pub fn new(error_reporter: &'a ErrorReporter) -> Self {
-        let globals = Rc::new(RefCell::new(Environment::new(None)));
-
-        globals.borrow_mut().define(
-            "clock",
-            LoxValue::Ref(Rc::new(RefCell::new(LoxRef::Function(Function::Native(
-                NativeFn {
-                    arity: 0,
-                    code: Arc::new(move |_args| -> Result<LoxValue, RuntimeError> {
-                        let time = SystemTime::now()
-                            .duration_since(SystemTime::UNIX_EPOCH)
-                            .unwrap();
-                        Ok(LoxValue::Number(time.as_secs() as f64))
-                    }),
-                },
-            ))))),
-        );
+impl<'a> Interpreter<'a> {
+    pub fn new(error_reporter: &'a ErrorReporter, options: InterpreterOptions) -> Self {
+        let heap = Rc::new(Heap::new());
+        heap.set_max_bytes(options.max_memory);
+        let globals = heap.alloc_env(None);
+
+        natives::define_globals(&mut globals.borrow_mut(), &heap);
+        natives::define_reflection_natives(&mut globals.borrow_mut(), &heap);
+        let tasks = TaskQueue::default();
+        natives::define_task_natives(&mut globals.borrow_mut(), &heap, &tasks);
+        if options.capabilities.contains(&Capability::Heap) {
+            natives::define_heap_natives(&mut globals.borrow_mut(), &heap);
+        }
+        if options.deterministic || !options.capabilities.contains(&Capability::System) {
+            let seed = options.seed.unwrap_or(0);
+            natives::define_deterministic_natives(&mut globals.borrow_mut(), &heap, seed);
+        } else {
+            natives::define_system_natives(&mut globals.borrow_mut(), &heap, options.seed);
+        }
 
-        Interpreter {
+        let trace = options.trace;
+        let mut interpreter = Interpreter {
+            ast: Arc::new(Ast::new()),
             env: globals.clone(),
             globals,
-            locals: HashMap::new(),
+            resolved: ResolvedProgram::new(),
+            heap,
+            call_stack: Vec::new(),
+            expr_depth: 0,
+            stmt_line: 0,
+            steps: 0,
+            deadline: None,
+            interrupt: None,
+            binary_chain: Vec::new(),
+            profiler: if options.profile {
+                Some(Profiler::new())
+            } else {
+                None
+            },
+            tracer: None,
+            coverage: if options.coverage {
+                Some(Coverage::new())
+            } else {
+                None
+            },
+            stats: if options.stats {
+                Some(Stats::new())
+            } else {
+                None
+            },
+            output: Box::new(io::stdout()),
+            debugger: None,
+            debugger_active: false,
+            tasks,
+            pending: PendingQueue::default(),
+            unresolved_scope: false,
+            options,
             error_reporter,
+            errors_reported: Cell::new(0),
+        };
+        if trace {
+            interpreter.set_trace_sink(Box::new(StderrSink));
         }
+        interpreter
     }
 
-    pub fn interpret(&mut self, stmts: &'b [Stmt]) {
-        // println!("Locals from resolver: {:?}", self.locals);
-        for stmt in stmts {
-            let result = self.evaluate_stmt(&stmt);
-            if result.is_err() {
+    pub fn tasks(&self) -> &TaskQueue {
+        &self.tasks
+    }
+
+    pub fn pending(&self) -> &PendingQueue {
+        &self.pending
+    }
+
+    pub fn heap(&self) -> &Heap {
+        &self.heap
+    }
+
+    pub fn profiler(&self) -> Option<&Profiler> {
+        self.profiler.as_ref()
+    }
+
+    pub fn coverage(&self) -> Option<&Coverage> {
+        self.coverage.as_ref()
+    }
+
+    pub fn stats(&self) -> Option<&Stats> {
+        self.stats.as_ref()
+    }
+
+    // Turns on tracing, writing to the given sink
+    pub fn set_trace_sink(&mut self, sink: Box<dyn TraceSink>) {
+        self.tracer = Some(Tracer::new(sink));
+    }
+
+    // Sends what the program prints somewhere other than stdout
+    pub fn set_output(&mut self, output: Box<dyn Write>) {
+        self.output = output;
+    }
+
+    // Only checked as calls are made, so it can change between runs
+    pub fn set_max_call_depth(&mut self, depth: usize) {
+        self.options.max_call_depth = depth;
+    }
+
+    pub fn set_interrupt(&mut self, interrupt: Arc<AtomicBool>) {
+        self.interrupt = Some(interrupt);
+    }
+
+    pub fn set_debugger(&mut self, debugger: Box<dyn DebugHook>) {
+        self.debugger_active = debugger.is_active();
+        self.debugger = Some(debugger);
+    }
+
+    // Defines a global native function alongside the builtin ones, replacing any global
+    // with the same name. The arity can be a number, or an Arity for natives that take a
+    // variable number of arguments. Errors are reported as runtime errors at the call.
+    pub fn register_native<E: Into<RuntimeError>>(
+        &mut self,
+        name: &str,
+        arity: impl Into<Arity>,
+        code: impl Fn(&[LoxValue]) -> Result<LoxValue, E> + 'static,
+    ) {
+        natives::define_native(
+            &mut self.globals.borrow_mut(),
+            &self.heap,
+            name,
+            arity,
+            move |args| code(args).map_err(Into::into),
+        );
+    }
+
+    // Defines a native that starts something running, such as a request, and returns
+    // straight away. Scripts pass it a callback as well as the arguments the arity is for,
+    // which is called with what the future gives once it's finished and nothing else is
+    // running. Errors from the future are reported as runtime errors then.
+    pub fn register_async_native<F, E>(
+        &mut self,
+        name: &str,
+        arity: impl Into<Arity>,
+        code: impl Fn(&[LoxValue]) -> F + 'static,
+    ) where
+        F: Future<Output = Result<LoxValue, E>> + 'static,
+        E: Into<RuntimeError>,
+    {
+        let arity = arity.into().plus(1);
+        let pending = self.pending.clone();
+        let callback_error = format!("{}() expects a function to call back last", name);
+        natives::define_native(
+            &mut self.globals.borrow_mut(),
+            &self.heap,
+            name,
+            arity,
+            move |args| {
+                let (callback, args) = args.split_last().unwrap();
+                let callback = match callback {
+                    LoxValue::Ref(r) => match &*r.borrow() {
+                        LoxRef::Function(f) if f.arity().accepts(1) => f.clone(),
+                        _ => return Err(callback_error.as_str().into()),
+                    },
+                    _ => return Err(callback_error.as_str().into()),
+                };
+                let future = code(args);
+                let future = async move { future.await.map_err(Into::into) };
+                pending.borrow_mut().push((Box::pin(future), callback));
+                Ok(LoxValue::Nil)
+            },
+        );
+    }
+
+    // Hands the Rust value to scripts as userdata of the kind, which they can call the
+    // kind's methods on, and pass back to natives, which can downcast it with
+    // LoxValue::user_data
+    pub fn user_data<T: Any>(&self, value: T, kind: &Rc<UserDataType>) -> LoxValue {
+        let user_data = UserData::new(value, kind.clone());
+        LoxValue::Ref(self.heap.alloc(LoxRef::UserData(user_data)))
+    }
+
+    // A new list of the items, which can be anything that converts into a LoxValue
+    pub fn list<T: Into<LoxValue>>(&self, items: impl IntoIterator<Item = T>) -> LoxValue {
+        let items = items.into_iter().map(Into::into).collect();
+        LoxValue::Ref(self.heap.alloc(LoxRef::List(items)))
+    }
+
+    // Defines a native made some other way, such as by #[lox_native]
+    pub fn register_native_fn(&mut self, native: NativeFn) {
+        natives::define_native_fn(&mut self.globals.borrow_mut(), &self.heap, native);
+    }
+
+    pub fn options(&self) -> &InterpreterOptions {
+        &self.options
+    }
+
+    pub fn error_reporter(&self) -> &'a ErrorReporter {
+        self.error_reporter
+    }
+
+    pub fn call_stack(&self) -> &[StackFrame] {
+        &self.call_stack
+    }
+
+    // The globals defined so far, including natives, sorted by name
+    pub fn globals(&self) -> Vec<(Symbol, LoxValue)> {
+        let mut globals = self.globals.borrow().variables();
+        globals.sort_by(|(a, _), (b, _)| a.as_str().cmp(b.as_str()));
+        globals
+    }
+
+    // The names of the globals defined so far, including natives
+    pub fn global_names(&self) -> HashSet<Symbol> {
+        let globals = self.globals.borrow().variables();
+        globals.into_iter().map(|(name, _)| name).collect()
+    }
+
+    // The variables in each scope that's currently visible, innermost first, not
+    // including globals
+    pub fn scopes(&self) -> Vec<Vec<(Symbol, LoxValue)>> {
+        let mut scopes = Vec::new();
+        let mut env = self.env.clone();
+        while !env.borrow().is_global() {
+            scopes.push(env.borrow().variables());
+            let enclosing = env.borrow().enclosing();
+            match enclosing {
+                Some(enclosing) => env = enclosing,
+                None => break,
+            }
+        }
+        scopes
+    }
+
+    // Evaluates an expression that hasn't been resolved, as though it appeared where
+    // execution currently is. `ast` is separate from the program's own.
+    pub fn evaluate_in_scope(&mut self, ast: &Ast, expr: ExprId) -> Result<LoxValue, RuntimeError> {
+        let unresolved_scope = std::mem::replace(&mut self.unresolved_scope, true);
+        let result = self.evaluate_expr(ast, expr);
+        self.unresolved_scope = unresolved_scope;
+        result
+    }
+
+    pub fn ast(&self) -> Arc<Ast> {
+        self.ast.clone()
+    }
+
+    // New code is parsed into the interpreter's AST, so that functions defined by earlier
+    // runs can still be called. An AST that's shared, such as a program's, or the one a
+    // running function has, is copied first, which keeps the ids in it the same. The copy
+    // shares all but the last chunk of nodes with the original.
+    pub fn ast_mut(&mut self) -> &mut Ast {
+        Arc::make_mut(&mut self.ast)
+    }
+
+    // Runs a compiled program, sharing its AST rather than copying it. The interpreter
+    // can't have been given any other code first, as the program's ids wouldn't fit in
+    // with it. Errors in running it are reported as for interpret().
+    pub fn run_program(&mut self, program: &Program) -> Result<(), RuntimeError> {
+        if !Arc::ptr_eq(&self.ast, program.ast()) {
+            if !self.ast.is_empty() {
+                return Err(RuntimeError::ProgramAfterOtherCode);
+            }
+            self.ast = program.ast().clone();
+            self.add_resolution(program.resolved().clone());
+        }
+        self.interpret(program.stmts());
+        Ok(())
+    }
+
+    pub fn interpret(&mut self, stmts: &[StmtId]) {
+        if self.run_stmts(stmts) {
+            // Errors have already been reported
+            let _ = tasks::run_all(self);
+        }
+    }
+
+    // Like interpret, but waits for async natives' futures without blocking the thread.
+    // Only a receive() with nothing else to run still blocks while they're pending.
+    pub async fn interpret_async(&mut self, stmts: &[StmtId]) {
+        if !self.run_stmts(stmts) {
+            return;
+        }
+        loop {
+            if tasks::run_ready(self).is_err() || self.pending.borrow().is_empty() {
                 return;
             }
+            future::poll_fn(|cx| {
+                if tasks::poll_pending(self, cx) {
+                    Poll::Ready(())
+                } else {
+                    Poll::Pending
+                }
+            })
+            .await;
+        }
+    }
+
+    // Scans, parses, resolves and runs the source, keeping what it defines for later
+    // runs. Nothing is printed apart from what the script prints: errors and warnings
+    // are handed back, unless the reporter sends them to a sink.
+    pub fn run(&mut self, source: &str) -> RunOutcome {
+        let reporter = self.error_reporter;
+        let mark = self.ast.mark();
+        let stmts =
+            Parser::new(Scanner::new(source, reporter), self.ast_mut(), reporter).parse_stmts();
+        let mut value = None;
+        if !reporter.had_error() {
+            let resolved = Resolver::new(&self.ast, reporter).resolve_stmts(&stmts);
+            self.add_resolution(resolved);
+        }
+        if !reporter.had_error() {
+            match self.keep_code(mark) {
+                Ok(()) => {
+                    value = self.run_for_value(&stmts);
+                    self.reclaim_code(mark);
+                }
+                Err(error) => {
+                    self.native_error(&error);
+                    self.drop_code(mark);
+                }
+            }
+        } else {
+            self.drop_code(mark);
+        }
+        let mut diagnostics = reporter.take_diagnostics();
+        diagnostics.extend(reporter.take_warnings().iter().map(Diagnostic::from));
+        let outcome = RunOutcome {
+            value,
+            diagnostics,
+            had_error: reporter.had_error(),
+            had_runtime_error: reporter.had_runtime_error(),
+        };
+        reporter.reset();
+        outcome
+    }
+
+    // Runs the source in the global scope for eval(), giving the value of its last
+    // statement if that's an expression, or nil. Like at the REPL, source that's just an
+    // expression can leave off the semicolon. The first error in scanning, parsing or
+    // resolving it is a runtime error at the call, so the script can't carry on past it.
+    pub fn eval(&mut self, source: &str) -> Result<LoxValue, RuntimeError> {
+        let mark = self.ast.mark();
+        // Errors while it runs are shown against it, rather than the script
+        let offset = self.error_reporter.add_source("<eval>", source);
+        let result = self.eval_source(source, offset.unwrap_or(0), mark);
+        self.reclaim_code(mark);
+        if let (Some(offset), true) = (offset, self.ast.mark() == mark) {
+            self.error_reporter.remove_source(offset);
+        }
+        result
+    }
+
+    fn eval_source(
+        &mut self,
+        source: &str,
+        offset: usize,
+        mark: AstMark,
+    ) -> Result<LoxValue, RuntimeError> {
+        let reporter = ErrorReporter::new();
+        // Kept to parse again as an expression if they don't parse as statements
+        let tokens = Scanner::new(source, &reporter)
+            .with_offset(offset)
+            .scan_tokens();
+        let scanned = !reporter.had_error();
+        let mut stmts =
+            Parser::new(tokens.iter().cloned(), self.ast_mut(), &reporter).parse_stmts();
+        if scanned && reporter.had_error() {
+            let line = tokens.first().map_or(1, |token| token.line);
+            let expr_reporter = ErrorReporter::new();
+            let mut parser = Parser::new(tokens, self.ast_mut(), &expr_reporter);
+            let expr = parser.parse_expr().ok().filter(|_| parser.is_at_end());
+            if let Some(expr) = expr {
+                // It was meant as an expression, so the statements' errors don't apply
+                reporter.reset();
+                let span = self.ast.expr_span(expr);
+                stmts = vec![self.ast_mut().add_stmt(Stmt::Expression(expr), line, span)];
+            }
+        }
+        if !reporter.had_error() {
+            let resolved = Resolver::new(&self.ast, &reporter).resolve_stmts(&stmts);
+            self.add_resolution(resolved);
+        }
+        if let Some(diagnostic) = reporter.take_diagnostics().first() {
+            let error = RuntimeError::EvalSource(format!(
+                "{}, at line {}",
+                diagnostic.message,
+                position(diagnostic.line, diagnostic.column)
+            ));
+            self.native_error(&error);
+            self.drop_code(mark);
+            return Err(error);
+        }
+        if let Err(error) = self.keep_code(mark) {
+            self.native_error(&error);
+            self.drop_code(mark);
+            return Err(error);
+        }
+        let ast = self.ast.clone();
+        let env = std::mem::replace(&mut self.env, self.globals.clone());
+        let unresolved_scope = std::mem::replace(&mut self.unresolved_scope, false);
+        let result = self.eval_stmts(&ast, &stmts);
+        self.env = env;
+        self.unresolved_scope = unresolved_scope;
+        result
+    }
+
+    // Code that declares functions or classes is kept once it's run, for their closures,
+    // and counts towards max_memory from then on
+    fn keep_code(&mut self, mark: AstMark) -> Result<(), RuntimeError> {
+        if self.ast.declares_functions_since(mark) {
+            self.heap.reserve_kept(self.ast.size_since(mark))?;
+        }
+        Ok(())
+    }
+
+    // Any other code added since the mark is dropped once it's run, with what the
+    // resolver found in it, so that a host or script running code over and over doesn't
+    // keep every piece
+    fn reclaim_code(&mut self, mark: AstMark) {
+        if !self.ast.declares_functions_since(mark) {
+            self.drop_code(mark);
+        }
+    }
+
+    // For code that's finished with, or that never ran because it had errors
+    fn drop_code(&mut self, mark: AstMark) {
+        if self.ast.mark() != mark {
+            self.resolved.remove(self.ast.expr_ids_since(mark));
+            self.ast_mut().truncate(mark);
+        }
+    }
+
+    fn eval_stmts(&mut self, ast: &Ast, stmts: &[StmtId]) -> Result<LoxValue, RuntimeError> {
+        let (last, rest) = match stmts.split_last() {
+            Some((last, rest)) => (*last, rest),
+            None => return Ok(LoxValue::Nil),
+        };
+        for stmt in rest {
+            self.evaluate_stmt(ast, *stmt)?;
+        }
+        match &ast[last] {
+            Stmt::Expression(expr) => {
+                self.before_stmt(ast, last)?;
+                self.evaluate_expr(ast, *expr)
+            }
+            _ => self.evaluate_stmt(ast, last).map(|()| LoxValue::Nil),
+        }
+    }
+
+    // Like interpret, but gives the value of the last statement if it's an expression
+    fn run_for_value(&mut self, stmts: &[StmtId]) -> Option<LoxValue> {
+        let ast = self.ast.clone();
+        let (last, rest) = match stmts.split_last() {
+            Some((last, rest)) => (*last, rest),
+            None => return None,
+        };
+        let expr = match &ast[last] {
+            Stmt::Expression(expr) => *expr,
+            _ => {
+                self.interpret(stmts);
+                return None;
+            }
+        };
+        if !self.run_stmts(rest) {
+            return None;
+        }
+        let value = self
+            .before_stmt(&ast, last)
+            .and_then(|()| self.evaluate_expr(&ast, expr));
+        let _ = tasks::run_all(self);
+        value.ok()
+    }
+
+    // Returns false if a statement failed and the run stopped there
+    fn run_stmts(&mut self, stmts: &[StmtId]) -> bool {
+        // println!("Locals from resolver: {:?}", self.resolved);
+        let ast = self.ast.clone();
+        self.start_run();
+        for stmt in stmts {
+            // Whatever the failed statement did before the error is kept, so a variable
+            // whose initializer fails is left undefined. Hitting a limit always stops the
+            // run, as the rest of it would only hit it again.
+            match self.evaluate_stmt(&ast, *stmt) {
+                Ok(()) => {}
+                Err(e) if self.options.keep_going && !e.is_limit() => {}
+                Err(_) => return false,
+            }
+        }
+        true
+    }
+
+    // Returns the value, or None if evaluating it failed
+    pub fn interpret_expr(&mut self, expr: ExprId) -> Option<LoxValue> {
+        let ast = self.ast.clone();
+        self.start_run();
+        let result = self.evaluate_expr(&ast, expr);
+        let _ = tasks::run_all(self);
+        result.ok()
+    }
+
+    pub fn define_global(&mut self, name: Symbol, value: LoxValue) {
+        self.globals.borrow_mut().define(name, value);
+    }
+
+    // Saves the globals, and everything they refer to, for restore to define again
+    pub fn snapshot(&self) -> Result<Snapshot, SnapshotError> {
+        snapshot::take(&self.ast, &self.globals.borrow())
+    }
+
+    // Defines the globals in the snapshot, replacing any with the same names
+    pub fn restore(&mut self, snapshot: &Snapshot) -> Result<(), SnapshotError> {
+        let (heap, globals) = (self.heap.clone(), self.globals.clone());
+        let resolved = snapshot::restore(snapshot, self.ast_mut(), &heap, &globals)?;
+        self.add_resolution(resolved);
+        Ok(())
+    }
+
+    // The value as print writes it
+    pub fn stringify(&self, value: &LoxValue) -> String {
+        value.formatted(self.options.number_format).to_string()
+    }
+
+    // The value as the REPL shows it, with more detail than print gives, such as the
+    // methods of a class
+    pub fn describe(&self, value: &LoxValue) -> String {
+        format!("{:#}", value.formatted(self.options.number_format))
+    }
+
+    pub fn global(&self, name: &str) -> Option<LoxValue> {
+        self.globals.borrow().get(&Symbol::lookup(name)?).ok()
+    }
+
+    // Calls the global function or class with the name, for hosts that run scripts to
+    // define functions they then call. Errors from running it are reported as usual, as
+    // well as being returned, but if it can't be called at all the error is only returned.
+    pub fn call(&mut self, name: &str, args: &[LoxValue]) -> Result<LoxValue, RuntimeError> {
+        let callee = match Symbol::lookup(name) {
+            Some(symbol) => self.globals.borrow().get(&symbol)?,
+            None => return Err(self.globals.borrow().undefined(name)),
+        };
+        self.call_value(&callee, args)
+    }
+
+    // Calls a function or class, such as one a script handed to a native
+    pub fn call_value(
+        &mut self,
+        callee: &LoxValue,
+        args: &[LoxValue],
+    ) -> Result<LoxValue, RuntimeError> {
+        let r = match callee {
+            LoxValue::Ref(r) => r.clone(),
+            _ => return Err(RuntimeError::CallOnNonCallable),
+        };
+        self.start_run();
+        let result = match &*r.borrow() {
+            LoxRef::Function(f) if f.arity().accepts(args.len()) => f.call(None, self, None, args),
+            LoxRef::Class(c) if c.arity().accepts(args.len()) => {
+                c.call(Some(r.clone()), self, None, args)
+            }
+            LoxRef::Function(_) | LoxRef::Class(_) => Err(RuntimeError::CallWrongNumberOfArgs),
+            _ => Err(RuntimeError::CallOnNonCallable),
+        };
+        let _ = tasks::run_all(self);
+        result
+    }
+
+    // Calls a function or class a native was given, such as a callback, in the middle of
+    // the run the native was called in. Errors are reported before they're returned, so
+    // the native only has to pass them on.
+    pub fn call_back(
+        &mut self,
+        callee: &LoxValue,
+        args: &[LoxValue],
+    ) -> Result<LoxValue, RuntimeError> {
+        let r = match callee {
+            LoxValue::Ref(r) => r.clone(),
+            _ => {
+                self.native_error(&RuntimeError::CallOnNonCallable);
+                return Err(RuntimeError::CallOnNonCallable);
+            }
+        };
+        let result = match &*r.borrow() {
+            LoxRef::Function(f) => self.evaluate_call(None, args, f, None),
+            LoxRef::Class(c) => self.evaluate_call(Some(r.clone()), args, c, None),
+            _ => {
+                self.native_error(&RuntimeError::CallOnNonCallable);
+                Err(RuntimeError::CallOnNonCallable)
+            }
+        };
+        result
+    }
+
+    fn evaluate_stmt(&mut self, ast: &Ast, stmt: StmtId) -> Result<(), RuntimeError> {
+        let stmt_line = self.stmt_line;
+        let result = self.before_stmt(ast, stmt).and_then(|()| {
+            grow_stack(|| {
+                if self.tracer.is_some() {
+                    self.trace_stmt(ast, stmt)
+                } else {
+                    self.execute_stmt(ast, stmt)
+                }
+            })
+        });
+        self.stmt_line = stmt_line;
+        result
+    }
+
+    fn before_stmt(&mut self, ast: &Ast, stmt: StmtId) -> Result<(), RuntimeError> {
+        self.stmt_line = ast.line(stmt);
+        if let Err(error) = self.use_step() {
+            self.report_on_line(ast.line(stmt), ast.stmt_span(stmt), &error);
+            return Err(error);
+        }
+        if self.debugger_active && !matches!(ast[stmt], Stmt::Block(_)) {
+            self.pause(ast.line(stmt));
+        }
+        if let Some(coverage) = &mut self.coverage {
+            if !matches!(ast[stmt], Stmt::Block(_)) {
+                coverage.hit(ast.line(stmt));
+            }
+        }
+        if let Some(stats) = &mut self.stats {
+            stats.stmt(&ast[stmt]);
+        }
+        Ok(())
+    }
+
+    // Generator bodies can't be run by recursing through execute_stmt, as they have to
+    // stop at a yield and carry on from there on the next call. Instead, the statements
+    // that can contain a yield (blocks, ifs and loops) are run from an explicit stack of
+    // frames, which is kept in the generator in between. Any other statement can't yield,
+    // so it's run as usual.
+    pub fn resume_generator(
+        &mut self,
+        generator: &Rc<RefCell<LoxRef>>,
+    ) -> Result<LoxValue, RuntimeError> {
+        let state = match &mut *generator.borrow_mut() {
+            LoxRef::Generator(g) => std::mem::replace(&mut g.state, GeneratorState::Running),
+            _ => return Err(RuntimeError::UnsupportedOperation),
+        };
+        let mut frames = match state {
+            GeneratorState::Suspended(frames) => frames,
+            GeneratorState::Running => {
+                let error = RuntimeError::GeneratorRunning;
+                self.native_error(&error);
+                return Err(error);
+            }
+            GeneratorState::Done => {
+                Self::set_generator_state(generator, GeneratorState::Done);
+                return Ok(LoxValue::Nil);
+            }
+        };
+
+        let ast = self.ast.clone();
+        let previous_env = self.env.clone();
+        let stmt_line = self.stmt_line;
+        let result = self.run_generator_frames(&ast, &mut frames);
+        self.env = previous_env;
+        self.stmt_line = stmt_line;
+        let (state, result) = match result {
+            Ok(Some(value)) => (GeneratorState::Suspended(frames), Ok(value)),
+            Ok(None) => (GeneratorState::Done, Ok(LoxValue::Nil)),
+            Err(RuntimeError::Return(value)) => (GeneratorState::Done, Ok(value)),
+            Err(e) => (GeneratorState::Done, Err(e)),
+        };
+        Self::set_generator_state(generator, state);
+        result
+    }
+
+    fn set_generator_state(generator: &Rc<RefCell<LoxRef>>, state: GeneratorState) {
+        if let LoxRef::Generator(g) = &mut *generator.borrow_mut() {
+            g.state = state;
+        }
+    }
+
+    // Returns the value yielded, or None once the body has run to the end
+    fn run_generator_frames(
+        &mut self,
+        ast: &Ast,
+        frames: &mut Vec<GeneratorFrame>,
+    ) -> Result<Option<LoxValue>, RuntimeError> {
+        loop {
+            let stmt = match frames.last_mut() {
+                None => return Ok(None),
+                Some(GeneratorFrame::Block { stmts, next, env }) => match stmts.get(*next) {
+                    Some(stmt) => {
+                        *next += 1;
+                        self.env = env.clone();
+                        *stmt
+                    }
+                    None => {
+                        frames.pop();
+                        continue;
+                    }
+                },
+                Some(GeneratorFrame::Loop {
+                    condition,
+                    body,
+                    env,
+                }) => {
+                    let (condition, body) = (*condition, *body);
+                    self.env = env.clone();
+                    if !is_truthy(&self.evaluate_expr(ast, condition)?) {
+                        frames.pop();
+                        continue;
+                    }
+                    body
+                }
+            };
+            match &ast[stmt] {
+                Stmt::Block(stmts) => {
+                    self.before_stmt(ast, stmt)?;
+                    frames.push(GeneratorFrame::Block {
+                        stmts: stmts.clone(),
+                        next: 0,
+                        env: self.heap.alloc_env(Some(self.env.clone())),
+                    });
+                }
+                Stmt::Break(_) => {
+                    self.before_stmt(ast, stmt)?;
+                    while let Some(frame) = frames.pop() {
+                        if let GeneratorFrame::Loop { .. } = frame {
+                            break;
+                        }
+                    }
+                }
+                Stmt::If(e) => {
+                    self.before_stmt(ast, stmt)?;
+                    let condition = self.evaluate_expr(ast, e.condition)?;
+                    let branch = if is_truthy(&condition) {
+                        Some(e.then_branch)
+                    } else {
+                        e.else_branch
+                    };
+                    if let Some(branch) = branch {
+                        frames.push(GeneratorFrame::Block {
+                            stmts: vec![branch],
+                            next: 0,
+                            env: self.env.clone(),
+                        });
+                    }
+                }
+                Stmt::While(WhileStmt { condition, body }) => {
+                    self.before_stmt(ast, stmt)?;
+                    frames.push(GeneratorFrame::Loop {
+                        condition: *condition,
+                        body: *body,
+                        env: self.env.clone(),
+                    });
+                }
+                Stmt::Yield(YieldStmt { keyword: _, value }) => {
+                    self.before_stmt(ast, stmt)?;
+                    return self.evaluate_expr(ast, *value).map(Some);
+                }
+                _ => self.evaluate_stmt(ast, stmt)?,
+            }
+        }
+    }
+
+    // The debugger is taken out while it runs, so any code it evaluates runs without
+    // stopping
+    fn pause(&mut self, line: usize) {
+        if let Some(mut debugger) = self.debugger.take() {
+            debugger.before_stmt(self, line);
+            self.debugger_active = debugger.is_active();
+            self.debugger = Some(debugger);
+        }
+    }
+
+    // Without a debugger attached, `debugger` statements do nothing
+    fn debugger_stmt(&mut self, line: usize) {
+        if let Some(mut debugger) = self.debugger.take() {
+            debugger.debugger_stmt(self, line);
+            self.debugger_active = debugger.is_active();
+            self.debugger = Some(debugger);
         }
     }
 
-    pub fn interpret_expr(&mut self, expr: &Expr) {
-        let result = self.evaluate_expr(expr);
-        if let Ok(val) = result {
-            println!("Result: {}", val);
+    // Statements are traced as the source `rlox fmt` would give them. Compound ones are
+    // only traced by their first line, as the statements inside them are traced as they
+    // run.
+    fn trace_stmt(&mut self, ast: &Ast, stmt: StmtId) -> Result<(), RuntimeError> {
+        let condition = |e| SourceEmitter::new(ast).emit_expr(e);
+        let text = match &ast[stmt] {
+            Stmt::Block(_) => return self.execute_stmt(ast, stmt),
+            Stmt::Class(class) => format!("class {}", class.name.lexeme),
+            Stmt::Function(function) => format!("fun {}", function.name.lexeme),
+            Stmt::If(e) => format!("if ({})", condition(e.condition)),
+            Stmt::While(e) => format!("while ({})", condition(e.condition)),
+            _ => SourceEmitter::new(ast).emit(&[stmt]).trim_end().to_string(),
+        };
+        let depth = self.call_stack.len();
+        let enclosing = match &mut self.tracer {
+            Some(tracer) => tracer.start_statement(depth, &text),
+            None => Vec::new(),
+        };
+        let result = self.execute_stmt(ast, stmt);
+        if let Some(tracer) = &mut self.tracer {
+            tracer.end_statement(depth, enclosing);
         }
+        result
     }
 
-    pub fn evaluate_stmt(&mut self, stmt: &'b Stmt) -> Result<(), RuntimeError<'b>> {
-        match stmt {
+    fn execute_stmt(&mut self, ast: &Ast, stmt: StmtId) -> Result<(), RuntimeError> {
+        match &ast[stmt] {
             Stmt::Block(vec) => {
-                let block_env = Rc::new(RefCell::new(Environment::new(Some(self.env.clone()))));
-                self.execute_block(vec, block_env)?;
+                let block_env = self.heap.alloc_env(Some(self.env.clone()));
+                self.execute_stmts(ast, vec, block_env)?;
+                Ok(())
+            }
+            Stmt::Break(_) => Err(RuntimeError::Breaking),
+            Stmt::Debugger => {
+                self.debugger_stmt(ast.line(stmt));
                 Ok(())
             }
-            Stmt::Break => Err(RuntimeError::Breaking),
             Stmt::Class(class) => {
-                {
-                    let mut env = self.env.borrow_mut();
-                    env.define(&class.name.lexeme, LoxValue::Nil);
-                }
-
                 let mut superclass_evaled = None;
-                if let Some(expr) = &class.superclass {
-                    let sc = self.evaluate_expr(&expr)?;
+                if let Some(expr) = class.superclass {
+                    let sc = self.evaluate_expr(ast, expr)?;
                     let mut is_class = true;
                     if let LoxValue::Ref(r) = &sc {
                         if !matches!(&*r.borrow(), LoxRef::Class(_)) {
@@ -134,19 +1144,19 @@ impl<'a, 'b> Interpreter<'a, 'b> {
                             .unwrap_err());
                     }
                     superclass_evaled = Some(sc.clone());
-                    self.env = Rc::new(RefCell::new(Environment::new(Some(self.env.clone()))));
-                    self.env.borrow_mut().define("super", sc);
+                    self.env = self.heap.alloc_env(Some(self.env.clone()));
+                    self.env.borrow_mut().define(symbol::SUPER.clone(), sc);
                 }
 
                 let mut methods_map = HashMap::new();
                 for method in &class.methods {
                     let f = Function::new_function(
-                        method,
+                        method.clone(),
                         self.env.clone(),
-                        method.name.lexeme == "init",
+                        method.name.symbol == *symbol::INIT,
                     );
-                    let f_ref = LoxValue::Ref(Rc::new(RefCell::new(LoxRef::Function(f))));
-                    methods_map.insert(method.name.lexeme.clone(), f_ref);
+                    let f_ref = LoxValue::Ref(self.heap.alloc(LoxRef::Function(f)));
+                    methods_map.insert(method.name.symbol.clone(), f_ref);
                 }
 
                 if superclass_evaled.is_some() {
@@ -154,46 +1164,53 @@ impl<'a, 'b> Interpreter<'a, 'b> {
                     self.env = env;
                 }
 
-                let c = LoxClass::new(class.name.lexeme.clone(), superclass_evaled, methods_map);
-                let mut env = self.env.borrow_mut();
-                env.assign(
-                    &class.name.lexeme,
-                    LoxValue::Ref(Rc::new(RefCell::new(LoxRef::Class(c)))),
-                )
+                // Methods only look the class name up when they're called, so it's fine to
+                // define it last
+                let c = LoxClass::new(
+                    class.name.lexeme.clone(),
+                    class.name.line,
+                    superclass_evaled,
+                    methods_map,
+                );
+                self.env.borrow_mut().define(
+                    class.name.symbol.clone(),
+                    LoxValue::Ref(self.heap.alloc(LoxRef::Class(c))),
+                );
+                Ok(())
             }
             Stmt::Expression(e) => {
-                self.evaluate_expr(e)?;
+                self.evaluate_expr(ast, *e)?;
                 Ok(())
             }
             Stmt::Function(stmt) => {
-                let callable = Function::new_function(&stmt, self.env.clone(), false);
+                let callable = Function::new_function(stmt.clone(), self.env.clone(), false);
                 self.env.borrow_mut().define(
-                    &stmt.name.lexeme,
-                    LoxValue::Ref(Rc::new(RefCell::new(LoxRef::Function(callable)))),
+                    stmt.name.symbol.clone(),
+                    LoxValue::Ref(self.heap.alloc(LoxRef::Function(callable))),
                 );
                 Ok(())
             }
             Stmt::If(e) => {
-                let condition = self.evaluate_expr(&e.condition)?;
+                let condition = self.evaluate_expr(ast, e.condition)?;
                 if is_truthy(&condition) {
-                    self.evaluate_stmt(&e.then_branch)?;
-                } else if let Some(else_branch) = &e.else_branch {
-                    self.evaluate_stmt(else_branch)?;
+                    self.evaluate_stmt(ast, e.then_branch)?;
+                } else if let Some(else_branch) = e.else_branch {
+                    self.evaluate_stmt(ast, else_branch)?;
                 }
                 Ok(())
             }
             Stmt::Print(e) => {
-                let val = self.evaluate_expr(e)?;
-                println!("{}", val);
-                Ok(())
+                let val = self.evaluate_expr(ast, *e)?;
+                let numbers = self.options.number_format;
+                writeln!(self.output, "{}", val.formatted(numbers)).map_err(RuntimeError::Output)
             }
             Stmt::Return(ReturnStmt { keyword: _, value }) => {
-                let val = self.evaluate_expr(value)?;
+                let val = self.evaluate_expr(ast, *value)?;
                 Err(RuntimeError::Return(val))
             }
             Stmt::While(WhileStmt { condition, body }) => {
-                while is_truthy(&self.evaluate_expr(&condition)?) {
-                    let result = self.evaluate_stmt(body);
+                while is_truthy(&self.evaluate_expr(ast, *condition)?) {
+                    let result = self.evaluate_stmt(ast, *body);
                     if let Err(e) = result {
                         if let RuntimeError::Breaking = e {
                             return Ok(());
@@ -205,22 +1222,36 @@ impl<'a, 'b> Interpreter<'a, 'b> {
                 Ok(())
             }
             Stmt::Var(vs) => {
-                let value = self.evaluate_expr(vs.initializer.as_ref())?;
-                self.env.borrow_mut().define(&vs.name.lexeme, value);
+                let value = self.evaluate_expr(ast, vs.initializer)?;
+                self.touch(&vs.name, &value);
+                self.env.borrow_mut().define(vs.name.symbol.clone(), value);
                 Ok(())
             }
+            // The resolver only allows yields in functions, which run them through
+            // resume_generator
+            Stmt::Yield(_) => Err(RuntimeError::UnsupportedOperation),
         }
     }
 
     pub fn execute_block(
         &mut self,
-        stmts: &'b [Stmt],
-        env: Rc<RefCell<Environment<'b>>>,
-    ) -> Result<(), RuntimeError<'b>> {
+        stmts: &[StmtId],
+        env: Rc<RefCell<Environment>>,
+    ) -> Result<(), RuntimeError> {
+        let ast = self.ast.clone();
+        self.execute_stmts(&ast, stmts, env)
+    }
+
+    fn execute_stmts(
+        &mut self,
+        ast: &Ast,
+        stmts: &[StmtId],
+        env: Rc<RefCell<Environment>>,
+    ) -> Result<(), RuntimeError> {
         let previous_env = self.env.clone();
         self.env = env;
         for stmt in stmts {
-            let result = self.evaluate_stmt(stmt);
+            let result = self.evaluate_stmt(ast, *stmt);
             if let Err(e) = result {
                 self.env = previous_env;
                 return Err(e);
@@ -230,96 +1261,184 @@ impl<'a, 'b> Interpreter<'a, 'b> {
         Ok(())
     }
 
-    fn evaluate_expr(&mut self, expr: &Expr) -> Result<LoxValue<'b>, RuntimeError<'b>> {
-        match expr {
-            Expr::Binary(binary) => {
-                let left = self.evaluate_expr(binary.left.as_ref())?;
-                let right = self.evaluate_expr(binary.right.as_ref())?;
-                self.evaluate_binary(&binary.operator, &left, &right)
+    fn evaluate_expr(&mut self, ast: &Ast, expr: ExprId) -> Result<LoxValue, RuntimeError> {
+        let error = if self.expr_depth >= self.options.max_expr_depth {
+            Some(RuntimeError::ExpressionTooDeep(self.options.max_expr_depth))
+        } else {
+            self.use_step().err()
+        };
+        if let Some(error) = error {
+            match ast.token(expr) {
+                Some(token) => self.report(token, error.code(), &error.to_string()),
+                None if self.stmt_line > 0 => {
+                    self.report_on_line(self.stmt_line, ast.expr_span(expr), &error)
+                }
+                None => self.native_error(&error),
             }
-            Expr::Call(CallExpr {
-                callee,
-                paren: _,
-                arguments,
-            }) => {
-                let callee = self.evaluate_expr(&callee)?;
-
-                let args: Vec<LoxValue> = arguments
-                    .iter()
-                    .map(|a| self.evaluate_expr(a).unwrap_or(LoxValue::Nil))
-                    .collect();
+            return Err(error);
+        }
+        if let Some(stats) = &mut self.stats {
+            stats.expr(&ast[expr]);
+        }
+        self.expr_depth += 1;
+        let result = grow_stack(|| self.evaluate_expr_inner(ast, expr));
+        self.expr_depth -= 1;
+        result
+    }
+
+    // Limits apply to each call to interpret, so each line of the REPL gets its own
+    fn start_run(&mut self) {
+        self.steps = 0;
+        self.deadline = self.options.timeout.map(|timeout| Instant::now() + timeout);
+        // An interrupt that came in between runs isn't meant for this one
+        if let Some(interrupt) = &self.interrupt {
+            interrupt.store(false, Ordering::Relaxed);
+        }
+    }
+
+    // Every limit on a run is checked here. Reading the clock is comparatively slow, so
+    // the deadline is only checked every so many steps.
+    fn use_step(&mut self) -> Result<(), RuntimeError> {
+        if let Some(max_steps) = self.options.max_steps {
+            if self.steps >= max_steps {
+                return Err(RuntimeError::BudgetExceeded(max_steps));
+            }
+        }
+        self.steps += 1;
+        if let Some(interrupt) = &self.interrupt {
+            if interrupt.swap(false, Ordering::Relaxed) {
+                return Err(RuntimeError::Interrupted);
+            }
+        }
+        if let (Some(deadline), Some(timeout)) = (self.deadline, self.options.timeout) {
+            if self.steps.is_multiple_of(1024) && Instant::now() >= deadline {
+                return Err(RuntimeError::Timeout(timeout));
+            }
+        }
+        self.heap.check_limit()
+    }
+
+    fn evaluate_expr_inner(&mut self, ast: &Ast, expr: ExprId) -> Result<LoxValue, RuntimeError> {
+        match &ast[expr] {
+            Expr::Binary(binary) if matches!(ast[binary.left], Expr::Binary(_)) => {
+                let base = self.binary_chain.len();
+                let result = self.evaluate_binary_chain(ast, expr, base);
+                self.binary_chain.truncate(base);
+                result
+            }
+            Expr::Binary(binary) => {
+                let left = self.evaluate_expr(ast, binary.left)?;
+                let right = self.evaluate_expr(ast, binary.right)?;
+                self.evaluate_binary(&binary.operator, ast.expr_span(expr), &left, &right)
+            }
+            Expr::Call(CallExpr {
+                callee,
+                paren,
+                arguments,
+            }) => {
+                let span = ast.expr_span(expr);
+                let callee = self.evaluate_expr(ast, *callee)?;
+
+                let args = arguments
+                    .iter()
+                    .map(|a| self.evaluate_expr(ast, *a))
+                    .collect::<Result<Vec<LoxValue>, RuntimeError>>()?;
                 if let LoxValue::Ref(r) = callee {
                     match &*r.borrow() {
                         LoxRef::Function(f) => {
                             let none: Option<Rc<RefCell<LoxRef>>> = None;
-                            self.evaluate_call(none, &args, f)
+                            self.evaluate_call(none, &args, f, Some(paren))
+                        }
+                        LoxRef::Class(c) => {
+                            self.evaluate_call(Some(r.clone()), &args, c, Some(paren))
                         }
-                        LoxRef::Class(c) => self.evaluate_call(Some(r.clone()), &args, c),
-                        LoxRef::Instance(_) => {
-                            self.error_reporter
-                                .runtime_error(0, &RuntimeError::CallOnNonCallable.to_string());
-                            Err(RuntimeError::CallOnNonCallable)
+                        LoxRef::Instance(_)
+                        | LoxRef::List(_)
+                        | LoxRef::Generator(_)
+                        | LoxRef::Channel(_)
+                        | LoxRef::UserData(_) => {
+                            self.error_in(paren, span, RuntimeError::CallOnNonCallable)
                         }
                     }
                 } else {
-                    self.error_reporter
-                        .runtime_error(0, &RuntimeError::CallOnNonCallable.to_string());
-                    Err(RuntimeError::CallOnNonCallable)
+                    self.error_in(paren, span, RuntimeError::CallOnNonCallable)
                 }
             }
             Expr::Get(GetExpr { name, object }) => {
-                let object = self.evaluate_expr(object)?;
+                let object = self.evaluate_expr(ast, *object)?;
                 if let LoxValue::Ref(r) = &object {
-                    if let LoxRef::Instance(i) = &*r.borrow() {
-                        return i.get(r.clone(), &name.lexeme).map_err(|_| {
-                            self.error(&name, RuntimeError::UndefinedProperty(name.lexeme.clone()))
-                                .unwrap_err()
-                        });
+                    match &*r.borrow() {
+                        LoxRef::Instance(i) => {
+                            return i.get(r.clone(), &name.symbol, &self.heap).map_err(|_| {
+                                let names =
+                                    i.property_names().iter().map(Symbol::to_string).collect();
+                                self.undefined_property(name, names)
+                            });
+                        }
+                        LoxRef::Generator(_) | LoxRef::Channel(_) | LoxRef::UserData(_) => {
+                            return match BuiltinMethod::lookup(r.clone(), &name.lexeme) {
+                                Some(method) => {
+                                    Ok(LoxValue::Ref(self.heap.alloc(LoxRef::Function(method))))
+                                }
+                                None => {
+                                    let names = BuiltinMethod::names(&r.borrow());
+                                    let names = names.iter().map(Symbol::to_string).collect();
+                                    Err(self.undefined_property(name, names))
+                                }
+                            };
+                        }
+                        _ => {}
                     }
                 }
-                self.error_reporter
-                    .runtime_error(0, &RuntimeError::FieldAccessOnNonInstance.to_string());
-                Err(RuntimeError::FieldAccessOnNonInstance)
+                self.error_in(
+                    name,
+                    ast.expr_span(expr),
+                    RuntimeError::FieldAccessOnNonInstance,
+                )
             }
-            Expr::Grouping(e) => self.evaluate_expr(e.as_ref()),
+            Expr::Grouping(e) => self.evaluate_expr(ast, *e),
             Expr::Literal(l) => Ok(LoxValue::try_from(l).unwrap_or(LoxValue::Nil)),
-            Expr::Logical(e) => self.evaluate_logical(&e.left, &e.operator, &e.right),
+            Expr::Logical(e) => self.evaluate_logical(ast, e.left, &e.operator, e.right),
             Expr::Set(e) => {
-                let val = self.evaluate_expr(&*e.object)?;
+                let val = self.evaluate_expr(ast, e.object)?;
                 if let LoxValue::Ref(r) = val {
                     if let LoxRef::Instance(ref mut i) = &mut *r.borrow_mut() {
-                        let val = self.evaluate_expr(&*e.value)?;
-                        i.set(&e.name.lexeme, val.clone());
+                        let val = self.evaluate_expr(ast, e.value)?;
+                        i.set(e.name.symbol.clone(), val.clone());
                         return Ok(val);
                     }
                 }
 
-                self.error_reporter
-                    .runtime_error(0, &RuntimeError::FieldAccessOnNonInstance.to_string());
-                Err(RuntimeError::FieldAccessOnNonInstance)
+                let span = ast.expr_span(expr);
+                self.error_in(&e.name, span, RuntimeError::FieldAccessOnNonInstance)
             }
             Expr::Super(se) => {
-                let distance = self
-                    .locals
-                    .get(&(expr as *const Expr))
-                    .expect("No distance computed for 'super' keyword");
-                let superclass = self.env.borrow().get_at(*distance, "super")?;
-                let object = self.env.borrow().get_at(distance - 1, "this")?;
+                let local = match self.local(expr) {
+                    Some(local) => local,
+                    None => {
+                        let error = RuntimeError::UndefinedVar(
+                            se.keyword.lexeme.clone(),
+                            Suggestions::default(),
+                        );
+                        return self.error(&se.keyword, error);
+                    }
+                };
+                // 'super' and 'this' are always the only variables in their scopes
+                let superclass = self.env.borrow().get_at(local.depth, 0);
+                let object = self.env.borrow().get_at(local.depth - 1, 0);
                 // method = superclass.findmethod
                 // method.bind(object)
                 if let LoxValue::Ref(r) = superclass {
                     if let LoxRef::Class(c) = &*r.borrow() {
-                        let mthd = c.find_method(&se.method.lexeme);
-                        if let Some(method) = mthd {
-                            // Now get the actual method function
-                            if let LoxValue::Ref(rm) = method {
-                                if let LoxRef::Function(f) = &*rm.borrow() {
-                                    // Now method.bind(object)
-                                    if let LoxValue::Ref(obj) = object {
-                                        return Ok(LoxValue::Ref(Rc::new(RefCell::new(
-                                            LoxRef::Function(f.bind(obj)),
-                                        ))));
-                                    }
+                        let mthd = c.find_method(&se.method.symbol);
+                        // Now get the actual method function
+                        if let Some(LoxValue::Ref(rm)) = mthd {
+                            if let LoxRef::Function(f) = &*rm.borrow() {
+                                // Now method.bind(object)
+                                if let LoxValue::Ref(obj) = object {
+                                    return Ok(LoxValue::Ref(
+                                        self.heap.alloc(LoxRef::Function(f.bind(obj, &self.heap))),
+                                    ));
                                 }
                             }
                         }
@@ -328,27 +1447,33 @@ impl<'a, 'b> Interpreter<'a, 'b> {
 
                 panic!("'super' and 'this' should both be valid here");
             }
-            Expr::This(token) => self.lookup_variable(token, expr),
+            Expr::This(this) => self.lookup_variable(&this.keyword, expr),
             Expr::Unary(unary) => {
-                let right = self.evaluate_expr(unary.right.as_ref())?;
-                self.evaluate_unary(&unary.operator, &right)
+                let right = self.evaluate_expr(ast, unary.right)?;
+                self.evaluate_unary(&unary.operator, ast.expr_span(expr), &right)
             }
-            Expr::Variable(token) => self.lookup_variable(token, expr),
+            Expr::Variable(var) => self.lookup_variable(&var.name, expr),
             Expr::Assign(assign_expr) => {
-                let value = self.evaluate_expr(assign_expr.value.as_ref())?;
-                // println!("Lookup for name {} with ptr {:?}", assign_expr.name.lexeme, assign_expr as *const Expr);
-                if let Some(distance) = self.locals.get(&(expr as *const Expr)) {
+                let value = self.evaluate_expr(ast, assign_expr.value)?;
+                self.touch(&assign_expr.name, &value);
+                if let Some(local) = self.local(expr) {
                     // println!("Assigning at distance {}", distance);
                     self.env
                         .borrow_mut()
-                        .assign_at(*distance, &assign_expr.name.lexeme, value.clone())
-                        .or_else(|e| self.error(&assign_expr.name, e).map(|_| ()))?;
+                        .assign_at(local.depth, local.slot, value.clone());
+                } else if self.unresolved_scope {
+                    let assigned = self
+                        .env
+                        .borrow_mut()
+                        .assign_named(&assign_expr.name.symbol, value.clone());
+                    assigned.map_err(|_| self.undefined_var(&assign_expr.name))?;
                 } else {
                     // println!("Assigning global: {}", &assign_expr.name.lexeme);
-                    self.globals
+                    let assigned = self
+                        .globals
                         .borrow_mut()
-                        .assign(&assign_expr.name.lexeme, value.clone())
-                        .or_else(|e| self.error(&assign_expr.name, e).map(|_| ()))?;
+                        .assign(&assign_expr.name.symbol, value.clone());
+                    assigned.map_err(|_| self.undefined_var(&assign_expr.name))?;
                 }
 
                 Ok(value)
@@ -356,35 +1481,173 @@ impl<'a, 'b> Interpreter<'a, 'b> {
         }
     }
 
+    // Binary operators are left associative, so something like `a + b + c + ...` nests
+    // down its left operands, as deep as the chain is long. Rather than recursing down
+    // them, the chain is pushed onto a work stack above `base` and folded from the
+    // innermost operator out.
+    fn evaluate_binary_chain(
+        &mut self,
+        ast: &Ast,
+        expr: ExprId,
+        base: usize,
+    ) -> Result<LoxValue, RuntimeError> {
+        let mut left = expr;
+        while let Expr::Binary(binary) = &ast[left] {
+            // The rest of the chain doesn't go through evaluate_expr
+            if let (Some(stats), true) = (&mut self.stats, left != expr) {
+                stats.expr(&ast[left]);
+            }
+            self.binary_chain.push(left);
+            left = binary.left;
+        }
+        let mut value = self.evaluate_expr(ast, left)?;
+        while self.binary_chain.len() > base {
+            if let Some((id, Expr::Binary(binary))) =
+                self.binary_chain.pop().map(|id| (id, &ast[id]))
+            {
+                let right = self.evaluate_expr(ast, binary.right)?;
+                value =
+                    self.evaluate_binary(&binary.operator, ast.expr_span(id), &value, &right)?;
+            }
+        }
+        Ok(value)
+    }
+
     fn evaluate_call(
         &mut self,
-        this: Option<Rc<RefCell<LoxRef<'b>>>>,
-        args: &[LoxValue<'b>],
-        callable: &impl LoxCallable<'b>,
-    ) -> Result<LoxValue<'b>, RuntimeError<'b>> {
-        if args.len() != callable.arity() {
-            self.error_reporter.runtime_error(
-                0,
-                &("Expected ".to_string()
-                    + &callable.arity().to_string()
-                    + " arguments but got "
-                    + &args.len().to_string()),
+        this: Option<Rc<RefCell<LoxRef>>>,
+        args: &[LoxValue],
+        callable: &impl LoxCallable,
+        paren: Option<&Token>,
+    ) -> Result<LoxValue, RuntimeError> {
+        if !callable.arity().accepts(args.len()) {
+            let (callee, line) = callable.callee();
+            let mut msg = format!(
+                "Expected {} arguments but got {} when calling {}",
+                callable.arity(),
+                args.len(),
+                callee
             );
+            if let Some(line) = line {
+                msg += &format!(", defined on line {}", line);
+            }
+            let code = RuntimeError::CallWrongNumberOfArgs.code();
+            match paren {
+                Some(paren) => self.report(paren, code, &msg),
+                None => self.report_in_call(code, &msg),
+            }
             return Err(RuntimeError::CallWrongNumberOfArgs);
         }
-        callable.call(this, self, &args).map_err(|e| {
-            self.error_reporter.runtime_error(0, &e.to_string());
-            e
-        })
+        if self.call_stack.len() >= self.options.max_call_depth {
+            let error = RuntimeError::StackOverflow(self.options.max_call_depth);
+            match paren {
+                Some(paren) => self.error_at(paren, &error),
+                None => self.native_error(&error),
+            }
+            return Err(error);
+        }
+        // Callbacks from natives are called from where the native was
+        let (line, column) = match (paren, self.call_stack.last()) {
+            (Some(paren), _) => (paren.line, paren.column),
+            (None, Some(frame)) => (frame.line, frame.column),
+            (None, None) => (0, 0),
+        };
+        self.call_stack.push(StackFrame {
+            function: callable.name(),
+            line,
+            column,
+        });
+        if let Some(stats) = &mut self.stats {
+            stats.call(self.call_stack.len());
+        }
+        if let Some(profiler) = &mut self.profiler {
+            profiler.enter(FunctionKey {
+                name: callable.name(),
+                line: callable.line(),
+            });
+        }
+        let expr_depth = std::mem::take(&mut self.expr_depth);
+        let unresolved_scope = std::mem::replace(&mut self.unresolved_scope, false);
+        let result = callable.call(this, self, paren, args);
+        self.expr_depth = expr_depth;
+        self.unresolved_scope = unresolved_scope;
+        if let Some(profiler) = &mut self.profiler {
+            profiler.exit();
+        }
+        self.call_stack.pop();
+        result
+    }
+
+    // Reports the error against where the innermost call was made from, for errors
+    // without a token of their own, such as those of a native called by the host
+    pub fn native_error(&self, error: &RuntimeError) {
+        self.report_in_call(error.code(), &error.to_string());
+    }
+
+    // Reports the error at the token, such as the paren of the call a native was given
+    pub fn error_at(&self, token: &Token, error: &RuntimeError) {
+        self.report(token, error.code(), &error.to_string());
+    }
+
+    pub(crate) fn errors_reported(&self) -> usize {
+        self.errors_reported.get()
+    }
+
+    // For errors in code without a token to report them at, such as limits that run out
+    // between statements. Only the line is known, so there's no column.
+    fn report_on_line(&self, line: usize, span: Span, error: &RuntimeError) {
+        self.errors_reported.set(self.errors_reported.get() + 1);
+        self.error_reporter.runtime_error_with_backtrace(
+            error.code(),
+            line,
+            0,
+            Some(span),
+            &error.to_string(),
+            &self.call_stack,
+        );
+    }
+
+    fn report_in_call(&self, code: &str, msg: &str) {
+        let (line, column) = self
+            .call_stack
+            .last()
+            .map_or((0, 0), |frame| (frame.line, frame.column));
+        self.errors_reported.set(self.errors_reported.get() + 1);
+        self.error_reporter.runtime_error_with_backtrace(
+            code,
+            line,
+            column,
+            None,
+            msg,
+            &self.call_stack,
+        );
+    }
+
+    fn report(&self, token: &Token, code: &str, msg: &str) {
+        self.report_in(token, token.span, code, msg);
+    }
+
+    // Reports the error at the token, underlining the expression it's in
+    fn report_in(&self, token: &Token, span: Span, code: &str, msg: &str) {
+        self.errors_reported.set(self.errors_reported.get() + 1);
+        self.error_reporter.runtime_error_with_backtrace(
+            code,
+            token.line,
+            token.column,
+            Some(span),
+            msg,
+            &self.call_stack,
+        );
     }
 
     fn evaluate_logical(
         &mut self,
-        left: &Expr,
+        ast: &Ast,
+        left: ExprId,
         op: &Token,
-        right: &Expr,
-    ) -> Result<LoxValue<'b>, RuntimeError<'b>> {
-        let left_val = self.evaluate_expr(left)?;
+        right: ExprId,
+    ) -> Result<LoxValue, RuntimeError> {
+        let left_val = self.evaluate_expr(ast, left)?;
         if let TokenType::Or = op.token_type {
             if is_truthy(&left_val) {
                 return Ok(left_val);
@@ -392,34 +1655,36 @@ impl<'a, 'b> Interpreter<'a, 'b> {
         } else if !is_truthy(&left_val) {
             return Ok(left_val);
         }
-        self.evaluate_expr(right)
+        self.evaluate_expr(ast, right)
     }
 
     fn evaluate_unary(
         &self,
         operator: &Token,
+        span: Span,
         right: &LoxValue,
-    ) -> Result<LoxValue<'b>, RuntimeError<'b>> {
+    ) -> Result<LoxValue, RuntimeError> {
         match (&operator.token_type, &right) {
             (TokenType::Minus, &LoxValue::Number(n)) => Ok(LoxValue::Number(n * -1.0)),
-            (TokenType::Bang, right) => Ok(LoxValue::Boolean(!is_truthy(&right))),
-            _ => self.error(operator, RuntimeError::UnsupportedOperation),
+            (TokenType::Bang, right) => Ok(LoxValue::Boolean(!is_truthy(right))),
+            _ => self.error_in(operator, span, RuntimeError::UnsupportedOperation),
         }
     }
 
     fn evaluate_binary(
         &self,
         operator: &Token,
-        left: &LoxValue<'b>,
-        right: &LoxValue<'b>,
-    ) -> Result<LoxValue<'b>, RuntimeError<'b>> {
+        span: Span,
+        left: &LoxValue,
+        right: &LoxValue,
+    ) -> Result<LoxValue, RuntimeError> {
         match (&operator.token_type, &left, &right) {
             (TokenType::Minus, &LoxValue::Number(nl), &LoxValue::Number(nr)) => {
                 Ok(LoxValue::Number(nl - nr))
             }
             (TokenType::Slash, &LoxValue::Number(nl), &LoxValue::Number(nr)) => {
                 if *nr == 0.0 {
-                    self.error(operator, RuntimeError::DivideByZero)
+                    self.error_in(operator, span, RuntimeError::DivideByZero)
                 } else {
                     Ok(LoxValue::Number(nl / nr))
                 }
@@ -431,16 +1696,23 @@ impl<'a, 'b> Interpreter<'a, 'b> {
                 Ok(LoxValue::Number(nl + nr))
             }
             (TokenType::Plus, &LoxValue::String(sl), &LoxValue::String(sr)) => {
-                let mut s = String::new();
-                s.push_str(&sl);
-                s.push_str(&sr);
-                Ok(LoxValue::String(s))
+                if let Err(e) = self.heap.reserve(sl.len() + sr.len()) {
+                    return self.error_in(operator, span, e);
+                }
+                let mut s = String::with_capacity(sl.len() + sr.len());
+                s.push_str(sl);
+                s.push_str(sr);
+                Ok(LoxValue::String(s.into()))
             }
             (TokenType::Plus, &LoxValue::String(sl), &non_string) => {
+                let non_string = self.stringify(non_string);
+                if let Err(e) = self.heap.reserve(sl.len() + non_string.len()) {
+                    return self.error_in(operator, span, e);
+                }
                 let mut s = String::new();
-                s.push_str(&sl);
-                s.push_str(&non_string.to_string());
-                Ok(LoxValue::String(s))
+                s.push_str(sl);
+                s.push_str(&non_string);
+                Ok(LoxValue::String(s.into()))
             }
             (TokenType::Greater, &LoxValue::Number(nl), &LoxValue::Number(nr)) => {
                 Ok(LoxValue::Boolean(nl > nr))
@@ -458,59 +1730,1143 @@ impl<'a, 'b> Interpreter<'a, 'b> {
             (TokenType::EqualEqual, left, right) => Ok(LoxValue::Boolean(left == right)),
 
             // Handle invalid cases
-            (TokenType::Minus, _, _) => self.error(operator, RuntimeError::OperandsMustBeNumbers),
-            (TokenType::Slash, _, _) => self.error(operator, RuntimeError::OperandsMustBeNumbers),
-            (TokenType::Star, _, _) => self.error(operator, RuntimeError::OperandsMustBeNumbers),
-            (TokenType::Plus, _, _) => self.error(operator, RuntimeError::PlusOperandsWrong),
-            (TokenType::Greater, _, _) => self.error(operator, RuntimeError::OperandsMustBeNumbers),
+            (TokenType::Minus, _, _) => {
+                self.error_in(operator, span, RuntimeError::OperandsMustBeNumbers)
+            }
+            (TokenType::Slash, _, _) => {
+                self.error_in(operator, span, RuntimeError::OperandsMustBeNumbers)
+            }
+            (TokenType::Star, _, _) => {
+                self.error_in(operator, span, RuntimeError::OperandsMustBeNumbers)
+            }
+            (TokenType::Plus, _, _) => {
+                self.error_in(operator, span, RuntimeError::PlusOperandsWrong)
+            }
+            (TokenType::Greater, _, _) => {
+                self.error_in(operator, span, RuntimeError::OperandsMustBeNumbers)
+            }
             (TokenType::GreaterEqual, _, _) => {
-                self.error(operator, RuntimeError::OperandsMustBeNumbers)
+                self.error_in(operator, span, RuntimeError::OperandsMustBeNumbers)
+            }
+            (TokenType::Less, _, _) => {
+                self.error_in(operator, span, RuntimeError::OperandsMustBeNumbers)
             }
-            (TokenType::Less, _, _) => self.error(operator, RuntimeError::OperandsMustBeNumbers),
             (TokenType::LessEqual, _, _) => {
-                self.error(operator, RuntimeError::OperandsMustBeNumbers)
+                self.error_in(operator, span, RuntimeError::OperandsMustBeNumbers)
             }
-            _ => self.error(operator, RuntimeError::UnsupportedOperation),
+            _ => self.error_in(operator, span, RuntimeError::UnsupportedOperation),
         }
     }
 
-    fn error(
+    fn error(&self, token: &Token, error: RuntimeError) -> Result<LoxValue, RuntimeError> {
+        self.error_in(token, token.span, error)
+    }
+
+    fn error_in(
         &self,
         token: &Token,
-        error: RuntimeError<'b>,
-    ) -> Result<LoxValue<'b>, RuntimeError<'b>> {
-        self.error_reporter
-            .runtime_error(token.line, &error.to_string());
+        span: Span,
+        error: RuntimeError,
+    ) -> Result<LoxValue, RuntimeError> {
+        self.report_in(token, span, error.code(), &error.to_string());
         Err(error)
     }
 
-    pub fn resolve(&mut self, expr: &Expr, distance: usize) {
-        // println!("Resolving expr with ptr {:?} and distance {}", expr as *const Expr, distance);
-        self.locals.insert(expr as *const Expr, distance);
+    // Takes what the resolver found in code that's about to be run
+    pub fn add_resolution(&mut self, resolved: ResolvedProgram) {
+        self.resolved.extend(resolved);
     }
 
-    fn lookup_variable(
-        &mut self,
-        name: &Token,
-        expr: &Expr,
-    ) -> Result<LoxValue<'b>, RuntimeError<'b>> {
-        // println!("Lookup for name {} with ptr {:?}", name.lexeme, expr as *const Expr);
-        if let Some(distance) = self.locals.get(&(expr as *const Expr)) {
-            self.env
-                .borrow_mut()
-                .get_at(*distance, &name.lexeme)
-                .map_err(|e: RuntimeError<'b>| self.error(name, e).unwrap_err())
+    fn lookup_variable(&mut self, name: &Token, id: ExprId) -> Result<LoxValue, RuntimeError> {
+        let value = if let Some(local) = self.local(id) {
+            self.env.borrow().get_at(local.depth, local.slot)
+        } else if self.unresolved_scope {
+            let value = self.env.borrow().get_named(&name.symbol);
+            value.ok_or_else(|| self.undefined_var(name))?
         } else {
             // println!("Have too look up global for {}", name.lexeme);
-            self.globals.borrow_mut().get(&name.lexeme)
+            let global = self.globals.borrow().get(&name.symbol);
+            global.map_err(|_| self.undefined_var(name))?
+        };
+        self.touch(name, &value);
+        Ok(value)
+    }
+
+    // Reports a variable that isn't defined, suggesting those in scope with similar names,
+    // including locals where a global was looked up for a misspelt one. Kept out of line,
+    // like undefined_property, to keep evaluate_expr's frame small for deep recursion.
+    #[cold]
+    #[inline(never)]
+    fn undefined_var(&self, name: &Token) -> RuntimeError {
+        let error = self.env.borrow().undefined(name.symbol.as_str());
+        self.error(name, error).unwrap_err()
+    }
+
+    #[cold]
+    #[inline(never)]
+    fn undefined_property(&self, name: &Token, names: Vec<String>) -> RuntimeError {
+        let suggestions = Suggestions::new(&name.lexeme, names);
+        let error = RuntimeError::UndefinedProperty(name.lexeme.clone(), suggestions);
+        self.error(name, error).unwrap_err()
+    }
+
+    fn local(&self, id: ExprId) -> Option<Local> {
+        if self.unresolved_scope {
+            None
+        } else {
+            self.resolved.local(id)
+        }
+    }
+
+    fn touch(&mut self, name: &Token, value: &LoxValue) {
+        if let Some(tracer) = &mut self.tracer {
+            tracer.touch(&name.lexeme, value);
         }
     }
 }
 
-fn is_truthy(val: &LoxValue) -> bool {
-    match val {
-        LoxValue::Nil => false,
-        LoxValue::Boolean(false) => false,
-        _ => true,
+pub fn is_truthy(val: &LoxValue) -> bool {
+    !matches!(val, LoxValue::Nil | LoxValue::Boolean(false))
+}
+
+// Lox calls and nested code recurse on the host stack. When it runs low, evaluation
+// carries on in a new piece of stack from the heap, so the limits in InterpreterOptions
+// hold on whatever thread the host runs the interpreter on.
+#[cfg(not(target_arch = "wasm32"))]
+fn grow_stack<T>(f: impl FnOnce() -> T) -> T {
+    // Room for the frames from one check to the next, even in debug builds
+    const RED_ZONE: usize = 256 * 1024;
+    const SEGMENT_SIZE: usize = 4 * 1024 * 1024;
+    stacker::maybe_grow(RED_ZONE, SEGMENT_SIZE, f)
+}
+
+// Browsers don't let the stack grow, so hosts there set limits that fit in it
+#[cfg(target_arch = "wasm32")]
+fn grow_stack<T>(f: impl FnOnce() -> T) -> T {
+    f()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::{parser::Parser, resolver::Resolver, scanner::Scanner};
+
+    fn parse(interpreter: &mut Interpreter, reporter: &ErrorReporter, src: &str) -> Vec<StmtId> {
+        Parser::new(Scanner::new(src, reporter), interpreter.ast_mut(), reporter).parse_stmts()
+    }
+
+    fn run<'a>(interpreter: &mut Interpreter<'a>, reporter: &'a ErrorReporter, src: &str) {
+        let stmts = parse(interpreter, reporter, src);
+        let resolved = Resolver::new(&interpreter.ast(), reporter).resolve_stmts(&stmts);
+        interpreter.add_resolution(resolved);
+        interpreter.interpret(&stmts);
+    }
+
+    fn interpret(src: &str, options: InterpreterOptions) -> ErrorReporter {
+        let reporter = ErrorReporter::new();
+        run(&mut Interpreter::new(&reporter, options), &reporter, src);
+        reporter
+    }
+
+    #[test]
+    pub fn unbounded_recursion_is_a_runtime_error() {
+        let options = InterpreterOptions {
+            max_call_depth: 100,
+            ..InterpreterOptions::default()
+        };
+        let reporter = interpret("fun f() { f(); } f();", options.clone());
+        assert!(reporter.had_runtime_error());
+
+        let src = "fun f(n) { if (n > 0) f(n - 1); } f(99);";
+        assert!(!interpret(src, options).had_runtime_error());
+    }
+
+    #[test]
+    pub fn default_call_depth_fits_an_ordinary_thread() {
+        // Threads get 2MB of stack unless they ask for more
+        let run = |src: &'static str| {
+            std::thread::spawn(move || interpret(src, InterpreterOptions::default()).take_errors())
+                .join()
+                .unwrap()
+        };
+        let errors = run("fun f(n) { return f(n + 1); } f(0);");
+        assert_eq!(errors.len(), 1);
+        assert!(errors[0].contains("Stack overflow"));
+
+        let src = "fun f(n) { if (n > 0) return f(n - 1) + 1; return 0; } var a = f(9999);";
+        assert!(run(src).is_empty());
+    }
+
+    #[test]
+    pub fn deep_expressions_are_a_runtime_error() {
+        let options = InterpreterOptions {
+            max_expr_depth: 50,
+            ..InterpreterOptions::default()
+        };
+        let src = format!("var a = {}1;", "-".repeat(100));
+        assert!(interpret(&src, options.clone()).had_runtime_error());
+
+        // Chains of binary operators don't nest, however long they are
+        let src = format!("var a = 0{};", " + 1".repeat(1000));
+        assert!(!interpret(&src, options).had_runtime_error());
+    }
+
+    #[test]
+    pub fn runaway_loops_exceed_the_budget() {
+        let options = InterpreterOptions {
+            max_steps: Some(1_000),
+            ..InterpreterOptions::default()
+        };
+        assert!(interpret("while (true) {}", options.clone()).had_runtime_error());
+
+        // Outside of any call, it's shown at the statement it stopped before
+        let diagnostics =
+            interpret("var a = 1;\nwhile (true) {}", options.clone()).take_diagnostics();
+        assert_eq!(
+            diagnostics[0].to_string(),
+            "[Line 2] Runtime Error: Execution budget exceeded: max steps 1000 taken"
+        );
+        assert_eq!(diagnostics[0].span, Some(Span { start: 24, end: 26 }));
+
+        let src = "for (var i = 0; i < 10; i = i + 1) {}";
+        assert!(!interpret(src, options).had_runtime_error());
+    }
+
+    #[test]
+    pub fn keeps_going_after_errors() {
+        let options = InterpreterOptions {
+            keep_going: true,
+            max_call_depth: 50,
+            ..InterpreterOptions::default()
+        };
+        let src = "var a = 1; var b = nope; a = a + 1; print b; a = a * 10;
+            fun f() { f(); } f(); a = 0;";
+        let reporter = ErrorReporter::new();
+        let mut interpreter = Interpreter::new(&reporter, options);
+        run(&mut interpreter, &reporter, src);
+        let errors = reporter.take_errors();
+        assert_eq!(errors.len(), 3);
+        assert!(errors[1].contains("Undefined variable b"));
+        assert!(errors[2].contains("Stack overflow"));
+        let mut ast = Ast::new();
+        let a = Parser::new(Scanner::new("a", &reporter), &mut ast, &reporter)
+            .parse_expr()
+            .unwrap();
+        let value = interpreter.evaluate_in_scope(&ast, a).unwrap();
+        assert_eq!(value, LoxValue::Number(20.0));
+    }
+
+    #[test]
+    pub fn host_natives_can_be_registered() {
+        let reporter = ErrorReporter::new();
+        let mut interpreter = Interpreter::new(&reporter, InterpreterOptions::default());
+        interpreter.register_native("double", 1, |args| match args[0] {
+            LoxValue::Number(n) => Ok(LoxValue::Number(n * 2.0)),
+            _ => Err("double() expects a number"),
+        });
+        interpreter.register_native("sum", Arity::AtLeast(1), |args| {
+            let mut total = 0.0;
+            for arg in args {
+                match arg {
+                    LoxValue::Number(n) => total += n,
+                    _ => return Err(format!("sum() can't add {}", arg)),
+                }
+            }
+            Ok(LoxValue::Number(total))
+        });
+        run(
+            &mut interpreter,
+            &reporter,
+            "var a = double(sum(1, 2, 3)); var b = sum(4);",
+        );
+        assert!(!reporter.had_runtime_error());
+        let mut ast = Ast::new();
+        let a = Parser::new(Scanner::new("a + b", &reporter), &mut ast, &reporter)
+            .parse_expr()
+            .unwrap();
+        let value = interpreter.evaluate_in_scope(&ast, a).unwrap();
+        assert_eq!(value, LoxValue::Number(16.0));
+
+        run(&mut interpreter, &reporter, "sum();");
+        let errors = reporter.take_errors();
+        assert!(errors[0].contains("Expected at least 1 arguments but got 0"));
+
+        reporter.reset();
+        run(&mut interpreter, &reporter, "double(\"two\");");
+        let errors = reporter.take_errors();
+        assert!(errors[0].contains("double() expects a number"));
+    }
+
+    struct Counter {
+        count: f64,
+    }
+
+    #[test]
+    pub fn hosts_can_hand_scripts_their_own_values() {
+        let reporter = ErrorReporter::new();
+        let mut interpreter = Interpreter::new(&reporter, InterpreterOptions::default());
+        let kind = Rc::new(
+            UserDataType::new("Counter")
+                .with_method("add", 1, |this, args| {
+                    let mut counter = this.user_data_mut::<Counter>().unwrap();
+                    match args[0] {
+                        LoxValue::Number(n) => counter.count += n,
+                        _ => return Err("add() expects a number"),
+                    }
+                    Ok(LoxValue::Number(counter.count))
+                })
+                .with_method("count", 0, |this, _| {
+                    Ok::<_, RuntimeError>(LoxValue::Number(
+                        this.user_data::<Counter>().unwrap().count,
+                    ))
+                }),
+        );
+        let counter = interpreter.user_data(Counter { count: 1.0 }, &kind);
+        interpreter
+            .globals
+            .borrow_mut()
+            .define(Symbol::intern("counter"), counter.clone());
+        interpreter.register_native("countOf", 1, |args| match args[0].user_data::<Counter>() {
+            Some(counter) => Ok(LoxValue::Number(counter.count)),
+            None => Err("countOf() expects a counter"),
+        });
+        run(
+            &mut interpreter,
+            &reporter,
+            "var add = counter.add; add(2); counter.add(3); \
+             var a = counter.count() + countOf(counter); \
+             var b = \"\" + counter; var c = listGet(methods(counter), 0);",
+        );
+        assert!(!reporter.had_runtime_error());
+        assert_eq!(counter.user_data::<Counter>().unwrap().count, 6.0);
+        assert!(counter.user_data::<String>().is_none());
+        assert_eq!(interpreter.global("a"), Some(LoxValue::Number(12.0)));
+        assert_eq!(
+            interpreter.global("b"),
+            Some(LoxValue::String("Counter userdata".into()))
+        );
+        assert_eq!(
+            interpreter.global("c"),
+            Some(LoxValue::String("add".into()))
+        );
+
+        for (src, error) in [
+            ("counter.cont();", "Did you mean 'count'?"),
+            (
+                "counter.add();",
+                "Expected 1 arguments but got 0 when calling add()",
+            ),
+            ("counter.add(\"1\");", "add() expects a number"),
+            ("countOf(1);", "countOf() expects a counter"),
+            ("counter.count = 1;", "Only instances have fields"),
+        ] {
+            reporter.reset();
+            run(&mut interpreter, &reporter, src);
+            let errors = reporter.take_errors();
+            assert!(errors[0].contains(error), "{}", errors[0]);
+        }
+    }
+
+    #[test]
+    pub fn natives_can_use_the_interpreter() {
+        let reporter = ErrorReporter::new();
+        let options = InterpreterOptions {
+            max_steps: Some(1_000),
+            ..InterpreterOptions::default()
+        };
+        let mut interpreter = Interpreter::new(&reporter, options);
+        // Calls the function on each number up to n, and gives the total
+        interpreter.register_native_fn(NativeFn {
+            name: "totalOf".to_string(),
+            arity: Arity::Exactly(2),
+            code: Arc::new(|interpreter, paren, args| {
+                let n = match args[1] {
+                    LoxValue::Number(n) => n,
+                    _ => return Err("totalOf() expects a number".into()),
+                };
+                if interpreter
+                    .options()
+                    .max_steps
+                    .is_some_and(|max| n as usize > max)
+                {
+                    let error = RuntimeError::from("totalOf() would take too many steps");
+                    interpreter.error_at(paren.unwrap(), &error);
+                    return Err(error);
+                }
+                let mut total = 0.0;
+                for i in 1..=n as usize {
+                    match interpreter.call_back(&args[0], &[LoxValue::Number(i as f64)])? {
+                        LoxValue::Number(n) => total += n,
+                        _ => return Err("totalOf() expects numbers back".into()),
+                    }
+                }
+                Ok(LoxValue::Number(total))
+            }),
+        });
+        let mut run = |src: &str| interpreter.run(src);
+        let outcome = run("fun square(x) { return x * x; } totalOf(square, 3);");
+        assert_eq!(outcome.value, Some(LoxValue::Number(14.0)));
+
+        // Reported once, where it happened in the callback
+        let outcome = run("fun half(x) { return x / 0; } totalOf(half, 3);");
+        assert_eq!(outcome.diagnostics.len(), 1);
+        assert_eq!(
+            outcome.diagnostics[0].message,
+            "Attempted to divide by zero"
+        );
+        assert_eq!(outcome.diagnostics[0].line, 1);
+
+        let outcome = run("fun hello(x) { return \"hi\"; }\ntotalOf(hello, 1);");
+        assert_eq!(outcome.diagnostics.len(), 1);
+        assert_eq!(
+            outcome.diagnostics[0].message,
+            "totalOf() expects numbers back"
+        );
+        assert_eq!(outcome.diagnostics[0].line, 2);
+
+        let outcome = run("totalOf(square, 5000);");
+        assert_eq!(
+            outcome.diagnostics[0].message,
+            "totalOf() would take too many steps"
+        );
+        assert!(outcome.diagnostics[0].span.is_some());
+        let outcome = run("totalOf(1, 2);");
+        assert_eq!(
+            outcome.diagnostics[0].message,
+            "Can only call functions and classes"
+        );
+    }
+
+    #[crate::lox_native]
+    fn hypot(x: f64, y: f64) -> f64 {
+        x.hypot(y)
+    }
+
+    #[crate::lox_native]
+    fn mean(numbers: Vec<f64>) -> Option<f64> {
+        if numbers.is_empty() {
+            return None;
+        }
+        Some(numbers.iter().sum::<f64>() / numbers.len() as f64)
+    }
+
+    #[crate::lox_native(name = "repeat")]
+    fn repeat_string(s: String, times: f64) -> Result<String, String> {
+        if times < 0.0 {
+            return Err("repeat() can't repeat a negative number of times".to_string());
+        }
+        Ok(s.repeat(times as usize))
+    }
+
+    #[test]
+    pub fn natives_can_be_made_from_rust_functions() {
+        let reporter = ErrorReporter::new();
+        let mut interpreter = Interpreter::new(&reporter, InterpreterOptions::default());
+        interpreter.register_native_fn(hypot_native());
+        interpreter.register_native_fn(repeat_string_native());
+        interpreter.register_native_fn(mean_native());
+        let numbers = interpreter.list(vec![2.0, 4.0, 6.0]);
+        interpreter
+            .globals
+            .borrow_mut()
+            .define(Symbol::intern("numbers"), numbers);
+        run(
+            &mut interpreter,
+            &reporter,
+            "var a = hypot(3, mean(numbers)); var b = repeat(\"ab\", a);",
+        );
+        assert!(!reporter.had_runtime_error());
+        let mut ast = Ast::new();
+        let b = Parser::new(Scanner::new("b", &reporter), &mut ast, &reporter)
+            .parse_expr()
+            .unwrap();
+        let value = interpreter.evaluate_in_scope(&ast, b).unwrap();
+        assert_eq!(value, LoxValue::String("ababababab".into()));
+
+        for (src, error) in [
+            ("hypot(3);", "Expected 2 arguments but got 1"),
+            (
+                "hypot(3, \"4\");",
+                "hypot() expects argument 2 to be a number",
+            ),
+            (
+                "repeat(\"ab\", -1);",
+                "repeat() can't repeat a negative number of times",
+            ),
+        ] {
+            reporter.reset();
+            run(&mut interpreter, &reporter, src);
+            assert!(reporter.take_errors()[0].contains(error));
+        }
+    }
+
+    #[crate::lox_native]
+    fn pad(s: String, width: f64, fill: Option<String>) -> String {
+        let fill = fill.unwrap_or_else(|| " ".to_string());
+        let missing = (width as usize).saturating_sub(s.chars().count());
+        fill.repeat(missing) + &s
+    }
+
+    #[crate::lox_native]
+    fn join(separator: String, parts: &[LoxValue]) -> String {
+        let parts: Vec<String> = parts.iter().map(LoxValue::to_string).collect();
+        parts.join(&separator)
+    }
+
+    #[crate::lox_native]
+    fn tally(label: String, start: Option<f64>, counts: &[LoxValue]) -> String {
+        let total = start.unwrap_or(0.0) + counts.len() as f64;
+        format!("{}: {}", label, total)
+    }
+
+    #[test]
+    pub fn natives_can_take_optional_and_rest_arguments() {
+        let reporter = ErrorReporter::new();
+        let mut interpreter = Interpreter::new(&reporter, InterpreterOptions::default());
+        interpreter.register_native_fn(pad_native());
+        interpreter.register_native_fn(join_native());
+        interpreter.register_native_fn(tally_native());
+        interpreter.register_native("initials", 1..=2, |args| {
+            let initial = |arg: &LoxValue| arg.to_string().chars().next().unwrap_or(' ');
+            let initials: String = args.iter().map(initial).collect();
+            Ok::<_, RuntimeError>(LoxValue::String(initials.into()))
+        });
+        assert_eq!(pad_native().arity, Arity::Between(2, 3));
+        assert_eq!(join_native().arity, Arity::AtLeast(1));
+        assert_eq!(tally_native().arity, Arity::AtLeast(1));
+        run(
+            &mut interpreter,
+            &reporter,
+            "var a = pad(\"7\", 3) + pad(\"7\", 3, \"0\"); \
+             var b = join(\", \") + join(\"-\", 1, true, \"c\"); \
+             var c = initials(\"Ada\") + initials(\"Ada\", \"Lovelace\"); \
+             var d = tally(\"none\") + \", \" + tally(\"some\", 1, nil, nil);",
+        );
+        assert!(!reporter.had_runtime_error());
+        for (name, expected) in [
+            ("a", "  7007"),
+            ("b", "1-true-c"),
+            ("c", "AAL"),
+            ("d", "none: 0, some: 3"),
+        ] {
+            let value = interpreter.global(name).unwrap();
+            assert_eq!(value, LoxValue::String(expected.into()));
+        }
+
+        for (src, error) in [
+            ("pad(\"7\");", "Expected 2 to 3 arguments but got 1"),
+            (
+                "pad(\"7\", 3, 0);",
+                "pad() expects argument 3 to be a string",
+            ),
+            ("join();", "Expected at least 1 arguments but got 0"),
+            ("initials(1, 2, 3);", "Expected 1 to 2 arguments but got 3"),
+        ] {
+            reporter.reset();
+            run(&mut interpreter, &reporter, src);
+            assert!(reporter.take_errors()[0].contains(error));
+        }
+    }
+
+    // Output that can be read back after the interpreter has written it
+    #[derive(Clone, Default)]
+    struct Captured(Rc<RefCell<Vec<u8>>>);
+
+    impl Write for Captured {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.0.borrow_mut().write(buf)
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    pub fn printing_goes_to_the_output() {
+        let reporter = ErrorReporter::new();
+        let mut interpreter = Interpreter::new(&reporter, InterpreterOptions::default());
+        let output = Captured::default();
+        interpreter.set_output(Box::new(output.clone()));
+        run(
+            &mut interpreter,
+            &reporter,
+            "print 1 + 2; print \"three\"; print nil;",
+        );
+        assert!(!reporter.had_runtime_error());
+        assert_eq!(
+            String::from_utf8(output.0.borrow().clone()).unwrap(),
+            "3\nthree\nNil\n"
+        );
+    }
+
+    #[test]
+    pub fn shows_functions_and_classes() {
+        let reporter = ErrorReporter::new();
+        let mut interpreter = Interpreter::new(&reporter, InterpreterOptions::default());
+        run(
+            &mut interpreter,
+            &reporter,
+            "fun add(a, b) { return a + b; }
+            class Shape { area() {} }
+            class Square < Shape { init(side) {} scale(by) {} }",
+        );
+        let shown = |name: &str| {
+            let value = interpreter.global(name).unwrap();
+            (interpreter.stringify(&value), interpreter.describe(&value))
+        };
+        assert_eq!(shown("add").0, "<fn add(2)>");
+        assert_eq!(shown("clock").0, "<native fn clock>");
+        assert_eq!(
+            shown("Square"),
+            (
+                "Square".to_string(),
+                "<class Square < Shape { init(1), scale(1) }>".to_string()
+            )
+        );
+    }
+
+    #[test]
+    pub fn numbers_can_print_raw() {
+        let reporter = ErrorReporter::new();
+        let options = InterpreterOptions {
+            number_format: NumberFormat::Raw,
+            ..InterpreterOptions::default()
+        };
+        let mut interpreter = Interpreter::new(&reporter, options);
+        let output = Captured::default();
+        interpreter.set_output(Box::new(output.clone()));
+        run(&mut interpreter, &reporter, "print 2; print \"x\" + 1 / 4;");
+        assert_eq!(
+            String::from_utf8(output.0.borrow().clone()).unwrap(),
+            "2.0\nx0.25\n"
+        );
+    }
+
+    // Finishes the second time it's polled, as if it had been waiting on something
+    struct Later {
+        value: Option<LoxValue>,
+        polled: bool,
+    }
+
+    impl Future for Later {
+        type Output = Result<LoxValue, RuntimeError>;
+
+        fn poll(
+            self: std::pin::Pin<&mut Self>,
+            cx: &mut std::task::Context<'_>,
+        ) -> Poll<Self::Output> {
+            let this = self.get_mut();
+            if this.polled {
+                Poll::Ready(this.value.take().ok_or_else(|| "Polled again".into()))
+            } else {
+                this.polled = true;
+                cx.waker().wake_by_ref();
+                Poll::Pending
+            }
+        }
+    }
+
+    struct Noop;
+
+    impl std::task::Wake for Noop {
+        fn wake(self: Arc<Self>) {}
+    }
+
+    #[test]
+    pub fn async_natives_call_back_when_finished() {
+        let reporter = ErrorReporter::new();
+        let mut interpreter = Interpreter::new(&reporter, InterpreterOptions::default());
+        let output = Captured::default();
+        interpreter.set_output(Box::new(output.clone()));
+        interpreter.register_async_native("later", 1, |args| Later {
+            value: Some(args[0].clone()),
+            polled: false,
+        });
+        let src = "fun then(n) { print n; }
+fun first(n) { print n; later(n + 1, then); }
+later(1, first);
+print \"first\";";
+        run(&mut interpreter, &reporter, src);
+        assert!(!reporter.had_runtime_error());
+        assert_eq!(
+            String::from_utf8(output.0.borrow_mut().split_off(0)).unwrap(),
+            "first\n1\n2\n"
+        );
+
+        let stmts = parse(&mut interpreter, &reporter, src);
+        let resolved = Resolver::new(&interpreter.ast(), &reporter).resolve_stmts(&stmts);
+        interpreter.add_resolution(resolved);
+        let waker = std::task::Waker::from(Arc::new(Noop));
+        let mut cx = std::task::Context::from_waker(&waker);
+        let mut running = Box::pin(interpreter.interpret_async(&stmts));
+        let mut polls = 1;
+        while running.as_mut().poll(&mut cx).is_pending() {
+            polls += 1;
+        }
+        drop(running);
+        assert_eq!(polls, 3);
+        assert_eq!(
+            String::from_utf8(output.0.borrow().clone()).unwrap(),
+            "first\n1\n2\n"
+        );
+
+        run(&mut interpreter, &reporter, "later(1, 2);");
+        assert!(reporter.had_runtime_error());
+    }
+
+    #[test]
+    pub fn runs_give_an_outcome() {
+        let reporter = ErrorReporter::new();
+        let mut interpreter = Interpreter::new(&reporter, InterpreterOptions::default());
+        let outcome = interpreter.run("var a = 20; a * 2 + 2;");
+        assert_eq!(outcome.value, Some(LoxValue::Number(42.0)));
+        assert!(outcome.diagnostics.is_empty());
+        assert!(interpreter.run("var b = a;").value.is_none());
+
+        let outcome = interpreter.run("b = 1; nope;");
+        assert!(outcome.had_runtime_error && !outcome.had_error);
+        assert_eq!(outcome.value, None);
+        assert_eq!(outcome.diagnostics.len(), 1);
+        assert!(outcome.diagnostics[0].runtime);
+
+        let outcome = interpreter.run("var = 3;");
+        assert!(outcome.had_error && !outcome.had_runtime_error);
+        // Nothing is left over for the next run
+        assert!(!reporter.had_error());
+        assert!(interpreter.run("a;").diagnostics.is_empty());
+    }
+
+    #[test]
+    pub fn errors_underline_the_expression_they_are_in() {
+        let reporter = ErrorReporter::new();
+        let mut interpreter = Interpreter::new(&reporter, InterpreterOptions::default());
+        let mut underlined = |src: &str| {
+            let outcome = interpreter.run(src);
+            let span = outcome.diagnostics[0].span.unwrap();
+            src[span.start..span.end].to_string()
+        };
+        assert_eq!(underlined("var s = \"a\";\nprint (s - 1) * 2;"), "s - 1");
+        assert_eq!(underlined("print 1 + 2 + -\"three\";"), "-\"three\"");
+        assert_eq!(underlined("var n = 1; print n.field;"), "n.field");
+        assert_eq!(underlined("var n = 1; n(1, 2);"), "n(1, 2)");
+    }
+
+    #[test]
+    pub fn suggests_names_like_undefined_ones() {
+        let reporter = ErrorReporter::new();
+        let mut interpreter = Interpreter::new(&reporter, InterpreterOptions::default());
+        let mut error = |src: &str| interpreter.run(src).diagnostics[0].message.clone();
+        assert_eq!(
+            error("var count = 0; print cuont;"),
+            "Undefined variable cuont. Did you mean 'count'?"
+        );
+        // Locals are suggested for what was looked up as a global
+        assert_eq!(
+            error("fun f() { var total = 0; totl = 1; } f();"),
+            "Undefined variable totl. Did you mean 'total'?"
+        );
+        assert_eq!(
+            error(
+                "class Shape { area() {} }
+                class Square < Shape { init() { this.side = 1; } }
+                Square().sid;"
+            ),
+            "Undefined property sid. Did you mean 'side'?"
+        );
+        assert_eq!(
+            error("Square().aera;"),
+            "Undefined property aera. Did you mean 'area'?"
+        );
+        assert_eq!(error("print unrelated;"), "Undefined variable unrelated");
+    }
+
+    #[test]
+    pub fn arity_errors_name_the_callee() {
+        let reporter = ErrorReporter::new();
+        let mut interpreter = Interpreter::new(&reporter, InterpreterOptions::default());
+        run(
+            &mut interpreter,
+            &reporter,
+            "fun add(a, b) { return a + b; }
+            class Point {
+              init(x, y) {}
+              move(dx) {}
+            }
+            class Empty {}",
+        );
+        let mut error = |src: &str| interpreter.run(src).diagnostics[0].message.clone();
+        for (src, message) in [
+            (
+                "add(1, 2, 3);",
+                "but got 3 when calling add(), defined on line 1",
+            ),
+            ("Point(1);", "calling Point.init(), defined on line 3"),
+            (
+                "Point(1, 2).move();",
+                "calling Point.move(), defined on line 4",
+            ),
+            ("Empty(1);", "calling Empty(), defined on line 6"),
+        ] {
+            assert!(error(src).ends_with(message), "{}", src);
+        }
+        assert!(error("clock(1);").ends_with("when calling clock()"));
+    }
+
+    #[test]
+    pub fn evaluates_source_at_runtime() {
+        let reporter = ErrorReporter::new();
+        let mut interpreter = Interpreter::new(&reporter, InterpreterOptions::default());
+        let output = Captured::default();
+        interpreter.set_output(Box::new(output.clone()));
+        run(
+            &mut interpreter,
+            &reporter,
+            "var a = 2;
+            fun f() { var a = 10; return eval(\"a * 3\"); }
+            print f();
+            eval(\"fun g(x) { return x + a; } var b = 5;\");
+            print g(b);",
+        );
+        assert!(!reporter.had_runtime_error());
+        assert_eq!(
+            String::from_utf8(output.0.borrow().clone()).unwrap(),
+            "6\n7\n"
+        );
+
+        let outcome = interpreter.run("var c = 1; eval(\"c = 2; print 1 +;\");");
+        let error = outcome.diagnostics.iter().find(|d| d.runtime).unwrap();
+        assert_eq!(error.code.as_deref(), Some("E0223"));
+        assert_eq!(
+            error.message,
+            "Couldn't evaluate source: Expect expression, at line 1:17"
+        );
+        // None of it ran
+        assert_eq!(interpreter.global("c"), Some(LoxValue::Number(1.0)));
+    }
+
+    #[test]
+    pub fn errors_in_evaluated_source_are_shown_against_it() {
+        let reporter = ErrorReporter::new();
+        reporter.keep_sources();
+        let src = "var first = 1;\nfor (var i = 0; i < 3; i = i + 1) eval(\"first\");\nprint eval(\"nil - 1\");";
+        reporter.add_source("host.lox", src);
+        run(
+            &mut Interpreter::new(&reporter, InterpreterOptions::default()),
+            &reporter,
+            src,
+        );
+        let errors: Vec<String> = reporter
+            .take_diagnostics()
+            .iter()
+            .map(|diagnostic| reporter.render(diagnostic, false))
+            .collect();
+        assert_eq!(errors.len(), 1);
+        assert!(
+            errors[0].contains(" --> <eval>:1:5\n  |\n1 | nil - 1\n  | ^^^^^^^\n"),
+            "{}",
+            errors[0]
+        );
+    }
+
+    #[test]
+    pub fn reflection_natives_dont_leak_names() {
+        let reporter = ErrorReporter::new();
+        let options = InterpreterOptions {
+            max_memory: Some(64 * 1024),
+            ..InterpreterOptions::default()
+        };
+        let mut interpreter = Interpreter::new(&reporter, options);
+        let outcome = interpreter
+            .run("class A {} var a = A(); getattr(a, \"reflection_natives_never_set\");");
+        assert!(outcome.diagnostics[0]
+            .message
+            .starts_with("Undefined property reflection_natives_never_set"));
+        assert_eq!(Symbol::lookup("reflection_natives_never_set"), None);
+
+        // Names setattr() makes go once what has them as fields does
+        interpreter.run("setattr(A(), \"reflection_natives_dropped\", 1);");
+        assert_eq!(Symbol::lookup("reflection_natives_dropped"), None);
+
+        // Until then, they're limited like the rest of the heap
+        let outcome = interpreter.run(
+            "for (var i = 0; i < 100000; i = i + 1) setattr(a, \"reflection_natives_\" + i, 1);",
+        );
+        assert!(outcome.diagnostics[0].message.starts_with("Out of memory"));
+    }
+
+    #[test]
+    pub fn code_is_freed_once_it_has_run() {
+        let reporter = ErrorReporter::new();
+        let mut interpreter = Interpreter::new(&reporter, InterpreterOptions::default());
+        interpreter.run("fun one() { return 1; }");
+        let mark = interpreter.ast().mark();
+        let outcome = interpreter.run(
+            "for (var i = 0; i < 1000; i = i + 1) eval(\"var q = 1 + 2 * 3 - 4;\");
+            var r = eval(\"one()\");",
+        );
+        assert!(outcome.diagnostics.is_empty());
+        assert_eq!(interpreter.ast().size_since(mark), 0);
+        assert_eq!(interpreter.global("r"), Some(LoxValue::Number(1.0)));
+        assert_eq!(interpreter.call("one", &[]).unwrap(), LoxValue::Number(1.0));
+        interpreter.run("eval(\"print 1 +;\");");
+        assert_eq!(interpreter.ast().size_since(mark), 0);
+
+        // Functions are kept, and count towards max_memory
+        let options = InterpreterOptions {
+            max_memory: Some(1024 * 1024),
+            ..InterpreterOptions::default()
+        };
+        let mut interpreter = Interpreter::new(&reporter, options);
+        let outcome =
+            interpreter.run("for (var i = 0; i < 100000; i = i + 1) eval(\"fun f() {}\");");
+        assert!(outcome.diagnostics[0].message.contains("memory"));
+    }
+
+    #[test]
+    pub fn hosts_can_call_script_functions() {
+        let reporter = ErrorReporter::new();
+        let mut interpreter = Interpreter::new(&reporter, InterpreterOptions::default());
+        run(
+            &mut interpreter,
+            &reporter,
+            "fun add(a, b) { return a + b; }
+            class Point { init(x) { this.x = x; } }
+            fun fail() { return 1 / 0; }
+            var answer = 42;",
+        );
+        let sum = interpreter.call("add", &[1.0.into(), 2.0.into()]).unwrap();
+        assert_eq!(sum, LoxValue::Number(3.0));
+        let point = interpreter.call("Point", &[5.0.into()]).unwrap();
+        assert_eq!(point.to_string(), "Point instance");
+        assert_eq!(interpreter.global("answer"), Some(LoxValue::Number(42.0)));
+
+        assert!(matches!(
+            interpreter.call("add", &[1.0.into()]),
+            Err(RuntimeError::CallWrongNumberOfArgs)
+        ));
+        assert!(matches!(
+            interpreter.call("answer", &[]),
+            Err(RuntimeError::CallOnNonCallable)
+        ));
+        assert!(matches!(
+            interpreter.call("missing", &[]),
+            Err(RuntimeError::UndefinedVar(..))
+        ));
+        assert!(!reporter.had_runtime_error());
+
+        assert!(matches!(
+            interpreter.call("fail", &[]),
+            Err(RuntimeError::DivideByZero)
+        ));
+        assert!(reporter.take_errors()[0].contains("Attempted to divide by zero"));
+    }
+
+    #[test]
+    pub fn sandboxes_leave_out_capabilities() {
+        let reporter = ErrorReporter::new();
+        let mut interpreter = Interpreter::new(&reporter, InterpreterOptions::sandboxed());
+        assert_eq!(interpreter.global("heapObjects"), None);
+        // The clock only counts reads
+        assert_eq!(
+            interpreter.call("clock", &[]).unwrap(),
+            LoxValue::Number(1.0)
+        );
+
+        // Whichever of the step and time limits comes first
+        run(&mut interpreter, &reporter, "while (true) {}");
+        assert!(reporter.take_errors()[0].contains("Execution"));
+
+        let interpreter = Interpreter::new(&reporter, InterpreterOptions::default());
+        assert!(interpreter.global("heapObjects").is_some());
+    }
+
+    #[test]
+    pub fn sandboxes_stop_unbounded_recursion() {
+        let errors = std::thread::spawn(|| {
+            let src = "fun f(n) { return f(n + 1); } f(0);";
+            interpret(src, InterpreterOptions::sandboxed()).take_errors()
+        })
+        .join()
+        .unwrap();
+        assert_eq!(errors.len(), 1);
+        assert!(errors[0].contains("max call depth 1000 exceeded"));
+    }
+
+    #[test]
+    pub fn runaway_loops_time_out() {
+        let options = InterpreterOptions {
+            timeout: Some(Duration::from_millis(10)),
+            ..InterpreterOptions::default()
+        };
+        let diagnostics = interpret("var a = 1;\nwhile (true) {}", options).take_diagnostics();
+        // It can run out at the condition or at the body, so either is on line 2
+        assert_eq!(diagnostics[0].line, 2);
+        assert!(diagnostics[0].span.unwrap().start >= 11);
+        assert_eq!(diagnostics[0].code.as_deref(), Some("E0215"));
+    }
+
+    #[test]
+    pub fn runs_can_be_interrupted() {
+        let reporter = ErrorReporter::new();
+        let mut interpreter = Interpreter::new(&reporter, InterpreterOptions::default());
+        let interrupt = Arc::new(AtomicBool::new(false));
+        interpreter.set_interrupt(interrupt.clone());
+        let interrupter = std::thread::spawn(move || {
+            std::thread::sleep(Duration::from_millis(20));
+            interrupt.store(true, Ordering::Relaxed);
+        });
+        run(&mut interpreter, &reporter, "var a = 1; while (true) {}");
+        interrupter.join().unwrap();
+        let errors = reporter.take_errors();
+        assert_eq!(errors.len(), 1);
+        assert!(errors[0].contains("Interrupted"));
+
+        // The interpreter can still be used afterwards
+        reporter.reset();
+        run(&mut interpreter, &reporter, "a = a + 1;");
+        assert!(!reporter.had_runtime_error());
+    }
+
+    #[test]
+    pub fn runaway_allocation_runs_out_of_memory() {
+        let options = InterpreterOptions {
+            max_memory: Some(100_000),
+            ..InterpreterOptions::default()
+        };
+        let src = "var s = \"x\"; while (true) s = s + s;";
+        assert!(interpret(src, options.clone()).had_runtime_error());
+
+        let src = "class Node {} var list = nil;
+            while (true) { var node = Node(); node.next = list; list = node; }";
+        assert!(interpret(src, options.clone()).had_runtime_error());
+
+        // Outside of any call, it's shown where it ran out rather than at line 0
+        let src = "class Node {}\nvar list = nil;\nwhile (true) {\n  var node = Node();\n  node.next = list;\n  list = node;\n}";
+        let diagnostics = interpret(src, options.clone()).take_diagnostics();
+        assert_eq!(diagnostics[0].code.as_deref(), Some("E0216"));
+        assert_eq!(diagnostics[0].line, 5);
+        assert_eq!(diagnostics[0].span, Some(Span { start: 68, end: 85 }));
+
+        let src = "class Node {} for (var i = 0; i < 10000; i = i + 1) { var node = Node(); }";
+        assert!(!interpret(src, options).had_runtime_error());
+    }
+
+    #[test]
+    pub fn profiles_function_calls() {
+        let options = InterpreterOptions {
+            profile: true,
+            ..InterpreterOptions::default()
+        };
+        let reporter = ErrorReporter::new();
+        let src = "fun fib(n) { if (n < 2) return n; return fib(n - 1) + fib(n - 2); }
+            class A {} fib(10); A(); clock();";
+        let mut interpreter = Interpreter::new(&reporter, options);
+        run(&mut interpreter, &reporter, src);
+        let profiler = interpreter.profiler().unwrap();
+        let calls = |name: &str, line| {
+            let key = FunctionKey {
+                name: name.to_string(),
+                line,
+            };
+            profiler
+                .functions()
+                .find(|(k, _)| **k == key)
+                .map_or(0, |(_, p)| p.calls)
+        };
+        assert_eq!(calls("fib", Some(1)), 177);
+        assert_eq!(calls("A", Some(2)), 1);
+        assert_eq!(calls("clock", None), 1);
+    }
+
+    #[test]
+    pub fn counts_evaluations() {
+        let options = InterpreterOptions {
+            stats: true,
+            ..InterpreterOptions::default()
+        };
+        let reporter = ErrorReporter::new();
+        let src = "fun f(n) { if (n < 1) return 0; return f(n - 1); } f(3); print 1 + 2 + 3;";
+        let mut interpreter = Interpreter::new(&reporter, options);
+        run(&mut interpreter, &reporter, src);
+        let stats = interpreter.stats().unwrap();
+        assert_eq!(stats.calls, 4);
+        assert_eq!(stats.peak_call_depth, 4);
+        assert_eq!(stats.exprs["binary"], 9);
+        assert_eq!(stats.stmts["return"], 4);
+        assert!(interpreter.heap().stats().environments_created >= 4);
+    }
+
+    struct TraceLines(Rc<RefCell<Vec<String>>>);
+
+    impl TraceSink for TraceLines {
+        fn write_line(&mut self, line: &str) {
+            self.0.borrow_mut().push(line.to_string());
+        }
+    }
+
+    #[test]
+    pub fn traces_statements_and_variables() {
+        let reporter = ErrorReporter::new();
+        let lines = Rc::new(RefCell::new(Vec::new()));
+        let mut interpreter = Interpreter::new(&reporter, InterpreterOptions::default());
+        interpreter.set_trace_sink(Box::new(TraceLines(lines.clone())));
+        let src = "var a = \"x\"; fun f(b, c) { return a + b * c; } if (true) print f(1, 2);";
+        run(&mut interpreter, &reporter, src);
+        assert_eq!(
+            *lines.borrow(),
+            vec![
+                "var a = \"x\";",
+                "  -- a = \"x\"",
+                "fun f",
+                "if (true)",
+                "print f(1, 2);",
+                "  return a + b * c;",
+                "    -- a = \"x\", b = 1, c = 2",
+                "  -- f = <fn f(2)>",
+            ]
+        );
+    }
+
+    #[test]
+    pub fn resolution_survives_cloning_the_ast() {
+        let reporter = ErrorReporter::new();
+        let src = "var a = \"global\"; { var a = 1; fun f() { a = a + 1; return a; } f(); }";
+        let mut interpreter = Interpreter::new(&reporter, InterpreterOptions::default());
+        let stmts = parse(&mut interpreter, &reporter, src);
+        let resolved = Resolver::new(&interpreter.ast(), &reporter).resolve_stmts(&stmts);
+        interpreter.add_resolution(resolved);
+        let cloned = (*interpreter.ast()).clone();
+        *interpreter.ast_mut() = cloned;
+        interpreter.interpret(&stmts.clone());
+        assert!(!reporter.had_runtime_error());
+    }
+
+    #[test]
+    pub fn closures_ignore_later_shadowing() {
+        let reporter = ErrorReporter::new();
+        let src = "var a = \"global\"; var seen = \"\";
+            { fun show() { seen = seen + a; } show(); var a = \"block\"; show(); }";
+        let mut interpreter = Interpreter::new(&reporter, InterpreterOptions::default());
+        run(&mut interpreter, &reporter, src);
+        assert!(!reporter.had_runtime_error());
+        let seen = interpreter.globals.borrow().get(&Symbol::intern("seen"));
+        assert!(matches!(seen, Ok(LoxValue::String(s)) if &*s == "globalglobal"));
+    }
+
+    #[test]
+    pub fn definitions_outlive_their_source() {
+        let reporter = ErrorReporter::new();
+        let mut interpreter = Interpreter::new(&reporter, InterpreterOptions::default());
+        for src in &[
+            "var a = 1; fun f(x) { var y = x + a; return y; }",
+            "class C { m() { return f(2); } }",
+            "var b = C().m();",
+        ] {
+            run(&mut interpreter, &reporter, src);
+        }
+        assert!(!reporter.had_runtime_error());
+        let b = interpreter.globals.borrow().get(&Symbol::intern("b"));
+        assert!(matches!(b, Ok(LoxValue::Number(n)) if n == 3.0));
     }
 }
@@ -0,0 +1,82 @@
+//! A tree-walking interpreter for Lox, the language from Crafting Interpreters, along
+//! with the tooling the `rlox` command is built from.
+//!
+//! Running code goes through each stage in turn: the [`scanner`] turns source into
+//! tokens, the [`parser`] builds statements from them in an [`ast::Ast`], the
+//! [`resolver`] works out where each variable lives, and the [`interpreter`] runs the
+//! statements. Errors from every stage go to an [`errors::ErrorReporter`], which keeps
+//! them for the caller to print or inspect.
+//!
+//! ```
+//! use rlox::{
+//!     errors::ErrorReporter,
+//!     interpreter::{Interpreter, InterpreterOptions},
+//!     parser::Parser,
+//!     resolver::Resolver,
+//!     scanner::Scanner,
+//! };
+//!
+//! let reporter = ErrorReporter::new();
+//! let mut interpreter = Interpreter::new(&reporter, InterpreterOptions::default());
+//! let stmts = Parser::new(
+//!     Scanner::new("var answer = 6 * 7;", &reporter),
+//!     interpreter.ast_mut(),
+//!     &reporter,
+//! )
+//! .parse_stmts();
+//! let resolved = Resolver::new(&interpreter.ast(), &reporter).resolve_stmts(&stmts);
+//! interpreter.add_resolution(resolved);
+//! interpreter.interpret(&stmts);
+//! assert!(!reporter.had_error() && !reporter.had_runtime_error());
+//! ```
+//!
+//! [`interpreter::Interpreter::run`] does all of that, and hands back the errors along
+//! with the value of the last statement, if it's an expression.
+
+// So the natives macro's paths work inside the crate too
+extern crate self as rlox;
+
+pub use rlox_macros::lox_native;
+
+// The language
+pub mod ast;
+pub mod errors;
+pub mod explain;
+pub mod interpreter;
+pub mod loxvalue;
+pub mod parser;
+pub mod program;
+pub mod render;
+pub mod resolver;
+pub mod scanner;
+pub mod snapshot;
+pub mod symbol;
+pub mod tokens;
+pub mod worker;
+
+// Tooling built on it
+pub mod codegen;
+pub mod debugger;
+pub mod doc;
+pub mod formatter;
+pub mod highlight;
+pub mod index;
+pub mod lint;
+pub mod optimizer;
+pub mod typecheck;
+
+// For hosts in other languages
+#[cfg(feature = "capi")]
+pub mod capi;
+#[cfg(feature = "wasm")]
+pub mod wasm;
+
+// What the interpreter uses to run code, which embedders reach through it
+mod coverage;
+mod env;
+mod gc;
+mod natives;
+mod profiler;
+mod stats;
+mod tasks;
+mod trace;
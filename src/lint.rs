@@ -0,0 +1,399 @@
+use std::collections::{HashMap, HashSet};
+
+use serde::Deserialize;
+
+use crate::{
+    ast::{Ast, Expr, ExprId, FunctionStmt, Stmt, StmtId},
+    errors::{ErrorReporter, Warning},
+    resolver::Resolver,
+    tokens::{TokenLiteral, TokenType},
+};
+
+// What `rlox lint` checks for, named by their ids in its output and config file. Unused
+// locals, shadowing and unreachable code are found by the resolver, which warns about
+// all but shadowing on every run.
+#[derive(
+    Clone,
+    Copy,
+    Debug,
+    Eq,
+    Hash,
+    PartialEq,
+    strum_macros::Display,
+    strum_macros::EnumIter,
+    strum_macros::EnumString,
+)]
+#[strum(serialize_all = "kebab-case")]
+pub enum Rule {
+    // The shorter names are what the rlox command's -W flag has always taken
+    #[strum(to_string = "unused-variable", serialize = "unused")]
+    UnusedVariable,
+    #[strum(to_string = "shadowing", serialize = "shadow")]
+    Shadowing,
+    UnreachableCode,
+    EmptyBlock,
+    CompareToBool,
+    ConstantCondition,
+    LongFunction,
+}
+
+// What's done with a rule's warnings when they're reported: left out, reported as
+// warnings, or reported as errors, which stop the program from running
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum Level {
+    Allow,
+    Warn,
+    Deny,
+}
+
+pub const CONFIG_FILE: &str = "rlox-lint.toml";
+pub const DEFAULT_MAX_FUNCTION_LINES: usize = 50;
+
+#[derive(Debug, PartialEq)]
+pub struct Config {
+    disabled: HashSet<Rule>,
+    // Functions longer than this, from `fun` to their last statement, are warned about
+    max_function_lines: usize,
+}
+
+// The config file, as in:
+//   max-function-lines = 30
+//   [rules]
+//   shadowing = false
+#[derive(Deserialize)]
+#[serde(default, deny_unknown_fields, rename_all = "kebab-case")]
+struct ConfigFile {
+    rules: HashMap<String, bool>,
+    max_function_lines: usize,
+}
+
+impl Default for ConfigFile {
+    fn default() -> Self {
+        ConfigFile {
+            rules: HashMap::new(),
+            max_function_lines: DEFAULT_MAX_FUNCTION_LINES,
+        }
+    }
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Config {
+            disabled: HashSet::new(),
+            max_function_lines: DEFAULT_MAX_FUNCTION_LINES,
+        }
+    }
+}
+
+impl Config {
+    pub fn from_toml(src: &str) -> Result<Config, String> {
+        let file: ConfigFile = toml::from_str(src).map_err(|e| e.to_string())?;
+        let mut disabled = HashSet::new();
+        for (id, enabled) in file.rules {
+            let rule: Rule = id
+                .parse()
+                .map_err(|_| format!("Unknown lint rule '{}'", id))?;
+            if !enabled {
+                disabled.insert(rule);
+            }
+        }
+        Ok(Config {
+            disabled,
+            max_function_lines: file.max_function_lines,
+        })
+    }
+
+    pub fn enabled(&self, rule: Rule) -> bool {
+        !self.disabled.contains(&rule)
+    }
+}
+
+// Resolves the program to find what the resolver warns about, then looks for the rest.
+// Errors are left with the reporter, and the warnings for rules that are turned on are
+// returned in the order they appear. `source` is what the program was parsed from.
+pub fn lint(
+    source: &str,
+    ast: &Ast,
+    program: &[StmtId],
+    config: &Config,
+    error_reporter: &ErrorReporter,
+) -> Vec<Warning> {
+    Resolver::new(ast, error_reporter)
+        .warn_shadowing(config.enabled(Rule::Shadowing))
+        .resolve_stmts(program);
+    let linter = Linter {
+        source,
+        ast,
+        config,
+        error_reporter,
+    };
+    for stmt in program {
+        linter.stmt(*stmt);
+    }
+    let mut warnings: Vec<Warning> = error_reporter
+        .take_warnings()
+        .into_iter()
+        .filter(|warning| config.enabled(warning.rule))
+        .collect();
+    warnings.sort_by_key(|warning| (warning.line, warning.column));
+    warnings
+}
+
+struct Linter<'a> {
+    source: &'a str,
+    ast: &'a Ast,
+    config: &'a Config,
+    error_reporter: &'a ErrorReporter,
+}
+
+impl<'a> Linter<'a> {
+    fn stmt(&self, id: StmtId) {
+        match &self.ast[id] {
+            Stmt::Block(stmts) => {
+                if stmts.is_empty() {
+                    self.warn(Rule::EmptyBlock, self.ast.line(id), 0, "Empty block");
+                }
+                stmts.iter().for_each(|stmt| self.stmt(*stmt));
+            }
+            Stmt::Break(_) | Stmt::Debugger => {}
+            Stmt::Class(class) => {
+                class.superclass.iter().for_each(|expr| self.expr(*expr));
+                class
+                    .methods
+                    .iter()
+                    .for_each(|method| self.function(method));
+            }
+            Stmt::Expression(expr) | Stmt::Print(expr) => self.expr(*expr),
+            Stmt::Function(function) => self.function(function),
+            Stmt::If(e) => {
+                self.condition(e.condition);
+                self.expr(e.condition);
+                self.stmt(e.then_branch);
+                e.else_branch.iter().for_each(|stmt| self.stmt(*stmt));
+            }
+            Stmt::Return(r) => self.expr(r.value),
+            Stmt::While(e) => {
+                // `while (true)` is how to loop until a break, and what `for (;;)` becomes
+                if !matches!(self.constant(e.condition), Some(TokenLiteral::True)) {
+                    self.condition(e.condition);
+                }
+                self.expr(e.condition);
+                self.stmt(e.body);
+            }
+            Stmt::Var(v) => self.expr(v.initializer),
+            Stmt::Yield(y) => self.expr(y.value),
+        }
+    }
+
+    fn function(&self, function: &FunctionStmt) {
+        let last_line = function
+            .body
+            .iter()
+            .map(|stmt| self.last_line(*stmt))
+            .max()
+            .unwrap_or(function.name.line);
+        let lines = last_line + 1 - function.name.line;
+        if lines > self.config.max_function_lines {
+            let msg = format!(
+                "Function '{}' is {} lines long, more than {}",
+                function.name.lexeme, lines, self.config.max_function_lines
+            );
+            self.warn(
+                Rule::LongFunction,
+                function.name.line,
+                function.name.column,
+                &msg,
+            );
+        }
+        function.body.iter().for_each(|stmt| self.stmt(*stmt));
+    }
+
+    // The line the statement's last nested statement starts on
+    fn last_line(&self, id: StmtId) -> usize {
+        let last = match &self.ast[id] {
+            Stmt::Block(stmts) => stmts.iter().map(|stmt| self.last_line(*stmt)).max(),
+            Stmt::Class(class) => class
+                .methods
+                .iter()
+                .flat_map(|method| &method.body)
+                .map(|stmt| self.last_line(*stmt))
+                .max(),
+            Stmt::Function(function) => {
+                function.body.iter().map(|stmt| self.last_line(*stmt)).max()
+            }
+            Stmt::If(e) => Some(self.last_line(e.else_branch.unwrap_or(e.then_branch))),
+            Stmt::While(e) => Some(self.last_line(e.body)),
+            _ => None,
+        };
+        last.unwrap_or(0).max(self.ast.line(id))
+    }
+
+    fn expr(&self, id: ExprId) {
+        match &self.ast[id] {
+            Expr::Assign(e) => self.expr(e.value),
+            Expr::Binary(e) => {
+                match e.operator.token_type {
+                    TokenType::EqualEqual | TokenType::BangEqual => {
+                        let is_bool = |expr| {
+                            matches!(
+                                self.constant(expr),
+                                Some(TokenLiteral::True | TokenLiteral::False)
+                            )
+                        };
+                        if is_bool(e.left) || is_bool(e.right) {
+                            self.warn(
+                                Rule::CompareToBool,
+                                e.operator.line,
+                                e.operator.column,
+                                "Comparison with a boolean literal",
+                            );
+                        }
+                    }
+                    TokenType::QuestionMark => self.condition(e.left),
+                    _ => {}
+                }
+                self.expr(e.left);
+                self.expr(e.right);
+            }
+            Expr::Call(e) => {
+                self.expr(e.callee);
+                e.arguments.iter().for_each(|arg| self.expr(*arg));
+            }
+            Expr::Get(e) => self.expr(e.object),
+            Expr::Grouping(e) => self.expr(*e),
+            Expr::Logical(e) => {
+                self.expr(e.left);
+                self.expr(e.right);
+            }
+            Expr::Set(e) => {
+                self.expr(e.object);
+                self.expr(e.value);
+            }
+            Expr::Unary(e) => self.expr(e.right),
+            Expr::Literal(_) | Expr::Super(_) | Expr::This(_) | Expr::Variable(_) => {}
+        }
+    }
+
+    fn condition(&self, expr: ExprId) {
+        if let Some(literal) = self.constant(expr) {
+            let truthy = !matches!(literal, TokenLiteral::False | TokenLiteral::Nil);
+            let msg = format!("Condition is always {}", truthy);
+            let (line, column) = self.position(expr);
+            self.warn(Rule::ConstantCondition, line, column, &msg);
+        }
+    }
+
+    // Where the expression starts. Spans are in bytes, while columns count chars as the
+    // scanner does.
+    fn position(&self, expr: ExprId) -> (usize, usize) {
+        let before = &self.source[..self.ast.expr_span(expr).start];
+        let line = before.matches('\n').count() + 1;
+        let column = before.rsplit('\n').next().unwrap_or("").chars().count() + 1;
+        (line, column)
+    }
+
+    fn constant(&self, mut expr: ExprId) -> Option<&'a TokenLiteral> {
+        while let Expr::Grouping(inner) = &self.ast[expr] {
+            expr = *inner;
+        }
+        match &self.ast[expr] {
+            Expr::Literal(literal) => Some(literal),
+            _ => None,
+        }
+    }
+
+    fn warn(&self, rule: Rule, line: usize, column: usize, msg: &str) {
+        if self.config.enabled(rule) {
+            self.error_reporter.warning(rule, line, column, msg);
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::{parser::Parser, scanner::Scanner};
+
+    fn lint_src(src: &str, config: &Config) -> Vec<String> {
+        let reporter = ErrorReporter::new();
+        let mut ast = Ast::new();
+        let stmts = Parser::new(Scanner::new(src, &reporter), &mut ast, &reporter).parse_stmts();
+        let warnings = lint(src, &ast, &stmts, config, &reporter);
+        assert!(!reporter.had_error() && !reporter.had_runtime_error());
+        warnings
+            .iter()
+            .map(|w| format!("{} [{}]", w, w.rule))
+            .collect()
+    }
+
+    const SRC: &str = "fun f(a) {
+  var unused = 1;
+  {
+    var a = 2;
+    print a;
+  }
+  if (a == true) {}
+  if (nil) print 1;
+  while (true) print a ? 1 : 2;
+  while ((0)) {}
+  print \"x\" ? 1 : 2;
+  return;
+  print 3;
+}";
+
+    #[test]
+    pub fn reports_each_rule_with_its_id() {
+        assert_eq!(
+            lint_src(SRC, &Config::default()),
+            vec![
+                "[line 2:7] Warning: Unused local variable 'unused' [unused-variable]",
+                "[line 4:9] Warning: 'a' shadows the declaration on line 1 [shadowing]",
+                "[line 7] Warning: Empty block [empty-block]",
+                "[line 7:9] Warning: Comparison with a boolean literal [compare-to-bool]",
+                "[line 8:7] Warning: Condition is always false [constant-condition]",
+                "[line 10] Warning: Empty block [empty-block]",
+                "[line 10:10] Warning: Condition is always true [constant-condition]",
+                "[line 11:9] Warning: Condition is always true [constant-condition]",
+                "[line 13] Warning: Unreachable code [unreachable-code]",
+            ]
+        );
+    }
+
+    #[test]
+    pub fn constant_conditions_are_reported_where_they_are() {
+        assert_eq!(
+            lint_src(
+                "var a = 1;\nif (a and\n    \"é\") print a;\nwhile (\n  (nil)) print a;",
+                &Config::default()
+            ),
+            vec!["[line 5:3] Warning: Condition is always false [constant-condition]"]
+        );
+        assert_eq!(
+            lint_src("print \"é\"; print nil ? 1 : 2;", &Config::default()),
+            vec!["[line 1:18] Warning: Condition is always false [constant-condition]"]
+        );
+    }
+
+    #[test]
+    pub fn rules_can_be_turned_off_in_config() {
+        let config = Config::from_toml(
+            "max-function-lines = 10\n[rules]\nshadowing = false\nconstant-condition = false\nempty-block = true",
+        )
+        .unwrap();
+        assert_eq!(
+            lint_src(SRC, &config),
+            vec![
+                "[line 1:5] Warning: Function 'f' is 13 lines long, more than 10 [long-function]",
+                "[line 2:7] Warning: Unused local variable 'unused' [unused-variable]",
+                "[line 7] Warning: Empty block [empty-block]",
+                "[line 7:9] Warning: Comparison with a boolean literal [compare-to-bool]",
+                "[line 10] Warning: Empty block [empty-block]",
+                "[line 13] Warning: Unreachable code [unreachable-code]",
+            ]
+        );
+        assert_eq!(
+            Config::from_toml("[rules]\nno-such-rule = false"),
+            Err("Unknown lint rule 'no-such-rule'".to_string())
+        );
+    }
+}
@@ -1,84 +1,247 @@
+//! The values Lox code works with. Objects such as functions, classes and instances
+//! live on the interpreter's heap and are shared through [`LoxValue::Ref`].
+
 use core::panic;
-use std::{cell::RefCell, collections::HashMap, convert::TryFrom, fmt::Display, rc::Rc, sync::Arc};
+use std::{
+    any::Any,
+    cell::{Ref, RefCell, RefMut},
+    collections::{HashMap, VecDeque},
+    convert::TryFrom,
+    fmt::Display,
+    ops::{RangeFrom, RangeInclusive},
+    rc::Rc,
+    sync::Arc,
+};
 use thiserror::Error;
 
 use crate::{
-    ast::FunctionStmt,
+    ast::{ExprId, FunctionStmt, StmtId},
     env::Environment,
+    gc::{addr, Heap, Trace},
     interpreter::{Interpreter, RuntimeError},
-    tokens::TokenLiteral,
+    symbol::{self, Symbol},
+    tasks,
+    tokens::{Token, TokenLiteral},
 };
 
-#[derive(Clone, Debug, PartialEq)]
-pub enum LoxValue<'a> {
+#[derive(Clone, Debug)]
+pub enum LoxValue {
     Nil,
     Boolean(bool),
     Number(f64),
-    String(String),
-    Ref(Rc<RefCell<LoxRef<'a>>>),
+    // Strings are immutable, so copies of a value can all share one allocation
+    String(Arc<str>),
+    Ref(Rc<RefCell<LoxRef>>),
+}
+
+// What == means in Lox. Nil, booleans, numbers and strings are equal when their values
+// are. Everything else is a reference to an object, and is only equal to the same object:
+// two instances with the same fields, or two lists with the same items, aren't equal.
+// Functions are the same when they have the same definition and closure, so a function
+// is equal to itself wherever it's stored, but each time a method is looked up on an
+// instance it's bound anew.
+impl PartialEq for LoxValue {
+    fn eq(&self, other: &Self) -> bool {
+        match (self, other) {
+            (LoxValue::Nil, LoxValue::Nil) => true,
+            (LoxValue::Boolean(a), LoxValue::Boolean(b)) => a == b,
+            (LoxValue::Number(a), LoxValue::Number(b)) => a == b,
+            (LoxValue::String(a), LoxValue::String(b)) => a == b,
+            (LoxValue::Ref(a), LoxValue::Ref(b)) => {
+                Rc::ptr_eq(a, b)
+                    || matches!(
+                        (&*a.borrow(), &*b.borrow()),
+                        (LoxRef::Function(f), LoxRef::Function(g)) if f == g
+                    )
+            }
+            _ => false,
+        }
+    }
+}
+
+// How numbers are written when they're printed or added to strings
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum NumberFormat {
+    // As the book's interpreters write them: 2.0 is 2, and very large and very small
+    // values have exponents, as 1e21 and 1e-7
+    #[default]
+    Lox,
+    // As Rust writes an f64, with a decimal point even when it's whole
+    Raw,
 }
 
-impl<'a> Display for LoxValue<'a> {
+pub fn format_number(n: f64, format: NumberFormat) -> String {
+    match format {
+        NumberFormat::Raw => format!("{:?}", n),
+        NumberFormat::Lox if n.is_nan() => "nan".to_string(),
+        NumberFormat::Lox if n.is_infinite() || n == 0.0 => n.to_string(),
+        NumberFormat::Lox if !(1e-6..1e21).contains(&n.abs()) => format!("{:e}", n),
+        NumberFormat::Lox => n.to_string(),
+    }
+}
+
+impl LoxValue {
+    // Displays as it prints with numbers written in the format given
+    pub fn formatted(&self, numbers: NumberFormat) -> Formatted<'_> {
+        Formatted {
+            value: self,
+            numbers,
+        }
+    }
+}
+
+pub struct Formatted<'a> {
+    value: &'a LoxValue,
+    numbers: NumberFormat,
+}
+
+impl Display for Formatted<'_> {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        match self {
-            LoxValue::Nil => f.write_str("Nil"),
-            LoxValue::Boolean(b) => {
-                if *b {
-                    f.write_str("true")
-                } else {
-                    f.write_str("false")
-                }
+        write_value(self.value, f, self.numbers, &mut Vec::new())
+    }
+}
+
+impl Display for LoxValue {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        self.formatted(NumberFormat::Lox).fmt(f)
+    }
+}
+
+fn write_value(
+    value: &LoxValue,
+    f: &mut std::fmt::Formatter<'_>,
+    numbers: NumberFormat,
+    writing: &mut Vec<*const LoxRef>,
+) -> std::fmt::Result {
+    match value {
+        LoxValue::Nil => f.write_str("Nil"),
+        LoxValue::Boolean(b) => {
+            if *b {
+                f.write_str("true")
+            } else {
+                f.write_str("false")
             }
-            LoxValue::Ref(r) => r.borrow().fmt(f),
-            LoxValue::Number(n) => f.write_fmt(format_args!("{}", n)),
-            LoxValue::String(s) => f.write_str(&s),
         }
+        LoxValue::Ref(r) => r.borrow().write(f, numbers, writing),
+        LoxValue::Number(n) => f.write_str(&format_number(*n, numbers)),
+        LoxValue::String(s) => f.write_str(s),
     }
 }
 
 #[derive(Debug, PartialEq)]
-pub enum LoxRef<'a> {
-    Function(Function<'a>),
-    Class(LoxClass<'a>),
-    Instance(LoxInstance<'a>),
+pub enum LoxRef {
+    Function(Function),
+    Class(LoxClass),
+    Instance(LoxInstance),
+    List(Vec<LoxValue>),
+    Generator(Generator),
+    // Values sent but not yet received, oldest first
+    Channel(VecDeque<LoxValue>),
+    UserData(UserData),
 }
 
-impl<'a> Display for LoxRef<'a> {
+impl Display for LoxRef {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        self.write(f, NumberFormat::Lox, &mut Vec::new())
+    }
+}
+
+impl LoxRef {
+    // `writing` has the lists and instances this is being written inside of. One that's
+    // inside itself, through a field or an item, is written as ... the second time, rather
+    // than forever.
+    fn write(
+        &self,
+        f: &mut std::fmt::Formatter<'_>,
+        numbers: NumberFormat,
+        writing: &mut Vec<*const LoxRef>,
+    ) -> std::fmt::Result {
+        let this = self as *const LoxRef;
+        let inside_itself = writing.contains(&this);
         match self {
-            LoxRef::Function(_) => f.write_str("(function)"),
-            LoxRef::Class(c) => f.write_str(&c.name),
+            LoxRef::Function(function) => function.fmt(f),
+            LoxRef::Class(c) => c.fmt(f),
+            // With the alternate flag, instances are shown with their fields
+            LoxRef::Instance(inst) if f.alternate() => {
+                write!(f, "{} instance", inst.class_name())?;
+                if inside_itself {
+                    return f.write_str(" {...}");
+                }
+                let mut fields: Vec<_> = inst.fields.iter().collect();
+                fields.sort_by_key(|(name, _)| name.as_str());
+                writing.push(this);
+                for (i, (name, value)) in fields.into_iter().enumerate() {
+                    write!(f, "{}{}: ", if i == 0 { " { " } else { ", " }, name)?;
+                    write_value(value, f, numbers, writing)?;
+                }
+                writing.pop();
+                if !inst.fields.is_empty() {
+                    f.write_str(" }")?;
+                }
+                Ok(())
+            }
             LoxRef::Instance(inst) => {
                 f.write_str(&inst.class_name())?;
                 f.write_str(" instance")
             }
+            LoxRef::List(_) if inside_itself => f.write_str("[...]"),
+            LoxRef::List(items) => {
+                f.write_str("[")?;
+                writing.push(this);
+                for (i, item) in items.iter().enumerate() {
+                    if i > 0 {
+                        f.write_str(", ")?;
+                    }
+                    write_value(item, f, numbers, writing)?;
+                }
+                writing.pop();
+                f.write_str("]")
+            }
+            LoxRef::Generator(g) => write!(f, "{} generator", g.name),
+            LoxRef::Channel(_) => f.write_str("channel"),
+            LoxRef::UserData(u) => write!(f, "{} userdata", u.type_name()),
         }
     }
 }
 
-pub trait LoxCallable<'a> {
+pub trait LoxCallable {
+    // `paren` closes the call in the Lox code it's made from, which calls made by the
+    // host, or by the interpreter to run a task, don't have
     fn call(
         &self,
-        this: Option<Rc<RefCell<LoxRef<'a>>>>,
-        interpreter: &mut Interpreter<'_, 'a>,
-        args: &[LoxValue<'a>],
-    ) -> Result<LoxValue<'a>, RuntimeError<'a>>;
+        this: Option<Rc<RefCell<LoxRef>>>,
+        interpreter: &mut Interpreter<'_>,
+        paren: Option<&Token>,
+        args: &[LoxValue],
+    ) -> Result<LoxValue, RuntimeError>;
+
+    fn arity(&self) -> Arity;
+
+    fn name(&self) -> String;
+
+    // Natives aren't defined in any Lox code
+    fn line(&self) -> Option<usize>;
 
-    fn arity(&self) -> usize;
+    // What errors in calling it say was called, such as add() or Point.move(), and the
+    // line that's defined on
+    fn callee(&self) -> (String, Option<usize>) {
+        (format!("{}()", self.name()), self.line())
+    }
 }
 
 #[derive(Clone, Debug)]
-pub enum Function<'a> {
-    UserDefined(UserFunction<'a>),
-    Native(NativeFn<'a>),
+pub enum Function {
+    UserDefined(UserFunction),
+    Native(NativeFn),
+    Method(BuiltinMethod),
 }
 
-impl<'a> Function<'a> {
+impl Function {
     pub fn new_function(
-        declaration: &'a FunctionStmt,
-        closure: Rc<RefCell<Environment<'a>>>,
+        declaration: Arc<FunctionStmt>,
+        closure: Rc<RefCell<Environment>>,
         is_initializer: bool,
-    ) -> Function<'a> {
+    ) -> Function {
         Function::UserDefined(UserFunction {
             code: declaration,
             closure,
@@ -86,85 +249,158 @@ impl<'a> Function<'a> {
         })
     }
 
-    pub fn bind(&self, this_ref: Rc<RefCell<LoxRef<'a>>>) -> Function<'a> {
+    pub fn bind(&self, this_ref: Rc<RefCell<LoxRef>>, heap: &Heap) -> Function {
         match self {
-            Function::UserDefined(f) => Function::UserDefined(f.bind(this_ref)),
-            Function::Native(_) => self.clone(),
+            Function::UserDefined(f) => Function::UserDefined(f.bind(this_ref, heap)),
+            Function::Native(_) | Function::Method(_) => self.clone(),
         }
     }
 }
 
-impl<'a> LoxCallable<'a> for Function<'a> {
+impl Display for Function {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Function::UserDefined(_) => write!(f, "<fn {}({})>", self.name(), self.arity()),
+            Function::Native(_) | Function::Method(_) => write!(f, "<native fn {}>", self.name()),
+        }
+    }
+}
+
+impl LoxCallable for Function {
     fn call(
         &self,
-        _this: Option<Rc<RefCell<LoxRef<'a>>>>,
-        interpreter: &mut Interpreter<'_, 'a>,
-        args: &[LoxValue<'a>],
-    ) -> Result<LoxValue<'a>, RuntimeError<'a>> {
+        _this: Option<Rc<RefCell<LoxRef>>>,
+        interpreter: &mut Interpreter<'_>,
+        paren: Option<&Token>,
+        args: &[LoxValue],
+    ) -> Result<LoxValue, RuntimeError> {
         match &self {
-            Function::Native(nfn) => nfn.call(args),
+            Function::Native(nfn) => Function::call_native(nfn, interpreter, paren, args),
             Function::UserDefined(ufn) => ufn.call(interpreter, args),
+            Function::Method(m) => m.call(interpreter, paren, args),
         }
     }
 
-    fn arity(&self) -> usize {
+    fn arity(&self) -> Arity {
         match &self {
             Function::Native(nfn) => nfn.arity,
-            Function::UserDefined(f) => f.code.params.len(),
+            Function::UserDefined(f) => Arity::Exactly(f.code.params.len()),
+            Function::Method(m) => m.arity(),
+        }
+    }
+
+    fn name(&self) -> String {
+        match &self {
+            Function::Native(nfn) => nfn.name.clone(),
+            Function::UserDefined(f) => f.code.name.lexeme.clone(),
+            Function::Method(m) => m.kind.name().to_string(),
+        }
+    }
+
+    fn line(&self) -> Option<usize> {
+        match &self {
+            Function::Native(_) | Function::Method(_) => None,
+            Function::UserDefined(f) => Some(f.code.name.line),
+        }
+    }
+
+    fn callee(&self) -> (String, Option<usize>) {
+        match (&self, self.class_name()) {
+            (Function::UserDefined(f), Some(class)) => (
+                format!("{}.{}()", class, f.code.name.lexeme),
+                Some(f.code.name.line),
+            ),
+            _ => (format!("{}()", self.name()), self.line()),
         }
     }
 }
 
-impl<'a> Display for Function<'a> {
-    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        match self {
-            Function::UserDefined(fun) => {
-                f.write_str("fun ")?;
-                f.write_str(&fun.code.name.lexeme)
+impl Function {
+    fn call_native(
+        nfn: &NativeFn,
+        interpreter: &mut Interpreter<'_>,
+        paren: Option<&Token>,
+        args: &[LoxValue],
+    ) -> Result<LoxValue, RuntimeError> {
+        // Errors from Lox code the native called back into have been reported where they
+        // happened, so only the native's own are reported here
+        let reported = interpreter.errors_reported();
+        nfn.call(interpreter, paren, args).inspect_err(|e| {
+            if interpreter.errors_reported() == reported {
+                match paren {
+                    Some(paren) => interpreter.error_at(paren, e),
+                    None => interpreter.native_error(e),
+                }
             }
-            Function::Native(_) => f.write_str("<builtin function>"),
+        })
+    }
+
+    // The class of the instance a method is bound to
+    fn class_name(&self) -> Option<String> {
+        let this = match self {
+            Function::UserDefined(f) => f.closure.borrow().get_named(&symbol::THIS)?,
+            Function::Native(_) | Function::Method(_) => return None,
+        };
+        match &this {
+            LoxValue::Ref(r) => match &*r.borrow() {
+                LoxRef::Instance(instance) => Some(instance.class_name()),
+                _ => None,
+            },
+            _ => None,
         }
     }
 }
 
 #[derive(Clone, Debug)]
-pub struct UserFunction<'a> {
-    pub code: &'a FunctionStmt,
-    closure: Rc<RefCell<Environment<'a>>>,
+pub struct UserFunction {
+    pub code: Arc<FunctionStmt>,
+    closure: Rc<RefCell<Environment>>,
     is_initializer: bool,
 }
 
-impl<'a> UserFunction<'a> {
-    pub fn bind(&self, this_ref: Rc<RefCell<LoxRef<'a>>>) -> UserFunction<'a> {
+impl UserFunction {
+    pub fn closure(&self) -> &Rc<RefCell<Environment>> {
+        &self.closure
+    }
+
+    pub fn is_initializer(&self) -> bool {
+        self.is_initializer
+    }
+
+    pub fn bind(&self, this_ref: Rc<RefCell<LoxRef>>, heap: &Heap) -> UserFunction {
         let mut new_fun = self.clone();
-        new_fun.closure = Rc::new(RefCell::new(Environment::new(Some(self.closure.clone()))));
+        new_fun.closure = heap.alloc_env(Some(self.closure.clone()));
         new_fun
             .closure
             .borrow_mut()
-            .define("this", LoxValue::Ref(this_ref));
+            .define(symbol::THIS.clone(), LoxValue::Ref(this_ref));
         new_fun
     }
 
     pub fn call(
         &self,
-        interpreter: &mut Interpreter<'_, 'a>,
-        args: &[LoxValue<'a>],
-    ) -> Result<LoxValue<'a>, RuntimeError<'a>> {
-        let env = Rc::new(RefCell::new(Environment::new(Some(self.closure.clone()))));
+        interpreter: &mut Interpreter<'_>,
+        args: &[LoxValue],
+    ) -> Result<LoxValue, RuntimeError> {
+        let env = interpreter.heap().alloc_env(Some(self.closure.clone()));
         if args.len() != self.code.params.len() {
             return Err(RuntimeError::CallWrongNumberOfArgs);
         }
         for (i, arg) in args.iter().enumerate() {
             env.borrow_mut()
-                .define(&self.code.params[i].lexeme, arg.clone());
+                .define(self.code.params[i].symbol.clone(), arg.clone());
+        }
+        if self.code.is_generator {
+            let generator = Generator::new(self.code.name.lexeme.clone(), &self.code.body, env);
+            return Ok(LoxValue::Ref(
+                interpreter.heap().alloc(LoxRef::Generator(generator)),
+            ));
         }
         let result = interpreter.execute_block(&self.code.body, env);
 
         let return_this = || {
-            self.closure
-                .borrow()
-                .get_at(0, "this")
-                .expect("Initializers should have 'this' defined")
+            // 'this' is the only variable in the bound method's closure
+            self.closure.borrow().get_at(0, 0)
         };
         match (result, self.is_initializer) {
             (Err(RuntimeError::Return(val)), false) => Ok(val),
@@ -176,58 +412,444 @@ impl<'a> UserFunction<'a> {
     }
 }
 
-#[derive(Clone)]
-pub struct NativeFn<'a> {
-    pub arity: usize,
-    pub code: Arc<dyn Fn(&[LoxValue]) -> Result<LoxValue<'a>, RuntimeError<'a>>>,
+// Calling a generator function gives one of these, which runs the function's body a bit
+// at a time as next() is called on it
+#[derive(Debug)]
+pub struct Generator {
+    pub name: String,
+    pub state: GeneratorState,
 }
 
-impl<'a> NativeFn<'a> {
-    pub fn call(&self, args: &[LoxValue]) -> Result<LoxValue<'a>, RuntimeError<'a>> {
-        if args.len() != self.arity {
-            return Err(RuntimeError::CallWrongNumberOfArgs);
+#[derive(Debug)]
+pub enum GeneratorState {
+    // Stopped at a yield, or yet to start
+    Suspended(Vec<GeneratorFrame>),
+    Running,
+    Done,
+}
+
+// The statements a generator is part way through, innermost last. Only statements that
+// can contain a yield need a frame, see Interpreter::resume_generator.
+#[derive(Debug)]
+pub enum GeneratorFrame {
+    Block {
+        stmts: Vec<StmtId>,
+        next: usize,
+        env: Rc<RefCell<Environment>>,
+    },
+    Loop {
+        condition: ExprId,
+        body: StmtId,
+        env: Rc<RefCell<Environment>>,
+    },
+}
+
+impl Generator {
+    pub fn new(name: String, body: &[StmtId], env: Rc<RefCell<Environment>>) -> Generator {
+        Generator {
+            name,
+            state: GeneratorState::Suspended(vec![GeneratorFrame::Block {
+                stmts: body.to_vec(),
+                next: 0,
+                env,
+            }]),
         }
-        (self.code)(args)
+    }
+
+    pub fn is_done(&self) -> bool {
+        matches!(self.state, GeneratorState::Done)
+    }
+}
+
+impl PartialEq for Generator {
+    // Generators are only equal to themselves, which LoxValue checks for
+    fn eq(&self, _other: &Self) -> bool {
+        false
     }
 }
 
-impl<'a> std::fmt::Debug for NativeFn<'a> {
+#[derive(Clone, Debug, PartialEq)]
+pub enum MethodKind {
+    // Runs a generator up to its next yield, and gives the value yielded. Once the
+    // generator is done, gives whatever it returned, then nil.
+    Next,
+    // Whether a generator has finished
+    Done,
+    // Adds a value to a channel
+    Send,
+    // Takes the oldest value from a channel, running other tasks until there is one
+    Receive,
+    // One of the methods of a kind of userdata, which the host gave
+    Host(Symbol),
+}
+
+impl MethodKind {
+    fn name(&self) -> &str {
+        match self {
+            MethodKind::Next => "next",
+            MethodKind::Done => "done",
+            MethodKind::Send => "send",
+            MethodKind::Receive => "receive",
+            MethodKind::Host(name) => name.as_str(),
+        }
+    }
+}
+
+// A method on one of the builtin kinds of object, bound to the object it was looked up
+// on
+#[derive(Clone, Debug)]
+pub struct BuiltinMethod {
+    receiver: Rc<RefCell<LoxRef>>,
+    kind: MethodKind,
+}
+
+impl BuiltinMethod {
+    pub fn lookup(receiver: Rc<RefCell<LoxRef>>, name: &str) -> Option<Function> {
+        let kind = match (&*receiver.borrow(), name) {
+            (LoxRef::Generator(_), "next") => MethodKind::Next,
+            (LoxRef::Generator(_), "done") => MethodKind::Done,
+            (LoxRef::Channel(_), "send") => MethodKind::Send,
+            (LoxRef::Channel(_), "receive") => MethodKind::Receive,
+            (LoxRef::UserData(u), name) => {
+                let name = Symbol::lookup(name)?;
+                u.kind.methods.get(&name)?;
+                MethodKind::Host(name)
+            }
+            _ => return None,
+        };
+        Some(Function::Method(BuiltinMethod { receiver, kind }))
+    }
+
+    // The methods there are for the receiver
+    pub fn names(receiver: &LoxRef) -> Vec<Symbol> {
+        let names: &[&str] = match receiver {
+            LoxRef::Generator(_) => &["next", "done"],
+            LoxRef::Channel(_) => &["send", "receive"],
+            LoxRef::UserData(u) => return u.kind.method_names(),
+            _ => &[],
+        };
+        names.iter().map(|name| Symbol::intern(name)).collect()
+    }
+
+    fn arity(&self) -> Arity {
+        match &self.kind {
+            MethodKind::Send => Arity::Exactly(1),
+            MethodKind::Next | MethodKind::Done | MethodKind::Receive => Arity::Exactly(0),
+            MethodKind::Host(_) => self.host_method().map_or(Arity::Exactly(0), |m| m.arity),
+        }
+    }
+
+    fn host_method(&self) -> Option<NativeFn> {
+        match (&self.kind, &*self.receiver.borrow()) {
+            (MethodKind::Host(name), LoxRef::UserData(u)) => u.kind.methods.get(name).cloned(),
+            _ => None,
+        }
+    }
+
+    fn call(
+        &self,
+        interpreter: &mut Interpreter<'_>,
+        paren: Option<&Token>,
+        args: &[LoxValue],
+    ) -> Result<LoxValue, RuntimeError> {
+        // The receiver isn't borrowed while the host's method runs, so that the method can
+        // borrow it, as downcasting does
+        if let Some(method) = self.host_method() {
+            let mut with_receiver = Vec::with_capacity(args.len() + 1);
+            with_receiver.push(LoxValue::Ref(self.receiver.clone()));
+            with_receiver.extend_from_slice(args);
+            return Function::call_native(&method, interpreter, paren, &with_receiver);
+        }
+        match (&self.kind, &mut *self.receiver.borrow_mut()) {
+            (MethodKind::Done, LoxRef::Generator(g)) => return Ok(LoxValue::Boolean(g.is_done())),
+            (MethodKind::Send, LoxRef::Channel(items)) => {
+                items.push_back(args[0].clone());
+                return Ok(LoxValue::Nil);
+            }
+            _ => {}
+        }
+        // These run Lox code, which may use the receiver itself, so it mustn't be
+        // borrowed in the meantime
+        match &self.kind {
+            MethodKind::Next => interpreter.resume_generator(&self.receiver),
+            MethodKind::Receive => tasks::receive(interpreter, &self.receiver),
+            MethodKind::Done | MethodKind::Send | MethodKind::Host(_) => {
+                Err(RuntimeError::UnsupportedOperation)
+            }
+        }
+    }
+}
+
+// How many arguments a function takes. Only natives can take a variable number, and
+// `1..=3` or `1..` can be given for one that does.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Arity {
+    Exactly(usize),
+    AtLeast(usize),
+    // Both inclusive
+    Between(usize, usize),
+}
+
+impl Arity {
+    pub fn accepts(&self, args: usize) -> bool {
+        match *self {
+            Arity::Exactly(n) => args == n,
+            Arity::AtLeast(n) => args >= n,
+            Arity::Between(min, max) => (min..=max).contains(&args),
+        }
+    }
+
+    // The same, but with `n` more arguments, as async natives take a callback as well
+    pub fn plus(self, n: usize) -> Arity {
+        match self {
+            Arity::Exactly(m) => Arity::Exactly(m + n),
+            Arity::AtLeast(m) => Arity::AtLeast(m + n),
+            Arity::Between(min, max) => Arity::Between(min + n, max + n),
+        }
+    }
+}
+
+impl From<usize> for Arity {
+    fn from(n: usize) -> Self {
+        Arity::Exactly(n)
+    }
+}
+
+impl From<RangeInclusive<usize>> for Arity {
+    fn from(range: RangeInclusive<usize>) -> Self {
+        match range.into_inner() {
+            (min, max) if min == max => Arity::Exactly(min),
+            (min, max) => Arity::Between(min, max),
+        }
+    }
+}
+
+impl From<RangeFrom<usize>> for Arity {
+    fn from(range: RangeFrom<usize>) -> Self {
+        Arity::AtLeast(range.start)
+    }
+}
+
+impl Display for Arity {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Arity::Exactly(n) => write!(f, "{}", n),
+            Arity::AtLeast(n) => write!(f, "at least {}", n),
+            Arity::Between(min, max) => write!(f, "{} to {}", min, max),
+        }
+    }
+}
+
+// Natives are given the interpreter they're running in, to call back into Lox code or
+// read its options, and the closing paren of the call, for errors to point at, when it's
+// called from Lox code
+pub type NativeCode =
+    dyn Fn(&mut Interpreter<'_>, Option<&Token>, &[LoxValue]) -> Result<LoxValue, RuntimeError>;
+
+#[derive(Clone)]
+pub struct NativeFn {
+    pub name: String,
+    pub arity: Arity,
+    pub code: Arc<NativeCode>,
+}
+
+impl NativeFn {
+    pub fn call(
+        &self,
+        interpreter: &mut Interpreter<'_>,
+        paren: Option<&Token>,
+        args: &[LoxValue],
+    ) -> Result<LoxValue, RuntimeError> {
+        // The arity's checked by whoever calls it, as evaluate_call does, so the error can
+        // say what was called and where
+        (self.code)(interpreter, paren, args)
+    }
+}
+
+impl std::fmt::Debug for NativeFn {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         f.debug_struct("NativeFn")
+            .field("name", &self.name)
             .field("arity", &self.arity)
             .finish()
     }
 }
 
-impl<'a> PartialEq for Function<'a> {
-    // Two native functions are never equal. This might not be right long-term...
+// A Rust value the host has handed to scripts, such as a database handle or a game
+// entity. Scripts can't see inside it, and can only use it through the methods of its
+// type.
+pub struct UserData {
+    value: Box<dyn Any>,
+    kind: Rc<UserDataType>,
+}
+
+impl UserData {
+    pub fn new<T: Any>(value: T, kind: Rc<UserDataType>) -> Self {
+        UserData {
+            value: Box::new(value),
+            kind,
+        }
+    }
+
+    pub fn type_name(&self) -> &str {
+        &self.kind.name
+    }
+
+    pub fn downcast_ref<T: Any>(&self) -> Option<&T> {
+        self.value.downcast_ref()
+    }
+
+    pub fn downcast_mut<T: Any>(&mut self) -> Option<&mut T> {
+        self.value.downcast_mut()
+    }
+}
+
+impl std::fmt::Debug for UserData {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("UserData")
+            .field("type", &self.kind.name)
+            .finish_non_exhaustive()
+    }
+}
+
+impl PartialEq for UserData {
+    // Userdata is only equal to itself, which LoxValue checks for
     fn eq(&self, _other: &Self) -> bool {
         false
     }
 }
 
+// The name and methods shared by userdata of one kind. Each method is a native that's
+// given the userdata it was called on before the arguments its arity is for.
+#[derive(Debug)]
+pub struct UserDataType {
+    name: String,
+    methods: HashMap<Symbol, NativeFn>,
+}
+
+impl UserDataType {
+    pub fn new(name: &str) -> Self {
+        UserDataType {
+            name: name.to_string(),
+            methods: HashMap::new(),
+        }
+    }
+
+    pub fn with_method<E: Into<RuntimeError>>(
+        mut self,
+        name: &str,
+        arity: impl Into<Arity>,
+        code: impl Fn(&LoxValue, &[LoxValue]) -> Result<LoxValue, E> + 'static,
+    ) -> Self {
+        let method = NativeFn {
+            name: name.to_string(),
+            arity: arity.into(),
+            code: Arc::new(move |_, _, args| code(&args[0], &args[1..]).map_err(Into::into)),
+        };
+        self.methods.insert(Symbol::intern(name), method);
+        self
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    pub fn method_names(&self) -> Vec<Symbol> {
+        self.methods.keys().cloned().collect()
+    }
+}
+
+// Helpers for natives given userdata, to get at the Rust value inside. Each gives None
+// if the value isn't userdata holding a T, and keeps it borrowed until what it gives is
+// dropped.
+impl LoxValue {
+    pub fn user_data<T: Any>(&self) -> Option<Ref<'_, T>> {
+        match self {
+            LoxValue::Ref(r) => Ref::filter_map(r.borrow(), |r| match r {
+                LoxRef::UserData(u) => u.downcast_ref(),
+                _ => None,
+            })
+            .ok(),
+            _ => None,
+        }
+    }
+
+    pub fn user_data_mut<T: Any>(&self) -> Option<RefMut<'_, T>> {
+        match self {
+            LoxValue::Ref(r) => RefMut::filter_map(r.borrow_mut(), |r| match r {
+                LoxRef::UserData(u) => u.downcast_mut(),
+                _ => None,
+            })
+            .ok(),
+            _ => None,
+        }
+    }
+}
+
+impl PartialEq for Function {
+    fn eq(&self, other: &Self) -> bool {
+        match (self, other) {
+            (Function::UserDefined(f), Function::UserDefined(g)) => {
+                Arc::ptr_eq(&f.code, &g.code) && Rc::ptr_eq(&f.closure, &g.closure)
+            }
+            (Function::Native(f), Function::Native(g)) => Arc::ptr_eq(&f.code, &g.code),
+            (Function::Method(f), Function::Method(g)) => {
+                Rc::ptr_eq(&f.receiver, &g.receiver) && f.kind == g.kind
+            }
+            _ => false,
+        }
+    }
+}
+
 #[derive(Clone, Debug, PartialEq)]
-pub struct LoxClass<'a> {
+pub struct LoxClass {
     name: String,
-    superclass: Option<LoxValue<'a>>,
-    methods: HashMap<String, LoxValue<'a>>,
+    line: usize,
+    superclass: Option<LoxValue>,
+    methods: HashMap<Symbol, LoxValue>,
 }
 
-impl<'a> LoxClass<'a> {
+impl LoxClass {
     // NB probably should be safer and assert that all these LoxValues are actually functions/classes here.
     pub fn new(
         name: String,
-        superclass: Option<LoxValue<'a>>,
-        methods: HashMap<String, LoxValue<'a>>,
-    ) -> LoxClass<'a> {
+        line: usize,
+        superclass: Option<LoxValue>,
+        methods: HashMap<Symbol, LoxValue>,
+    ) -> LoxClass {
         LoxClass {
             name,
+            line,
             superclass,
             methods,
         }
     }
 
-    pub fn find_method(&self, name: &str) -> Option<LoxValue<'a>> {
+    pub fn line(&self) -> usize {
+        self.line
+    }
+
+    pub fn superclass(&self) -> Option<&LoxValue> {
+        self.superclass.as_ref()
+    }
+
+    // Only the class's own methods, not those it inherits
+    pub fn methods(&self) -> &HashMap<Symbol, LoxValue> {
+        &self.methods
+    }
+
+    // Including those it inherits
+    pub fn method_names(&self) -> Vec<Symbol> {
+        let mut names: Vec<Symbol> = self.methods.keys().cloned().collect();
+        if let Some(LoxValue::Ref(r)) = &self.superclass {
+            if let LoxRef::Class(sc) = &*r.borrow() {
+                names.extend(sc.method_names());
+            }
+        }
+        names
+    }
+
+    pub fn find_method(&self, name: &Symbol) -> Option<LoxValue> {
         if let Some(mthd) = self.methods.get(name) {
             return Some(mthd.clone());
         }
@@ -240,23 +862,56 @@ impl<'a> LoxClass<'a> {
     }
 }
 
-impl<'a> LoxCallable<'a> for LoxClass<'a> {
+// Just the name, or with the alternate flag, as `{:#}` does, what it inherits from and the
+// methods it defines with their arities
+impl Display for LoxClass {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        if !f.alternate() {
+            return f.write_str(&self.name);
+        }
+        write!(f, "<class {}", self.name)?;
+        if let Some(LoxValue::Ref(r)) = &self.superclass {
+            if let LoxRef::Class(sc) = &*r.borrow() {
+                write!(f, " < {}", sc.name)?;
+            }
+        }
+        let mut methods: Vec<_> = self.methods.iter().collect();
+        methods.sort_by_key(|(name, _)| name.as_str());
+        for (i, (name, method)) in methods.into_iter().enumerate() {
+            f.write_str(if i == 0 { " { " } else { ", " })?;
+            match method {
+                LoxValue::Ref(r) => match &*r.borrow() {
+                    LoxRef::Function(m) => write!(f, "{}({})", name, m.arity())?,
+                    _ => f.write_str(name.as_str())?,
+                },
+                _ => f.write_str(name.as_str())?,
+            }
+        }
+        if !self.methods.is_empty() {
+            f.write_str(" }")?;
+        }
+        f.write_str(">")
+    }
+}
+
+impl LoxCallable for LoxClass {
     fn call(
         &self,
-        this: Option<Rc<RefCell<LoxRef<'a>>>>,
-        interpreter: &mut Interpreter<'_, 'a>,
-        args: &[LoxValue<'a>],
-    ) -> Result<LoxValue<'a>, RuntimeError<'a>> {
+        this: Option<Rc<RefCell<LoxRef>>>,
+        interpreter: &mut Interpreter<'_>,
+        paren: Option<&Token>,
+        args: &[LoxValue],
+    ) -> Result<LoxValue, RuntimeError> {
         if let Some(this) = this {
             if let LoxRef::Class(_) = *this.borrow() {
-                let instance_ref = Rc::new(RefCell::new(LoxRef::Instance(LoxInstance::new(
-                    this.clone(),
-                ))));
-                if let Some(loxval) = self.find_method("init") {
+                let instance_ref = interpreter
+                    .heap()
+                    .alloc(LoxRef::Instance(LoxInstance::new(this.clone())));
+                if let Some(loxval) = self.find_method(&symbol::INIT) {
                     if let LoxValue::Ref(r) = loxval {
                         if let LoxRef::Function(f) = &*r.borrow() {
-                            let bound_f = f.bind(instance_ref.clone());
-                            bound_f.call(Some(this.clone()), interpreter, args)?;
+                            let bound_f = f.bind(instance_ref.clone(), interpreter.heap());
+                            bound_f.call(Some(this.clone()), interpreter, paren, args)?;
                             return Ok(LoxValue::Ref(instance_ref));
                         }
                     }
@@ -269,8 +924,8 @@ impl<'a> LoxCallable<'a> for LoxClass<'a> {
         panic!("Should have 'this' when calling a class object");
     }
 
-    fn arity(&self) -> usize {
-        if let Some(loxval) = self.find_method("init") {
+    fn arity(&self) -> Arity {
+        if let Some(loxval) = self.find_method(&symbol::INIT) {
             if let LoxValue::Ref(r) = loxval {
                 if let LoxRef::Function(f) = &*r.borrow() {
                     return f.arity();
@@ -278,16 +933,34 @@ impl<'a> LoxCallable<'a> for LoxClass<'a> {
             }
             panic!("Method is not a function");
         }
-        0
+        Arity::Exactly(0)
+    }
+
+    fn name(&self) -> String {
+        self.name.clone()
+    }
+
+    fn line(&self) -> Option<usize> {
+        Some(self.line)
+    }
+
+    // Its initializer is what takes the arguments, if it has one
+    fn callee(&self) -> (String, Option<usize>) {
+        if let Some(LoxValue::Ref(r)) = self.find_method(&symbol::INIT) {
+            if let LoxRef::Function(f) = &*r.borrow() {
+                return (format!("{}.init()", self.name), f.line());
+            }
+        }
+        (format!("{}()", self.name), Some(self.line))
     }
 }
 
 #[derive(Clone, Debug, PartialEq)]
-pub struct LoxInstance<'a> {
+pub struct LoxInstance {
     // Ugly that we don't strongly type this to LoxClass vs LoxRef here.
     // That's because we're taking the Rc<RefCell<>> from the LoxValue.
-    class: Rc<RefCell<LoxRef<'a>>>,
-    fields: HashMap<String, LoxValue<'a>>,
+    class: Rc<RefCell<LoxRef>>,
+    fields: HashMap<Symbol, LoxValue>,
 }
 
 #[derive(Debug, Error)]
@@ -296,13 +969,30 @@ pub enum LoxInstanceError {
     LookupError(String),
 }
 
-impl<'a> LoxInstance<'a> {
-    pub fn new(class: Rc<RefCell<LoxRef<'a>>>) -> LoxInstance<'a> {
+impl LoxInstance {
+    pub fn new(class: Rc<RefCell<LoxRef>>) -> LoxInstance {
         LoxInstance {
             class,
             fields: HashMap::new(),
         }
     }
+    pub fn class(&self) -> &Rc<RefCell<LoxRef>> {
+        &self.class
+    }
+
+    pub fn fields(&self) -> &HashMap<Symbol, LoxValue> {
+        &self.fields
+    }
+
+    // Its fields and its class's methods
+    pub fn property_names(&self) -> Vec<Symbol> {
+        let mut names: Vec<Symbol> = self.fields.keys().cloned().collect();
+        if let LoxRef::Class(c) = &*self.class.borrow() {
+            names.extend(c.method_names());
+        }
+        names
+    }
+
     pub fn class_name(&self) -> String {
         if let LoxRef::Class(c) = &*self.class.borrow() {
             c.name.clone()
@@ -311,11 +1001,12 @@ impl<'a> LoxInstance<'a> {
         }
     }
 
-    pub fn get<'b>(
+    pub fn get(
         &self,
-        self_ref: Rc<RefCell<LoxRef<'a>>>,
-        name: &'b str,
-    ) -> Result<LoxValue<'a>, LoxInstanceError> {
+        self_ref: Rc<RefCell<LoxRef>>,
+        name: &Symbol,
+        heap: &Heap,
+    ) -> Result<LoxValue, LoxInstanceError> {
         if let Some(val) = self.fields.get(name) {
             return Ok(val.clone());
         }
@@ -323,9 +1014,9 @@ impl<'a> LoxInstance<'a> {
         if let LoxRef::Class(c) = &*self.class.borrow() {
             if let Some(LoxValue::Ref(r)) = c.find_method(name) {
                 if let LoxRef::Function(f) = &*r.borrow() {
-                    return Ok(LoxValue::Ref(Rc::new(RefCell::new(LoxRef::Function(
-                        f.bind(self_ref),
-                    )))));
+                    return Ok(LoxValue::Ref(
+                        heap.alloc(LoxRef::Function(f.bind(self_ref, heap))),
+                    ));
                 }
             }
         }
@@ -333,14 +1024,255 @@ impl<'a> LoxInstance<'a> {
         Err(LoxInstanceError::LookupError(name.to_string()))
     }
 
-    pub fn set(&mut self, name: &str, value: LoxValue<'a>) {
-        self.fields.insert(name.to_string(), value);
+    pub fn set(&mut self, name: Symbol, value: LoxValue) {
+        self.fields.insert(name, value);
+    }
+}
+
+impl Trace for LoxValue {
+    fn trace(&self, visit: &mut dyn FnMut(usize)) {
+        if let LoxValue::Ref(r) = self {
+            visit(addr(r));
+        }
+    }
+
+    // Strings can be shared, in which case they're counted once for every value
+    fn size(&self) -> usize {
+        match self {
+            LoxValue::String(s) => std::mem::size_of::<LoxValue>() + s.len(),
+            _ => std::mem::size_of::<LoxValue>(),
+        }
+    }
+}
+
+impl Trace for LoxRef {
+    fn trace(&self, visit: &mut dyn FnMut(usize)) {
+        match self {
+            LoxRef::Function(Function::UserDefined(f)) => visit(addr(&f.closure)),
+            LoxRef::Function(Function::Native(_)) => {}
+            LoxRef::Function(Function::Method(m)) => visit(addr(&m.receiver)),
+            LoxRef::Class(c) => {
+                if let Some(sc) = &c.superclass {
+                    sc.trace(visit);
+                }
+                c.methods.values().for_each(|m| m.trace(visit));
+            }
+            LoxRef::Instance(i) => {
+                visit(addr(&i.class));
+                i.fields.values().for_each(|f| f.trace(visit));
+            }
+            LoxRef::List(items) => items.iter().for_each(|item| item.trace(visit)),
+            LoxRef::Channel(items) => items.iter().for_each(|item| item.trace(visit)),
+            // What's inside is the host's, so it can't refer to anything on the heap
+            LoxRef::UserData(_) => {}
+            LoxRef::Generator(g) => {
+                if let GeneratorState::Suspended(frames) = &g.state {
+                    for frame in frames {
+                        match frame {
+                            GeneratorFrame::Block { env, .. }
+                            | GeneratorFrame::Loop { env, .. } => visit(addr(env)),
+                        }
+                    }
+                }
+            }
+        }
+    }
+
+    fn size(&self) -> usize {
+        let entries = |values: &mut dyn Iterator<Item = &LoxValue>| -> usize {
+            values
+                .map(|v| std::mem::size_of::<Symbol>() + v.size())
+                .sum()
+        };
+        std::mem::size_of::<RefCell<LoxRef>>()
+            + match self {
+                LoxRef::Function(_) => 0,
+                LoxRef::Class(c) => c.name.len() + entries(&mut c.methods.values()),
+                LoxRef::Instance(i) => entries(&mut i.fields.values()),
+                LoxRef::List(items) => items.iter().map(|item| item.size()).sum(),
+                LoxRef::Channel(items) => items.iter().map(|item| item.size()).sum(),
+                LoxRef::UserData(u) => std::mem::size_of_val(&*u.value),
+                LoxRef::Generator(g) => match &g.state {
+                    GeneratorState::Suspended(frames) => {
+                        frames.len() * std::mem::size_of::<GeneratorFrame>()
+                    }
+                    GeneratorState::Running | GeneratorState::Done => 0,
+                },
+            }
+    }
+}
+
+// Conversions from Rust values. Lists and instances are made with the interpreter, as
+// they live on its heap.
+impl From<f64> for LoxValue {
+    fn from(n: f64) -> Self {
+        LoxValue::Number(n)
+    }
+}
+
+impl From<bool> for LoxValue {
+    fn from(b: bool) -> Self {
+        LoxValue::Boolean(b)
+    }
+}
+
+impl From<String> for LoxValue {
+    fn from(s: String) -> Self {
+        LoxValue::String(s.into())
+    }
+}
+
+impl From<&str> for LoxValue {
+    fn from(s: &str) -> Self {
+        LoxValue::String(s.into())
+    }
+}
+
+impl From<()> for LoxValue {
+    fn from(_: ()) -> Self {
+        LoxValue::Nil
+    }
+}
+
+impl<T: Into<LoxValue>> From<Option<T>> for LoxValue {
+    fn from(value: Option<T>) -> Self {
+        value.map_or(LoxValue::Nil, Into::into)
+    }
+}
+
+impl LoxValue {
+    // What kind of value this is, as said in errors
+    pub fn describe(&self) -> &'static str {
+        match self {
+            LoxValue::Nil => "nil",
+            LoxValue::Boolean(_) => "a boolean",
+            LoxValue::Number(_) => "a number",
+            LoxValue::String(_) => "a string",
+            LoxValue::Ref(r) => match &*r.borrow() {
+                LoxRef::Function(_) => "a function",
+                LoxRef::Class(_) => "a class",
+                LoxRef::Instance(_) => "an instance",
+                LoxRef::List(_) => "a list",
+                LoxRef::Generator(_) => "a generator",
+                LoxRef::Channel(_) => "a channel",
+                LoxRef::UserData(_) => "userdata",
+            },
+        }
+    }
+}
+
+// A value that isn't the kind the Rust type needs
+#[derive(Clone, Debug, Error, PartialEq)]
+#[error("Expected {expected} but got {found}")]
+pub struct ConversionError {
+    pub expected: &'static str,
+    pub found: &'static str,
+}
+
+impl ConversionError {
+    fn new(expected: &'static str, value: &LoxValue) -> Self {
+        ConversionError {
+            expected,
+            found: value.describe(),
+        }
+    }
+}
+
+impl From<ConversionError> for RuntimeError {
+    fn from(e: ConversionError) -> Self {
+        RuntimeError::NativeArgument(e.to_string())
+    }
+}
+
+// Conversions to Rust values
+impl TryFrom<&LoxValue> for f64 {
+    type Error = ConversionError;
+
+    fn try_from(value: &LoxValue) -> Result<Self, Self::Error> {
+        match value {
+            LoxValue::Number(n) => Ok(*n),
+            _ => Err(ConversionError::new("a number", value)),
+        }
+    }
+}
+
+impl TryFrom<&LoxValue> for bool {
+    type Error = ConversionError;
+
+    fn try_from(value: &LoxValue) -> Result<Self, Self::Error> {
+        match value {
+            LoxValue::Boolean(b) => Ok(*b),
+            _ => Err(ConversionError::new("a boolean", value)),
+        }
+    }
+}
+
+impl TryFrom<&LoxValue> for String {
+    type Error = ConversionError;
+
+    fn try_from(value: &LoxValue) -> Result<Self, Self::Error> {
+        match value {
+            LoxValue::String(s) => Ok(s.to_string()),
+            _ => Err(ConversionError::new("a string", value)),
+        }
+    }
+}
+
+// Nil is None
+impl<T> TryFrom<&LoxValue> for Option<T>
+where
+    T: for<'v> TryFrom<&'v LoxValue, Error = ConversionError>,
+{
+    type Error = ConversionError;
+
+    fn try_from(value: &LoxValue) -> Result<Self, Self::Error> {
+        match value {
+            LoxValue::Nil => Ok(None),
+            _ => T::try_from(value).map(Some),
+        }
+    }
+}
+
+impl<T> TryFrom<&LoxValue> for Vec<T>
+where
+    T: for<'v> TryFrom<&'v LoxValue, Error = ConversionError>,
+{
+    type Error = ConversionError;
+
+    fn try_from(value: &LoxValue) -> Result<Self, Self::Error> {
+        if let LoxValue::Ref(r) = value {
+            if let LoxRef::List(items) = &*r.borrow() {
+                return items.iter().map(T::try_from).collect();
+            }
+        }
+        Err(ConversionError::new("a list", value))
+    }
+}
+
+// An instance's fields, but not its methods
+impl<T> TryFrom<&LoxValue> for HashMap<String, T>
+where
+    T: for<'v> TryFrom<&'v LoxValue, Error = ConversionError>,
+{
+    type Error = ConversionError;
+
+    fn try_from(value: &LoxValue) -> Result<Self, Self::Error> {
+        if let LoxValue::Ref(r) = value {
+            if let LoxRef::Instance(instance) = &*r.borrow() {
+                return instance
+                    .fields
+                    .iter()
+                    .map(|(name, field)| Ok((name.to_string(), T::try_from(field)?)))
+                    .collect();
+            }
+        }
+        Err(ConversionError::new("an instance", value))
     }
 }
 
 pub struct LoxValueError {}
 
-impl<'a> TryFrom<&TokenLiteral> for LoxValue<'a> {
+impl TryFrom<&TokenLiteral> for LoxValue {
     type Error = LoxValueError;
 
     fn try_from(l: &TokenLiteral) -> Result<Self, Self::Error> {
@@ -356,3 +1288,111 @@ impl<'a> TryFrom<&TokenLiteral> for LoxValue<'a> {
         }
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    pub fn values_are_compact() {
+        // Values are copied around constantly, so keep them no bigger than a fat pointer
+        // and a tag
+        assert!(std::mem::size_of::<LoxValue>() <= 24);
+
+        let s = LoxValue::String("shared".into());
+        if let (LoxValue::String(a), LoxValue::String(b)) = (&s, &s.clone()) {
+            assert!(Arc::ptr_eq(a, b));
+        }
+    }
+
+    #[test]
+    pub fn formats_numbers_as_the_book_does() {
+        let lox = |n| format_number(n, NumberFormat::Lox);
+        assert_eq!(lox(2.0), "2");
+        assert_eq!(lox(-0.5), "-0.5");
+        assert_eq!(lox(-0.0), "-0");
+        assert_eq!(lox(123456789.0), "123456789");
+        assert_eq!(lox(1e21), "1e21");
+        assert_eq!(lox(-2.5e-7), "-2.5e-7");
+        assert_eq!(lox(f64::NAN), "nan");
+        assert_eq!(lox(f64::NEG_INFINITY), "-inf");
+        assert_eq!(format_number(2.0, NumberFormat::Raw), "2.0");
+
+        let list = LoxValue::Ref(Rc::new(RefCell::new(LoxRef::List(vec![
+            LoxValue::Number(1.0),
+            LoxValue::Number(0.25),
+        ]))));
+        assert_eq!(list.to_string(), "[1, 0.25]");
+        assert_eq!(list.formatted(NumberFormat::Raw).to_string(), "[1.0, 0.25]");
+    }
+
+    #[test]
+    pub fn writes_values_inside_themselves_once() {
+        let list = Rc::new(RefCell::new(LoxRef::List(vec![LoxValue::Number(1.0)])));
+        let inner = LoxValue::Ref(Rc::new(RefCell::new(LoxRef::List(vec![LoxValue::Ref(
+            list.clone(),
+        )]))));
+        if let LoxRef::List(items) = &mut *list.borrow_mut() {
+            items.push(inner.clone());
+            items.push(inner);
+        }
+        assert_eq!(
+            LoxValue::Ref(list.clone()).to_string(),
+            "[1, [[...]], [[...]]]"
+        );
+
+        let class = LoxClass::new("Node".to_string(), 1, None, HashMap::new());
+        let node = Rc::new(RefCell::new(LoxRef::Instance(LoxInstance::new(Rc::new(
+            RefCell::new(LoxRef::Class(class)),
+        )))));
+        if let LoxRef::Instance(inst) = &mut *node.borrow_mut() {
+            inst.fields
+                .insert(Symbol::intern("next"), LoxValue::Ref(node.clone()));
+            inst.fields
+                .insert(Symbol::intern("items"), LoxValue::Ref(list.clone()));
+        }
+        let node = LoxValue::Ref(node);
+        assert_eq!(node.to_string(), "Node instance");
+        assert_eq!(
+            format!("{:#}", node),
+            "Node instance { items: [1, [[...]], [[...]]], next: Node instance {...} }"
+        );
+    }
+
+    #[test]
+    pub fn converts_to_and_from_rust_values() {
+        assert_eq!(LoxValue::from(1.5), LoxValue::Number(1.5));
+        assert_eq!(LoxValue::from("hi"), LoxValue::String("hi".into()));
+        assert_eq!(LoxValue::from(None::<bool>), LoxValue::Nil);
+        assert_eq!(LoxValue::from(Some(true)), LoxValue::Boolean(true));
+        assert_eq!(f64::try_from(&LoxValue::Number(2.0)), Ok(2.0));
+        assert_eq!(Option::<String>::try_from(&LoxValue::Nil), Ok(None));
+        assert_eq!(
+            String::try_from(&LoxValue::Number(2.0)),
+            Err(ConversionError {
+                expected: "a string",
+                found: "a number"
+            })
+        );
+
+        let heap = Heap::new();
+        let list = LoxValue::Ref(heap.alloc(LoxRef::List(vec![1.0.into(), LoxValue::Nil])));
+        assert_eq!(
+            Vec::<Option<f64>>::try_from(&list),
+            Ok(vec![Some(1.0), None])
+        );
+        assert!(Vec::<f64>::try_from(&list).is_err());
+
+        let class = heap.alloc(LoxRef::Class(LoxClass::new(
+            "Point".to_string(),
+            1,
+            None,
+            HashMap::new(),
+        )));
+        let mut point = LoxInstance::new(class);
+        point.set(Symbol::intern("x"), 3.0.into());
+        let point = LoxValue::Ref(heap.alloc(LoxRef::Instance(point)));
+        let fields = HashMap::<String, f64>::try_from(&point).unwrap();
+        assert_eq!(fields.get("x"), Some(&3.0));
+    }
+}
@@ -0,0 +1,502 @@
+use std::{collections::HashMap, error::Error};
+
+use lsp_server::{Connection, ErrorCode, Message, Notification, Request, Response};
+use lsp_types::{
+    notification::{
+        DidChangeTextDocument, DidCloseTextDocument, DidOpenTextDocument, Notification as _,
+        PublishDiagnostics,
+    },
+    request::{DocumentSymbolRequest, GotoDefinition, HoverRequest, Request as _},
+    DiagnosticSeverity, DidChangeTextDocumentParams, DidCloseTextDocumentParams,
+    DidOpenTextDocumentParams, DocumentSymbol, DocumentSymbolParams, DocumentSymbolResponse,
+    GotoDefinitionParams, GotoDefinitionResponse, Hover, HoverContents, HoverParams,
+    HoverProviderCapability, Location, MarkupContent, MarkupKind, NumberOrString, OneOf, Position,
+    PublishDiagnosticsParams, Range, ServerCapabilities, SymbolKind, TextDocumentSyncCapability,
+    TextDocumentSyncKind, Url,
+};
+
+use rlox::{
+    ast::Ast,
+    errors::ErrorReporter,
+    index::{Declaration, DeclarationKind, Index},
+    parser::Parser,
+    resolver::Resolver,
+    scanner::Scanner,
+    tokens::Span,
+};
+
+type LspResult<T> = Result<T, Box<dyn Error + Send + Sync>>;
+
+// Serves the language server protocol over stdin and stdout until the editor shuts it
+// down. Documents are sent whole on each change and analyzed again from scratch.
+pub fn run() -> LspResult<()> {
+    let (connection, io_threads) = Connection::stdio();
+    let capabilities = ServerCapabilities {
+        text_document_sync: Some(TextDocumentSyncCapability::Kind(TextDocumentSyncKind::FULL)),
+        definition_provider: Some(OneOf::Left(true)),
+        hover_provider: Some(HoverProviderCapability::Simple(true)),
+        document_symbol_provider: Some(OneOf::Left(true)),
+        ..ServerCapabilities::default()
+    };
+    connection.initialize(serde_json::to_value(capabilities)?)?;
+    main_loop(&connection)?;
+    // The writer thread only finishes once the connection's sender is gone
+    drop(connection);
+    io_threads.join()?;
+    Ok(())
+}
+
+fn main_loop(connection: &Connection) -> LspResult<()> {
+    let mut documents: HashMap<Url, Document> = HashMap::new();
+    for message in &connection.receiver {
+        match message {
+            Message::Request(request) => {
+                if connection.handle_shutdown(&request)? {
+                    return Ok(());
+                }
+                let response = handle_request(&documents, request);
+                connection.sender.send(response.into())?;
+            }
+            Message::Notification(notification) => {
+                let (uri, text) = match notification.method.as_str() {
+                    DidOpenTextDocument::METHOD => {
+                        let params: DidOpenTextDocumentParams =
+                            serde_json::from_value(notification.params)?;
+                        (params.text_document.uri, Some(params.text_document.text))
+                    }
+                    DidChangeTextDocument::METHOD => {
+                        let params: DidChangeTextDocumentParams =
+                            serde_json::from_value(notification.params)?;
+                        let text = params.content_changes.into_iter().last().map(|c| c.text);
+                        (params.text_document.uri, text)
+                    }
+                    DidCloseTextDocument::METHOD => {
+                        let params: DidCloseTextDocumentParams =
+                            serde_json::from_value(notification.params)?;
+                        (params.text_document.uri, None)
+                    }
+                    _ => continue,
+                };
+                let diagnostics = match text {
+                    Some(text) => {
+                        let document = Document::new(text);
+                        let diagnostics = document.analysis.diagnostics.clone();
+                        documents.insert(uri.clone(), document);
+                        diagnostics
+                    }
+                    None => {
+                        documents.remove(&uri);
+                        Vec::new()
+                    }
+                };
+                let params = PublishDiagnosticsParams {
+                    uri,
+                    diagnostics,
+                    version: None,
+                };
+                let notification =
+                    Notification::new(PublishDiagnostics::METHOD.to_string(), params);
+                connection.sender.send(notification.into())?;
+            }
+            Message::Response(_) => {}
+        }
+    }
+    Ok(())
+}
+
+fn handle_request(documents: &HashMap<Url, Document>, request: Request) -> Response {
+    let id = request.id.clone();
+    let result = match request.method.as_str() {
+        GotoDefinition::METHOD => params::<GotoDefinitionParams>(request).map(|params| {
+            let (uri, position) = (
+                params.text_document_position_params.text_document.uri,
+                params.text_document_position_params.position,
+            );
+            let range = documents
+                .get(&uri)
+                .and_then(|document| document.definition(position));
+            let response =
+                range.map(|range| GotoDefinitionResponse::Scalar(Location { uri, range }));
+            serde_json::to_value(response)
+        }),
+        HoverRequest::METHOD => params::<HoverParams>(request).map(|params| {
+            let position = params.text_document_position_params;
+            let hover = documents
+                .get(&position.text_document.uri)
+                .and_then(|document| document.hover(position.position));
+            serde_json::to_value(hover)
+        }),
+        DocumentSymbolRequest::METHOD => params::<DocumentSymbolParams>(request).map(|params| {
+            let symbols = documents
+                .get(&params.text_document.uri)
+                .map(|document| DocumentSymbolResponse::Nested(document.analysis.symbols.clone()));
+            serde_json::to_value(symbols)
+        }),
+        method => {
+            let msg = format!("Unsupported request '{}'", method);
+            return Response::new_err(id, ErrorCode::MethodNotFound as i32, msg);
+        }
+    };
+    match result {
+        Ok(Ok(value)) => Response {
+            id,
+            result: Some(value),
+            error: None,
+        },
+        Ok(Err(e)) | Err(e) => {
+            Response::new_err(id, ErrorCode::InternalError as i32, e.to_string())
+        }
+    }
+}
+
+fn params<P: serde::de::DeserializeOwned>(request: Request) -> Result<P, serde_json::Error> {
+    serde_json::from_value(request.params)
+}
+
+struct Document {
+    text: String,
+    analysis: Analysis,
+}
+
+impl Document {
+    fn new(text: String) -> Self {
+        let analysis = analyze(&text);
+        Document { text, analysis }
+    }
+
+    // The declaration of the name under the cursor
+    fn declaration(&self, position: Position) -> Option<&Declaration> {
+        let offset = LineIndex::new(&self.text).offset(position);
+        self.analysis.index.declaration_at(offset)
+    }
+
+    fn definition(&self, position: Position) -> Option<Range> {
+        let declaration = self.declaration(position)?;
+        Some(LineIndex::new(&self.text).range(declaration.name.span))
+    }
+
+    fn hover(&self, position: Position) -> Option<Hover> {
+        let declaration = self.declaration(position)?;
+        Some(Hover {
+            contents: HoverContents::Markup(MarkupContent {
+                kind: MarkupKind::Markdown,
+                value: format!("```lox\n{}\n```", declaration.detail),
+            }),
+            range: None,
+        })
+    }
+}
+
+#[derive(Default)]
+struct Analysis {
+    diagnostics: Vec<lsp_types::Diagnostic>,
+    index: Index,
+    symbols: Vec<DocumentSymbol>,
+}
+
+fn analyze(text: &str) -> Analysis {
+    let reporter = ErrorReporter::new();
+    let mut ast = Ast::new();
+    let stmts = Parser::new(Scanner::new(text, &reporter), &mut ast, &reporter).parse_stmts();
+    let resolved = Resolver::new(&ast, &reporter)
+        .with_declarations()
+        .resolve_stmts(&stmts);
+
+    let lines = LineIndex::new(text);
+    let mut diagnostics: Vec<lsp_types::Diagnostic> = reporter
+        .take_diagnostics()
+        .into_iter()
+        .map(|error| lsp_types::Diagnostic {
+            range: match error.span {
+                Some(span) => lines.range(span),
+                None => lines.range_at(error.line, error.column),
+            },
+            severity: Some(DiagnosticSeverity::ERROR),
+            code: error.code.map(NumberOrString::String),
+            source: Some("rlox".to_string()),
+            message: error.message,
+            ..lsp_types::Diagnostic::default()
+        })
+        .collect();
+    diagnostics.extend(
+        reporter
+            .take_warnings()
+            .into_iter()
+            .map(|warning| lsp_types::Diagnostic {
+                range: lines.range_at(warning.line, warning.column),
+                severity: Some(DiagnosticSeverity::WARNING),
+                code: Some(NumberOrString::String(warning.rule.to_string())),
+                source: Some("rlox".to_string()),
+                message: warning.message,
+                ..lsp_types::Diagnostic::default()
+            }),
+    );
+
+    let index = Index::new(&ast, &stmts, &resolved);
+    let symbols = index
+        .top_level
+        .iter()
+        .map(|declaration| symbol(&index, *declaration, &lines))
+        .collect();
+    Analysis {
+        diagnostics,
+        index,
+        symbols,
+    }
+}
+
+// Parameters are left out, as editors list them with their functions anyway
+#[allow(deprecated)]
+fn symbol(index: &Index, declaration: usize, lines: &LineIndex) -> DocumentSymbol {
+    let declaration = &index.declarations[declaration];
+    let range = lines.range(declaration.name.span);
+    let children = declaration
+        .children
+        .iter()
+        .map(|child| symbol(index, *child, lines))
+        .collect();
+    DocumentSymbol {
+        name: declaration.name.lexeme.clone(),
+        detail: Some(declaration.detail.clone()),
+        kind: match declaration.kind {
+            DeclarationKind::Variable | DeclarationKind::Parameter => SymbolKind::VARIABLE,
+            DeclarationKind::Function => SymbolKind::FUNCTION,
+            DeclarationKind::Class => SymbolKind::CLASS,
+            DeclarationKind::Method => SymbolKind::METHOD,
+        },
+        tags: None,
+        deprecated: None,
+        range,
+        selection_range: range,
+        children: Some(children),
+    }
+}
+
+// Converts between byte offsets in the source and LSP positions, which count lines
+// from 0 and characters in UTF-16 code units
+struct LineIndex<'a> {
+    text: &'a str,
+    starts: Vec<usize>,
+}
+
+impl<'a> LineIndex<'a> {
+    fn new(text: &'a str) -> Self {
+        let mut starts = vec![0];
+        starts.extend(text.match_indices('\n').map(|(i, _)| i + 1));
+        LineIndex { text, starts }
+    }
+
+    fn line(&self, line: usize) -> &'a str {
+        let start = self.starts[line.min(self.starts.len() - 1)];
+        let end = self.text[start..]
+            .find('\n')
+            .map_or(self.text.len(), |i| start + i);
+        &self.text[start..end]
+    }
+
+    fn position(&self, offset: usize) -> Position {
+        let line = self.starts.partition_point(|start| *start <= offset) - 1;
+        let character = self.text[self.starts[line]..offset].encode_utf16().count();
+        Position::new(line as u32, character as u32)
+    }
+
+    fn offset(&self, position: Position) -> usize {
+        let line = position.line as usize;
+        if line >= self.starts.len() {
+            return self.text.len();
+        }
+        let mut units = 0;
+        for (i, c) in self.line(line).char_indices() {
+            if units >= position.character as usize {
+                return self.starts[line] + i;
+            }
+            units += c.len_utf16();
+        }
+        self.starts[line] + self.line(line).len()
+    }
+
+    fn range(&self, span: Span) -> Range {
+        Range::new(self.position(span.start), self.position(span.end))
+    }
+
+    // For errors given as a line and column, both from 1, with column 0 meaning the
+    // whole line. The range covers the word at the column.
+    fn range_at(&self, line: usize, column: usize) -> Range {
+        let line = line.saturating_sub(1).min(self.starts.len() - 1);
+        let start = self.starts[line];
+        let text = self.line(line);
+        if column == 0 {
+            return self.range(Span {
+                start,
+                end: start + text.len(),
+            });
+        }
+        let from = text
+            .char_indices()
+            .nth(column - 1)
+            .map_or(text.len(), |(i, _)| i);
+        let word = text[from..]
+            .find(|c: char| !(c.is_alphanumeric() || c == '_'))
+            .unwrap_or(text.len() - from);
+        let to = from + word.max(text[from..].chars().next().map_or(0, char::len_utf8));
+        self.range(Span {
+            start: start + from,
+            end: start + to,
+        })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use lsp_server::RequestId;
+
+    const SRC: &str = "var total = 0;
+fun add(a, b: Number) -> Number {
+  var sum = a + b;
+  return sum;
+}
+class Counter < Base {
+  bump() { total = add(total, 1); }
+}
+{ var ünïcode = 1; print ünïcode; }
+print undefined;
+";
+
+    fn at(line: u32, character: u32) -> Position {
+        Position::new(line, character)
+    }
+
+    #[test]
+    pub fn finds_definitions_through_the_resolver() {
+        let document = Document::new(SRC.to_string());
+        // `sum` in the return, and `b` in the addition
+        assert_eq!(
+            document.definition(at(3, 10)),
+            Some(Range::new(at(2, 6), at(2, 9)))
+        );
+        assert_eq!(
+            document.definition(at(2, 16)),
+            Some(Range::new(at(1, 11), at(1, 12)))
+        );
+        // Globals used inside a method
+        assert_eq!(
+            document.definition(at(6, 11)),
+            Some(Range::new(at(0, 4), at(0, 9)))
+        );
+        assert_eq!(
+            document.definition(at(6, 19)),
+            Some(Range::new(at(1, 4), at(1, 7)))
+        );
+        // Positions count UTF-16 units, not bytes
+        assert_eq!(
+            document.definition(at(8, 27)),
+            Some(Range::new(at(8, 6), at(8, 13)))
+        );
+        assert_eq!(document.definition(at(9, 8)), None);
+    }
+
+    #[test]
+    pub fn hovers_with_the_declaration() {
+        let document = Document::new(SRC.to_string());
+        let hover = |position| match document.hover(position).map(|h| h.contents) {
+            Some(HoverContents::Markup(markup)) => markup.value,
+            other => panic!("Unexpected hover {:?}", other),
+        };
+        assert_eq!(
+            hover(at(6, 20)),
+            "```lox\nfun add(a, b: Number) -> Number\n```"
+        );
+        assert_eq!(hover(at(2, 17)), "```lox\nparameter b: Number\n```");
+        assert_eq!(hover(at(5, 8)), "```lox\nclass Counter < Base\n```");
+    }
+
+    #[test]
+    pub fn lists_symbols_and_diagnostics() {
+        let analysis = analyze(SRC);
+        let names: Vec<(String, Vec<String>)> = analysis
+            .symbols
+            .iter()
+            .map(|symbol| {
+                let children = symbol.children.iter().flatten();
+                (
+                    symbol.name.clone(),
+                    children.map(|child| child.name.clone()).collect(),
+                )
+            })
+            .collect();
+        assert_eq!(
+            names,
+            vec![
+                ("total".to_string(), vec![]),
+                ("add".to_string(), vec!["sum".to_string()]),
+                ("Counter".to_string(), vec!["bump".to_string()]),
+                ("ünïcode".to_string(), vec![]),
+            ]
+        );
+
+        let analysis = analyze("{ var unused = 1; }\nprint (1;\n");
+        let diagnostics: Vec<(Range, String)> = analysis
+            .diagnostics
+            .into_iter()
+            .map(|d| (d.range, d.message))
+            .collect();
+        assert_eq!(
+            diagnostics,
+            vec![
+                (
+                    Range::new(at(1, 8), at(1, 9)),
+                    "Expect ')' after expression".to_string()
+                ),
+                (
+                    Range::new(at(0, 6), at(0, 12)),
+                    "Unused local variable 'unused'".to_string()
+                ),
+            ]
+        );
+    }
+
+    #[test]
+    pub fn serves_requests_over_a_connection() {
+        let (server, client) = Connection::memory();
+        let thread = std::thread::spawn(move || main_loop(&server).unwrap());
+        let uri = Url::parse("file:///test.lox").unwrap();
+        let open = serde_json::json!({
+            "textDocument": {"uri": uri, "languageId": "lox", "version": 1, "text": SRC}
+        });
+        client
+            .sender
+            .send(Notification::new(DidOpenTextDocument::METHOD.to_string(), open).into())
+            .unwrap();
+        match client.receiver.recv().unwrap() {
+            Message::Notification(n) => assert_eq!(n.method, PublishDiagnostics::METHOD),
+            other => panic!("Expected diagnostics, got {:?}", other),
+        }
+
+        let params = serde_json::json!({
+            "textDocument": {"uri": uri},
+            "position": {"line": 3, "character": 10}
+        });
+        let request = Request::new(
+            RequestId::from(1),
+            GotoDefinition::METHOD.to_string(),
+            params,
+        );
+        client.sender.send(request.into()).unwrap();
+        match client.receiver.recv().unwrap() {
+            Message::Response(response) => assert_eq!(
+                response.result.unwrap()["range"]["start"],
+                serde_json::json!({"line": 2, "character": 6})
+            ),
+            other => panic!("Expected a response, got {:?}", other),
+        }
+
+        let shutdown = Request::new(RequestId::from(2), "shutdown".to_string(), ());
+        client.sender.send(shutdown.into()).unwrap();
+        client
+            .sender
+            .send(Notification::new("exit".to_string(), ()).into())
+            .unwrap();
+        thread.join().unwrap();
+    }
+}
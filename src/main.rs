@@ -1,207 +1,1108 @@
-use std::collections::LinkedList;
+use std::collections::HashMap;
 use std::io;
-use std::io::BufRead;
-use std::io::Write;
+use std::io::{Read, Write};
+use std::time::Duration;
 
-use clap::{App, Arg};
+use clap::{value_t, App, Arg, SubCommand};
+use notify::Watcher;
+use strum::IntoEnumIterator;
 
-mod ast;
-mod env;
-mod interpreter;
-mod loxvalue;
-mod parser;
-mod resolver;
-mod scanner;
-mod tokens;
+mod bench;
+mod lsp;
+mod repl;
+mod testing;
+
+use rlox::{
+    ast, codegen, debugger, doc, errors, explain, formatter, highlight, interpreter, lint,
+    loxvalue, optimizer, parser, resolver, scanner, typecheck,
+};
 
 use scanner::Scanner;
-use tokens::Token;
 
 use crate::ast::PrettyPrinter;
+use crate::interpreter::InterpreterOptions;
+use crate::loxvalue::NumberFormat;
+use rlox::render::{ColorChoice, ErrorFormat};
 
-mod errors {
-    use crate::tokens::{Token, TokenType};
-    use std::{
-        cell::RefCell,
-        sync::{Arc, Mutex},
-    };
+// Lox calls grow the stack as they need, but parsing and the passes over the AST recurse
+// as deeply as code is nested, which --max-parse-depth can allow a lot of. They run on a
+// thread with plenty of room, even in debug builds.
+const INTERPRETER_STACK_SIZE: usize = 256 * 1024 * 1024;
+
+struct Options {
+    verbose: bool,
+    optimize: bool,
+    // Where to write coverage in lcov format, rather than printing a listing
+    lcov: Option<String>,
+    max_parse_depth: usize,
+    // Errors in the source to report before giving up, if limited
+    max_errors: Option<usize>,
+    // Levels given to lint rules with -W, -D and -A, for the reporter
+    lint_levels: HashMap<lint::Rule, lint::Level>,
+    // Whether using a global that's never declared is an error before running
+    strict: bool,
+    typecheck: bool,
+    // Whether errors show the line of source they're on
+    snippets: bool,
+    color: ColorChoice,
+    error_format: ErrorFormat,
+    interpreter: InterpreterOptions,
+}
 
-    pub struct ErrorReporter {
-        errors_collected: Arc<Mutex<Vec<String>>>,
-        had_error: RefCell<bool>,
-        had_runtime_error: RefCell<bool>,
+fn main() {
+    let interpreter_thread = std::thread::Builder::new()
+        .stack_size(INTERPRETER_STACK_SIZE)
+        .spawn(run_main)
+        .expect("Could not start interpreter thread");
+    if interpreter_thread.join().is_err() {
+        std::process::exit(101);
     }
+}
 
-    impl ErrorReporter {
-        pub fn new() -> ErrorReporter {
-            ErrorReporter {
-                errors_collected: Arc::new(Mutex::new(Vec::new())),
-                had_error: RefCell::new(false),
-                had_runtime_error: RefCell::new(false),
-            }
-        }
+fn run_main() {
+    let matches = App::new("rlox")
+        .version("0.1")
+        .arg(
+            Arg::with_name("verbose")
+                .short("V")
+                .long("verbose")
+                .help("Verbose output"),
+        )
+        .arg(
+            Arg::with_name("optimize")
+                .short("O")
+                .help("Fold constants and remove dead code before running"),
+        )
+        .arg(
+            Arg::with_name("max-call-depth")
+                .long("max-call-depth")
+                .value_name("N")
+                .help("Maximum depth of nested function calls before a stack overflow error"),
+        )
+        .arg(
+            Arg::with_name("max-expr-depth")
+                .long("max-expr-depth")
+                .value_name("N")
+                .help("Maximum nesting depth of an expression within a function call"),
+        )
+        .arg(
+            Arg::with_name("max-parse-depth")
+                .long("max-parse-depth")
+                .value_name("N")
+                .help("Maximum nesting depth of statements, blocks and expressions in the source"),
+        )
+        .arg(
+            Arg::with_name("max-errors")
+                .long("max-errors")
+                .value_name("N")
+                .default_value("20")
+                .help("Stop after N errors in the source, or 0 to report them all"),
+        )
+        .arg(
+            Arg::with_name("max-steps")
+                .long("max-steps")
+                .value_name("N")
+                .help("Maximum number of statements and expressions to evaluate per run"),
+        )
+        .arg(
+            Arg::with_name("timeout")
+                .long("timeout")
+                .value_name("DURATION")
+                .validator(|d| parse_duration(&d).map(|_| ()))
+                .help("Abort a run that takes longer than this, e.g. 5s or 500ms"),
+        )
+        .arg(
+            Arg::with_name("max-memory")
+                .long("max-memory")
+                .value_name("SIZE")
+                .validator(|s| parse_size(&s).map(|_| ()))
+                .help("Approximate limit on memory used by Lox values, e.g. 64M"),
+        )
+        .arg(
+            Arg::with_name("deterministic")
+                .long("deterministic")
+                .help("Make clock() and random() give the same results every run"),
+        )
+        .arg(
+            Arg::with_name("raw-numbers")
+                .long("raw-numbers")
+                .help("Print numbers as Rust writes an f64, so 2 prints as 2.0"),
+        )
+        .arg(
+            Arg::with_name("seed")
+                .long("seed")
+                .value_name("N")
+                .help("Seed for random()"),
+        )
+        .arg(
+            Arg::with_name("profile")
+                .long("profile")
+                .help("Print how long was spent in each function after running"),
+        )
+        .arg(
+            Arg::with_name("trace")
+                .long("trace")
+                .help("Print each statement and the variables it uses as it runs"),
+        )
+        .arg(
+            Arg::with_name("eval")
+                .short("e")
+                .value_name("CODE")
+                .conflicts_with("FILE")
+                .help("Run CODE instead of a script, printing its value if it's an expression"),
+        )
+        .arg(
+            Arg::with_name("interactive")
+                .short("i")
+                .requires("FILE")
+                .help("Run a script, then start a REPL where what it defined can be used"),
+        )
+        .arg(
+            Arg::with_name("check")
+                .long("check")
+                .requires("FILE")
+                .help("Report errors in a script without running it"),
+        )
+        .arg(
+            Arg::with_name("dump-tokens")
+                .long("dump-tokens")
+                .value_name("FORMAT")
+                .possible_values(&["text", "json"])
+                .min_values(0)
+                .require_equals(true)
+                .requires("FILE")
+                .help("Print the tokens in a script instead of running it, as text or JSON lines"),
+        )
+        .arg(
+            Arg::with_name("dump-ast")
+                .long("dump-ast")
+                .requires("FILE")
+                .help("Print the parsed script as JSON instead of running it"),
+        )
+        .arg(
+            Arg::with_name("target")
+                .long("target")
+                .value_name("LANGUAGE")
+                .possible_values(&["rust"])
+                .require_equals(true)
+                .requires("FILE")
+                .help("Print the script transpiled to another language instead of running it"),
+        )
+        .arg(
+            Arg::with_name("warn")
+                .short("W")
+                .value_name("WARNING")
+                .validator(|name| lint_rules(&name).map(|_| ()))
+                .multiple(true)
+                .number_of_values(1)
+                .help("Warn about a lint rule, such as shadow, which is off by default, or all"),
+        )
+        .arg(
+            Arg::with_name("deny")
+                .short("D")
+                .value_name("WARNING")
+                .validator(|name| lint_rules(&name).map(|_| ()))
+                .multiple(true)
+                .number_of_values(1)
+                .help("Make a lint rule's warnings errors, which stop the script running"),
+        )
+        .arg(
+            Arg::with_name("allow")
+                .short("A")
+                .value_name("WARNING")
+                .validator(|name| lint_rules(&name).map(|_| ()))
+                .multiple(true)
+                .number_of_values(1)
+                .help("Leave out a lint rule's warnings"),
+        )
+        .arg(
+            Arg::with_name("strict")
+                .long("strict")
+                .help("Report uses of globals that are never declared before running"),
+        )
+        .arg(
+            Arg::with_name("typecheck")
+                .long("typecheck")
+                .help("Check values against type annotations before running"),
+        )
+        .arg(
+            Arg::with_name("color")
+                .long("color")
+                .value_name("WHEN")
+                .possible_values(&["always", "never", "auto"])
+                .default_value("auto")
+                .require_equals(true)
+                .help("Colour errors and warnings, or only when printing to a terminal"),
+        )
+        .arg(
+            Arg::with_name("error-format")
+                .long("error-format")
+                .value_name("FORMAT")
+                .possible_values(&["human", "json"])
+                .default_value("human")
+                .require_equals(true)
+                .help("Print errors and warnings for people, or as JSON lines on stderr"),
+        )
+        .arg(
+            Arg::with_name("explain")
+                .long("explain")
+                .value_name("CODE")
+                .conflicts_with("FILE")
+                .help("Describe the error with a code, such as E0210, and show an example"),
+        )
+        .arg(
+            Arg::with_name("no-snippets")
+                .long("no-snippets")
+                .help("Print errors without the line of source they're on"),
+        )
+        .arg(
+            Arg::with_name("keep-going")
+                .long("keep-going")
+                .help("Carry on with the next top-level statement after a runtime error"),
+        )
+        .arg(
+            Arg::with_name("stats")
+                .long("stats")
+                .help("Print counts of what the interpreter did after running"),
+        )
+        .arg(
+            Arg::with_name("coverage")
+                .long("coverage")
+                .help("Print the script with how many times each line ran after running"),
+        )
+        .arg(
+            Arg::with_name("lcov")
+                .long("lcov")
+                .value_name("FILE")
+                .help("Write which lines ran to FILE in lcov format"),
+        )
+        .arg(Arg::with_name("FILE").help("Script to run, or - to read it from stdin"))
+        .subcommand(
+            SubCommand::with_name("bench")
+                .about("Time repeated runs of a script")
+                .arg(
+                    Arg::with_name("iterations")
+                        .long("iterations")
+                        .short("n")
+                        .value_name("N")
+                        .default_value("10")
+                        .help("How many times to run the script with each engine"),
+                )
+                .arg(
+                    Arg::with_name("compare")
+                        .long("compare")
+                        .help("Run it with and without the optimizer, side by side"),
+                )
+                .arg(Arg::with_name("FILE").required(true)),
+        )
+        .subcommand(
+            SubCommand::with_name("debug")
+                .about("Run a script under the interactive debugger")
+                .arg(Arg::with_name("FILE").required(true)),
+        )
+        .subcommand(
+            SubCommand::with_name("doc")
+                .about("Print the functions and classes scripts declare, with their doc comments")
+                .arg(
+                    Arg::with_name("format")
+                        .long("format")
+                        .value_name("FORMAT")
+                        .possible_values(&["markdown", "html"])
+                        .default_value("markdown")
+                        .help("Write the listing as Markdown or HTML"),
+                )
+                .arg(Arg::with_name("FILE").required(true).multiple(true)),
+        )
+        .subcommand(
+            SubCommand::with_name("fmt")
+                .about("Format a script in place, or print it formatted if FILE is -")
+                .arg(
+                    Arg::with_name("check")
+                        .long("check")
+                        .help("Report whether the script is formatted without changing it"),
+                )
+                .arg(Arg::with_name("FILE").required(true)),
+        )
+        .subcommand(
+            SubCommand::with_name("highlight")
+                .about("Print a script with syntax highlighting, for a terminal or a web page")
+                .arg(
+                    Arg::with_name("format")
+                        .long("format")
+                        .value_name("FORMAT")
+                        .possible_values(&["ansi", "html"])
+                        .default_value("ansi")
+                        .help("Colour with terminal escape codes, or mark up as HTML"),
+                )
+                .arg(Arg::with_name("FILE").required(true)),
+        )
+        .subcommand(
+            SubCommand::with_name("lint")
+                .about("Report likely mistakes and style issues in a script")
+                .arg(
+                    Arg::with_name("config")
+                        .long("config")
+                        .value_name("FILE")
+                        .help("Read which rules are on from FILE instead of rlox-lint.toml"),
+                )
+                .arg(Arg::with_name("FILE").required(true)),
+        )
+        .subcommand(
+            SubCommand::with_name("lsp")
+                .about("Run a language server for editors, speaking LSP over stdin and stdout"),
+        )
+        .subcommand(
+            SubCommand::with_name("test")
+                .about("Run scripts and check them against their // expect: comments")
+                .arg(
+                    Arg::with_name("PATH")
+                        .multiple(true)
+                        .default_value("tests")
+                        .help("Scripts, or directories to look through for .lox files"),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("watch")
+                .about("Run a script again each time it changes")
+                .arg(Arg::with_name("FILE").required(true)),
+        )
+        .subcommand(
+            SubCommand::with_name("ast")
+                .about("Print the parsed program, as JSON, a Graphviz graph or Lox")
+                .arg(
+                    Arg::with_name("dot")
+                        .long("dot")
+                        .help("Print a graph in the DOT language instead of JSON"),
+                )
+                .arg(
+                    Arg::with_name("source")
+                        .long("source")
+                        .conflicts_with("dot")
+                        .help("Print the program back out as Lox instead of JSON"),
+                )
+                .arg(Arg::with_name("FILE").required(true)),
+        )
+        .get_matches();
 
-        pub fn error(&self, line: usize, message: &str) {
-            self.report(line, "", message);
+    let mut options = Options {
+        verbose: matches.is_present("verbose"),
+        optimize: matches.is_present("optimize"),
+        lcov: None,
+        max_parse_depth: parser::DEFAULT_MAX_DEPTH,
+        max_errors: match value_t!(matches, "max-errors", usize).unwrap_or_else(|e| e.exit()) {
+            0 => None,
+            max => Some(max),
+        },
+        lint_levels: HashMap::new(),
+        strict: matches.is_present("strict"),
+        typecheck: matches.is_present("typecheck"),
+        snippets: !matches.is_present("no-snippets"),
+        color: matches.value_of("color").unwrap().parse().unwrap(),
+        error_format: matches.value_of("error-format").unwrap().parse().unwrap(),
+        interpreter: InterpreterOptions::default(),
+    };
+    // Later flags win over earlier ones, as in `-A all -W unused`
+    let mut levels = Vec::new();
+    for (arg, level) in &[
+        ("warn", lint::Level::Warn),
+        ("deny", lint::Level::Deny),
+        ("allow", lint::Level::Allow),
+    ] {
+        if let (Some(indices), Some(names)) = (matches.indices_of(arg), matches.values_of(arg)) {
+            levels.extend(
+                indices
+                    .zip(names)
+                    .map(|(index, name)| (index, *level, name)),
+            );
         }
-
-        pub fn token_error(&self, t: Token, msg: &str) {
-            if let TokenType::Eof = t.token_type {
-                self.report(t.line, " at end", msg);
-            } else {
-                let mut location: String = " at '".to_string();
-                location.push_str(&t.lexeme);
-                location.push_str("'");
-                self.report(t.line, &location, msg);
-            }
+    }
+    levels.sort_by_key(|(index, ..)| *index);
+    for (_, level, name) in levels {
+        for rule in lint_rules(name).unwrap() {
+            options.lint_levels.insert(rule, level);
         }
+    }
+    if matches.is_present("max-parse-depth") {
+        options.max_parse_depth =
+            value_t!(matches, "max-parse-depth", usize).unwrap_or_else(|e| e.exit());
+    }
+    if matches.is_present("max-call-depth") {
+        options.interpreter.max_call_depth =
+            value_t!(matches, "max-call-depth", usize).unwrap_or_else(|e| e.exit());
+    }
+    if matches.is_present("max-expr-depth") {
+        options.interpreter.max_expr_depth =
+            value_t!(matches, "max-expr-depth", usize).unwrap_or_else(|e| e.exit());
+    }
+    if matches.is_present("max-steps") {
+        options.interpreter.max_steps =
+            Some(value_t!(matches, "max-steps", usize).unwrap_or_else(|e| e.exit()));
+    }
+    if let Some(timeout) = matches.value_of("timeout") {
+        options.interpreter.timeout = parse_duration(timeout).ok();
+    }
+    if let Some(max_memory) = matches.value_of("max-memory") {
+        options.interpreter.max_memory = parse_size(max_memory).ok();
+    }
+    options.interpreter.deterministic = matches.is_present("deterministic");
+    options.interpreter.profile = matches.is_present("profile");
+    options.interpreter.trace = matches.is_present("trace");
+    options.interpreter.keep_going = matches.is_present("keep-going");
+    if matches.is_present("raw-numbers") {
+        options.interpreter.number_format = NumberFormat::Raw;
+    }
+    options.interpreter.stats = matches.is_present("stats");
+    options.interpreter.coverage = matches.is_present("coverage") || matches.is_present("lcov");
+    options.lcov = matches.value_of("lcov").map(|f| f.to_string());
+    if matches.is_present("seed") {
+        options.interpreter.seed =
+            Some(value_t!(matches, "seed", u64).unwrap_or_else(|e| e.exit()));
+    }
 
-        pub fn runtime_error(&self, line: usize, msg: &str) {
-            self.had_runtime_error.replace(true);
-            self.errors_collected
-                .lock()
-                .unwrap()
-                .push(format!("[Line {}] Runtime Error: {}", line, msg));
+    if let Some(code) = matches.value_of("explain") {
+        explain_error(code);
+    }
+    if let Some(debug) = matches.subcommand_matches("debug") {
+        run_file(debug.value_of("FILE").unwrap(), &options, true);
+        return;
+    }
+    if let Some(fmt) = matches.subcommand_matches("fmt") {
+        format_file(fmt.value_of("FILE").unwrap(), fmt.is_present("check"));
+    }
+    if let Some(watch) = matches.subcommand_matches("watch") {
+        watch_file(watch.value_of("FILE").unwrap(), &options);
+    }
+    if matches.subcommand_matches("lsp").is_some() {
+        if let Err(e) = lsp::run() {
+            eprintln!("Language server failed: {}", e);
+            std::process::exit(1);
+        }
+        return;
+    }
+    if let Some(bench) = matches.subcommand_matches("bench") {
+        let iterations = value_t!(bench, "iterations", usize).unwrap_or_else(|e| e.exit());
+        let engines: &[bench::Engine] = if bench.is_present("compare") {
+            &[bench::Engine::Interpreter, bench::Engine::Optimized]
+        } else {
+            &[bench::Engine::Interpreter]
+        };
+        let file = bench.value_of("FILE").unwrap();
+        std::process::exit(if bench::run_bench(file, iterations.max(1), engines) {
+            0
+        } else {
+            1
+        });
+    }
+    if let Some(test) = matches.subcommand_matches("test") {
+        let paths: Vec<&str> = test.values_of("PATH").unwrap().collect();
+        std::process::exit(if testing::run_tests(&paths) { 0 } else { 1 });
+    }
+    if let Some(highlight) = matches.subcommand_matches("highlight") {
+        let format = highlight.value_of("format").unwrap().parse().unwrap();
+        let contents = read_source(highlight.value_of("FILE").unwrap());
+        print!("{}", highlight::highlight(&contents, format));
+        return;
+    }
+    if let Some(doc) = matches.subcommand_matches("doc") {
+        let format = doc.value_of("format").unwrap().parse().unwrap();
+        document_files(doc.values_of("FILE").unwrap().collect(), format);
+    }
+    if let Some(lint) = matches.subcommand_matches("lint") {
+        lint_file(lint.value_of("FILE").unwrap(), lint.value_of("config"));
+    }
+    if let Some(ast) = matches.subcommand_matches("ast") {
+        let format = if ast.is_present("dot") {
+            AstFormat::Dot
+        } else if ast.is_present("source") {
+            AstFormat::Source
+        } else {
+            AstFormat::Json
+        };
+        dump_ast(ast.value_of("FILE").unwrap(), format);
+    }
+    if let Some(code) = matches.value_of("eval") {
+        eval_code(code, &options);
+    }
+    if let Some(f) = matches.value_of("FILE") {
+        if matches.is_present("check") {
+            check_file(f, &options);
+        }
+        if matches.is_present("dump-tokens") {
+            dump_tokens(f, matches.value_of("dump-tokens") == Some("json"));
         }
+        if matches.is_present("dump-ast") {
+            dump_ast(f, AstFormat::Json);
+        }
+        if let Some(target) = matches.value_of("target") {
+            transpile_file(f, target.parse().unwrap());
+        }
+        if matches.is_present("interactive") {
+            repl::run_prompt(&options, Some(f));
+            return;
+        }
+        run_file(f, &options, false);
+        return;
+    }
+    repl::run_prompt(&options, None);
+}
+
+// Durations are a number with an optional unit of ms, s or m, defaulting to seconds
+fn parse_duration(s: &str) -> Result<Duration, String> {
+    let split = s.find(|c: char| c.is_ascii_alphabetic()).unwrap_or(s.len());
+    let (amount, unit) = s.split_at(split);
+    let amount: f64 = amount
+        .parse()
+        .map_err(|_| format!("Invalid duration '{}'", s))?;
+    let seconds = match unit {
+        "ms" => amount / 1000.0,
+        "s" | "" => amount,
+        "m" => amount * 60.0,
+        _ => return Err(format!("Unknown unit in duration '{}'", s)),
+    };
+    if seconds.is_finite() && seconds >= 0.0 {
+        Ok(Duration::from_secs_f64(seconds))
+    } else {
+        Err(format!("Invalid duration '{}'", s))
+    }
+}
+
+// Sizes are a number of bytes with an optional K, M or G suffix
+fn parse_size(s: &str) -> Result<usize, String> {
+    let split = s.find(|c: char| c.is_ascii_alphabetic()).unwrap_or(s.len());
+    let (amount, unit) = s.split_at(split);
+    let amount: usize = amount
+        .parse()
+        .map_err(|_| format!("Invalid size '{}'", s))?;
+    let multiplier = match unit.to_ascii_uppercase().as_str() {
+        "" | "B" => 1,
+        "K" | "KB" => 1 << 10,
+        "M" | "MB" => 1 << 20,
+        "G" | "GB" => 1 << 30,
+        _ => return Err(format!("Unknown unit in size '{}'", s)),
+    };
+    amount
+        .checked_mul(multiplier)
+        .ok_or_else(|| format!("Size '{}' is too large", s))
+}
 
-        pub fn report(&self, line: usize, location: &str, msg: &str) {
-            self.had_error.replace(true);
-            self.errors_collected
-                .lock()
-                .unwrap()
-                .push(format!("[line {}] Error {}: {}", line, location, msg));
+// Statements are resolved even when others failed to parse, to report as much as
+// possible in one go
+// A filename of `-` means the program is read from stdin, for when it's piped in
+fn read_source(filename: &str) -> String {
+    if filename == "-" {
+        let mut contents = String::new();
+        io::stdin()
+            .read_to_string(&mut contents)
+            .expect("Could not read program from stdin");
+        contents
+    } else {
+        std::fs::read_to_string(filename).expect("Could not read input file")
+    }
+}
+
+fn check_file(filename: &str, options: &Options) -> ! {
+    let contents = read_source(filename);
+    let error_reporter = errors::ErrorReporter::new();
+    set_up_reporter(&error_reporter, options, filename, &contents);
+    let mut interpreter =
+        interpreter::Interpreter::new(&error_reporter, InterpreterOptions::default());
+    let stmts = parser::Parser::new(
+        Scanner::new(&contents, &error_reporter),
+        interpreter.ast_mut(),
+        &error_reporter,
+    )
+    .parse_stmts();
+    analyze(&mut interpreter, &error_reporter, options, &stmts);
+    error_reporter.print_warnings();
+    error_reporter.print_collected_errors();
+    // The type checker reports its errors as runtime errors, but they're found before running
+    if error_reporter.had_error() || error_reporter.had_runtime_error() {
+        std::process::exit(65);
+    }
+    std::process::exit(0);
+}
+
+// Errors go to stderr, to keep them out of the output. Errors in scanning still give
+// tokens, so those are printed anyway. Comments are included, for tools that want them.
+fn dump_tokens(filename: &str, json: bool) -> ! {
+    let contents = read_source(filename);
+    let error_reporter = errors::ErrorReporter::new();
+    let mut out = io::stdout().lock();
+    for token in Scanner::new(&contents, &error_reporter).with_comments() {
+        let _ = if json {
+            writeln!(out, "{}", serde_json::to_string(&token).unwrap())
+        } else {
+            writeln!(out, "{}:{} {}", token.line, token.column, token)
+        };
+    }
+    drop(out);
+    exit_with_errors(&error_reporter);
+}
+
+// How `rlox ast` prints the program
+enum AstFormat {
+    Json,
+    Dot,
+    // Lox again, as the parser sees it, with `for` loops desugared and comments gone
+    Source,
+}
+
+fn dump_ast(filename: &str, format: AstFormat) -> ! {
+    let contents = read_source(filename);
+    let error_reporter = errors::ErrorReporter::new();
+    let mut ast = ast::Ast::new();
+    let stmts = parser::Parser::new(
+        Scanner::new(&contents, &error_reporter),
+        &mut ast,
+        &error_reporter,
+    )
+    .parse_stmts();
+    if !error_reporter.had_error() {
+        match format {
+            AstFormat::Json => println!("{}", ast.to_json(&stmts)),
+            AstFormat::Dot => print!("{}", ast::DotPrinter::new(&ast).print(&stmts)),
+            AstFormat::Source => print!("{}", ast::SourceEmitter::new(&ast).emit(&stmts)),
         }
+    }
+    exit_with_errors(&error_reporter);
+}
 
-        pub fn had_error(&self) -> bool {
-            *self.had_error.borrow()
+// Files with errors are left out, and make the exit status 65 once the rest are done
+fn document_files(filenames: Vec<&str>, format: doc::Format) -> ! {
+    let mut failed = false;
+    for filename in filenames {
+        let contents = read_source(filename);
+        let error_reporter = errors::ErrorReporter::new();
+        let tokens = Scanner::new(&contents, &error_reporter)
+            .with_trivia()
+            .scan_tokens();
+        let mut ast = ast::Ast::new();
+        let stmts = parser::Parser::new(tokens, &mut ast, &error_reporter).parse_stmts();
+        if error_reporter.had_error() {
+            failed = true;
+            for error in error_reporter.take_errors() {
+                eprintln!("{}: {}", filename, error);
+            }
+            continue;
         }
+        print!("{}", doc::document(filename, &ast, &stmts, format));
+    }
+    std::process::exit(if failed { 65 } else { 0 });
+}
 
-        pub fn had_runtime_error(&self) -> bool {
-            *self.had_runtime_error.borrow()
+fn transpile_file(filename: &str, target: codegen::Target) -> ! {
+    let contents = read_source(filename);
+    let error_reporter = errors::ErrorReporter::new();
+    let mut ast = ast::Ast::new();
+    let stmts = parser::Parser::new(
+        Scanner::new(&contents, &error_reporter),
+        &mut ast,
+        &error_reporter,
+    )
+    .parse_stmts();
+    if !error_reporter.had_error() {
+        if let Some(code) = codegen::transpile(target, &ast, &stmts, &error_reporter) {
+            print!("{}", code);
         }
+    }
+    for error in error_reporter.take_errors() {
+        eprintln!("{}", error);
+    }
+    if error_reporter.had_error() {
+        std::process::exit(65);
+    }
+    std::process::exit(0);
+}
 
-        pub fn print_collected_errors(&self) {
-            for s in &*self.errors_collected.lock().unwrap() {
-                println!("{}", s);
-            }
+// The config is read from rlox-lint.toml in the current directory when there is one and
+// no other is given. Exits with 1 if there were warnings.
+// A rule by name, or all of them
+fn lint_rules(name: &str) -> Result<Vec<lint::Rule>, String> {
+    if name == "all" {
+        return Ok(lint::Rule::iter().collect());
+    }
+    name.parse()
+        .map(|rule| vec![rule])
+        .map_err(|_| format!("Unknown lint rule '{}'", name))
+}
+
+fn explain_error(code: &str) -> ! {
+    match explain::lookup(code) {
+        Some(explanation) => {
+            print!("{}", explanation);
+            std::process::exit(0);
+        }
+        None => {
+            eprintln!("No error has the code {}", code);
+            std::process::exit(64);
         }
+    }
+}
 
-        pub fn reset(&mut self) {
-            self.had_error.replace(false);
-            self.had_runtime_error.replace(false);
+fn lint_file(filename: &str, config_file: Option<&str>) -> ! {
+    let config = match config_file {
+        Some(path) => Some(std::fs::read_to_string(path).unwrap_or_else(|e| {
+            eprintln!("Could not read {}: {}", path, e);
+            std::process::exit(66);
+        })),
+        None => std::fs::read_to_string(lint::CONFIG_FILE).ok(),
+    };
+    let config = match config.as_deref().map(lint::Config::from_toml) {
+        None => lint::Config::default(),
+        Some(Ok(config)) => config,
+        Some(Err(e)) => {
+            eprintln!("Invalid lint config: {}", e);
+            std::process::exit(78);
         }
+    };
+
+    let contents = read_source(filename);
+    let error_reporter = errors::ErrorReporter::new();
+    let mut ast = ast::Ast::new();
+    let stmts = parser::Parser::new(
+        Scanner::new(&contents, &error_reporter),
+        &mut ast,
+        &error_reporter,
+    )
+    .parse_stmts();
+    let warnings = lint::lint(&contents, &ast, &stmts, &config, &error_reporter);
+    for warning in &warnings {
+        println!("{} [{}]", warning, warning.rule);
     }
+    error_reporter.print_collected_errors();
+    if error_reporter.had_error() {
+        std::process::exit(65);
+    }
+    std::process::exit(if warnings.is_empty() { 0 } else { 1 });
 }
 
-fn main() {
-    let matches = App::new("rlox")
-        .version("0.1")
-        .arg(
-            Arg::with_name("verbose")
-                .short("V")
-                .long("verbose")
-                .help("Verbose output"),
-        )
-        .arg(Arg::with_name("FILE"))
-        .get_matches();
+// Exits with 1 for --check if the script isn't formatted
+fn format_file(filename: &str, check: bool) -> ! {
+    let contents = read_source(filename);
+    let error_reporter = errors::ErrorReporter::new();
+    let formatted = match formatter::format(&contents, &error_reporter) {
+        Some(formatted) => formatted,
+        None => exit_with_errors(&error_reporter),
+    };
+    if check {
+        if formatted != contents {
+            eprintln!("{} is not formatted", filename);
+            std::process::exit(1);
+        }
+    } else if filename == "-" {
+        print!("{}", formatted);
+    } else if formatted != contents {
+        if let Err(e) = std::fs::write(filename, formatted) {
+            eprintln!("Could not write {}: {}", filename, e);
+            std::process::exit(74);
+        }
+    }
+    std::process::exit(0);
+}
 
-    let verbose = matches.is_present("verbose");
-    if let Some(f) = matches.value_of("FILE") {
-        run_file(&f, verbose);
-        return;
+fn exit_with_errors(error_reporter: &errors::ErrorReporter) -> ! {
+    for error in error_reporter.take_errors() {
+        eprintln!("{}", error);
     }
-    run_prompt(verbose);
+    std::process::exit(if error_reporter.had_error() { 65 } else { 0 });
 }
 
-fn run_file(filename: &str, verbose: bool) {
+fn run_file(filename: &str, options: &Options, debug: bool) {
     // println!("running file {:?}", filename);
-    let contents = std::fs::read_to_string(filename).expect("Could not read input file");
+    let contents = read_source(filename);
+    let status = run_script(filename, &contents, options, debug);
+    if status != 0 {
+        std::process::exit(status);
+    }
+}
+
+// Runs a script in a fresh interpreter, giving the status to exit with
+fn run_script(filename: &str, contents: &str, options: &Options, debug: bool) -> i32 {
+    let error_reporter = errors::ErrorReporter::new();
+    set_up_reporter(&error_reporter, options, filename, contents);
+    let mut interpreter =
+        interpreter::Interpreter::new(&error_reporter, options.interpreter.clone());
+    interpreter.set_debugger(Box::new(debugger::Debugger::new(
+        filename,
+        contents,
+        debug,
+        debugger_input(),
+        Box::new(io::stdout()),
+    )));
+    run(
+        contents,
+        0,
+        false,
+        options,
+        &mut interpreter,
+        &error_reporter,
+    );
+    print_reports(&interpreter);
+    write_coverage(&interpreter, filename, contents, options);
+    if error_reporter.had_error() {
+        65
+    } else if error_reporter.had_runtime_error() {
+        70
+    } else {
+        0
+    }
+}
+
+// Runs the script again whenever it changes, until killed. The directory is watched
+// rather than the file, as many editors save by replacing the file.
+fn watch_file(filename: &str, options: &Options) -> ! {
+    let path = std::fs::canonicalize(filename).unwrap_or_else(|e| {
+        eprintln!("Could not watch {}: {}", filename, e);
+        std::process::exit(66);
+    });
+    let (tx, rx) = std::sync::mpsc::channel();
+    let mut watcher = notify::watcher(tx, Duration::from_millis(100)).unwrap_or_else(|e| {
+        eprintln!("Could not watch {}: {}", filename, e);
+        std::process::exit(1);
+    });
+    let dir = path.parent().unwrap_or(&path);
+    if let Err(e) = watcher.watch(dir, notify::RecursiveMode::NonRecursive) {
+        eprintln!("Could not watch {}: {}", filename, e);
+        std::process::exit(1);
+    }
+
+    loop {
+        // Clear the screen, so only this run's output and errors are showing
+        print!("\x1B[2J\x1B[H");
+        match std::fs::read_to_string(&path) {
+            Ok(contents) => {
+                let status = run_script(filename, &contents, options, false);
+                let result = if status == 0 { "Finished" } else { "Failed" };
+                eprintln!("\n[{}] Watching {} for changes...", result, filename);
+            }
+            Err(e) => eprintln!("Could not read {}: {}", filename, e),
+        }
+        let _ = io::stdout().flush();
+
+        loop {
+            use notify::DebouncedEvent::*;
+            match rx.recv() {
+                Ok(Create(changed)) | Ok(Write(changed)) | Ok(Rename(_, changed))
+                    if changed == path =>
+                {
+                    break
+                }
+                Ok(_) => {}
+                Err(_) => std::process::exit(1),
+            }
+        }
+    }
+}
+
+fn eval_code(code: &str, options: &Options) -> ! {
     let error_reporter = errors::ErrorReporter::new();
-    run(&contents, false, verbose, &error_reporter);
+    set_up_reporter(&error_reporter, options, "<eval>", code);
+    let mut interpreter =
+        interpreter::Interpreter::new(&error_reporter, options.interpreter.clone());
+    let value = run(code, 0, true, options, &mut interpreter, &error_reporter);
+    match value {
+        // Printed as `print` would, so strings come out without quotes
+        Some(loxvalue::LoxValue::Nil) | None => {}
+        Some(value) => println!("{}", interpreter.stringify(&value)),
+    }
+    print_reports(&interpreter);
     if error_reporter.had_error() {
         std::process::exit(65);
     }
     if error_reporter.had_runtime_error() {
         std::process::exit(70);
     }
+    std::process::exit(0);
+}
+
+// Has printed errors show the line of source they're on, unless that's turned off, and
+// coloured as asked. Source given to a reporter that's been set up before, as each of the
+// REPL's inputs is, is kept along with what came before it. Gives the offset to scan the
+// source from.
+fn set_up_reporter(
+    error_reporter: &errors::ErrorReporter,
+    options: &Options,
+    name: &str,
+    source: &str,
+) -> usize {
+    // JSON diagnostics only need the name
+    if options.snippets || options.error_format == ErrorFormat::Json {
+        error_reporter.keep_sources();
+    }
+    let offset = error_reporter.add_source(name, source).unwrap_or(0);
+    error_reporter.set_color(options.color);
+    error_reporter.set_error_format(options.error_format);
+    error_reporter.set_max_errors(options.max_errors);
+    for (rule, level) in &options.lint_levels {
+        error_reporter.set_level(*rule, *level);
+    }
+    offset
 }
 
-fn run_prompt(verbose: bool) {
-    let stdin = io::stdin();
-    let mut buf = String::new();
-    let mut error_reporter = errors::ErrorReporter::new();
+// Stdin is already buffered, and reading it a byte at a time leaves any lines after the
+// debugger's commands for the REPL
+fn debugger_input() -> Box<dyn io::BufRead> {
+    Box::new(io::BufReader::with_capacity(1, io::stdin()))
+}
 
-    loop {
-        print!("> ");
-        io::stdout().lock().flush().unwrap();
-        if stdin.lock().read_line(&mut buf).is_ok() {
-            run(&buf, true, verbose, &error_reporter);
-            error_reporter.reset();
-            buf.clear();
+// Reports go to stderr to keep them apart from the script's own output
+fn print_reports(interpreter: &interpreter::Interpreter) {
+    if let Some(profiler) = interpreter.profiler() {
+        eprint!("{}", profiler.report());
+    }
+    if let Some(stats) = interpreter.stats() {
+        eprint!("{}", stats.report(&interpreter.heap().stats()));
+    }
+}
+
+fn write_coverage(
+    interpreter: &interpreter::Interpreter,
+    filename: &str,
+    contents: &str,
+    options: &Options,
+) {
+    let coverage = match interpreter.coverage() {
+        Some(coverage) => coverage,
+        None => return,
+    };
+    let ast = interpreter.ast();
+    if let Some(lcov) = &options.lcov {
+        if let Err(e) = std::fs::write(lcov, coverage.lcov(&ast, filename)) {
+            eprintln!("Could not write coverage to {}: {}", lcov, e);
         }
+    } else {
+        eprint!("{}", coverage.report(&ast, contents));
     }
 }
 
-fn run(code: &str, allow_exprs: bool, verbose: bool, error_reporter: &errors::ErrorReporter) {
-    let scanner: Scanner = Scanner::new(code, error_reporter);
-    let tokens: LinkedList<Token> = scanner.scan_tokens();
+fn resolver<'a>(
+    ast: &'a ast::Ast,
+    interpreter: &interpreter::Interpreter,
+    error_reporter: &'a errors::ErrorReporter,
+    options: &Options,
+) -> resolver::Resolver<'a> {
+    // Shadowing is only checked for when it's asked for
+    let shadowing = options.lint_levels.get(&lint::Rule::Shadowing);
+    let resolver = resolver::Resolver::new(ast, error_reporter)
+        .warn_shadowing(shadowing.is_some_and(|level| *level != lint::Level::Allow));
+    if options.strict {
+        resolver.strict(interpreter.global_names())
+    } else {
+        resolver
+    }
+}
 
-    if verbose {
-        for t in &tokens {
-            println!("Token: {:?}", t);
-        }
+// Everything that's checked before running
+fn analyze<'a>(
+    interpreter: &mut interpreter::Interpreter<'a>,
+    error_reporter: &'a errors::ErrorReporter,
+    options: &Options,
+    stmts: &[ast::StmtId],
+) {
+    let ast = interpreter.ast();
+    let resolved = resolver(&ast, interpreter, error_reporter, options).resolve_stmts(stmts);
+    interpreter.add_resolution(resolved);
+    if options.typecheck {
+        typecheck::TypeChecker::new(&ast, error_reporter).check_stmts(stmts);
     }
+}
+
+// With allow_exprs, code that's a single expression has its value returned rather than
+// being thrown away, and may leave off the semicolon
+fn run<'a>(
+    code: &str,
+    offset: usize,
+    allow_exprs: bool,
+    options: &Options,
+    interpreter: &mut interpreter::Interpreter<'a>,
+    error_reporter: &'a errors::ErrorReporter,
+) -> Option<loxvalue::LoxValue> {
+    // Kept to parse again as an expression if they don't parse as statements
+    let tokens = Scanner::new(code, error_reporter)
+        .with_offset(offset)
+        .scan_tokens();
 
     if error_reporter.had_error() {
         error_reporter.print_collected_errors();
     }
 
-    let mut parser = parser::Parser::new(tokens.clone().into_iter().collect(), &error_reporter);
+    let mut parser = parser::Parser::new(
+        tokens.iter().cloned(),
+        interpreter.ast_mut(),
+        error_reporter,
+    )
+    .with_max_depth(options.max_parse_depth);
     let stmts = parser.parse_stmts();
 
-    let mut interpreter = interpreter::Interpreter::new(error_reporter);
-    let resolver = resolver::Resolver::new(&mut interpreter, error_reporter);
-
     if error_reporter.had_error() {
         if allow_exprs {
             // Try to parse and evaluate a statement instead
             let mut expr_parser =
-                parser::Parser::new(tokens.into_iter().collect(), &error_reporter);
+                parser::Parser::new(tokens, interpreter.ast_mut(), error_reporter)
+                    .with_max_depth(options.max_parse_depth);
             if let Ok(expr) = expr_parser.parse_expr() {
-                resolver.resolve_expr(&expr);
-                if error_reporter.had_runtime_error() {
+                // It was meant as an expression, so the statement's errors don't apply
+                error_reporter.reset();
+                let ast = interpreter.ast();
+                let resolved =
+                    resolver(&ast, interpreter, error_reporter, options).resolve_expr(expr);
+                interpreter.add_resolution(resolved);
+                if error_reporter.had_error() {
                     error_reporter.print_collected_errors();
-                    return;
+                    return None;
                 }
-                interpreter.interpret_expr(&expr);
+                if options.optimize {
+                    optimizer::Optimizer::new(interpreter.ast_mut()).optimize_expr(expr);
+                }
+                let value = interpreter.interpret_expr(expr);
                 if error_reporter.had_runtime_error() {
                     error_reporter.print_collected_errors();
                 }
-            } else {
-                error_reporter.print_collected_errors();
+                return value;
             }
-            return;
-        } else {
-            error_reporter.print_collected_errors();
-            return;
         }
+        error_reporter.print_collected_errors();
+        return None;
     }
 
-    if verbose {
-        let pp = PrettyPrinter {};
+    if options.verbose {
+        let ast = interpreter.ast();
+        let pp = PrettyPrinter::new(&ast);
         for stmt in &stmts {
-            let s = pp.print_stmt(&stmt);
+            let s = pp.print_stmt(*stmt);
             println!("Parsed: {:?}", s);
         }
     }
 
-    resolver.resolve_stmts(&stmts);
-    if error_reporter.had_runtime_error() {
+    analyze(interpreter, error_reporter, options, &stmts);
+    error_reporter.print_warnings();
+    // Errors from here on are the resolver's and type checker's, and warnings that are
+    // denied
+    if error_reporter.had_error() || error_reporter.had_runtime_error() {
         error_reporter.print_collected_errors();
-        return;
+        return None;
     }
-    interpreter.interpret(&stmts);
+    let stmts = if options.optimize {
+        optimizer::Optimizer::new(interpreter.ast_mut()).optimize_stmts(&stmts)
+    } else {
+        stmts
+    };
+    let single_expr = match (allow_exprs, &stmts[..]) {
+        (true, [stmt]) => match &interpreter.ast()[*stmt] {
+            ast::Stmt::Expression(expr) => Some(*expr),
+            _ => None,
+        },
+        _ => None,
+    };
+    let value = match single_expr {
+        Some(expr) => interpreter.interpret_expr(expr),
+        None => {
+            interpreter.interpret(&stmts);
+            None
+        }
+    };
     if error_reporter.had_runtime_error() {
         error_reporter.print_collected_errors();
     }
+    value
 }
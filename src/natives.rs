@@ -0,0 +1,355 @@
+use std::{cell::Cell, rc::Rc, sync::Arc, time::SystemTime};
+
+use crate::{
+    env::Environment,
+    errors::Suggestions,
+    gc::Heap,
+    interpreter::{Interpreter, RuntimeError},
+    loxvalue::{Arity, BuiltinMethod, Function, LoxCallable, LoxRef, LoxValue, NativeFn},
+    symbol::Symbol,
+    tasks::{Task, TaskQueue},
+    tokens::Token,
+};
+
+pub fn define_globals(globals: &mut Environment, heap: &Rc<Heap>) {
+    define_native(globals, heap, "chr", 1, |args| {
+        let c = code_point(&args[0])
+            .ok_or_else(|| native_error("chr() expects a valid Unicode code point"))?;
+        Ok(LoxValue::String(c.to_string().into()))
+    });
+
+    define_native(globals, heap, "ord", 1, |args| {
+        if let LoxValue::String(s) = &args[0] {
+            let mut chars = s.chars();
+            if let (Some(c), None) = (chars.next(), chars.next()) {
+                return Ok(LoxValue::Number(c as u32 as f64));
+            }
+        }
+        Err(native_error("ord() expects a single-character string"))
+    });
+
+    let list_heap = heap.clone();
+    define_native(globals, heap, "codePoints", 1, move |args| {
+        if let LoxValue::String(s) = &args[0] {
+            let points = s.chars().map(|c| LoxValue::Number(c as u32 as f64));
+            Ok(LoxValue::Ref(
+                list_heap.alloc(LoxRef::List(points.collect())),
+            ))
+        } else {
+            Err(native_error("codePoints() expects a string"))
+        }
+    });
+
+    define_native(globals, heap, "len", 1, |args| match &args[0] {
+        LoxValue::String(s) => Ok(LoxValue::Number(s.chars().count() as f64)),
+        LoxValue::Ref(r) => match &*r.borrow() {
+            LoxRef::List(items) => Ok(LoxValue::Number(items.len() as f64)),
+            _ => Err(native_error("len() expects a string or a list")),
+        },
+        _ => Err(native_error("len() expects a string or a list")),
+    });
+
+    define_native(globals, heap, "listGet", 2, |args| {
+        if let LoxValue::Ref(r) = &args[0] {
+            if let LoxRef::List(items) = &*r.borrow() {
+                let item = index(&args[1])
+                    .and_then(|i| items.get(i))
+                    .ok_or_else(|| native_error("listGet() index out of range"))?;
+                return Ok(item.clone());
+            }
+        }
+        Err(native_error("listGet() expects a list"))
+    });
+
+    let new_list_heap = heap.clone();
+    define_native(globals, heap, "list", 0.., move |args| {
+        Ok(LoxValue::Ref(
+            new_list_heap.alloc(LoxRef::List(args.to_vec())),
+        ))
+    });
+
+    define_native(globals, heap, "min", 1.., |args| {
+        let n = numbers(args, "min() expects numbers")?;
+        Ok(LoxValue::Number(
+            n.into_iter().fold(f64::INFINITY, f64::min),
+        ))
+    });
+
+    define_native(globals, heap, "max", 1.., |args| {
+        let n = numbers(args, "max() expects numbers")?;
+        Ok(LoxValue::Number(
+            n.into_iter().fold(f64::NEG_INFINITY, f64::max),
+        ))
+    });
+
+    define_interpreter_native(
+        globals,
+        heap,
+        "eval",
+        1,
+        |interpreter, _, args| match &args[0] {
+            LoxValue::String(source) => interpreter.eval(source),
+            _ => Err(native_error("eval() expects a string")),
+        },
+    );
+}
+
+// Natives that look at instances and classes, and get and set properties by name, so
+// that what a script does with an instance doesn't have to be written out field by field
+pub fn define_reflection_natives(globals: &mut Environment, heap: &Rc<Heap>) {
+    let fields_heap = heap.clone();
+    define_native(globals, heap, "fields", 1, move |args| {
+        if let LoxValue::Ref(r) = &args[0] {
+            if let LoxRef::Instance(instance) = &*r.borrow() {
+                let names = instance.fields().keys().cloned().collect();
+                return Ok(name_list(&fields_heap, names));
+            }
+        }
+        Err(native_error("fields() expects an instance"))
+    });
+
+    let methods_heap = heap.clone();
+    define_native(globals, heap, "methods", 1, move |args| {
+        if let LoxValue::Ref(r) = &args[0] {
+            let names = match &*r.borrow() {
+                LoxRef::Class(class) => Some(class.method_names()),
+                user_data @ LoxRef::UserData(_) => Some(BuiltinMethod::names(user_data)),
+                LoxRef::Instance(instance) => match &*instance.class().borrow() {
+                    LoxRef::Class(class) => Some(class.method_names()),
+                    _ => None,
+                },
+                _ => None,
+            };
+            if let Some(names) = names {
+                return Ok(name_list(&methods_heap, names));
+            }
+        }
+        Err(native_error(
+            "methods() expects a class, an instance or userdata",
+        ))
+    });
+
+    let getattr_heap = heap.clone();
+    define_native(globals, heap, "getattr", 2, move |args| {
+        if let (LoxValue::Ref(r), LoxValue::String(name)) = (&args[0], &args[1]) {
+            if let LoxRef::Instance(instance) = &*r.borrow() {
+                // A name there's no symbol for can't be a property
+                let value = Symbol::lookup(name)
+                    .and_then(|name| instance.get(r.clone(), &name, &getattr_heap).ok());
+                return value.ok_or_else(|| {
+                    let names = instance.property_names();
+                    let suggestions = Suggestions::new(name, names.iter().map(Symbol::to_string));
+                    RuntimeError::UndefinedProperty(name.to_string(), suggestions)
+                });
+            }
+        }
+        Err(native_error(
+            "getattr() expects an instance and a property name",
+        ))
+    });
+
+    define_native(globals, heap, "setattr", 3, move |args| {
+        if let (LoxValue::Ref(r), LoxValue::String(name)) = (&args[0], &args[1]) {
+            if let LoxRef::Instance(instance) = &mut *r.borrow_mut() {
+                instance.set(Symbol::intern(name), args[2].clone());
+                return Ok(args[2].clone());
+            }
+        }
+        Err(native_error(
+            "setattr() expects an instance, a property name and a value",
+        ))
+    });
+}
+
+// The names as a list of strings, sorted so they come out the same every run
+fn name_list(heap: &Heap, names: Vec<Symbol>) -> LoxValue {
+    let mut names: Vec<&str> = names.iter().map(|name| name.as_str()).collect();
+    names.sort_unstable();
+    names.dedup();
+    let names = names.into_iter().map(|name| LoxValue::String(name.into()));
+    LoxValue::Ref(heap.alloc(LoxRef::List(names.collect())))
+}
+
+// Natives that look at how the interpreter manages memory
+pub fn define_heap_natives(globals: &mut Environment, heap: &Rc<Heap>) {
+    let gc_heap = heap.clone();
+    define_native(globals, heap, "collectGarbage", 0, move |_args| {
+        Ok(LoxValue::Number(gc_heap.collect() as f64))
+    });
+
+    let stats_heap = heap.clone();
+    define_native(globals, heap, "heapObjects", 0, move |_args| {
+        Ok(LoxValue::Number(stats_heap.stats().objects as f64))
+    });
+}
+
+pub fn define_task_natives(globals: &mut Environment, heap: &Rc<Heap>, tasks: &TaskQueue) {
+    let spawned = tasks.clone();
+    define_native(globals, heap, "spawn", 1, move |args| {
+        if let LoxValue::Ref(r) = &args[0] {
+            if let LoxRef::Function(f) = &*r.borrow() {
+                if f.arity().accepts(0) {
+                    spawned.borrow_mut().push_back(Task::Start(f.clone()));
+                    return Ok(LoxValue::Nil);
+                }
+            }
+        }
+        Err(native_error(
+            "spawn() expects a function with no parameters",
+        ))
+    });
+
+    let channel_heap = heap.clone();
+    define_native(globals, heap, "channel", 0, move |_args| {
+        Ok(LoxValue::Ref(
+            channel_heap.alloc(LoxRef::Channel(Default::default())),
+        ))
+    });
+}
+
+// Natives whose results depend on when and where the script runs
+pub fn define_system_natives(globals: &mut Environment, heap: &Heap, seed: Option<u64>) {
+    define_native(globals, heap, "clock", 0, |_args| {
+        let time = now();
+        Ok(LoxValue::Number(time.as_secs() as f64))
+    });
+
+    let seed = seed.unwrap_or_else(|| now().as_nanos() as u64);
+    define_random(globals, heap, seed);
+}
+
+// Stand-ins for the system natives that give the same results every run. The clock
+// counts up by one each time it's read.
+pub fn define_deterministic_natives(globals: &mut Environment, heap: &Heap, seed: u64) {
+    let ticks = Cell::new(0u64);
+    define_native(globals, heap, "clock", 0, move |_args| {
+        ticks.set(ticks.get() + 1);
+        Ok(LoxValue::Number(ticks.get() as f64))
+    });
+
+    define_random(globals, heap, seed);
+}
+
+// Returns a number in [0, 1), using splitmix64
+fn define_random(globals: &mut Environment, heap: &Heap, seed: u64) {
+    let state = Cell::new(seed);
+    define_native(globals, heap, "random", 0, move |_args| {
+        state.set(state.get().wrapping_add(0x9E37_79B9_7F4A_7C15));
+        let mut z = state.get();
+        z = (z ^ (z >> 30)).wrapping_mul(0xBF58_476D_1CE4_E5B9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94D0_49BB_1331_11EB);
+        z ^= z >> 31;
+        Ok(LoxValue::Number((z >> 11) as f64 / (1u64 << 53) as f64))
+    });
+}
+
+fn now() -> std::time::Duration {
+    SystemTime::now()
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .unwrap()
+}
+
+pub fn define_native(
+    env: &mut Environment,
+    heap: &Heap,
+    name: &str,
+    arity: impl Into<Arity>,
+    code: impl Fn(&[LoxValue]) -> Result<LoxValue, RuntimeError> + 'static,
+) {
+    define_interpreter_native(env, heap, name, arity, move |_, _, args| code(args));
+}
+
+// For natives that need more than their arguments, such as to call back into Lox code
+pub fn define_interpreter_native(
+    env: &mut Environment,
+    heap: &Heap,
+    name: &str,
+    arity: impl Into<Arity>,
+    code: impl Fn(&mut Interpreter<'_>, Option<&Token>, &[LoxValue]) -> Result<LoxValue, RuntimeError>
+        + 'static,
+) {
+    define_native_fn(
+        env,
+        heap,
+        NativeFn {
+            name: name.to_string(),
+            arity: arity.into(),
+            code: Arc::new(code),
+        },
+    );
+}
+
+pub fn define_native_fn(env: &mut Environment, heap: &Heap, native: NativeFn) {
+    env.define(
+        Symbol::intern(&native.name),
+        LoxValue::Ref(heap.alloc(LoxRef::Function(Function::Native(native)))),
+    );
+}
+
+fn native_error(msg: &str) -> RuntimeError {
+    RuntimeError::NativeArgument(msg.to_string())
+}
+
+// The arguments as numbers, for a native that takes any number of them
+fn numbers(args: &[LoxValue], msg: &str) -> Result<Vec<f64>, RuntimeError> {
+    args.iter()
+        .map(|arg| match arg {
+            LoxValue::Number(n) => Ok(*n),
+            _ => Err(native_error(msg)),
+        })
+        .collect()
+}
+
+fn index(val: &LoxValue) -> Option<usize> {
+    match val {
+        LoxValue::Number(n) if n.fract() == 0.0 && *n >= 0.0 => Some(*n as usize),
+        _ => None,
+    }
+}
+
+fn code_point(val: &LoxValue) -> Option<char> {
+    match val {
+        LoxValue::Number(n) if n.fract() == 0.0 && *n >= 0.0 => char::from_u32(*n as u32),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::{errors::ErrorReporter, interpreter::InterpreterOptions};
+
+    #[test]
+    pub fn deterministic_natives_repeat() {
+        let mut runs = Vec::new();
+        for seed in &[7, 7, 8] {
+            let reporter = ErrorReporter::new();
+            let options = InterpreterOptions {
+                deterministic: true,
+                seed: Some(*seed),
+                ..InterpreterOptions::default()
+            };
+            let mut interpreter = Interpreter::new(&reporter, options);
+            let mut values = Vec::new();
+            for name in ["random", "random", "random", "clock", "clock"] {
+                values.push(interpreter.call(name, &[]).unwrap());
+            }
+            runs.push(values);
+        }
+        assert_eq!(runs[0], runs[1]);
+        assert_ne!(runs[0][..3], runs[2][..3]);
+        assert_eq!(runs[0][3..], [LoxValue::Number(1.0), LoxValue::Number(2.0)]);
+        assert!(runs[0][..3]
+            .iter()
+            .all(|v| matches!(v, LoxValue::Number(n) if (0.0..1.0).contains(n))));
+    }
+
+    #[test]
+    pub fn code_points_round_trip() {
+        assert_eq!(code_point(&LoxValue::Number(65.0)), Some('A'));
+        assert_eq!(code_point(&LoxValue::Number(0x1F600 as f64)), Some('😀'));
+        assert_eq!(code_point(&LoxValue::Number(65.5)), None);
+        assert_eq!(code_point(&LoxValue::Number(0xD800 as f64)), None);
+        assert_eq!(code_point(&LoxValue::String("A".into())), None);
+    }
+}
@@ -0,0 +1,264 @@
+use std::{convert::TryFrom, sync::Arc};
+
+use crate::{
+    ast::{Ast, Expr, ExprId, FunctionStmt, IfStmt, Stmt, StmtId, WhileStmt},
+    interpreter::is_truthy,
+    loxvalue::LoxValue,
+    tokens::{TokenLiteral, TokenType},
+};
+
+// Runs between the resolver and the interpreter, rewriting nodes in place. Folded
+// expressions keep their ids, so the variables the resolver recorded against them are
+// still found. Anything that would be a runtime error is left alone so that it's still
+// reported when (and if) it runs.
+pub struct Optimizer<'a> {
+    ast: &'a mut Ast,
+}
+
+impl<'a> Optimizer<'a> {
+    pub fn new(ast: &'a mut Ast) -> Self {
+        Optimizer { ast }
+    }
+
+    // Returns the statements that are left once unreachable ones have been dropped
+    pub fn optimize_stmts(&mut self, stmts: &[StmtId]) -> Vec<StmtId> {
+        let mut optimized = Vec::with_capacity(stmts.len());
+        for stmt in stmts {
+            self.optimize_stmt(*stmt);
+            optimized.push(*stmt);
+            if let Stmt::Return(_) | Stmt::Break(_) = self.ast[*stmt] {
+                break;
+            }
+        }
+        optimized
+    }
+
+    pub fn optimize_expr(&mut self, id: ExprId) {
+        match self.ast[id].clone() {
+            Expr::Assign(e) => self.optimize_expr(e.value),
+            Expr::Binary(_) => self.optimize_binary_chain(id),
+            Expr::Call(e) => {
+                self.optimize_expr(e.callee);
+                for arg in e.arguments {
+                    self.optimize_expr(arg);
+                }
+            }
+            Expr::Get(e) => self.optimize_expr(e.object),
+            Expr::Grouping(e) => {
+                self.optimize_expr(e);
+                if let Some(l) = self.literal(e) {
+                    self.ast.replace_expr(id, Expr::Literal(l));
+                }
+            }
+            Expr::Literal(_) | Expr::Super(_) | Expr::This(_) | Expr::Variable(_) => {}
+            Expr::Logical(e) => {
+                self.optimize_expr(e.left);
+                self.optimize_expr(e.right);
+                if let Some(left) = self.literal(e.left) {
+                    let short_circuits = truthy(&left) == (e.operator.token_type == TokenType::Or);
+                    if short_circuits {
+                        self.ast.replace_expr(id, Expr::Literal(left));
+                    } else {
+                        // The right operand keeps its own id, as it may have been resolved
+                        self.ast.replace_expr(id, Expr::Grouping(e.right));
+                    }
+                }
+            }
+            Expr::Set(e) => {
+                self.optimize_expr(e.object);
+                self.optimize_expr(e.value);
+            }
+            Expr::Unary(e) => {
+                self.optimize_expr(e.right);
+                if let Some(right) = self.literal(e.right) {
+                    if let Some(l) = fold_unary(&e.operator.token_type, &right) {
+                        self.ast.replace_expr(id, Expr::Literal(l));
+                    }
+                }
+            }
+        }
+    }
+
+    // A chain of binary operators nests down its left operands, as deep as it's long, so
+    // as in the interpreter it's walked down rather than recursed into, then folded from
+    // the innermost operator out
+    fn optimize_binary_chain(&mut self, id: ExprId) {
+        let mut chain = Vec::new();
+        let mut left = id;
+        while let Expr::Binary(e) = &self.ast[left] {
+            chain.push(left);
+            left = e.left;
+        }
+        self.optimize_expr(left);
+        for id in chain.into_iter().rev() {
+            if let Expr::Binary(e) = self.ast[id].clone() {
+                self.optimize_expr(e.right);
+                if let (Some(left), Some(right)) = (self.literal(e.left), self.literal(e.right)) {
+                    if let Some(l) = fold_binary(&e.operator.token_type, &left, &right) {
+                        self.ast.replace_expr(id, Expr::Literal(l));
+                    }
+                }
+            }
+        }
+    }
+
+    fn optimize_stmt(&mut self, id: StmtId) {
+        match self.ast[id].clone() {
+            Stmt::Block(stmts) => {
+                let stmts = self.optimize_stmts(&stmts);
+                self.ast.replace_stmt(id, Stmt::Block(stmts));
+            }
+            Stmt::Break(_) | Stmt::Debugger => {}
+            Stmt::Class(mut class) => {
+                for method in &mut class.methods {
+                    self.optimize_function(method);
+                }
+                self.ast.replace_stmt(id, Stmt::Class(class));
+            }
+            Stmt::Expression(e) | Stmt::Print(e) => self.optimize_expr(e),
+            Stmt::Function(mut function) => {
+                self.optimize_function(&mut function);
+                self.ast.replace_stmt(id, Stmt::Function(function));
+            }
+            Stmt::If(IfStmt {
+                condition,
+                then_branch,
+                else_branch,
+            }) => {
+                self.optimize_expr(condition);
+                self.optimize_stmt(then_branch);
+                if let Some(else_branch) = else_branch {
+                    self.optimize_stmt(else_branch);
+                }
+                if let Some(condition) = self.literal(condition) {
+                    let branch = if truthy(&condition) {
+                        Some(then_branch)
+                    } else {
+                        else_branch
+                    };
+                    let stmt = match branch {
+                        Some(branch) => self.ast[branch].clone(),
+                        None => Stmt::Block(Vec::new()),
+                    };
+                    self.ast.replace_stmt(id, stmt);
+                }
+            }
+            Stmt::Return(r) => self.optimize_expr(r.value),
+            Stmt::While(WhileStmt { condition, body }) => {
+                self.optimize_expr(condition);
+                self.optimize_stmt(body);
+                if matches!(self.literal(condition), Some(l) if !truthy(&l)) {
+                    self.ast.replace_stmt(id, Stmt::Block(Vec::new()));
+                }
+            }
+            Stmt::Var(v) => self.optimize_expr(v.initializer),
+            Stmt::Yield(y) => self.optimize_expr(y.value),
+        }
+    }
+
+    // Closures created from earlier REPL lines may share the declaration, in which case
+    // it's copied rather than changed under them
+    fn optimize_function(&mut self, function: &mut Arc<FunctionStmt>) {
+        let body = self.optimize_stmts(&function.body);
+        Arc::make_mut(function).body = body;
+    }
+
+    fn literal(&self, id: ExprId) -> Option<TokenLiteral> {
+        match &self.ast[id] {
+            Expr::Literal(l) => Some(l.clone()),
+            _ => None,
+        }
+    }
+}
+
+fn value(l: &TokenLiteral) -> LoxValue {
+    LoxValue::try_from(l).unwrap_or(LoxValue::Nil)
+}
+
+fn truthy(l: &TokenLiteral) -> bool {
+    is_truthy(&value(l))
+}
+
+fn boolean(b: bool) -> TokenLiteral {
+    if b {
+        TokenLiteral::True
+    } else {
+        TokenLiteral::False
+    }
+}
+
+// Mirrors Interpreter::evaluate_binary for the cases that can't fail
+fn fold_binary(
+    operator: &TokenType,
+    left: &TokenLiteral,
+    right: &TokenLiteral,
+) -> Option<TokenLiteral> {
+    use TokenLiteral::{Number, String};
+    match (operator, left, right) {
+        (TokenType::Minus, Number(l), Number(r)) => Some(Number(l - r)),
+        (TokenType::Slash, Number(l), Number(r)) if *r != 0.0 => Some(Number(l / r)),
+        (TokenType::Star, Number(l), Number(r)) => Some(Number(l * r)),
+        (TokenType::Plus, Number(l), Number(r)) => Some(Number(l + r)),
+        (TokenType::Plus, String(l), String(r)) => Some(String(format!("{}{}", l, r).into())),
+        (TokenType::Greater, Number(l), Number(r)) => Some(boolean(l > r)),
+        (TokenType::GreaterEqual, Number(l), Number(r)) => Some(boolean(l >= r)),
+        (TokenType::Less, Number(l), Number(r)) => Some(boolean(l < r)),
+        (TokenType::LessEqual, Number(l), Number(r)) => Some(boolean(l <= r)),
+        (TokenType::BangEqual, l, r) => Some(boolean(value(l) != value(r))),
+        (TokenType::EqualEqual, l, r) => Some(boolean(value(l) == value(r))),
+        _ => None,
+    }
+}
+
+fn fold_unary(operator: &TokenType, right: &TokenLiteral) -> Option<TokenLiteral> {
+    match (operator, right) {
+        (TokenType::Minus, TokenLiteral::Number(n)) => Some(TokenLiteral::Number(n * -1.0)),
+        (TokenType::Bang, right) => Some(boolean(!truthy(right))),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::{ast::PrettyPrinter, errors::ErrorReporter, parser::Parser, scanner::Scanner};
+
+    fn optimize(src: &str) -> String {
+        let reporter = ErrorReporter::new();
+        let mut ast = Ast::new();
+        let stmts = Parser::new(Scanner::new(src, &reporter), &mut ast, &reporter).parse_stmts();
+        assert!(!reporter.had_error());
+        let stmts = Optimizer::new(&mut ast).optimize_stmts(&stmts);
+        let pp = PrettyPrinter::new(&ast);
+        stmts.iter().map(|s| pp.print_stmt(*s)).collect()
+    }
+
+    #[test]
+    pub fn folds_constants() {
+        assert_eq!(optimize("print 1 + 2 * (3 - 1);"), "print 5;");
+        assert_eq!(optimize("print \"a\" + \"b\" == \"ab\";"), "print true;");
+        assert_eq!(
+            optimize("print !nil and -2 < x;"),
+            "print (group (< -2 x));"
+        );
+        // Left for the interpreter to report
+        assert_eq!(optimize("print 1 / 0;"), "print (/ 1 0);");
+        assert_eq!(optimize("print -\"a\";"), "print (- \"a\");");
+    }
+
+    #[test]
+    pub fn folds_long_chains_without_recursing() {
+        let src = format!("print 0{};", " + 1".repeat(200_000));
+        assert_eq!(optimize(&src), "print 200000;");
+    }
+
+    #[test]
+    pub fn removes_dead_code() {
+        assert_eq!(optimize("if (1 > 2) print 1; else print 2;"), "print 2;");
+        assert_eq!(optimize("while (false) print 1; print 2;"), "print 2;");
+        assert_eq!(
+            optimize("fun f() { return 1; print 2; }"),
+            "fun f() {return 1;}"
+        );
+    }
+}
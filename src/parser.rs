@@ -1,19 +1,23 @@
+//! Builds statements from tokens, adding them to an [`Ast`]. Errors are
+//! reported and parsing carries on from the next statement, so one run finds as many as
+//! it can.
+
+use std::{iter::Peekable, sync::Arc};
+
 use thiserror::Error;
 
 use crate::{
     ast::{
-        AssignExpr, BinaryExpr, CallExpr, ClassStmt, Expr, FunctionStmt, GetExpr, IfStmt,
-        LogicalExpr, ReturnStmt, SetExpr, Stmt, SuperExpr, UnaryExpr, VarStmt, WhileStmt,
+        AssignExpr, Ast, BinaryExpr, CallExpr, ClassStmt, Expr, ExprId, FunctionStmt, GetExpr,
+        IfStmt, LogicalExpr, ReturnStmt, SetExpr, Stmt, StmtId, SuperExpr, ThisExpr, UnaryExpr,
+        VarStmt, VariableExpr, WhileStmt, YieldStmt,
     },
     errors::ErrorReporter,
-    tokens::{Token, TokenLiteral, TokenType},
+    tokens::{Span, Token, TokenLiteral, TokenType},
 };
 
 #[derive(Debug, Error)]
 pub enum ParseError {
-    #[error("Break statement outside of a loop")]
-    BreakOutsideOfLoop,
-
     #[error("Expect property name after '.'")]
     CallExpectPropertyName,
 
@@ -29,8 +33,8 @@ pub enum ParseError {
     #[error("Expect '{{' after class name")]
     ClassExpectLeftBrace,
 
-    #[error("Expect '}}' after class definition")]
-    ClassExpectRightBrace,
+    #[error("Expect '}}' to close the class body opened on line {0}")]
+    ClassExpectRightBrace(usize),
 
     #[error("Expect superclass name")]
     ClassExpectSuperClass,
@@ -77,8 +81,8 @@ pub enum ParseError {
     #[error("Invalid assignment target")]
     InvalidAssignmentTarget,
 
-    #[error("Expect '}}' at end of block")]
-    RightBraceExpected,
+    #[error("Expect '}}' to close the block opened on line {0}")]
+    RightBraceExpected(usize),
 
     #[error("Expect ')' after expression")]
     RightParenMissing,
@@ -86,12 +90,21 @@ pub enum ParseError {
     #[error("Expect ';' after statement")]
     SemiColonExpected,
 
+    #[error("Expect ';' after statement starting on line {0}")]
+    SemiColonExpectedAfter(usize),
+
     #[error("Expect '.' after super")]
     SuperExpectDot,
 
     #[error("Expect superclass method name")]
     SuperExpectMethodName,
 
+    #[error("Expect type name")]
+    TypeNameExpected,
+
+    #[error("Too deeply nested (more than {0} levels)")]
+    TooDeeplyNested(usize),
+
     #[error("Expect n name")]
     VariableNameExpected,
 
@@ -102,25 +115,99 @@ pub enum ParseError {
     WhileStmtRightParenExpected,
 }
 
-pub struct Parser<'a> {
-    tokens: Vec<Token>,
-    current: usize,
-    loop_depth: u32,
+impl ParseError {
+    // Stays the same from one version to the next, for looking up with `rlox --explain`.
+    // Both kinds of missing ';' are the same problem, so they share one.
+    pub fn code(&self) -> &'static str {
+        match self {
+            ParseError::CallExpectPropertyName => "E0101",
+            ParseError::CallRightParenExpected => "E0102",
+            ParseError::CallTooManyArgs => "E0103",
+            ParseError::ClassExpectIdentifier => "E0104",
+            ParseError::ClassExpectLeftBrace => "E0105",
+            ParseError::ClassExpectRightBrace(_) => "E0106",
+            ParseError::ClassExpectSuperClass => "E0107",
+            ParseError::ColonExpectedInTernary => "E0108",
+            ParseError::ExpressionExpected => "E0109",
+            ParseError::ForStmtLeftParenExpected => "E0110",
+            ParseError::ForStmtRightParenExpected => "E0111",
+            ParseError::ForStmtSemiColonExpected => "E0112",
+            ParseError::FunctionExpectBlockOpen => "E0113",
+            ParseError::FunctionExpectIdentifier => "E0114",
+            ParseError::FunctionExpectLeftParen => "E0115",
+            ParseError::FunctionExpectParamName => "E0116",
+            ParseError::FunctionExpectRightParen => "E0117",
+            ParseError::FunctionTooManyArgs => "E0118",
+            ParseError::IfStmtLeftParenExpected => "E0119",
+            ParseError::IfStmtRightParenExpected => "E0120",
+            ParseError::InvalidAssignmentTarget => "E0121",
+            ParseError::RightBraceExpected(_) => "E0122",
+            ParseError::RightParenMissing => "E0123",
+            ParseError::SemiColonExpected | ParseError::SemiColonExpectedAfter(_) => "E0124",
+            ParseError::SuperExpectDot => "E0125",
+            ParseError::SuperExpectMethodName => "E0126",
+            ParseError::TypeNameExpected => "E0127",
+            ParseError::TooDeeplyNested(_) => "E0128",
+            ParseError::VariableNameExpected => "E0129",
+            ParseError::WhileStmtLeftParenExpected => "E0130",
+            ParseError::WhileStmtRightParenExpected => "E0131",
+        }
+    }
+}
+
+// Enough for any sensible program, while leaving plenty of stack to spare
+pub const DEFAULT_MAX_DEPTH: usize = 256;
+
+// Reads tokens as it goes, so a Scanner can be passed in directly. The token stream must
+// end with an Eof, as the scanner's does.
+pub struct Parser<'a, I: Iterator<Item = Token>> {
+    tokens: Peekable<I>,
+    // The last token consumed
+    previous: Option<Token>,
+    // Whether the function being parsed has yielded so far
+    yields: bool,
+    // How many blocks the parser is inside, so that recovering from an error can stop at
+    // the end of the innermost
+    block_depth: u32,
+    // Line that the innermost statement being parsed starts on, and its byte offset
+    stmt_line: usize,
+    stmt_start: usize,
+    // How deeply statements, blocks and expressions are nested, which is limited so
+    // that parsing can't overflow the stack
+    depth: usize,
+    max_depth: usize,
+    ast: &'a mut Ast,
     error_reporter: &'a ErrorReporter,
 }
 
-impl<'a> Parser<'a> {
-    pub fn new(tokens: Vec<Token>, error_reporter: &'a ErrorReporter) -> Self {
+impl<'a, I: Iterator<Item = Token>> Parser<'a, I> {
+    // Parsed nodes are added to `ast`, which may already hold earlier parses
+    pub fn new(
+        tokens: impl IntoIterator<IntoIter = I>,
+        ast: &'a mut Ast,
+        error_reporter: &'a ErrorReporter,
+    ) -> Self {
         Parser {
-            tokens,
-            current: 0,
-            loop_depth: 0,
+            tokens: tokens.into_iter().peekable(),
+            previous: None,
+            yields: false,
+            block_depth: 0,
+            stmt_line: 0,
+            stmt_start: 0,
+            depth: 0,
+            max_depth: DEFAULT_MAX_DEPTH,
+            ast,
             error_reporter,
         }
     }
 
-    pub fn parse_stmts(&mut self) -> Vec<Stmt> {
-        let mut statements = Vec::<Stmt>::new();
+    pub fn with_max_depth(mut self, max_depth: usize) -> Self {
+        self.max_depth = max_depth;
+        self
+    }
+
+    pub fn parse_stmts(&mut self) -> Vec<StmtId> {
+        let mut statements = Vec::<StmtId>::new();
         while !self.is_at_end() {
             if let Ok(s) = self.declaration() {
                 statements.push(s);
@@ -129,97 +216,180 @@ impl<'a> Parser<'a> {
         statements
     }
 
-    pub fn parse_expr(&mut self) -> Result<Expr, ParseError> {
+    pub fn parse_expr(&mut self) -> Result<ExprId, ParseError> {
         self.expression_list()
     }
 
-    fn declaration(&mut self) -> Result<Stmt, ParseError> {
+    fn declaration(&mut self) -> Result<StmtId, ParseError> {
+        let enclosing = self.start_stmt();
+        let stmt_result = self.declaration_inner();
+        (self.stmt_line, self.stmt_start) = enclosing;
+        stmt_result
+    }
+
+    fn declaration_inner(&mut self) -> Result<StmtId, ParseError> {
         let stmt_result = if self.match_any(&[TokenType::Class]) {
             self.class_declaration()
         } else if self.match_any(&[TokenType::Fun]) {
-            Ok(Stmt::Function(self.function()?))
+            let function = Arc::new(self.function()?);
+            Ok(self.add_stmt(Stmt::Function(function)))
         } else if self.match_any(&[TokenType::Var]) {
             self.var_declaration()
         } else {
             self.statement()
         };
         if stmt_result.is_err() {
-            self.synchronize();
+            if self.error_reporter.too_many_errors() {
+                // Nothing after this would be reported, so there's no use parsing it
+                while !self.is_at_end() {
+                    self.advance();
+                }
+            } else {
+                self.synchronize();
+            }
         }
         stmt_result
     }
 
-    fn class_declaration(&mut self) -> Result<Stmt, ParseError> {
+    fn class_declaration(&mut self) -> Result<StmtId, ParseError> {
+        let doc = doc_comment(&self.previous());
         let name = self.consume(TokenType::Identifier, ParseError::ClassExpectIdentifier)?;
 
         let superclass = if self.match_any(&[TokenType::Less]) {
             self.consume(TokenType::Identifier, ParseError::ClassExpectSuperClass)?;
-            Some(Expr::Variable(self.previous()))
+            let name = self.previous();
+            let start = name.span.start;
+            Some(self.add_expr(Expr::Variable(VariableExpr { name }), start))
         } else {
             None
         };
 
-        self.consume(TokenType::LeftBrace, ParseError::ClassExpectLeftBrace)?;
+        let opened = self.consume(TokenType::LeftBrace, ParseError::ClassExpectLeftBrace)?;
 
         let mut methods = Vec::new();
         while !self.check(&TokenType::RightBrace) && !self.is_at_end() {
-            methods.push(self.function()?);
+            methods.push(Arc::new(self.function()?));
         }
 
-        self.consume(TokenType::RightBrace, ParseError::ClassExpectRightBrace)?;
+        self.consume(
+            TokenType::RightBrace,
+            ParseError::ClassExpectRightBrace(opened.line),
+        )?;
 
-        Ok(Stmt::Class(Box::new(ClassStmt {
+        Ok(self.add_stmt(Stmt::Class(Box::new(ClassStmt {
             name,
+            doc,
             superclass,
             methods,
-        })))
+        }))))
     }
 
     fn function(&mut self) -> Result<FunctionStmt, ParseError> {
+        // Doc comments go before `fun`, or before a method's name
+        let doc = match self.previous.as_ref() {
+            Some(fun) if fun.token_type == TokenType::Fun => doc_comment(fun),
+            _ => doc_comment(&self.peek()),
+        };
         let name = self.consume(TokenType::Identifier, ParseError::FunctionExpectIdentifier)?;
         self.consume(TokenType::LeftParen, ParseError::FunctionExpectLeftParen)?;
         let mut params = Vec::<Token>::new();
+        let mut param_types = Vec::new();
         if !self.check(&TokenType::RightParen) {
             loop {
                 if params.len() > 255 {
-                    return Err(self.error_at(self.peek(), ParseError::FunctionTooManyArgs));
+                    return Err(self.error(ParseError::FunctionTooManyArgs));
                 }
                 params.push(
                     self.consume(TokenType::Identifier, ParseError::FunctionExpectParamName)?,
                 );
+                param_types.push(self.type_annotation()?);
                 if !self.match_any(&[TokenType::Comma]) {
                     break;
                 }
             }
         }
         self.consume(TokenType::RightParen, ParseError::FunctionExpectRightParen)?;
+        let return_type = if self.match_any(&[TokenType::Arrow]) {
+            Some(self.type_name()?)
+        } else {
+            None
+        };
         self.consume(TokenType::LeftBrace, ParseError::FunctionExpectBlockOpen)?;
-        let body = self.block()?;
-        Ok(FunctionStmt { name, params, body })
+        let enclosing_yields = std::mem::replace(&mut self.yields, false);
+        let body = self.block();
+        let is_generator = std::mem::replace(&mut self.yields, enclosing_yields);
+        Ok(FunctionStmt {
+            name,
+            params,
+            param_types,
+            return_type,
+            body: body?,
+            doc,
+            is_generator,
+        })
     }
 
-    fn var_declaration(&mut self) -> Result<Stmt, ParseError> {
+    fn var_declaration(&mut self) -> Result<StmtId, ParseError> {
         let name = self.consume(TokenType::Identifier, ParseError::VariableNameExpected)?;
-        let mut initializer = Expr::Literal(TokenLiteral::Nil);
-        if self.match_any(&[TokenType::Equal]) {
-            initializer = self.expression()?;
-        }
-        self.consume(TokenType::SemiColon, ParseError::SemiColonExpected)?;
-        Ok(Stmt::Var(VarStmt {
+        let type_annotation = self.type_annotation()?;
+        let initializer = if self.match_any(&[TokenType::Equal]) {
+            self.expression()?
+        } else {
+            self.implicit(TokenLiteral::Nil)
+        };
+        self.consume_semicolon()?;
+        Ok(self.add_stmt(Stmt::Var(VarStmt {
             name,
-            initializer: Box::new(initializer),
-        }))
+            type_annotation,
+            initializer,
+        })))
     }
 
-    fn statement(&mut self) -> Result<Stmt, ParseError> {
+    // An optional `: Type` after a variable or parameter name
+    fn type_annotation(&mut self) -> Result<Option<Token>, ParseError> {
+        if self.match_any(&[TokenType::Colon]) {
+            Ok(Some(self.type_name()?))
+        } else {
+            Ok(None)
+        }
+    }
+
+    // `nil` is a keyword, so it's allowed as well as identifiers
+    fn type_name(&mut self) -> Result<Token, ParseError> {
+        if self.match_any(&[TokenType::Identifier, TokenType::Nil]) {
+            Ok(self.previous())
+        } else {
+            Err(self.error(ParseError::TypeNameExpected))
+        }
+    }
+
+    fn statement(&mut self) -> Result<StmtId, ParseError> {
+        let enclosing = self.start_stmt();
+        let stmt_result = self.nested(Self::statement_inner);
+        (self.stmt_line, self.stmt_start) = enclosing;
+        stmt_result
+    }
+
+    // Returns where the enclosing statement started, to go back to once this one's parsed
+    fn start_stmt(&mut self) -> (usize, usize) {
+        let next = self.peek();
+        let line = std::mem::replace(&mut self.stmt_line, next.line);
+        (
+            line,
+            std::mem::replace(&mut self.stmt_start, next.span.start),
+        )
+    }
+
+    fn statement_inner(&mut self) -> Result<StmtId, ParseError> {
         if self.match_any(&[TokenType::Break]) {
             return self.break_statement();
         }
+        if self.match_any(&[TokenType::Debugger]) {
+            self.consume_semicolon()?;
+            return Ok(self.add_stmt(Stmt::Debugger));
+        }
         if self.match_any(&[TokenType::For]) {
-            self.loop_depth += 1;
-            let result = self.for_statement();
-            self.loop_depth -= 1;
-            return result;
+            return self.for_statement();
         }
         if self.match_any(&[TokenType::If]) {
             return self.if_statement();
@@ -231,26 +401,25 @@ impl<'a> Parser<'a> {
             return self.return_statement();
         }
         if self.match_any(&[TokenType::While]) {
-            self.loop_depth += 1;
-            let result = self.while_statement();
-            self.loop_depth -= 1;
-            return result;
+            return self.while_statement();
+        }
+        if self.match_any(&[TokenType::Yield]) {
+            return self.yield_statement();
         }
         if self.match_any(&[TokenType::LeftBrace]) {
-            return Ok(Stmt::Block(self.block()?));
+            let block = self.block()?;
+            return Ok(self.add_stmt(Stmt::Block(block)));
         }
         self.expression_statement()
     }
 
-    fn break_statement(&mut self) -> Result<Stmt, ParseError> {
-        if self.loop_depth == 0 {
-            return Err(self.error(ParseError::BreakOutsideOfLoop));
-        }
-        self.consume(TokenType::SemiColon, ParseError::SemiColonExpected)?;
-        Ok(Stmt::Break)
+    fn break_statement(&mut self) -> Result<StmtId, ParseError> {
+        let keyword = self.previous();
+        self.consume_semicolon()?;
+        Ok(self.add_stmt(Stmt::Break(keyword)))
     }
 
-    fn for_statement(&mut self) -> Result<Stmt, ParseError> {
+    fn for_statement(&mut self) -> Result<StmtId, ParseError> {
         self.consume(TokenType::LeftParen, ParseError::ForStmtLeftParenExpected)?;
         let initializer = if self.match_any(&[TokenType::SemiColon]) {
             None
@@ -260,13 +429,14 @@ impl<'a> Parser<'a> {
             Some(self.expression_statement()?)
         };
 
-        let mut condition = Expr::Literal(TokenLiteral::True);
-        if !self.check(&TokenType::SemiColon) {
-            condition = self.expression()?;
-        }
+        let condition = if !self.check(&TokenType::SemiColon) {
+            self.expression()?
+        } else {
+            self.implicit(TokenLiteral::True)
+        };
         self.consume(TokenType::SemiColon, ParseError::ForStmtSemiColonExpected)?;
 
-        let mut increment: Option<Expr> = None;
+        let mut increment: Option<ExprId> = None;
         if !self.check(&TokenType::RightParen) {
             increment = Some(self.expression()?);
         }
@@ -275,141 +445,190 @@ impl<'a> Parser<'a> {
         let mut body = self.statement()?;
 
         if let Some(inc) = increment {
-            body = Stmt::Block(vec![body, Stmt::Expression(inc)]);
+            let inc = self.add_stmt(Stmt::Expression(inc));
+            body = self.add_stmt(Stmt::Block(vec![body, inc]));
         }
 
-        body = Stmt::While(WhileStmt {
-            condition: Box::new(condition),
-            body: Box::new(body),
-        });
+        body = self.add_stmt(Stmt::While(WhileStmt { condition, body }));
 
         if let Some(init) = initializer {
-            body = Stmt::Block(vec![init, body]);
+            body = self.add_stmt(Stmt::Block(vec![init, body]));
         }
 
         Ok(body)
     }
 
-    fn if_statement(&mut self) -> Result<Stmt, ParseError> {
+    fn if_statement(&mut self) -> Result<StmtId, ParseError> {
         self.consume(TokenType::LeftParen, ParseError::IfStmtLeftParenExpected)?;
-        let condition = Box::new(self.expression_list()?);
+        let condition = self.expression_list()?;
         self.consume(TokenType::RightParen, ParseError::IfStmtRightParenExpected)?;
-        let then_branch = Box::new(self.statement()?);
-        let mut else_branch: Option<Box<Stmt>> = None;
+        let then_branch = self.statement()?;
+        let mut else_branch: Option<StmtId> = None;
         if self.match_any(&[TokenType::Else]) {
-            else_branch = Some(Box::new(self.statement()?));
+            else_branch = Some(self.statement()?);
         }
-        Ok(Stmt::If(IfStmt {
+        Ok(self.add_stmt(Stmt::If(IfStmt {
             condition,
             then_branch,
             else_branch,
-        }))
+        })))
     }
 
-    fn print_statement(&mut self) -> Result<Stmt, ParseError> {
+    fn print_statement(&mut self) -> Result<StmtId, ParseError> {
         let expr = self.expression_list()?;
-        self.consume(TokenType::SemiColon, ParseError::SemiColonExpected)?;
-        Ok(Stmt::Print(expr))
+        self.consume_semicolon()?;
+        Ok(self.add_stmt(Stmt::Print(expr)))
     }
 
-    fn return_statement(&mut self) -> Result<Stmt, ParseError> {
+    fn return_statement(&mut self) -> Result<StmtId, ParseError> {
         let keyword = self.previous();
-        let mut value = Expr::Literal(TokenLiteral::Nil);
-        if !self.check(&TokenType::SemiColon) {
-            value = self.expression_list()?;
-        }
-        self.consume(TokenType::SemiColon, ParseError::SemiColonExpected)?;
-        Ok(Stmt::Return(ReturnStmt {
-            keyword,
-            value: Box::new(value),
-        }))
+        let value = if !self.check(&TokenType::SemiColon) {
+            self.expression_list()?
+        } else {
+            self.implicit(TokenLiteral::Nil)
+        };
+        self.consume_semicolon()?;
+        Ok(self.add_stmt(Stmt::Return(ReturnStmt { keyword, value })))
     }
 
-    fn while_statement(&mut self) -> Result<Stmt, ParseError> {
+    // Where a yield is allowed is checked by the resolver
+    fn yield_statement(&mut self) -> Result<StmtId, ParseError> {
+        let keyword = self.previous();
+        let value = if !self.check(&TokenType::SemiColon) {
+            self.expression_list()?
+        } else {
+            self.implicit(TokenLiteral::Nil)
+        };
+        self.consume_semicolon()?;
+        self.yields = true;
+        Ok(self.add_stmt(Stmt::Yield(YieldStmt { keyword, value })))
+    }
+
+    fn while_statement(&mut self) -> Result<StmtId, ParseError> {
         self.consume(TokenType::LeftParen, ParseError::WhileStmtLeftParenExpected)?;
-        let condition = Box::new(self.expression_list()?);
+        let condition = self.expression_list()?;
         self.consume(
             TokenType::RightParen,
             ParseError::WhileStmtRightParenExpected,
         )?;
-        let body = Box::new(self.statement()?);
+        let body = self.statement()?;
+
+        Ok(self.add_stmt(Stmt::While(WhileStmt { condition, body })))
+    }
+
+    fn add_stmt(&mut self, stmt: Stmt) -> StmtId {
+        let span = self.span_from(self.stmt_start);
+        self.ast.add_stmt(stmt, self.stmt_line, span)
+    }
+
+    fn add_expr(&mut self, expr: Expr, start: usize) -> ExprId {
+        let span = self.span_from(start);
+        self.ast.add_expr(expr, span)
+    }
+
+    // Stands in for an expression that was left out, such as a missing initializer
+    fn implicit(&mut self, literal: TokenLiteral) -> ExprId {
+        let start = self.peek().span.start;
+        self.ast
+            .add_expr(Expr::Literal(literal), Span { start, end: start })
+    }
+
+    // Up to the end of the last token consumed
+    fn span_from(&self, start: usize) -> Span {
+        let end = self.previous.as_ref().map_or(start, |token| token.span.end);
+        Span { start, end }
+    }
+
+    fn start_of(&self, expr: ExprId) -> usize {
+        self.ast.expr_span(expr).start
+    }
 
-        Ok(Stmt::While(WhileStmt { condition, body }))
+    // A statement that fails to parse has already been reported and skipped over, so the
+    // rest of the block is still parsed to find any other errors
+    fn block(&mut self) -> Result<Vec<StmtId>, ParseError> {
+        self.nested(Self::block_inner)
     }
 
-    fn block(&mut self) -> Result<Vec<Stmt>, ParseError> {
-        let mut stmts: Vec<Stmt> = Vec::new();
+    // Called just after the opening brace
+    fn block_inner(&mut self) -> Result<Vec<StmtId>, ParseError> {
+        let opened = self.previous().line;
+        let mut stmts: Vec<StmtId> = Vec::new();
 
+        self.block_depth += 1;
         while !self.check(&TokenType::RightBrace) && !self.is_at_end() {
-            stmts.push(self.declaration()?);
+            if let Ok(stmt) = self.declaration() {
+                stmts.push(stmt);
+            }
         }
-        self.consume(TokenType::RightBrace, ParseError::RightBraceExpected)?;
+        self.block_depth -= 1;
+        self.consume(
+            TokenType::RightBrace,
+            ParseError::RightBraceExpected(opened),
+        )?;
         Ok(stmts)
     }
 
-    fn expression_statement(&mut self) -> Result<Stmt, ParseError> {
+    fn expression_statement(&mut self) -> Result<StmtId, ParseError> {
         let expr = self.expression_list()?;
-        self.consume(TokenType::SemiColon, ParseError::SemiColonExpected)?;
-        Ok(Stmt::Expression(expr))
+        self.consume_semicolon()?;
+        Ok(self.add_stmt(Stmt::Expression(expr)))
     }
 
-    fn expression_list(&mut self) -> Result<Expr, ParseError> {
+    fn expression_list(&mut self) -> Result<ExprId, ParseError> {
         let mut expr = self.ternary_conditional()?;
         while self.match_any(&[TokenType::Comma]) {
             let operator = self.previous();
-            let right = Box::new(self.ternary_conditional()?);
-            expr = Expr::Binary(BinaryExpr {
-                left: Box::new(expr),
-                operator,
-                right,
-            });
+            let right = self.ternary_conditional()?;
+            expr = self.binary(expr, operator, right);
         }
         Ok(expr)
     }
 
-    fn ternary_conditional(&mut self) -> Result<Expr, ParseError> {
+    fn ternary_conditional(&mut self) -> Result<ExprId, ParseError> {
         let mut expr = self.expression()?;
         while self.match_any(&[TokenType::QuestionMark]) {
             let operator = self.previous();
             let true_expr = self.expression()?;
             let colon_op = self.consume(TokenType::Colon, ParseError::ColonExpectedInTernary)?;
             let false_expr = self.expression()?;
-            let expr_options = Expr::Binary(BinaryExpr {
-                left: Box::new(true_expr),
-                operator: colon_op,
-                right: Box::new(false_expr),
-            });
-            expr = Expr::Binary(BinaryExpr {
-                left: Box::new(expr),
-                operator,
-                right: Box::new(expr_options),
-            });
+            let expr_options = self.binary(true_expr, colon_op, false_expr);
+            expr = self.binary(expr, operator, expr_options);
         }
         Ok(expr)
     }
 
-    fn expression(&mut self) -> Result<Expr, ParseError> {
-        self.assignment()
+    fn expression(&mut self) -> Result<ExprId, ParseError> {
+        self.nested(Self::assignment)
     }
 
-    fn assignment(&mut self) -> Result<Expr, ParseError> {
+    fn assignment(&mut self) -> Result<ExprId, ParseError> {
         let expr = self.or()?;
         if self.match_any(&[TokenType::Equal]) {
             let eq_token = self.previous();
-            let val = self.assignment()?;
-            match expr {
-                Expr::Variable(name) => {
-                    return Ok(Expr::Assign(AssignExpr {
-                        name,
-                        value: Box::new(val),
-                    }));
+            let value = self.assignment()?;
+            // The target becomes the assignment, keeping its id
+            match &self.ast[expr] {
+                Expr::Variable(VariableExpr { name }) => {
+                    let name = name.clone();
+                    self.ast
+                        .replace_expr(expr, Expr::Assign(AssignExpr { name, value }));
+                    self.ast
+                        .extend_expr_span(expr, self.ast.expr_span(value).end);
+                    return Ok(expr);
                 }
                 Expr::Get(GetExpr { name, object }) => {
-                    return Ok(Expr::Set(SetExpr {
-                        object,
-                        name,
-                        value: Box::new(val),
-                    }))
+                    let (name, object) = (name.clone(), *object);
+                    self.ast.replace_expr(
+                        expr,
+                        Expr::Set(SetExpr {
+                            object,
+                            name,
+                            value,
+                        }),
+                    );
+                    self.ast
+                        .extend_expr_span(expr, self.ast.expr_span(value).end);
+                    return Ok(expr);
                 }
                 _ => {}
             }
@@ -419,49 +638,53 @@ impl<'a> Parser<'a> {
         Ok(expr)
     }
 
-    fn or(&mut self) -> Result<Expr, ParseError> {
+    fn or(&mut self) -> Result<ExprId, ParseError> {
         let mut expr = self.and()?;
         while self.match_any(&[TokenType::Or]) {
             let operator = self.previous();
-            let right = Box::new(self.and()?);
-            expr = Expr::Logical(LogicalExpr {
-                left: Box::new(expr),
-                operator,
-                right,
-            });
+            let right = self.and()?;
+            let start = self.start_of(expr);
+            expr = self.add_expr(
+                Expr::Logical(LogicalExpr {
+                    left: expr,
+                    operator,
+                    right,
+                }),
+                start,
+            );
         }
         Ok(expr)
     }
 
-    fn and(&mut self) -> Result<Expr, ParseError> {
+    fn and(&mut self) -> Result<ExprId, ParseError> {
         let mut expr = self.equality()?;
         while self.match_any(&[TokenType::And]) {
             let operator = self.previous();
-            let right = Box::new(self.equality()?);
-            expr = Expr::Logical(LogicalExpr {
-                left: Box::new(expr),
-                operator,
-                right,
-            });
+            let right = self.equality()?;
+            let start = self.start_of(expr);
+            expr = self.add_expr(
+                Expr::Logical(LogicalExpr {
+                    left: expr,
+                    operator,
+                    right,
+                }),
+                start,
+            );
         }
         Ok(expr)
     }
 
-    fn equality(&mut self) -> Result<Expr, ParseError> {
+    fn equality(&mut self) -> Result<ExprId, ParseError> {
         let mut expr = self.comparison()?;
         while self.match_any(&[TokenType::BangEqual, TokenType::EqualEqual]) {
             let operator = self.previous();
-            let right = Box::new(self.comparison()?);
-            expr = Expr::Binary(BinaryExpr {
-                left: Box::new(expr),
-                operator,
-                right,
-            });
+            let right = self.comparison()?;
+            expr = self.binary(expr, operator, right);
         }
         Ok(expr)
     }
 
-    fn comparison(&mut self) -> Result<Expr, ParseError> {
+    fn comparison(&mut self) -> Result<ExprId, ParseError> {
         let mut expr = self.term()?;
         while self.match_any(&[
             TokenType::Greater,
@@ -470,56 +693,56 @@ impl<'a> Parser<'a> {
             TokenType::LessEqual,
         ]) {
             let operator = self.previous();
-            let right = Box::new(self.term()?);
-            expr = Expr::Binary(BinaryExpr {
-                left: Box::new(expr),
-                operator,
-                right,
-            });
+            let right = self.term()?;
+            expr = self.binary(expr, operator, right);
         }
         Ok(expr)
     }
 
-    fn term(&mut self) -> Result<Expr, ParseError> {
+    fn term(&mut self) -> Result<ExprId, ParseError> {
         let mut expr = self.factor()?;
         while self.match_any(&[TokenType::Minus, TokenType::Plus]) {
             let operator = self.previous();
-            let right = Box::new(self.factor()?);
-            expr = Expr::Binary(BinaryExpr {
-                left: Box::new(expr),
-                operator,
-                right,
-            });
+            let right = self.factor()?;
+            expr = self.binary(expr, operator, right);
         }
         Ok(expr)
     }
 
-    fn factor(&mut self) -> Result<Expr, ParseError> {
+    fn factor(&mut self) -> Result<ExprId, ParseError> {
         let mut expr = self.unary()?;
         while self.match_any(&[TokenType::Slash, TokenType::Star]) {
             let operator = self.previous();
-            let right = Box::new(self.unary()?);
-            expr = Expr::Binary(BinaryExpr {
-                left: Box::new(expr),
-                operator,
-                right,
-            });
+            let right = self.unary()?;
+            expr = self.binary(expr, operator, right);
         }
         Ok(expr)
     }
 
-    fn unary(&mut self) -> Result<Expr, ParseError> {
+    fn binary(&mut self, left: ExprId, operator: Token, right: ExprId) -> ExprId {
+        let start = self.start_of(left);
+        self.add_expr(
+            Expr::Binary(BinaryExpr {
+                left,
+                operator,
+                right,
+            }),
+            start,
+        )
+    }
+
+    fn unary(&mut self) -> Result<ExprId, ParseError> {
         if self.match_any(&[TokenType::Bang, TokenType::Minus]) {
-            Ok(Expr::Unary(UnaryExpr {
-                operator: self.previous(),
-                right: Box::new(self.unary()?),
-            }))
+            let operator = self.previous();
+            let start = operator.span.start;
+            let right = self.nested(Self::unary)?;
+            Ok(self.add_expr(Expr::Unary(UnaryExpr { operator, right }), start))
         } else {
             self.call()
         }
     }
 
-    fn call(&mut self) -> Result<Expr, ParseError> {
+    fn call(&mut self) -> Result<ExprId, ParseError> {
         let mut expr = self.primary()?;
 
         loop {
@@ -528,10 +751,8 @@ impl<'a> Parser<'a> {
             } else if self.match_any(&[TokenType::Dot]) {
                 let name =
                     self.consume(TokenType::Identifier, ParseError::CallExpectPropertyName)?;
-                expr = Expr::Get(GetExpr {
-                    name,
-                    object: Box::new(expr),
-                })
+                let start = self.start_of(expr);
+                expr = self.add_expr(Expr::Get(GetExpr { name, object: expr }), start)
             } else {
                 break;
             }
@@ -539,66 +760,111 @@ impl<'a> Parser<'a> {
         Ok(expr)
     }
 
-    fn finish_call(&mut self, callee: Expr) -> Result<Expr, ParseError> {
-        let mut arguments: Vec<Expr> = vec![];
+    fn finish_call(&mut self, callee: ExprId) -> Result<ExprId, ParseError> {
+        let mut arguments: Vec<ExprId> = vec![];
         if !self.check(&TokenType::RightParen) {
             loop {
                 if arguments.len() >= 255 {
-                    return Err(self.error_at(self.peek(), ParseError::CallTooManyArgs));
+                    return Err(self.error(ParseError::CallTooManyArgs));
+                }
+                match self.expression() {
+                    Ok(argument) => arguments.push(argument),
+                    Err(e) => {
+                        if !self.skip_in_parens(true) {
+                            return Err(e);
+                        }
+                    }
                 }
-                arguments.push(self.expression()?);
                 if !self.match_any(&[TokenType::Comma]) {
                     break;
                 }
             }
         }
         let paren = self.consume(TokenType::RightParen, ParseError::CallRightParenExpected)?;
-        Ok(Expr::Call(CallExpr {
-            callee: Box::new(callee),
-            paren,
-            arguments,
-        }))
+        let start = self.start_of(callee);
+        Ok(self.add_expr(
+            Expr::Call(CallExpr {
+                callee,
+                paren,
+                arguments,
+            }),
+            start,
+        ))
     }
 
-    fn primary(&mut self) -> Result<Expr, ParseError> {
+    fn primary(&mut self) -> Result<ExprId, ParseError> {
+        let start = self.peek().span.start;
         if self.match_any(&[TokenType::False]) {
-            return Ok(Expr::Literal(TokenLiteral::False));
+            return Ok(self.add_expr(Expr::Literal(TokenLiteral::False), start));
         }
         if self.match_any(&[TokenType::True]) {
-            return Ok(Expr::Literal(TokenLiteral::True));
+            return Ok(self.add_expr(Expr::Literal(TokenLiteral::True), start));
         }
         if self.match_any(&[TokenType::Nil]) {
-            return Ok(Expr::Literal(TokenLiteral::Nil));
+            return Ok(self.add_expr(Expr::Literal(TokenLiteral::Nil), start));
         }
 
         if self.match_any(&[TokenType::Number, TokenType::String]) {
-            return Ok(Expr::Literal(self.previous().literal));
+            let literal = self.previous().literal;
+            return Ok(self.add_expr(Expr::Literal(literal), start));
         }
 
         if self.match_any(&[TokenType::Super]) {
             let keyword = self.previous();
             self.consume(TokenType::Dot, ParseError::SuperExpectDot)?;
             let method = self.consume(TokenType::Identifier, ParseError::SuperExpectMethodName)?;
-            return Ok(Expr::Super(SuperExpr { keyword, method }));
+            return Ok(self.add_expr(Expr::Super(SuperExpr { keyword, method }), start));
         }
 
         if self.match_any(&[TokenType::This]) {
-            return Ok(Expr::This(self.previous()));
+            let keyword = self.previous();
+            return Ok(self.add_expr(Expr::This(ThisExpr { keyword }), start));
         }
 
         if self.match_any(&[TokenType::Identifier]) {
-            return Ok(Expr::Variable(self.previous()));
+            let name = self.previous();
+            return Ok(self.add_expr(Expr::Variable(VariableExpr { name }), start));
         }
 
         if self.match_any(&[TokenType::LeftParen]) {
-            let expr = self.expression()?;
+            let expr = match self.expression() {
+                Ok(expr) => expr,
+                // The error's been reported, and nothing will run, so nil stands in for
+                // the broken expression
+                Err(_) if self.skip_in_parens(false) => self.implicit(TokenLiteral::Nil),
+                Err(e) => return Err(e),
+            };
             self.consume(TokenType::RightParen, ParseError::RightParenMissing)?;
-            return Ok(Expr::Grouping(Box::new(expr)));
+            return Ok(self.add_expr(Expr::Grouping(expr), start));
         }
 
         Err(self.error(ParseError::ExpressionExpected))
     }
 
+    fn nested<T>(
+        &mut self,
+        parse: impl FnOnce(&mut Self) -> Result<T, ParseError>,
+    ) -> Result<T, ParseError> {
+        if self.depth >= self.max_depth {
+            return Err(self.error(ParseError::TooDeeplyNested(self.max_depth)));
+        }
+        self.depth += 1;
+        let result = parse(self);
+        self.depth -= 1;
+        result
+    }
+
+    // Says where the statement started if it's on an earlier line than the error, as
+    // the missing ';' may well be at the end of that line rather than this one
+    fn consume_semicolon(&mut self) -> Result<Token, ParseError> {
+        let error = if self.peek().line > self.stmt_line {
+            ParseError::SemiColonExpectedAfter(self.stmt_line)
+        } else {
+            ParseError::SemiColonExpected
+        };
+        self.consume(TokenType::SemiColon, error)
+    }
+
     fn consume(&mut self, tt: TokenType, error: ParseError) -> Result<Token, ParseError> {
         if self.check(&tt) {
             return Ok(self.advance());
@@ -616,7 +882,7 @@ impl<'a> Parser<'a> {
         false
     }
 
-    fn check(&self, tt: &TokenType) -> bool {
+    fn check(&mut self, tt: &TokenType) -> bool {
         if self.is_at_end() {
             return false;
         }
@@ -625,55 +891,232 @@ impl<'a> Parser<'a> {
 
     fn advance(&mut self) -> Token {
         if !self.is_at_end() {
-            self.current += 1;
+            self.previous = self.tokens.next();
         }
         self.previous()
     }
 
-    fn is_at_end(&self) -> bool {
-        if let TokenType::Eof = self.peek().token_type {
-            true
-        } else {
-            false
-        }
+    pub fn is_at_end(&mut self) -> bool {
+        matches!(self.peek().token_type, TokenType::Eof)
     }
 
-    fn peek(&self) -> Token {
-        self.tokens[self.current].clone()
+    fn peek(&mut self) -> Token {
+        self.tokens.peek().cloned().expect("tokens end with an Eof")
     }
 
     fn previous(&self) -> Token {
-        self.tokens[self.current - 1].clone()
+        self.previous.clone().expect("a token has been consumed")
     }
 
-    fn error(&self, error: ParseError) -> ParseError {
-        self.error_at(self.peek(), error)
+    fn error(&mut self, error: ParseError) -> ParseError {
+        let token = self.peek();
+        self.error_at(token, error)
     }
 
     fn error_at(&self, token: Token, error: ParseError) -> ParseError {
-        self.error_reporter.token_error(token, &error.to_string());
+        self.error_reporter
+            .token_error_with_code(error.code(), token, &error.to_string());
         error
     }
 
+    // Skips the rest of a broken expression inside parentheses, stopping before the
+    // closing paren (or the next comma, if `at_comma`). Returns false if the end of the
+    // statement came first.
+    fn skip_in_parens(&mut self, at_comma: bool) -> bool {
+        let mut depth = 0;
+        while !self.is_at_end() {
+            match self.peek().token_type {
+                TokenType::RightParen if depth == 0 => return true,
+                TokenType::Comma if depth == 0 && at_comma => return true,
+                TokenType::SemiColon | TokenType::RightBrace if depth == 0 => return false,
+                TokenType::LeftParen | TokenType::LeftBrace => depth += 1,
+                TokenType::RightParen | TokenType::RightBrace => depth -= 1,
+                _ => {}
+            }
+            self.advance();
+        }
+        false
+    }
+
+    // Skips to the start of the next statement. Braces opened along the way are skipped
+    // as a whole, but a closing brace that ends the enclosing block is left for it.
     fn synchronize(&mut self) {
-        self.advance();
+        let ends_block = |p: &mut Self| p.block_depth > 0 && p.check(&TokenType::RightBrace);
+        if ends_block(self) {
+            return;
+        }
+        let mut depth = 0;
+        if let TokenType::LeftBrace = self.advance().token_type {
+            depth += 1;
+        }
         while !self.is_at_end() {
-            if let TokenType::SemiColon = self.previous().token_type {
+            if depth == 0 && self.previous().token_type == TokenType::SemiColon {
+                return;
+            }
+            if depth == 0 && ends_block(self) {
                 return;
             }
 
+            match self.peek().token_type {
+                TokenType::LeftBrace => depth += 1,
+                TokenType::RightBrace if depth > 0 => depth -= 1,
+                _ => {}
+            }
+            if depth > 0 {
+                self.advance();
+                continue;
+            }
             match self.peek().token_type {
                 TokenType::Class
+                | TokenType::Debugger
                 | TokenType::For
                 | TokenType::Fun
                 | TokenType::If
                 | TokenType::Print
                 | TokenType::Return
                 | TokenType::Var
-                | TokenType::While => return,
+                | TokenType::While
+                | TokenType::Yield => return,
                 _ => {}
             }
             self.advance();
         }
     }
 }
+
+fn doc_comment(token: &Token) -> Option<String> {
+    token.trivia.as_ref()?.doc_comment()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::scanner::Scanner;
+
+    fn errors(src: &str) -> Vec<String> {
+        let reporter = ErrorReporter::new();
+        let mut ast = Ast::new();
+        Parser::new(Scanner::new(src, &reporter), &mut ast, &reporter).parse_stmts();
+        reporter.take_errors()
+    }
+
+    #[test]
+    pub fn spans_each_node() {
+        let src = "var a;\nif (a) print -a.b(1, 2) + 3;\nfor (;;) a = (a or 1) ? 2 : 3;";
+        let reporter = ErrorReporter::new();
+        let mut ast = Ast::new();
+        let stmts = Parser::new(Scanner::new(src, &reporter), &mut ast, &reporter).parse_stmts();
+        let text = |span: Span| &src[span.start..span.end];
+        let stmts: Vec<&str> = stmts.iter().map(|id| text(ast.stmt_span(*id))).collect();
+        assert_eq!(
+            stmts,
+            vec![
+                "var a;",
+                "if (a) print -a.b(1, 2) + 3;",
+                "for (;;) a = (a or 1) ? 2 : 3;"
+            ]
+        );
+        let mut exprs: Vec<&str> = ast
+            .expr_ids_since(Ast::new().mark())
+            .map(|id| text(ast.expr_span(id)))
+            .collect();
+        exprs.sort();
+        assert_eq!(
+            exprs,
+            vec![
+                "",
+                "",
+                "(a or 1)",
+                "-a.b(1, 2)",
+                "-a.b(1, 2) + 3",
+                "1",
+                "1",
+                "2",
+                "2",
+                "2 : 3",
+                "3",
+                "3",
+                "a",
+                "a",
+                "a",
+                "a = (a or 1)",
+                "a = (a or 1) ? 2 : 3",
+                "a or 1",
+                "a.b",
+                "a.b(1, 2)",
+            ]
+        );
+    }
+
+    // Test threads have small stacks, so these stay well under the default limit
+    #[test]
+    pub fn limits_nesting() {
+        let errors = |src: &str, max_depth| {
+            let reporter = ErrorReporter::new();
+            let mut ast = Ast::new();
+            Parser::new(Scanner::new(src, &reporter), &mut ast, &reporter)
+                .with_max_depth(max_depth)
+                .parse_stmts();
+            reporter.take_errors()
+        };
+        let nested = |depth| format!("print {}1{};", "(".repeat(depth), ")".repeat(depth));
+        assert!(errors(&nested(30), 32).is_empty());
+        assert_eq!(
+            errors(&format!("{} print 2;", nested(10_000)), 32),
+            vec!["[line 1:38] Error  at '(': Too deeply nested (more than 32 levels)"]
+        );
+        assert_eq!(errors(&"-".repeat(10_000), 32).len(), 1);
+        assert_eq!(errors("{{{}}}", 4).len(), 1);
+    }
+
+    #[test]
+    pub fn points_at_unclosed_braces() {
+        assert_eq!(
+            errors("fun f() {\n  if (true) {\n    print 1;\n  \n}\n"),
+            vec!["[line 6:1] Error  at end: Expect '}' to close the block opened on line 1"]
+        );
+        assert_eq!(
+            errors("class A {\n  f() {}\n"),
+            vec!["[line 3:1] Error  at end: Expect '}' to close the class body opened on line 1"]
+        );
+    }
+
+    #[test]
+    pub fn reports_every_independent_error() {
+        let src = "fun f() {
+  print 1 +;
+  var x = 1
+  print x;
+}
+print f(1 +, 2 +);
+print (1 * ) + 2;
+{ print 1 }
+print \"end\";";
+        assert_eq!(
+            errors(src),
+            vec![
+                "[line 2:12] Error  at ';': Expect expression",
+                "[line 4:3] Error  at 'print': Expect ';' after statement starting on line 3",
+                "[line 6:12] Error  at ',': Expect expression",
+                "[line 6:17] Error  at ')': Expect expression",
+                "[line 7:12] Error  at ')': Expect expression",
+                "[line 8:11] Error  at '}': Expect ';' after statement",
+            ]
+        );
+    }
+
+    #[test]
+    pub fn stops_at_the_error_limit() {
+        let reporter = ErrorReporter::new();
+        reporter.set_max_errors(Some(2));
+        let mut ast = Ast::new();
+        let stmts = Parser::new(
+            Scanner::new("print 1 +;\nprint (;\nprint 2 *;\nprint 3;", &reporter),
+            &mut ast,
+            &reporter,
+        )
+        .parse_stmts();
+        assert!(stmts.is_empty());
+        assert_eq!(reporter.take_errors().len(), 2);
+    }
+}
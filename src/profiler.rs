@@ -0,0 +1,134 @@
+use std::{
+    collections::HashMap,
+    fmt::Write,
+    time::{Duration, Instant},
+};
+
+// Functions are told apart by the line they're defined on as well as their name, as
+// different functions can share a name. Natives aren't defined on any line.
+#[derive(Clone, Debug, Eq, Hash, PartialEq)]
+pub struct FunctionKey {
+    pub name: String,
+    pub line: Option<usize>,
+}
+
+#[derive(Clone, Copy, Debug, Default)]
+pub struct FunctionProfile {
+    pub calls: usize,
+    // Time from entering the function to returning, counted once for recursive calls
+    pub total: Duration,
+    // Time spent in the function itself, not in any functions it calls
+    pub own: Duration,
+    active: usize,
+}
+
+struct Frame {
+    key: FunctionKey,
+    start: Instant,
+    children: Duration,
+}
+
+#[derive(Default)]
+pub struct Profiler {
+    functions: HashMap<FunctionKey, FunctionProfile>,
+    stack: Vec<Frame>,
+}
+
+impl Profiler {
+    pub fn new() -> Self {
+        Profiler::default()
+    }
+
+    pub fn enter(&mut self, key: FunctionKey) {
+        let profile = self.functions.entry(key.clone()).or_default();
+        profile.calls += 1;
+        profile.active += 1;
+        self.stack.push(Frame {
+            key,
+            start: Instant::now(),
+            children: Duration::default(),
+        });
+    }
+
+    pub fn exit(&mut self) {
+        let frame = match self.stack.pop() {
+            Some(frame) => frame,
+            None => return,
+        };
+        let elapsed = frame.start.elapsed();
+        if let Some(profile) = self.functions.get_mut(&frame.key) {
+            profile.own += elapsed.saturating_sub(frame.children);
+            profile.active -= 1;
+            if profile.active == 0 {
+                profile.total += elapsed;
+            }
+        }
+        if let Some(caller) = self.stack.last_mut() {
+            caller.children += elapsed;
+        }
+    }
+
+    pub fn functions(&self) -> impl Iterator<Item = (&FunctionKey, &FunctionProfile)> {
+        self.functions.iter()
+    }
+
+    // Functions are listed with the ones that spent the most time in their own code first
+    pub fn report(&self) -> String {
+        let mut functions: Vec<_> = self.functions().collect();
+        functions.sort_by(|(ka, a), (kb, b)| b.own.cmp(&a.own).then(ka.name.cmp(&kb.name)));
+
+        let mut s = format!(
+            "{:>10} {:>12} {:>12}  {}\n",
+            "calls", "total ms", "self ms", "function"
+        );
+        for (key, profile) in functions {
+            let name = match key.line {
+                Some(line) => format!("{} (line {})", key.name, line),
+                None => format!("{} (native)", key.name),
+            };
+            let _ = writeln!(
+                s,
+                "{:>10} {:>12.3} {:>12.3}  {}",
+                profile.calls,
+                profile.total.as_secs_f64() * 1000.0,
+                profile.own.as_secs_f64() * 1000.0,
+                name
+            );
+        }
+        s
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    pub fn recursive_calls_are_timed_once() {
+        let key = FunctionKey {
+            name: "f".to_string(),
+            line: Some(1),
+        };
+        let native = FunctionKey {
+            name: "clock".to_string(),
+            line: None,
+        };
+        let mut profiler = Profiler::new();
+        profiler.enter(key.clone());
+        profiler.enter(key.clone());
+        profiler.enter(native.clone());
+        std::thread::sleep(Duration::from_millis(20));
+        profiler.exit();
+        profiler.exit();
+        profiler.exit();
+
+        let get = |key| *profiler.functions().find(|(k, _)| *k == key).unwrap().1;
+        let f = get(&key);
+        let clock = get(&native);
+        assert_eq!(f.calls, 2);
+        assert!(f.total >= clock.total);
+        assert!(f.total < clock.total * 2);
+        assert!(f.own < clock.own);
+        assert!(profiler.report().contains("clock (native)"));
+    }
+}
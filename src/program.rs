@@ -0,0 +1,162 @@
+//! Code that's parsed and resolved once, to be run by any number of interpreters. A host
+//! running many scripts in isolation can compile the code they have in common, such as a
+//! library of functions, into a [`Program`], then give each script an interpreter of its
+//! own, with its own globals and limits, that starts by running it. The program's AST is
+//! shared between them, and only copied by an interpreter that's given more code. A
+//! program can be cloned and sent to other threads, for interpreters running on each.
+
+use std::sync::Arc;
+
+use crate::{
+    ast::{Ast, StmtId},
+    errors::ErrorReporter,
+    parser::Parser,
+    resolver::{ResolvedProgram, Resolver},
+    scanner::Scanner,
+};
+
+#[derive(Clone)]
+pub struct Program {
+    ast: Arc<Ast>,
+    stmts: Arc<[StmtId]>,
+    resolved: Arc<ResolvedProgram>,
+}
+
+impl Program {
+    // None if the source has errors, which are left with the reporter
+    pub fn compile(source: &str, reporter: &ErrorReporter) -> Option<Program> {
+        let mut ast = Ast::new();
+        let stmts = Parser::new(Scanner::new(source, reporter), &mut ast, reporter).parse_stmts();
+        if reporter.had_error() {
+            return None;
+        }
+        let resolved = Resolver::new(&ast, reporter).resolve_stmts(&stmts);
+        if reporter.had_error() {
+            return None;
+        }
+        Some(Program {
+            ast: Arc::new(ast),
+            stmts: stmts.into(),
+            resolved: Arc::new(resolved),
+        })
+    }
+
+    pub fn ast(&self) -> &Arc<Ast> {
+        &self.ast
+    }
+
+    pub fn stmts(&self) -> &[StmtId] {
+        &self.stmts
+    }
+
+    pub fn resolved(&self) -> &ResolvedProgram {
+        &self.resolved
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::{
+        interpreter::{Interpreter, InterpreterOptions, RuntimeError},
+        loxvalue::LoxValue,
+    };
+
+    const LIBRARY: &str = "
+var calls = 0;
+fun greet(name) {
+  calls = calls + 1;
+  return \"Hello, \" + name;
+}
+";
+
+    fn run(interpreter: &mut Interpreter, src: &str) {
+        let reporter = interpreter.error_reporter();
+        let stmts =
+            Parser::new(Scanner::new(src, reporter), interpreter.ast_mut(), reporter).parse_stmts();
+        let resolved = Resolver::new(&interpreter.ast(), reporter).resolve_stmts(&stmts);
+        interpreter.add_resolution(resolved);
+        interpreter.interpret(&stmts);
+    }
+
+    #[test]
+    pub fn interpreters_share_a_program_but_not_its_globals() {
+        let library = Program::compile(LIBRARY, &ErrorReporter::new()).unwrap();
+        let (first_reporter, second_reporter) = (ErrorReporter::new(), ErrorReporter::new());
+        let mut first = Interpreter::new(&first_reporter, InterpreterOptions::default());
+        let mut second = Interpreter::new(&second_reporter, InterpreterOptions::sandboxed());
+        first.run_program(&library).unwrap();
+        second.run_program(&library).unwrap();
+        assert!(Arc::ptr_eq(&first.ast(), library.ast()));
+        assert!(Arc::ptr_eq(&second.ast(), library.ast()));
+
+        run(
+            &mut first,
+            "var greeting = greet(\"you\"); greet(\"again\");",
+        );
+        assert!(!first_reporter.had_runtime_error());
+        assert_eq!(first.global("greeting"), Some("Hello, you".into()));
+        assert_eq!(first.global("calls"), Some(LoxValue::Number(2.0)));
+        // Giving the first more code left the program as it was for the second
+        assert!(!Arc::ptr_eq(&first.ast(), library.ast()));
+        assert!(Arc::ptr_eq(&second.ast(), library.ast()));
+        assert_eq!(second.global("calls"), Some(LoxValue::Number(0.0)));
+        assert_eq!(second.global("greeting"), None);
+
+        let result = second.call("greet", &["me".into()]);
+        assert_eq!(result.unwrap(), "Hello, me".into());
+        assert_eq!(second.global("calls"), Some(LoxValue::Number(1.0)));
+        assert!(matches!(
+            second.call("heapObjects", &[]),
+            Err(RuntimeError::UndefinedVar(..))
+        ));
+    }
+
+    #[test]
+    pub fn programs_are_shared_between_threads() {
+        let library = Program::compile(LIBRARY, &ErrorReporter::new()).unwrap();
+        let greetings: Vec<_> = ["one", "two"]
+            .iter()
+            .map(|&name| {
+                let library = library.clone();
+                std::thread::spawn(move || {
+                    let reporter = ErrorReporter::new();
+                    let mut interpreter =
+                        Interpreter::new(&reporter, InterpreterOptions::default());
+                    interpreter.run_program(&library).unwrap();
+                    interpreter
+                        .call("greet", &[name.into()])
+                        .unwrap()
+                        .to_string()
+                })
+            })
+            .collect();
+        let greetings: Vec<_> = greetings.into_iter().map(|t| t.join().unwrap()).collect();
+        assert_eq!(greetings, ["Hello, one", "Hello, two"]);
+    }
+
+    #[test]
+    pub fn programs_arent_run_after_other_code() {
+        let library = Program::compile(LIBRARY, &ErrorReporter::new()).unwrap();
+        let reporter = ErrorReporter::new();
+        let mut interpreter = Interpreter::new(&reporter, InterpreterOptions::default());
+        run(&mut interpreter, "var a = 1;");
+        assert!(matches!(
+            interpreter.run_program(&library),
+            Err(RuntimeError::ProgramAfterOtherCode)
+        ));
+        assert_eq!(interpreter.global("calls"), None);
+
+        // Running the same program again is fine
+        let mut interpreter = Interpreter::new(&reporter, InterpreterOptions::default());
+        interpreter.run_program(&library).unwrap();
+        interpreter.run_program(&library).unwrap();
+    }
+
+    #[test]
+    pub fn programs_with_errors_arent_compiled() {
+        let reporter = ErrorReporter::new();
+        assert!(Program::compile("fun f( {}", &reporter).is_none());
+        assert!(reporter.had_error());
+    }
+}
@@ -0,0 +1,302 @@
+//! Turns diagnostics into text for people to read, with the line of source each one is
+//! on, and colour for terminals, or into JSON for other programs. Errors and warnings the
+//! rlox command and its REPL print all come through here, by way of the
+//! [`ErrorReporter`](crate::errors::ErrorReporter).
+
+use std::io::IsTerminal;
+
+use serde::Serialize;
+
+use crate::{
+    errors::{position, Diagnostic, Severity},
+    explain,
+    tokens::Span,
+};
+
+const RED: &str = "\x1b[1;31m";
+const YELLOW: &str = "\x1b[1;33m";
+const BLUE: &str = "\x1b[1;34m";
+const RESET: &str = "\x1b[0m";
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, strum_macros::EnumString)]
+#[strum(serialize_all = "lowercase")]
+pub enum ColorChoice {
+    Always,
+    #[default]
+    Never,
+    // Colour for terminals, but not for pipes or files, or when NO_COLOR is set
+    Auto,
+}
+
+impl ColorChoice {
+    pub fn enabled(self, stream: &impl IsTerminal) -> bool {
+        match self {
+            ColorChoice::Always => true,
+            ColorChoice::Never => false,
+            ColorChoice::Auto => {
+                stream.is_terminal() && std::env::var_os("NO_COLOR").is_none_or(|v| v.is_empty())
+            }
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, strum_macros::EnumString)]
+#[strum(serialize_all = "lowercase")]
+pub enum ErrorFormat {
+    #[default]
+    Human,
+    // A JSON object on each line, for editors and CI to read
+    Json,
+}
+
+struct Painter {
+    color: bool,
+}
+
+impl Painter {
+    fn paint(&self, code: &str, text: &str) -> String {
+        if self.color && !text.is_empty() {
+            format!("{}{}{}", code, text, RESET)
+        } else {
+            text.to_string()
+        }
+    }
+}
+
+// Reads as the diagnostic prints, with the line of the source it's on underlined where
+// the problem is, the way rustc shows errors, when the source is given. Errors are red,
+// warnings yellow, and notes, such as the calls a runtime error happened in, blue.
+pub fn render(diagnostic: &Diagnostic, source: Option<(&str, &str)>, color: bool) -> String {
+    let painter = Painter { color };
+    let position = position(diagnostic.line, diagnostic.column);
+    let (headline, code) = if diagnostic.severity == Severity::Warning {
+        let tag = painter.paint(YELLOW, "Warning");
+        (format!("[line {}] {}", position, tag), YELLOW)
+    } else if diagnostic.runtime {
+        let tag = painter.paint(RED, "Runtime Error");
+        (format!("[Line {}] {}", position, tag), RED)
+    } else {
+        let tag = painter.paint(RED, "Error");
+        (
+            format!("[line {}] {} {}", position, tag, diagnostic.location),
+            RED,
+        )
+    };
+    let mut out = format!("{}: {}", headline, diagnostic.message);
+    if let Some((file, source)) = source {
+        if let Some(snippet) = snippet(diagnostic, file, source, &painter, code) {
+            out.push('\n');
+            out.push_str(&snippet);
+        }
+    }
+    for line in &diagnostic.backtrace {
+        out.push_str(&format!("\n    {}", painter.paint(BLUE, line)));
+    }
+    out
+}
+
+#[derive(Serialize)]
+struct JsonDiagnostic<'a> {
+    code: Option<&'a str>,
+    severity: Severity,
+    runtime: bool,
+    message: &'a str,
+    file: Option<&'a str>,
+    line: usize,
+    // Null when it isn't known
+    column: Option<usize>,
+    // Byte offsets, end exclusive
+    span: Option<Span>,
+    backtrace: &'a [String],
+}
+
+// On a single line, named after the file it's in when that's given
+pub fn render_json(diagnostic: &Diagnostic, file: Option<&str>) -> String {
+    serde_json::to_string(&JsonDiagnostic {
+        code: diagnostic.code.as_deref(),
+        severity: diagnostic.severity,
+        runtime: diagnostic.runtime,
+        message: &diagnostic.message,
+        file,
+        line: diagnostic.line,
+        column: Some(diagnostic.column).filter(|&column| column > 0),
+        span: diagnostic.span,
+        backtrace: &diagnostic.backtrace,
+    })
+    .unwrap()
+}
+
+fn snippet(
+    diagnostic: &Diagnostic,
+    file: &str,
+    source: &str,
+    painter: &Painter,
+    code: &str,
+) -> Option<String> {
+    let line = diagnostic.line;
+    let text = source.lines().nth(line.checked_sub(1)?)?;
+    // Chars before the underline, and how many to underline. Spans are only used when
+    // they're on the line, which a runtime error's might not be.
+    let line_start = text.as_ptr() as usize - source.as_ptr() as usize;
+    let line_end = line_start + text.len();
+    let underline = match diagnostic.span {
+        Some(span)
+            if span.start >= line_start
+                && span.start <= line_end
+                && source.is_char_boundary(span.start) =>
+        {
+            let end = span.end.clamp(span.start, line_end);
+            let end = (end..=line_end).find(|&i| source.is_char_boundary(i))?;
+            Some((
+                &text[..span.start - line_start],
+                source[span.start..end].chars().count(),
+            ))
+        }
+        _ if diagnostic.column > 0 => {
+            let start = text
+                .char_indices()
+                .nth(diagnostic.column - 1)
+                .map_or(text.len(), |(i, _)| i);
+            Some((&text[..start], 1))
+        }
+        _ => None,
+    };
+
+    let gutter = line.to_string().len();
+    let margin = |s: &str| painter.paint(BLUE, s);
+    let mut out = format!(
+        "{:gutter$}{} {}:{}\n",
+        "",
+        margin("-->"),
+        file,
+        position(line, diagnostic.column),
+        gutter = gutter
+    );
+    out.push_str(&format!(
+        "{:gutter$} {}\n",
+        "",
+        margin("|"),
+        gutter = gutter
+    ));
+    out.push_str(&format!("{} {}", margin(&format!("{} |", line)), text));
+    if let Some((before, width)) = underline {
+        // Tabs are kept so the underline lines up however wide they're shown
+        let indent: String = before
+            .chars()
+            .map(|c| if c == '\t' { '\t' } else { ' ' })
+            .collect();
+        out.push_str(&format!(
+            "\n{:gutter$} {} {}{}",
+            "",
+            margin("|"),
+            indent,
+            painter.paint(code, &"^".repeat(width.max(1))),
+            gutter = gutter
+        ));
+    }
+    // Denied warnings are errors too, but it's only the codes of parse and runtime errors
+    // that have explanations
+    let explained = diagnostic
+        .code
+        .as_deref()
+        .filter(|c| explain::lookup(c).is_some());
+    if let (Severity::Error, Some(error_code)) = (diagnostic.severity, explained) {
+        out.push_str(&format!(
+            "\n{:gutter$} {} help: for more about this error, try `rlox --explain {}`",
+            "",
+            margin("="),
+            error_code,
+            gutter = gutter
+        ));
+    }
+    Some(out)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn diagnostic(line: usize, column: usize, span: Option<Span>) -> Diagnostic {
+        Diagnostic {
+            severity: Severity::Error,
+            code: None,
+            line,
+            column,
+            span,
+            location: String::new(),
+            message: "Operands must be numbers".to_string(),
+            runtime: true,
+            backtrace: vec!["at f() called from line 1".to_string()],
+        }
+    }
+
+    #[test]
+    pub fn renders_the_line_with_the_problem_underlined() {
+        let source = "var a = 1;\n\tprint a + \"b\";\n";
+        let error = diagnostic(2, 12, Some(Span { start: 22, end: 25 }));
+        assert_eq!(
+            render(&error, Some(("a.lox", source)), false),
+            "[Line 2:12] Runtime Error: Operands must be numbers
+ --> a.lox:2:12
+  |
+2 | \tprint a + \"b\";
+  | \t          ^^^
+    at f() called from line 1"
+        );
+        // Without the source, or with a line that isn't in it, it reads as it prints
+        assert_eq!(render(&error, None, false), error.to_string());
+        let coded = Diagnostic {
+            code: Some("E0204".to_string()),
+            ..diagnostic(1, 7, None)
+        };
+        assert!(render(&coded, Some(("a.lox", source)), false).contains(
+            "\n  |       ^\n  = help: for more about this error, try `rlox --explain E0204`\n"
+        ));
+        let past_the_end = diagnostic(10, 1, None);
+        assert_eq!(
+            render(&past_the_end, Some(("a.lox", source)), false),
+            past_the_end.to_string()
+        );
+    }
+
+    #[test]
+    pub fn colours_by_severity() {
+        let error = diagnostic(1, 1, None);
+        let rendered = render(&error, Some(("a.lox", "nil + 1;")), true);
+        assert!(rendered.starts_with("[Line 1:1] \x1b[1;31mRuntime Error\x1b[0m: "));
+        assert!(rendered.contains("\x1b[1;31m^\x1b[0m"));
+        assert!(rendered.ends_with("\x1b[1;34mat f() called from line 1\x1b[0m"));
+
+        let warning = Diagnostic {
+            severity: Severity::Warning,
+            runtime: false,
+            ..error
+        };
+        assert!(render(&warning, None, true).contains("\x1b[1;33mWarning\x1b[0m: "));
+        assert!(!ColorChoice::Never.enabled(&std::io::stdout()));
+        assert!(ColorChoice::Always.enabled(&std::io::stdout()));
+    }
+
+    #[test]
+    pub fn renders_json_on_one_line() {
+        let error = Diagnostic {
+            code: Some("E0204".to_string()),
+            ..diagnostic(2, 12, Some(Span { start: 22, end: 25 }))
+        };
+        assert_eq!(
+            render_json(&error, Some("a.lox")),
+            r#"{"code":"E0204","severity":"error","runtime":true,"message":"Operands must be numbers","file":"a.lox","line":2,"column":12,"span":{"start":22,"end":25},"backtrace":["at f() called from line 1"]}"#
+        );
+        let warning = Diagnostic {
+            severity: Severity::Warning,
+            code: Some("empty-block".to_string()),
+            runtime: false,
+            backtrace: Vec::new(),
+            ..diagnostic(3, 0, None)
+        };
+        assert_eq!(
+            render_json(&warning, None),
+            r#"{"code":"empty-block","severity":"warning","runtime":false,"message":"Operands must be numbers","file":null,"line":3,"column":null,"span":null,"backtrace":[]}"#
+        );
+    }
+}
@@ -0,0 +1,378 @@
+use std::{
+    collections::HashSet,
+    io,
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+};
+
+use rustyline::{
+    completion::Completer, error::ReadlineError, highlight::Highlighter, hint::Hinter,
+    validate::Validator, Context, Helper,
+};
+
+use rlox::{
+    debugger::Debugger, errors::ErrorReporter, interpreter::Interpreter, loxvalue::LoxValue,
+    scanner, snapshot::Snapshot, symbol::Symbol,
+};
+
+use crate::{debugger_input, print_reports, run, set_up_reporter, Options};
+
+const HELP: &str = "Commands:
+  :help           show this message
+  :env            list the globals defined in this session
+  :load FILE      run a file, keeping what it defines
+  :save FILE      save the globals defined so far to a snapshot
+  :restore FILE   define the globals saved in a snapshot
+  :clear          forget everything defined in this session
+  :quit           leave the REPL
+Anything else is run as Lox. An expression has its value printed and kept in _.";
+
+// A fresh interpreter for the REPL, and the globals it starts with, which :env leaves out
+fn new_session<'a>(
+    error_reporter: &'a ErrorReporter,
+    options: &Options,
+    interrupt: &Arc<AtomicBool>,
+) -> (Interpreter<'a>, HashSet<Symbol>) {
+    // Shared by every input, so definitions from earlier prompts stay visible
+    let mut interpreter = Interpreter::new(error_reporter, options.interpreter.clone());
+    interpreter.set_debugger(Box::new(Debugger::new(
+        "<stdin>",
+        "",
+        false,
+        debugger_input(),
+        Box::new(io::stdout()),
+    )));
+    interpreter.set_interrupt(interrupt.clone());
+    let builtins = interpreter.global_names();
+    (interpreter, builtins)
+}
+
+// Runs a file in the session, keeping what it defines
+fn load<'a>(
+    filename: &str,
+    options: &Options,
+    interpreter: &mut Interpreter<'a>,
+    error_reporter: &'a ErrorReporter,
+) {
+    match std::fs::read_to_string(filename) {
+        Ok(contents) => {
+            let offset = set_up_reporter(error_reporter, options, filename, &contents);
+            run(
+                &contents,
+                offset,
+                false,
+                options,
+                interpreter,
+                error_reporter,
+            );
+            error_reporter.reset();
+        }
+        Err(e) => println!("Could not read {}: {}", filename, e),
+    }
+}
+
+// Starts by loading `preload`, if given, as though with :load
+pub fn run_prompt(options: &Options, preload: Option<&str>) {
+    let error_reporter = ErrorReporter::new();
+    // Ctrl-C while code is running stops it and goes back to the prompt. While a line is
+    // being read, the editor handles Ctrl-C itself.
+    let interrupt = Arc::new(AtomicBool::new(false));
+    let handler_interrupt = interrupt.clone();
+    if let Err(e) = ctrlc::set_handler(move || handler_interrupt.store(true, Ordering::Relaxed)) {
+        eprintln!("Could not handle Ctrl-C: {}", e);
+    }
+    let (mut interpreter, mut builtins) = new_session(&error_reporter, options, &interrupt);
+    if let Some(filename) = preload {
+        load(filename, options, &mut interpreter, &error_reporter);
+    }
+
+    // The editor only has the terminal while it's reading a line, as a debugger
+    // statement reads from stdin too
+    let mut editor = rustyline::Editor::<LoxHelper>::new();
+    editor.set_helper(Some(LoxHelper::default()));
+    let history = history_file();
+    if let Some(history) = &history {
+        // There's no history the first time
+        let _ = editor.load_history(history);
+    }
+
+    // Lines typed so far, when they don't make a complete input yet
+    let mut buf = String::new();
+    loop {
+        if let Some(helper) = editor.helper_mut() {
+            let globals = interpreter.global_names();
+            helper.globals = globals.iter().map(|name| name.to_string()).collect();
+        }
+        let prompt = if buf.is_empty() { "> " } else { "..> " };
+        match editor.readline(prompt) {
+            // Commands are only recognised at the start of an input
+            Ok(line) if buf.is_empty() && line.trim_start().starts_with(':') => {
+                editor.add_history_entry(line.trim());
+                let line = line.trim().trim_start_matches(':');
+                let (name, arg) = match line.split_once(' ') {
+                    Some((name, arg)) => (name, arg.trim()),
+                    None => (line, ""),
+                };
+                match name {
+                    "h" | "help" => println!("{}", HELP),
+                    "e" | "env" => {
+                        let globals = interpreter.globals();
+                        for (name, value) in globals.iter().filter(|(n, _)| !builtins.contains(n)) {
+                            println!("{} = {}", name, value);
+                        }
+                    }
+                    "l" | "load" if arg.is_empty() => println!("Usage: :load FILE"),
+                    "l" | "load" => load(arg, options, &mut interpreter, &error_reporter),
+                    "s" | "save" | "r" | "restore" if arg.is_empty() => {
+                        println!("Usage: :{} FILE", name)
+                    }
+                    "s" | "save" => {
+                        let saved = interpreter.snapshot().and_then(|s| s.save(arg));
+                        if let Err(e) = saved {
+                            println!("Could not save {}: {}", arg, e);
+                        }
+                    }
+                    "r" | "restore" => {
+                        let restored = Snapshot::load(arg).and_then(|s| interpreter.restore(&s));
+                        if let Err(e) = restored {
+                            println!("Could not restore {}: {}", arg, e);
+                        }
+                    }
+                    "c" | "clear" => {
+                        let session = new_session(&error_reporter, options, &interrupt);
+                        interpreter = session.0;
+                        builtins = session.1;
+                        error_reporter.reset();
+                    }
+                    "q" | "quit" => break,
+                    _ => println!("Unknown command ':{}', try :help", name),
+                }
+            }
+            Ok(line) => {
+                buf.push_str(&line);
+                buf.push('\n');
+                if scanner::is_incomplete(&buf) {
+                    continue;
+                }
+                editor.add_history_entry(buf.trim_end());
+                if let Some(value) = run_input(&buf, options, &mut interpreter, &error_reporter) {
+                    echo(&mut interpreter, value);
+                }
+                error_reporter.reset();
+                buf.clear();
+            }
+            // Ctrl-C abandons the input being typed
+            Err(ReadlineError::Interrupted) => buf.clear(),
+            // Ctrl-D, or the end of piped input. Anything left unfinished is run so its
+            // errors are shown.
+            Err(ReadlineError::Eof) => {
+                if !buf.is_empty() {
+                    run_input(&buf, options, &mut interpreter, &error_reporter);
+                }
+                break;
+            }
+            Err(e) => {
+                eprintln!("Could not read input: {}", e);
+                break;
+            }
+        }
+    }
+    if let Some(history) = &history {
+        if let Err(e) = editor.save_history(history) {
+            eprintln!("Could not save history to {}: {}", history.display(), e);
+        }
+    }
+    print_reports(&interpreter);
+}
+
+// Each input is kept with the reporter, as functions defined in it can go wrong once
+// they're called from later ones
+fn run_input<'a>(
+    input: &str,
+    options: &Options,
+    interpreter: &mut Interpreter<'a>,
+    error_reporter: &'a ErrorReporter,
+) -> Option<LoxValue> {
+    let offset = set_up_reporter(error_reporter, options, "<stdin>", input);
+    run(input, offset, true, options, interpreter, error_reporter)
+}
+
+// Prints the value of an expression typed at the prompt, and keeps it in `_` for the
+// next one. Nil isn't shown, so calling a function for its side effects prints nothing
+// extra.
+fn echo(interpreter: &mut Interpreter, value: LoxValue) {
+    match &value {
+        LoxValue::Nil => return,
+        LoxValue::String(s) => println!("\"{}\"", s),
+        _ => println!("{}", interpreter.describe(&value)),
+    }
+    interpreter.define_global(Symbol::intern("_"), value);
+}
+
+// Completes keywords and the names of globals
+#[derive(Default)]
+struct LoxHelper {
+    // Kept up to date with the interpreter before each line is read
+    globals: Vec<String>,
+}
+
+impl LoxHelper {
+    fn completions(&self, line: &str, pos: usize) -> (usize, Vec<String>) {
+        let before = &line[..pos];
+        let start = before
+            .char_indices()
+            .rev()
+            .take_while(|(_, c)| scanner::is_alphanumeric(*c))
+            .last()
+            .map_or(pos, |(i, _)| i);
+        let prefix = &before[start..];
+        // Fields and methods aren't known until the code runs
+        let after_dot = before[..start].ends_with('.');
+        if prefix.is_empty() || after_dot || !prefix.starts_with(scanner::is_alpha) {
+            return (pos, Vec::new());
+        }
+
+        let keywords = scanner::KEYWORDS.iter().map(|(keyword, _)| *keyword);
+        let globals = self.globals.iter().map(|name| name.as_str());
+        let mut candidates: Vec<String> = keywords
+            .chain(globals)
+            .filter(|name| name.starts_with(prefix))
+            .map(|name| name.to_string())
+            .collect();
+        candidates.sort();
+        candidates.dedup();
+        (start, candidates)
+    }
+}
+
+impl Completer for LoxHelper {
+    type Candidate = String;
+
+    fn complete(
+        &self,
+        line: &str,
+        pos: usize,
+        _ctx: &Context<'_>,
+    ) -> rustyline::Result<(usize, Vec<String>)> {
+        Ok(self.completions(line, pos))
+    }
+}
+
+impl Hinter for LoxHelper {
+    type Hint = String;
+}
+
+impl Highlighter for LoxHelper {}
+
+impl Validator for LoxHelper {}
+
+impl Helper for LoxHelper {}
+
+fn history_file() -> Option<PathBuf> {
+    std::env::var_os("HOME").map(|home| Path::new(&home).join(".rlox_history"))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use rlox::{
+        errors::{Diagnostic, DiagnosticSink},
+        interpreter::InterpreterOptions,
+        parser::DEFAULT_MAX_DEPTH,
+        render::{ColorChoice, ErrorFormat},
+    };
+    use std::{cell::RefCell, collections::HashMap, rc::Rc};
+
+    #[derive(Clone, Default)]
+    struct Recorder(Rc<RefCell<Vec<Diagnostic>>>);
+
+    impl DiagnosticSink for Recorder {
+        fn report(&mut self, diagnostic: Diagnostic) {
+            self.0.borrow_mut().push(diagnostic);
+        }
+    }
+
+    #[test]
+    pub fn shows_errors_against_the_input_they_are_in() {
+        let options = Options {
+            verbose: false,
+            optimize: false,
+            lcov: None,
+            max_parse_depth: DEFAULT_MAX_DEPTH,
+            max_errors: None,
+            lint_levels: HashMap::new(),
+            strict: false,
+            typecheck: false,
+            snippets: true,
+            color: ColorChoice::Never,
+            error_format: ErrorFormat::Human,
+            interpreter: InterpreterOptions::default(),
+        };
+        let recorder = Recorder::default();
+        let error_reporter = ErrorReporter::with_sink(Box::new(recorder.clone()));
+        let mut interpreter = Interpreter::new(&error_reporter, options.interpreter.clone());
+        let inputs = [
+            "fun greet(name) {\n  return \"hi \" - name;\n}\n",
+            "greet(1);\n",
+        ];
+        for input in inputs {
+            run_input(input, &options, &mut interpreter, &error_reporter);
+            error_reporter.reset();
+        }
+        let diagnostics = recorder.0.borrow();
+        assert_eq!(diagnostics.len(), 1);
+        assert_eq!(
+            error_reporter.render(&diagnostics[0], false),
+            "[Line 2:16] Runtime Error: Operands must be numbers
+ --> <stdin>:2:16
+  |
+2 |   return \"hi \" - name;
+  |          ^^^^^^^^^^^^
+  = help: for more about this error, try `rlox --explain E0204`
+    at greet() called from line 1:8"
+        );
+    }
+
+    #[test]
+    pub fn completes_keywords_and_globals() {
+        let helper = LoxHelper {
+            globals: vec![
+                "clock".to_string(),
+                "counter".to_string(),
+                "fib".to_string(),
+            ],
+        };
+        assert_eq!(
+            helper.completions("print co", 8),
+            (6, vec!["counter".to_string()])
+        );
+        assert_eq!(
+            helper.completions("f", 1),
+            (
+                0,
+                vec![
+                    "false".to_string(),
+                    "fib".to_string(),
+                    "for".to_string(),
+                    "fun".to_string()
+                ]
+            )
+        );
+        assert_eq!(
+            helper.completions("var x = fib(c) + 1;", 13),
+            (
+                12,
+                vec![
+                    "class".to_string(),
+                    "clock".to_string(),
+                    "counter".to_string()
+                ]
+            )
+        );
+        assert!(helper.completions("point.co", 8).1.is_empty());
+        assert!(helper.completions("1 + ", 4).1.is_empty());
+    }
+}
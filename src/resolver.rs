@@ -1,12 +1,86 @@
-use std::{borrow::Borrow, collections::HashMap};
+//! Works out which declaration each variable refers to before the code is run, and
+//! reports variables used in ways they can't be, such as reading a local in its own
+//! initializer.
+
+use std::collections::{HashMap, HashSet};
+
+use thiserror::Error;
 
 use crate::{
-    ast::{AssignExpr, Expr, FunctionStmt, IfStmt, ReturnStmt, Stmt, VarStmt, WhileStmt},
+    ast::{
+        AssignExpr, Ast, Expr, ExprId, FunctionStmt, IfStmt, ReturnStmt, Stmt, StmtId, SuperExpr,
+        ThisExpr, VarStmt, VariableExpr, WhileStmt, YieldStmt,
+    },
     errors::ErrorReporter,
-    interpreter::Interpreter,
-    tokens::{Token, TokenLiteral},
+    lint::Rule,
+    symbol::{self, Symbol},
+    tokens::{Span, Token, TokenLiteral},
 };
 
+// Found before the code is run, so reported like parse errors
+#[derive(Debug, Error)]
+pub enum ResolveError {
+    #[error("Already a variable with this name in this scope: '{0}'")]
+    AlreadyDeclared(String),
+
+    #[error("Can't break outside of a loop")]
+    BreakOutsideLoop,
+
+    #[error("A class can't inherit from itself")]
+    ClassInheritsFromItself,
+
+    #[error("Duplicate parameter '{0}', first declared at {1}:{2}")]
+    DuplicateParameter(String, usize, usize),
+
+    #[error("Can't return from an initializer")]
+    ReturnFromInitializer,
+
+    #[error("Can't return from top-level code")]
+    ReturnFromTopLevel,
+
+    #[error("Can't use 'super' outside of a class")]
+    SuperOutsideClass,
+
+    #[error("Can't use 'super' in a class with no superclass")]
+    SuperWithoutSuperclass,
+
+    #[error("Can't use 'this' outside of a class")]
+    ThisOutsideClass,
+
+    #[error("Undefined variable '{0}', which is never declared")]
+    UndeclaredVariable(String),
+
+    #[error("Variable is undefined")]
+    VariableReadInInitializer,
+
+    #[error("Can't yield from an initializer")]
+    YieldFromInitializer,
+
+    #[error("Can't yield from top-level code")]
+    YieldFromTopLevel,
+}
+
+impl ResolveError {
+    // As for parse errors, for `rlox --explain`
+    pub fn code(&self) -> &'static str {
+        match self {
+            ResolveError::AlreadyDeclared(_) => "E0301",
+            ResolveError::BreakOutsideLoop => "E0302",
+            ResolveError::ClassInheritsFromItself => "E0303",
+            ResolveError::DuplicateParameter(..) => "E0304",
+            ResolveError::ReturnFromInitializer => "E0305",
+            ResolveError::ReturnFromTopLevel => "E0306",
+            ResolveError::SuperOutsideClass => "E0307",
+            ResolveError::SuperWithoutSuperclass => "E0308",
+            ResolveError::ThisOutsideClass => "E0309",
+            ResolveError::UndeclaredVariable(_) => "E0310",
+            ResolveError::VariableReadInInitializer => "E0311",
+            ResolveError::YieldFromInitializer => "E0312",
+            ResolveError::YieldFromTopLevel => "E0313",
+        }
+    }
+}
+
 #[derive(Clone, Debug)]
 enum FunctionType {
     None,
@@ -15,6 +89,39 @@ enum FunctionType {
     Method,
 }
 
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum VariableKind {
+    Class,
+    Function,
+    Parameter,
+    Variable,
+    // `this` and `super`
+    Implicit,
+}
+
+// Slots are handed out in declaration order, matching the order the interpreter defines
+// variables in at runtime
+struct Variable {
+    defined: bool,
+    slot: usize,
+    kind: VariableKind,
+    // Whether the variable is ever read, as opposed to only assigned to
+    used: bool,
+    name: Option<Token>,
+}
+
+impl Variable {
+    fn implicit() -> Self {
+        Variable {
+            defined: true,
+            slot: 0,
+            kind: VariableKind::Implicit,
+            used: true,
+            name: None,
+        }
+    }
+}
+
 #[derive(Clone, Copy)]
 enum ClassType {
     None,
@@ -22,70 +129,214 @@ enum ClassType {
     Subclass,
 }
 
-pub struct Resolver<'a, 'b, 'c> {
-    interpreter: &'b mut Interpreter<'a, 'c>,
+// Where the resolver found a local variable: how many scopes out from where it's used,
+// and its index within that scope
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Local {
+    pub depth: usize,
+    pub slot: usize,
+}
+
+// What resolving some code found, for the interpreter to use when running it. Variables
+// that aren't in it are globals, and are looked up by name.
+#[derive(Clone, Debug, Default)]
+pub struct ResolvedProgram {
+    locals: HashMap<ExprId, Local>,
+    // Where each local was declared, when the resolver was asked to keep that for tools
+    declarations: HashMap<ExprId, Span>,
+}
+
+impl ResolvedProgram {
+    pub fn new() -> Self {
+        ResolvedProgram::default()
+    }
+
+    pub fn local(&self, id: ExprId) -> Option<Local> {
+        self.locals.get(&id).copied()
+    }
+
+    // The name in the declaration of the local a variable refers to. `this` and `super`
+    // have none.
+    pub fn declaration(&self, id: ExprId) -> Option<Span> {
+        self.declarations.get(&id).copied()
+    }
+
+    // Adds what was found in later code, such as another line of the REPL
+    pub fn extend(&mut self, other: ResolvedProgram) {
+        self.locals.extend(other.locals);
+        self.declarations.extend(other.declarations);
+    }
+
+    // Drops what was found for expressions that are gone from the AST
+    pub fn remove(&mut self, ids: impl Iterator<Item = ExprId>) {
+        for id in ids {
+            self.locals.remove(&id);
+            self.declarations.remove(&id);
+        }
+    }
+}
+
+pub struct Resolver<'a> {
+    ast: &'a Ast,
     error_reporter: &'a ErrorReporter,
-    scopes_stack: Vec<HashMap<String, bool>>,
+    resolved: ResolvedProgram,
+    scopes_stack: Vec<HashMap<Symbol, Variable>>,
     current_function: FunctionType,
     current_class: ClassType,
+    // How many loops enclose the current statement within its function
+    loop_depth: usize,
+    warn_shadowing: bool,
+    record_declarations: bool,
+    strict: bool,
+    // In strict mode, the globals that are already defined and the ones declared at the
+    // top level of the code being resolved, which can be used anywhere in it
+    globals: HashSet<Symbol>,
 }
 
-impl<'a, 'b, 'c> Resolver<'a, 'b, 'c> {
-    pub fn new(
-        interpreter: &'b mut Interpreter<'a, 'c>,
-        error_reporter: &'a ErrorReporter,
-    ) -> Resolver<'a, 'b, 'c> {
+impl<'a> Resolver<'a> {
+    pub fn new(ast: &'a Ast, error_reporter: &'a ErrorReporter) -> Resolver<'a> {
         Resolver {
-            interpreter,
+            ast,
             error_reporter,
+            resolved: ResolvedProgram::new(),
             scopes_stack: Vec::new(),
             current_function: FunctionType::None,
             current_class: ClassType::None,
+            loop_depth: 0,
+            warn_shadowing: false,
+            record_declarations: false,
+            strict: false,
+            globals: HashSet::new(),
         }
     }
 
-    // resolve_stmts and resolve_expr are wrappers around "inner" private functions here
-    // that don't consume self and release the interpreter mut ref. The intention is
-    // that users of Resolver are free to use the interpreter after resolution, but in
-    // recursive calls we don't want to release it.
+    // Turns on strict mode, given the globals that are already defined, such as
+    // natives. Globals can be used before they're declared, so the ones in the code
+    // being resolved are collected up front.
+    pub fn strict(mut self, defined_globals: HashSet<Symbol>) -> Self {
+        self.strict = true;
+        self.globals = defined_globals;
+        self
+    }
+
+    // Off by default, as shadowing is often deliberate
+    pub fn warn_shadowing(mut self, warn: bool) -> Self {
+        self.warn_shadowing = warn;
+        self
+    }
+
+    // Keeps where each local was declared, for go-to-definition and the like
+    pub fn with_declarations(mut self) -> Self {
+        self.record_declarations = true;
+        self
+    }
+
+    // resolve_stmts and resolve_expr are wrappers around "inner" private functions that
+    // don't consume self, and hand back what was found for the interpreter to use
 
-    pub fn resolve_stmts(mut self, stmts: &[Stmt]) {
-        self.resolve_stmts_inner(stmts);
+    pub fn resolve_stmts(mut self, stmts: &[StmtId]) -> ResolvedProgram {
+        let ast = self.ast;
+        if self.strict {
+            self.globals
+                .extend(stmts.iter().filter_map(|stmt| match &ast[*stmt] {
+                    Stmt::Class(class) => Some(class.name.symbol.clone()),
+                    Stmt::Function(function) => Some(function.name.symbol.clone()),
+                    Stmt::Var(var) => Some(var.name.symbol.clone()),
+                    _ => None,
+                }));
+        }
+        self.resolve_stmts_inner(ast, stmts);
+        self.resolved
     }
 
-    pub fn resolve_expr(mut self, expr: &Expr) {
-        self.resolve_expr_inner(expr);
+    // Resolves a function as though it were declared inside scopes holding the given
+    // names, outermost first, in slot order. Used to recreate a function that closes
+    // over environments that already exist. If the scopes have `this`, it's resolved as
+    // a method.
+    pub fn resolve_function_in(
+        mut self,
+        scopes: &[Vec<Symbol>],
+        function: &FunctionStmt,
+        is_initializer: bool,
+    ) -> ResolvedProgram {
+        let ast = self.ast;
+        for names in scopes {
+            self.begin_scope();
+            let scope = self.scopes_stack.last_mut().unwrap();
+            for (slot, name) in names.iter().enumerate() {
+                let variable = Variable {
+                    slot,
+                    ..Variable::implicit()
+                };
+                scope.insert(name.clone(), variable);
+            }
+        }
+        let has = |name| scopes.iter().any(|names| names.contains(name));
+        self.current_class = if has(&symbol::SUPER) {
+            ClassType::Subclass
+        } else if has(&symbol::THIS) {
+            ClassType::Class
+        } else {
+            ClassType::None
+        };
+        let ftype = match self.current_class {
+            _ if is_initializer => FunctionType::Initializer,
+            ClassType::None => FunctionType::Function,
+            ClassType::Class | ClassType::Subclass => FunctionType::Method,
+        };
+        self.resolve_function(ast, function, ftype);
+        self.resolved
     }
 
-    fn resolve_stmts_inner(&mut self, stmts: &[Stmt]) {
+    pub fn resolve_expr(mut self, expr: ExprId) -> ResolvedProgram {
+        let ast = self.ast;
+        self.resolve_expr_inner(ast, expr);
+        self.resolved
+    }
+
+    // Statements after a return or break are still resolved, to report any errors in
+    // them, but only the first is warned about
+    fn resolve_stmts_inner(&mut self, ast: &Ast, stmts: &[StmtId]) {
+        let mut exited = false;
         for s in stmts {
-            self.resolve_stmt(s);
+            if exited {
+                self.error_reporter.warning(
+                    Rule::UnreachableCode,
+                    ast.line(*s),
+                    0,
+                    "Unreachable code",
+                );
+                exited = false;
+            }
+            self.resolve_stmt(ast, *s);
+            if let Stmt::Return(_) | Stmt::Break(_) = ast[*s] {
+                exited = true;
+            }
         }
     }
 
-    fn resolve_stmt(&mut self, stmt: &Stmt) {
-        match stmt {
+    fn resolve_stmt(&mut self, ast: &Ast, stmt: StmtId) {
+        match &ast[stmt] {
             Stmt::Block(stmts) => {
                 self.begin_scope();
-                self.resolve_stmts_inner(stmts);
+                self.resolve_stmts_inner(ast, stmts);
                 self.end_scope();
             }
             Stmt::Class(stmt) => {
                 let enclosing_class = self.current_class;
                 self.current_class = ClassType::Class;
-                self.declare(&stmt.name.lexeme);
-                self.define(&stmt.name.lexeme);
+                self.declare(&stmt.name, VariableKind::Class);
+                self.define(&stmt.name.symbol);
 
                 let mut has_superclass = false;
-                if let Some(expr) = &stmt.superclass {
+                if let Some(expr) = stmt.superclass {
                     self.current_class = ClassType::Subclass;
-                    if let Expr::Variable(sc_token) = expr {
-                        if stmt.name.lexeme == sc_token.lexeme {
-                            self.error_reporter
-                                .runtime_error(sc_token.line, "A class can't inherit from itself");
+                    if let Expr::Variable(sc) = &ast[expr] {
+                        if stmt.name.symbol == sc.name.symbol {
+                            self.error(&sc.name, ResolveError::ClassInheritsFromItself);
                         }
                     }
-                    self.resolve_expr_inner(&expr);
+                    self.resolve_expr_inner(ast, expr);
                     has_superclass = true;
                 }
 
@@ -94,20 +345,20 @@ impl<'a, 'b, 'c> Resolver<'a, 'b, 'c> {
                     self.scopes_stack
                         .last_mut()
                         .unwrap()
-                        .insert("super".to_string(), true);
+                        .insert(symbol::SUPER.clone(), Variable::implicit());
                 }
 
                 self.begin_scope();
                 if let Some(scope) = self.scopes_stack.last_mut() {
-                    scope.insert("this".to_string(), true);
+                    scope.insert(symbol::THIS.clone(), Variable::implicit());
                 }
                 for method in &stmt.methods {
-                    let ftype = if method.name.lexeme == "init" {
+                    let ftype = if method.name.symbol == *symbol::INIT {
                         FunctionType::Initializer
                     } else {
                         FunctionType::Method
                     };
-                    self.resolve_function(method, ftype)
+                    self.resolve_function(ast, method, ftype)
                 }
                 self.end_scope();
                 if has_superclass {
@@ -116,178 +367,505 @@ impl<'a, 'b, 'c> Resolver<'a, 'b, 'c> {
                 self.current_class = enclosing_class;
             }
             Stmt::Function(stmt) => {
-                self.declare(&stmt.name.lexeme);
-                self.define(&stmt.name.lexeme);
-                self.resolve_function(stmt, FunctionType::Function);
+                self.declare(&stmt.name, VariableKind::Function);
+                self.define(&stmt.name.symbol);
+                self.resolve_function(ast, stmt, FunctionType::Function);
             }
-            Stmt::Var(VarStmt { name, initializer }) => {
-                self.declare(&name.lexeme);
+            Stmt::Var(VarStmt {
+                name, initializer, ..
+            }) => {
+                self.declare(name, VariableKind::Variable);
                 // Not sure whether we should care about the distinction b/w
                 // var a;
                 // and
                 // var a = nil;
                 // which are both currently represented identically in the AST.
-                match initializer.borrow() {
+                match &ast[*initializer] {
                     Expr::Literal(TokenLiteral::Nil) => {}
-                    expr => {
-                        self.resolve_expr_inner(expr);
+                    _ => {
+                        self.resolve_expr_inner(ast, *initializer);
                     }
                 }
-                self.define(&name.lexeme);
+                self.define(&name.symbol);
             }
             Stmt::If(IfStmt {
                 condition,
                 then_branch,
                 else_branch,
             }) => {
-                self.resolve_expr_inner(condition.borrow());
-                self.resolve_stmt(then_branch.borrow());
+                self.resolve_expr_inner(ast, *condition);
+                self.resolve_stmt(ast, *then_branch);
                 if let Some(else_branch) = else_branch {
-                    self.resolve_stmt(else_branch.borrow());
+                    self.resolve_stmt(ast, *else_branch);
                 }
             }
-            Stmt::Print(expr) => self.resolve_expr_inner(expr),
+            Stmt::Print(expr) => self.resolve_expr_inner(ast, *expr),
             Stmt::Return(ReturnStmt { keyword, value }) => {
                 if let FunctionType::None = self.current_function {
-                    self.error_reporter
-                        .runtime_error(keyword.line, "Can't return from top-level code");
+                    self.error(keyword, ResolveError::ReturnFromTopLevel);
                 }
-                if let Expr::Literal(TokenLiteral::Nil) = value.borrow() {
+                if let Expr::Literal(TokenLiteral::Nil) = &ast[*value] {
                 } else {
                     if let FunctionType::Initializer = self.current_function {
-                        self.error_reporter
-                            .runtime_error(keyword.line, "Can't return from an initializer");
+                        self.error(keyword, ResolveError::ReturnFromInitializer);
+                    }
+                    self.resolve_expr_inner(ast, *value);
+                }
+            }
+            Stmt::Yield(YieldStmt { keyword, value }) => {
+                match self.current_function {
+                    FunctionType::None => self.error(keyword, ResolveError::YieldFromTopLevel),
+                    FunctionType::Initializer => {
+                        self.error(keyword, ResolveError::YieldFromInitializer)
                     }
-                    self.resolve_expr_inner(value.borrow());
+                    FunctionType::Function | FunctionType::Method => {}
                 }
+                self.resolve_expr_inner(ast, *value);
             }
             Stmt::While(WhileStmt { condition, body }) => {
-                self.resolve_expr_inner(condition.borrow());
-                self.resolve_stmt(body.borrow());
+                self.resolve_expr_inner(ast, *condition);
+                self.loop_depth += 1;
+                self.resolve_stmt(ast, *body);
+                self.loop_depth -= 1;
+            }
+            Stmt::Break(keyword) => {
+                if self.loop_depth == 0 {
+                    self.error(keyword, ResolveError::BreakOutsideLoop);
+                }
             }
-            Stmt::Break => {}
-            Stmt::Expression(expr) => self.resolve_expr_inner(expr),
+            Stmt::Debugger => {}
+            Stmt::Expression(expr) => self.resolve_expr_inner(ast, *expr),
         }
     }
 
-    fn resolve_expr_inner(&mut self, expr: &Expr) {
-        match expr {
+    fn resolve_expr_inner(&mut self, ast: &Ast, id: ExprId) {
+        match &ast[id] {
             Expr::Assign(AssignExpr { name, value }) => {
-                self.resolve_expr_inner(value.borrow());
-                self.resolve_local(expr, name);
+                self.resolve_expr_inner(ast, *value);
+                self.resolve_variable(id, name, false);
             }
-            Expr::Variable(token) => {
+            Expr::Variable(VariableExpr { name: token }) => {
                 if let Some(scope) = self.scopes_stack.last() {
-                    if let Some(false) = scope.get(&token.lexeme) {
-                        self.error_reporter
-                            .runtime_error(0, "Variable is undefined");
+                    if let Some(Variable { defined: false, .. }) = scope.get(&token.symbol) {
+                        self.error(token, ResolveError::VariableReadInInitializer);
                     }
                 }
-                self.resolve_local(expr, token);
+                self.resolve_variable(id, token, true);
             }
             Expr::Binary(expr) => {
-                self.resolve_expr_inner(expr.left.borrow());
-                self.resolve_expr_inner(expr.right.borrow());
+                // Walked down rather than recursed into, as chains nest as deep as they're long
+                let mut rights = vec![expr.right];
+                let mut left = expr.left;
+                while let Expr::Binary(expr) = &ast[left] {
+                    rights.push(expr.right);
+                    left = expr.left;
+                }
+                self.resolve_expr_inner(ast, left);
+                for right in rights.into_iter().rev() {
+                    self.resolve_expr_inner(ast, right);
+                }
             }
             Expr::Call(expr) => {
-                self.resolve_expr_inner(expr.callee.borrow());
+                self.resolve_expr_inner(ast, expr.callee);
                 for arg in &expr.arguments {
-                    self.resolve_expr_inner(arg);
+                    self.resolve_expr_inner(ast, *arg);
                 }
             }
             Expr::Get(expr) => {
-                self.resolve_expr_inner(expr.object.borrow());
+                self.resolve_expr_inner(ast, expr.object);
             }
-            Expr::Grouping(expr) => self.resolve_expr_inner(expr.borrow()),
+            Expr::Grouping(expr) => self.resolve_expr_inner(ast, *expr),
             Expr::Literal(_) => {}
             Expr::Logical(expr) => {
-                self.resolve_expr_inner(expr.left.borrow());
-                self.resolve_expr_inner(expr.right.borrow());
+                self.resolve_expr_inner(ast, expr.left);
+                self.resolve_expr_inner(ast, expr.right);
             }
             Expr::Set(expr) => {
-                self.resolve_expr_inner(expr.value.borrow());
-                self.resolve_expr_inner(expr.object.borrow());
+                self.resolve_expr_inner(ast, expr.value);
+                self.resolve_expr_inner(ast, expr.object);
             }
-            Expr::Super(sexpr) => {
+            Expr::Super(SuperExpr { keyword, .. }) => {
                 if let ClassType::None = self.current_class {
-                    self.error_reporter
-                        .runtime_error(sexpr.keyword.line, "Can't use 'super' outside of a class");
+                    self.error(keyword, ResolveError::SuperOutsideClass);
                 } else if !matches!(self.current_class, ClassType::Subclass) {
-                    self.error_reporter.runtime_error(
-                        sexpr.keyword.line,
-                        "Can't use 'super' in a class with no superclass",
-                    );
+                    self.error(keyword, ResolveError::SuperWithoutSuperclass);
                 }
-                self.resolve_local(expr, &sexpr.keyword);
+                self.resolve_local(id, keyword, true);
             }
-            Expr::This(keyword) => {
+            Expr::This(ThisExpr { keyword }) => {
                 if let ClassType::None = self.current_class {
-                    self.error_reporter
-                        .runtime_error(keyword.line, "Can't use 'this' outside of a class");
+                    self.error(keyword, ResolveError::ThisOutsideClass);
                 }
-                self.resolve_local(expr, keyword);
+                self.resolve_local(id, keyword, true);
             }
             Expr::Unary(expr) => {
-                self.resolve_expr_inner(expr.right.borrow());
+                self.resolve_expr_inner(ast, expr.right);
             }
         }
     }
 
-    fn resolve_local(&mut self, expr: &Expr, name: &Token) {
-        for (i, scope) in self.scopes_stack.iter().rev().enumerate() {
-            if scope.contains_key(&name.lexeme) {
-                // println!("Resolving {} which has ptr {:?} and distance {}", name.lexeme, expr as *const Expr, i);
-                self.interpreter.resolve(expr, i);
-                return;
+    fn resolve_variable(&mut self, id: ExprId, name: &Token, read: bool) {
+        let local = self.resolve_local(id, name, read);
+        if !local && self.strict && !self.globals.contains(&name.symbol) {
+            self.error(name, ResolveError::UndeclaredVariable(name.lexeme.clone()));
+        }
+    }
+
+    // Returns false if the name isn't a local, in which case it's left to be looked up
+    // as a global at runtime
+    fn resolve_local(&mut self, id: ExprId, name: &Token, read: bool) -> bool {
+        for (i, scope) in self.scopes_stack.iter_mut().rev().enumerate() {
+            if let Some(var) = scope.get_mut(&name.symbol) {
+                var.used |= read;
+                self.resolved.locals.insert(
+                    id,
+                    Local {
+                        depth: i,
+                        slot: var.slot,
+                    },
+                );
+                if let (true, Some(declared)) = (self.record_declarations, &var.name) {
+                    self.resolved.declarations.insert(id, declared.span);
+                }
+                return true;
             }
         }
+        false
     }
 
-    fn resolve_function(&mut self, stmt: &FunctionStmt, ftype: FunctionType) {
+    fn resolve_function(&mut self, ast: &Ast, stmt: &FunctionStmt, ftype: FunctionType) {
         let enclosing_function = self.current_function.clone();
         self.current_function = ftype;
+        // A loop outside the function can't be broken out of from inside it
+        let enclosing_loop_depth = std::mem::replace(&mut self.loop_depth, 0);
         self.begin_scope();
         for token in &stmt.params {
-            self.declare(&token.lexeme);
-            self.define(&token.lexeme);
+            self.declare(token, VariableKind::Parameter);
+            self.define(&token.symbol);
         }
-        self.resolve_stmts_inner(&stmt.body);
+        self.resolve_stmts_inner(ast, &stmt.body);
         self.end_scope();
         self.current_function = enclosing_function;
+        self.loop_depth = enclosing_loop_depth;
     }
 
     fn begin_scope(&mut self) {
         self.scopes_stack.push(HashMap::new());
     }
 
+    // Globals aren't checked for being used, as a later REPL line or script might use
+    // them. Neither are parameters, which often have to be there to fit a call.
     fn end_scope(&mut self) {
-        self.scopes_stack.pop();
+        let scope = match self.scopes_stack.pop() {
+            Some(scope) => scope,
+            None => return,
+        };
+        let mut unused: Vec<&Variable> = scope
+            .values()
+            .filter(|var| !var.used && var.kind != VariableKind::Parameter)
+            .collect();
+        unused.sort_by_key(|var| var.slot);
+        for var in unused {
+            let name = match &var.name {
+                Some(name) if !name.lexeme.starts_with('_') => name,
+                _ => continue,
+            };
+            let kind = match var.kind {
+                VariableKind::Class => "class",
+                VariableKind::Function => "function",
+                _ => "variable",
+            };
+            let msg = format!("Unused local {} '{}'", kind, name.lexeme);
+            self.error_reporter
+                .warning(Rule::UnusedVariable, name.line, name.column, &msg);
+        }
     }
 
-    fn declare(&mut self, name: &str) {
-        match self.scopes_stack.last_mut() {
-            None => {}
-            Some(scope) => {
-                if scope.contains_key(&name.to_string()) {
-                    self.error_reporter.runtime_error(
-                        0,
-                        &format!(
-                            "Already a varibale with this name in this scope: '{}'",
-                            name
-                        ),
-                    );
+    fn error(&self, token: &Token, error: ResolveError) {
+        self.error_reporter
+            .static_error(error.code(), token, &error.to_string());
+    }
+
+    fn declare(&mut self, name: &Token, kind: VariableKind) {
+        if self.warn_shadowing {
+            self.check_shadowing(name);
+        }
+        let duplicate = self
+            .scopes_stack
+            .last()
+            .and_then(|scope| scope.get(&name.symbol))
+            .map(|existing| match (&existing.name, kind) {
+                (Some(first), VariableKind::Parameter) => {
+                    ResolveError::DuplicateParameter(name.lexeme.clone(), first.line, first.column)
                 }
-                scope.insert(name.to_string(), false);
-            }
+                _ => ResolveError::AlreadyDeclared(name.lexeme.clone()),
+            });
+        if let Some(error) = duplicate {
+            self.error(name, error);
+        }
+        if let Some(scope) = self.scopes_stack.last_mut() {
+            let slot = scope.len();
+            scope.insert(
+                name.symbol.clone(),
+                Variable {
+                    defined: false,
+                    slot,
+                    kind,
+                    used: false,
+                    name: Some(name.clone()),
+                },
+            );
         }
     }
 
-    fn define(&mut self, name: &str) {
+    // Only enclosing locals are checked, as globals aren't kept in scopes
+    fn check_shadowing(&self, name: &Token) {
+        let enclosing = match self.scopes_stack.split_last() {
+            Some((_, enclosing)) => enclosing,
+            None => return,
+        };
+        let shadowed = enclosing
+            .iter()
+            .rev()
+            .find_map(|scope| scope.get(&name.symbol))
+            .and_then(|var| var.name.as_ref());
+        if let Some(shadowed) = shadowed {
+            let msg = format!(
+                "'{}' shadows the declaration on line {}",
+                name.lexeme, shadowed.line
+            );
+            self.error_reporter
+                .warning(Rule::Shadowing, name.line, name.column, &msg);
+        }
+    }
+
+    fn define(&mut self, name: &Symbol) {
         match self.scopes_stack.last_mut() {
             None => {}
             Some(scope) => {
-                scope.insert(name.to_string(), true);
+                if let Some(var) = scope.get_mut(name) {
+                    var.defined = true;
+                }
             }
         }
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::{
+        explain,
+        interpreter::{Interpreter, InterpreterOptions},
+        parser::Parser,
+        scanner::Scanner,
+    };
+
+    fn parse(src: &str, reporter: &ErrorReporter) -> (Ast, Vec<StmtId>) {
+        let mut ast = Ast::new();
+        let stmts = Parser::new(Scanner::new(src, reporter), &mut ast, reporter).parse_stmts();
+        assert!(!reporter.had_error());
+        (ast, stmts)
+    }
+
+    fn resolve(src: &str) -> ErrorReporter {
+        let reporter = ErrorReporter::new();
+        let (ast, stmts) = parse(src, &reporter);
+        Resolver::new(&ast, &reporter).resolve_stmts(&stmts);
+        reporter
+    }
+
+    fn warnings(src: &str) -> Vec<String> {
+        let reporter = resolve(src);
+        assert!(!reporter.had_error());
+        reporter
+            .take_warnings()
+            .iter()
+            .map(|w| w.to_string())
+            .collect()
+    }
+
+    #[test]
+    pub fn resolves_without_an_interpreter() {
+        let reporter = ErrorReporter::new();
+        let src = "var g = 1;\n{\n  var a = 1;\n  var b = 2;\n  { print b; }\n  print g + a;\n}";
+        let (ast, stmts) = parse(src, &reporter);
+        let resolved = Resolver::new(&ast, &reporter).resolve_stmts(&stmts);
+
+        let print = |stmt: StmtId| match &ast[stmt] {
+            Stmt::Print(expr) => *expr,
+            _ => panic!("Expected a print statement"),
+        };
+        let block = |stmt: StmtId| match &ast[stmt] {
+            Stmt::Block(stmts) => stmts.clone(),
+            _ => panic!("Expected a block"),
+        };
+        let outer = block(stmts[1]);
+        let b = print(block(outer[2])[0]);
+        assert_eq!(resolved.local(b), Some(Local { depth: 1, slot: 1 }));
+        let (g, a) = match &ast[print(outer[3])] {
+            Expr::Binary(binary) => (binary.left, binary.right),
+            _ => panic!("Expected a binary expression"),
+        };
+        assert_eq!(resolved.local(g), None);
+        assert_eq!(resolved.local(a), Some(Local { depth: 0, slot: 0 }));
+    }
+
+    #[test]
+    pub fn warns_about_shadowing_when_asked() {
+        let src = "fun f(a) {\n  {\n    var a = 1;\n    print a;\n  }\n}";
+        assert!(warnings(src).is_empty());
+
+        let reporter = ErrorReporter::new();
+        let (ast, stmts) = parse(src, &reporter);
+        Resolver::new(&ast, &reporter)
+            .warn_shadowing(true)
+            .resolve_stmts(&stmts);
+        let warnings: Vec<String> = reporter
+            .take_warnings()
+            .iter()
+            .map(|w| w.to_string())
+            .collect();
+        assert_eq!(
+            warnings,
+            vec!["[line 3:9] Warning: 'a' shadows the declaration on line 1"]
+        );
+    }
+
+    #[test]
+    pub fn rejects_break_outside_of_loops() {
+        let src = "break;\nwhile (true) {\n  fun f() { break; }\n  for (;;) break;\n  break;\n}";
+        let reporter = resolve(src);
+        assert!(reporter.had_error() && !reporter.had_runtime_error());
+        assert_eq!(
+            reporter.take_errors(),
+            vec![
+                "[line 1:1] Error  at 'break': Can't break outside of a loop",
+                "[line 3:13] Error  at 'break': Can't break outside of a loop",
+            ]
+        );
+    }
+
+    #[test]
+    pub fn strict_mode_rejects_undeclared_globals() {
+        let src = "fun f() {\n  return later + clock() + undeclared;\n}\nvar later = 1;\nlater = typo = 2;";
+        assert!(!resolve(src).had_error());
+
+        let reporter = ErrorReporter::new();
+        let natives = Interpreter::new(&reporter, InterpreterOptions::default()).global_names();
+        let (ast, stmts) = parse(src, &reporter);
+        Resolver::new(&ast, &reporter)
+            .strict(natives)
+            .resolve_stmts(&stmts);
+        assert_eq!(
+            reporter.take_errors(),
+            vec![
+                "[line 2:28] Error  at 'undeclared': Undefined variable 'undeclared', which is never declared",
+                "[line 5:9] Error  at 'typo': Undefined variable 'typo', which is never declared",
+            ]
+        );
+    }
+
+    #[test]
+    pub fn rejects_duplicate_parameters() {
+        let reporter = resolve("fun f(a, b,\n  a) {}");
+        assert!(reporter.had_error() && !reporter.had_runtime_error());
+        let diagnostics = reporter.take_diagnostics();
+        assert_eq!(
+            diagnostics[0].to_string(),
+            "[line 2:3] Error  at 'a': Duplicate parameter 'a', first declared at 1:7"
+        );
+        assert_eq!(diagnostics[0].span, Some(Span { start: 14, end: 15 }));
+        assert_eq!(
+            resolve("{ var a = 1; var a = 2; }").take_errors(),
+            vec!["[line 1:18] Error  at 'a': Already a variable with this name in this scope: 'a'"]
+        );
+    }
+
+    #[test]
+    pub fn every_error_has_a_code() {
+        let src = "break;
+return 1;
+yield 1;
+print this;
+print super.x;
+print undeclared;
+class A < A {}
+class B {
+  init() { return 1; }
+  m() { super.m(); }
+}
+class C {
+  init() { yield 1; }
+}
+fun f(a, a) {
+  var b = 1;
+  var b = 2;
+  {
+    var c = c;
+  }
+}";
+        let reporter = ErrorReporter::new();
+        let (ast, stmts) = parse(src, &reporter);
+        Resolver::new(&ast, &reporter)
+            .strict(HashSet::new())
+            .resolve_stmts(&stmts);
+        let diagnostics = reporter.take_diagnostics();
+        assert_eq!(diagnostics.len(), 13);
+        for diagnostic in &diagnostics {
+            let code = diagnostic.code.as_deref();
+            assert!(
+                code.and_then(explain::lookup).is_some(),
+                "{} has no explanation",
+                diagnostic
+            );
+        }
+        let codes: HashSet<_> = diagnostics.iter().map(|d| d.code.clone()).collect();
+        assert_eq!(codes.len(), diagnostics.len());
+    }
+
+    #[test]
+    pub fn warns_about_unused_locals() {
+        let src = "var global = 1;
+fun f(unused_param) {
+  var a = 1;
+  var b = 2;
+  var _ignored = 3;
+  fun helper() {}
+  class Local {}
+  a = 4;
+  return b;
+}";
+        assert_eq!(
+            warnings(src),
+            vec![
+                "[line 3:7] Warning: Unused local variable 'a'",
+                "[line 6:7] Warning: Unused local function 'helper'",
+                "[line 7:9] Warning: Unused local class 'Local'",
+            ]
+        );
+        assert!(warnings("{ var a = 1; { print a; } }").is_empty());
+    }
+
+    #[test]
+    pub fn warns_about_unreachable_code() {
+        let src = "fun f() {
+  return 1;
+  print 2;
+  print 3;
+}
+while (true) {
+  if (true) break;
+  break;
+
+  print 4;
+}";
+        assert_eq!(
+            warnings(src),
+            vec![
+                "[line 3] Warning: Unreachable code",
+                "[line 10] Warning: Unreachable code",
+            ]
+        );
+    }
+}
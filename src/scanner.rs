@@ -1,64 +1,119 @@
+//! Turns source into tokens. [`Scanner`] is an iterator of them, ending with an `Eof`
+//! token, and can keep comments and whitespace for tools that put the source back
+//! together.
+
 use std::collections::HashMap;
-use std::collections::LinkedList;
+
+use unicode_xid::UnicodeXID;
 
 use crate::errors::ErrorReporter;
-use crate::tokens::{Token, TokenLiteral, TokenType};
+use crate::tokens::{Span, Token, TokenLiteral, TokenType, Trivia, TriviaKind, TriviaPiece};
 
+pub const KEYWORDS: &[(&str, TokenType)] = &[
+    ("and", TokenType::And),
+    ("break", TokenType::Break),
+    ("class", TokenType::Class),
+    ("debugger", TokenType::Debugger),
+    ("else", TokenType::Else),
+    ("false", TokenType::False),
+    ("for", TokenType::For),
+    ("fun", TokenType::Fun),
+    ("if", TokenType::If),
+    ("nil", TokenType::Nil),
+    ("or", TokenType::Or),
+    ("print", TokenType::Print),
+    ("return", TokenType::Return),
+    ("super", TokenType::Super),
+    ("this", TokenType::This),
+    ("true", TokenType::True),
+    ("var", TokenType::Var),
+    ("while", TokenType::While),
+    ("yield", TokenType::Yield),
+];
+
+// The source is scanned a char (Unicode scalar value) at a time, so columns count chars
+// rather than bytes, and spans are converted back to byte offsets. Identifiers follow
+// the Unicode XID rules, as Rust's do, with `_` allowed at the start.
+//
+// Tokens are scanned as they're asked for, ending with a single Eof. Tools that need to
+// reproduce the source can ask for comments as tokens, or for everything between
+// tokens to be kept as trivia on them.
 pub struct Scanner<'a> {
     source: Vec<char>,
-    tokens: LinkedList<Token>,
+    // Byte offset of each char in the source, plus one for the end of the source
+    offsets: Vec<usize>,
+    // The token scan_token found, if any
+    token: Option<Token>,
+    finished: bool,
+    // Whether the source ended inside a string or comment
+    unterminated: bool,
+    comments: bool,
+    keep_trivia: bool,
+    // Trivia since the last token, which leads the next one
+    trivia: Vec<TriviaPiece>,
     start: usize,
+    start_line: usize,
+    start_column: usize,
     current: usize,
     line: usize,
+    // Index of the first char of the current line
+    line_start: usize,
     kw_map: HashMap<String, TokenType>,
     error_reporter: &'a ErrorReporter,
 }
 
 impl<'a> Scanner<'a> {
     pub fn new(src: &str, error_reporter: &'a ErrorReporter) -> Self {
-        let mut kw_map: HashMap<String, TokenType> = HashMap::new();
-        kw_map.insert("and".to_string(), TokenType::And);
-        kw_map.insert("break".to_string(), TokenType::Break);
-        kw_map.insert("class".to_string(), TokenType::Class);
-        kw_map.insert("else".to_string(), TokenType::Else);
-        kw_map.insert("false".to_string(), TokenType::False);
-        kw_map.insert("for".to_string(), TokenType::For);
-        kw_map.insert("fun".to_string(), TokenType::Fun);
-        kw_map.insert("if".to_string(), TokenType::If);
-        kw_map.insert("nil".to_string(), TokenType::Nil);
-        kw_map.insert("or".to_string(), TokenType::Or);
-        kw_map.insert("print".to_string(), TokenType::Print);
-        kw_map.insert("return".to_string(), TokenType::Return);
-        kw_map.insert("super".to_string(), TokenType::Super);
-        kw_map.insert("this".to_string(), TokenType::This);
-        kw_map.insert("true".to_string(), TokenType::True);
-        kw_map.insert("var".to_string(), TokenType::Var);
-        kw_map.insert("while".to_string(), TokenType::While);
+        let kw_map = KEYWORDS
+            .iter()
+            .map(|(keyword, token_type)| (keyword.to_string(), token_type.clone()))
+            .collect();
+
+        let mut offsets: Vec<usize> = src.char_indices().map(|(i, _)| i).collect();
+        offsets.push(src.len());
 
         Scanner {
             source: src.chars().collect(),
-            tokens: LinkedList::new(),
+            offsets,
+            token: None,
+            finished: false,
+            unterminated: false,
+            comments: false,
+            keep_trivia: false,
+            trivia: Vec::new(),
             start: 0,
+            start_line: 1,
+            start_column: 1,
             current: 0,
             line: 1,
+            line_start: 0,
             kw_map,
             error_reporter,
         }
     }
 
-    pub fn scan_tokens(mut self) -> LinkedList<Token> {
-        while !self.is_at_end() {
-            self.start = self.current;
-            self.scan_token();
-        }
+    // Makes Comment tokens, rather than skipping comments
+    pub fn with_comments(mut self) -> Self {
+        self.comments = true;
+        self
+    }
 
-        self.tokens.push_back(Token::new(
-            TokenType::Eof,
-            "".to_string(),
-            TokenLiteral::None,
-            self.line,
-        ));
-        self.tokens
+    // Keeps the whitespace and comments around each token, so the source can be put back
+    // together from the tokens
+    pub fn with_trivia(mut self) -> Self {
+        self.keep_trivia = true;
+        self
+    }
+
+    // Has spans count from `offset` rather than the start of the source, for source run
+    // alongside other source, so that the reporter can tell which one an error is in
+    pub fn with_offset(mut self, offset: usize) -> Self {
+        self.offsets.iter_mut().for_each(|o| *o += offset);
+        self
+    }
+
+    pub fn scan_tokens(self) -> Vec<Token> {
+        self.collect()
     }
 
     fn scan_token(&mut self) {
@@ -72,7 +127,13 @@ impl<'a> Scanner<'a> {
             ':' => self.add_token(TokenType::Colon),
             ',' => self.add_token(TokenType::Comma),
             '.' => self.add_token(TokenType::Dot),
-            '-' => self.add_token(TokenType::Minus),
+            '-' => {
+                if self.match_char('>') {
+                    self.add_token(TokenType::Arrow);
+                } else {
+                    self.add_token(TokenType::Minus);
+                }
+            }
             '+' => self.add_token(TokenType::Plus),
             '?' => self.add_token(TokenType::QuestionMark),
             ';' => self.add_token(TokenType::SemiColon),
@@ -114,29 +175,36 @@ impl<'a> Scanner<'a> {
                     }
                 } else if self.match_char('*') {
                     // Multi-line comment
-                    let start_line = self.line;
                     while !self.is_at_end() && (self.peek() != '*' || self.peek_next() != '/') {
-                        if self.peek() == '\n' {
-                            self.line += 1;
+                        if self.advance() == '\n' {
+                            self.newline();
                         }
-                        self.advance();
                     }
                     if self.is_at_end() {
-                        self.error_reporter
-                            .error(start_line, "Unterminated multi-line comment on line {}");
+                        self.unterminated = true;
+                        self.error_reporter.error(
+                            self.start_line,
+                            self.start_column,
+                            "Unterminated multi-line comment",
+                        );
+                    } else {
+                        // Consume the closing */
+                        self.advance();
+                        self.advance();
                     }
-                    // Consume the closing */
-                    self.advance();
-                    self.advance();
                 } else {
                     self.add_token(TokenType::Slash);
+                    return;
+                }
+                if self.comments {
+                    self.add_token(TokenType::Comment);
                 }
             }
 
             // Whitespace
             ' ' | '\r' | '\t' => {}
             '\n' => {
-                self.line += 1;
+                self.newline();
             }
 
             '"' => {
@@ -151,8 +219,21 @@ impl<'a> Scanner<'a> {
             }
 
             _ => {
-                self.error_reporter
-                    .error(self.line, "Unexpected token at line {}");
+                self.error_reporter.error(
+                    self.start_line,
+                    self.start_column,
+                    "Unexpected character",
+                );
+            }
+        }
+    }
+
+    // A #! line at the very start is skipped like a comment, so scripts can be run
+    // directly on Unix. `#` isn't a comment anywhere else.
+    fn skip_shebang(&mut self) {
+        if self.peek() == '#' && self.peek_next() == '!' {
+            while self.peek() != '\n' && !self.is_at_end() {
+                self.advance();
             }
         }
     }
@@ -189,17 +270,20 @@ impl<'a> Scanner<'a> {
         self.add_token_with_literal(TokenType::Number, TokenLiteral::Number(num));
     }
 
+    // Strings have no escapes, so they hold exactly the text between the quotes, which
+    // may be any Unicode. Natives like len() and codePoints() work in chars too.
     fn scan_string(&mut self) {
         while self.peek() != '"' && !self.is_at_end() {
-            if self.peek() == '\n' {
-                self.line += 1;
+            if self.advance() == '\n' {
+                self.newline();
             }
-            self.advance();
         }
 
         if self.is_at_end() {
+            self.unterminated = true;
             self.error_reporter
-                .error(self.line, "Unterminated string on line {}");
+                .error(self.start_line, self.start_column, "Unterminated string");
+            return;
         }
 
         // Consume the closing "
@@ -209,7 +293,69 @@ impl<'a> Scanner<'a> {
         let value: String = self.source[self.start + 1..self.current - 1]
             .iter()
             .collect();
-        self.add_token_with_literal(TokenType::String, TokenLiteral::String(value));
+        self.add_token_with_literal(TokenType::String, TokenLiteral::String(value.into()));
+    }
+
+    // Keeps what was just scanned, which didn't make a token, as trivia
+    fn add_trivia(&mut self) {
+        if !self.keep_trivia || self.start == self.current {
+            return;
+        }
+        let text: String = self.source[self.start..self.current].iter().collect();
+        let kind = match text.as_str() {
+            "\n" => TriviaKind::Newline,
+            t if t.starts_with("//") => TriviaKind::LineComment,
+            t if t.starts_with("/*") => TriviaKind::BlockComment,
+            t if t.starts_with("#!") => TriviaKind::Shebang,
+            t if t.trim().is_empty() => TriviaKind::Whitespace,
+            _ => TriviaKind::Skipped,
+        };
+        match self.trivia.last_mut() {
+            Some(last) if last.kind == kind && kind != TriviaKind::Newline => {
+                last.text.push_str(&text)
+            }
+            _ => self.trivia.push(TriviaPiece { kind, text }),
+        }
+    }
+
+    // Scans up to the end of the line, as long as there's only trivia
+    fn trailing_trivia(&mut self) -> Vec<TriviaPiece> {
+        let leading = std::mem::take(&mut self.trivia);
+        while !self.is_at_end() {
+            let whitespace = matches!(self.peek(), ' ' | '\r' | '\t' | '\n');
+            let comment = self.peek() == '/' && matches!(self.peek_next(), '/' | '*');
+            if !whitespace && (!comment || self.comments) {
+                break;
+            }
+            self.start = self.current;
+            self.start_line = self.line;
+            self.start_column = self.column();
+            let newline = self.peek() == '\n';
+            self.scan_token();
+            self.add_trivia();
+            if newline {
+                break;
+            }
+        }
+        std::mem::replace(&mut self.trivia, leading)
+    }
+
+    fn with_token_trivia(&mut self, mut token: Token) -> Token {
+        if self.keep_trivia {
+            let trailing = self.trailing_trivia();
+            let leading = std::mem::take(&mut self.trivia);
+            token.trivia = Some(Box::new(Trivia { leading, trailing }));
+        }
+        token
+    }
+
+    fn newline(&mut self) {
+        self.line += 1;
+        self.line_start = self.current;
+    }
+
+    fn column(&self) -> usize {
+        self.current - self.line_start + 1
     }
 
     fn is_at_end(&self) -> bool {
@@ -227,10 +373,21 @@ impl<'a> Scanner<'a> {
     }
 
     fn add_token_with_literal(&mut self, t: TokenType, literal: TokenLiteral) {
+        debug_assert!(self.token.is_none());
         let text: String = self.source[self.start..self.current].iter().collect();
+        let span = Span {
+            start: self.offsets[self.start],
+            end: self.offsets[self.current],
+        };
         // println!("Adding token {}: {}", t.to_string(), text);
-        self.tokens
-            .push_back(Token::new(t, text, literal, self.line));
+        self.token = Some(Token::new(
+            t,
+            text,
+            literal,
+            self.start_line,
+            self.start_column,
+            span,
+        ));
     }
 
     fn match_char(&mut self, expected: char) -> bool {
@@ -260,14 +417,233 @@ impl<'a> Scanner<'a> {
     }
 }
 
+// Whether more lines are needed to finish the source, as it has a brace or paren that
+// hasn't been closed, or ends inside a string or comment. Used by the REPL to decide
+// whether to keep reading.
+pub fn is_incomplete(src: &str) -> bool {
+    let reporter = ErrorReporter::new();
+    let mut scanner = Scanner::new(src, &reporter);
+    let mut depth = 0;
+    for token in &mut scanner {
+        match token.token_type {
+            TokenType::LeftBrace | TokenType::LeftParen => depth += 1,
+            TokenType::RightBrace | TokenType::RightParen => depth -= 1,
+            _ => {}
+        }
+    }
+    scanner.unterminated || depth > 0
+}
+
+impl Iterator for Scanner<'_> {
+    type Item = Token;
+
+    fn next(&mut self) -> Option<Token> {
+        if self.current == 0 {
+            self.start = 0;
+            self.skip_shebang();
+            self.add_trivia();
+        }
+        // Whitespace, comments and errors don't make tokens, so keep going until one does
+        while !self.is_at_end() {
+            self.start = self.current;
+            self.start_line = self.line;
+            self.start_column = self.column();
+            self.scan_token();
+            if let Some(token) = self.token.take() {
+                return Some(self.with_token_trivia(token));
+            }
+            self.add_trivia();
+        }
+
+        if self.finished {
+            return None;
+        }
+        self.finished = true;
+        let end = self.offsets[self.current];
+        let eof = Token::new(
+            TokenType::Eof,
+            "".to_string(),
+            TokenLiteral::None,
+            self.line,
+            self.column(),
+            Span { start: end, end },
+        );
+        Some(self.with_token_trivia(eof))
+    }
+}
+
 fn is_digit(c: char) -> bool {
-    c >= '0' && c <= '9'
+    c.is_ascii_digit()
 }
 
-fn is_alpha(c: char) -> bool {
-    (c >= 'a' && c <= 'z') || (c >= 'A' && c <= 'Z') || c == '_'
+pub fn is_alpha(c: char) -> bool {
+    c == '_' || c.is_xid_start()
 }
 
-fn is_alphanumeric(c: char) -> bool {
-    is_alpha(c) || is_digit(c)
+pub fn is_alphanumeric(c: char) -> bool {
+    c.is_xid_continue()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn scan(src: &str) -> Vec<Token> {
+        let reporter = ErrorReporter::new();
+        let tokens = Scanner::new(src, &reporter).scan_tokens();
+        assert!(!reporter.had_error());
+        tokens
+    }
+
+    #[test]
+    pub fn scans_unicode_identifiers() {
+        let tokens = scan("var café = \"ünï\";\nπ_2 + 日本;");
+        let summary: Vec<_> = tokens
+            .iter()
+            .map(|t| (t.token_type.clone(), t.lexeme.as_str(), t.line, t.column))
+            .collect();
+        assert_eq!(
+            summary,
+            vec![
+                (TokenType::Var, "var", 1, 1),
+                (TokenType::Identifier, "café", 1, 5),
+                (TokenType::Equal, "=", 1, 10),
+                (TokenType::String, "\"ünï\"", 1, 12),
+                (TokenType::SemiColon, ";", 1, 17),
+                (TokenType::Identifier, "π_2", 2, 1),
+                (TokenType::Plus, "+", 2, 5),
+                (TokenType::Identifier, "日本", 2, 7),
+                (TokenType::SemiColon, ";", 2, 9),
+                (TokenType::Eof, "", 2, 10),
+            ]
+        );
+        // Spans are in bytes, so they can slice the source
+        assert_eq!(tokens[1].span, Span { start: 4, end: 9 });
+        assert!(matches!(&tokens[3].literal, TokenLiteral::String(s) if &**s == "ünï"));
+    }
+
+    #[test]
+    pub fn scans_tokens_as_they_are_asked_for() {
+        let reporter = ErrorReporter::new();
+        let mut scanner = Scanner::new("a // comment\n  b", &reporter);
+        assert_eq!(scanner.next().unwrap().lexeme, "a");
+        assert_eq!(scanner.next().unwrap().lexeme, "b");
+        assert_eq!(scanner.next().unwrap().token_type, TokenType::Eof);
+        assert!(scanner.next().is_none());
+    }
+
+    #[test]
+    pub fn skips_a_leading_shebang() {
+        let tokens = scan("#!/usr/bin/env rlox\nprint 1;");
+        assert_eq!(tokens[0].token_type, TokenType::Print);
+        assert_eq!((tokens[0].line, tokens[0].column), (2, 1));
+
+        let reporter = ErrorReporter::new();
+        Scanner::new("print 1;\n#!/usr/bin/env rlox\n", &reporter).scan_tokens();
+        assert!(reporter.had_error());
+    }
+
+    #[test]
+    pub fn keeps_trivia_around_tokens() {
+        let src = "#!/usr/bin/env rlox\n// Leading\nvar a = 1; // trailing\n\n  /* block\n */ print a; ~\n";
+        let reporter = ErrorReporter::new();
+        let tokens = Scanner::new(src, &reporter).with_trivia().scan_tokens();
+        assert!(reporter.had_error());
+
+        // Every bit of the source belongs to one token
+        let mut rebuilt = String::new();
+        for token in &tokens {
+            let trivia = token.trivia.as_ref().unwrap();
+            trivia
+                .leading
+                .iter()
+                .for_each(|t| rebuilt.push_str(&t.text));
+            rebuilt.push_str(&token.lexeme);
+            trivia
+                .trailing
+                .iter()
+                .for_each(|t| rebuilt.push_str(&t.text));
+        }
+        assert_eq!(rebuilt, src);
+
+        let kinds = |pieces: &[TriviaPiece]| pieces.iter().map(|t| t.kind).collect::<Vec<_>>();
+        let var = tokens[0].trivia.as_ref().unwrap();
+        assert_eq!(
+            kinds(&var.leading),
+            vec![
+                TriviaKind::Shebang,
+                TriviaKind::Newline,
+                TriviaKind::LineComment,
+                TriviaKind::Newline
+            ]
+        );
+        let semicolon = tokens[4].trivia.as_ref().unwrap();
+        assert_eq!(
+            kinds(&semicolon.trailing),
+            vec![
+                TriviaKind::Whitespace,
+                TriviaKind::LineComment,
+                TriviaKind::Newline
+            ]
+        );
+        assert_eq!(semicolon.trailing[1].text, "// trailing");
+        let print = tokens[5].trivia.as_ref().unwrap();
+        assert_eq!(
+            kinds(&print.leading),
+            vec![
+                TriviaKind::Newline,
+                TriviaKind::Whitespace,
+                TriviaKind::BlockComment,
+                TriviaKind::Whitespace
+            ]
+        );
+        // Trailing trivia stops at anything that isn't whitespace or a comment
+        let last = tokens[7].trivia.as_ref().unwrap();
+        assert_eq!(kinds(&last.trailing), vec![TriviaKind::Whitespace]);
+        let eof = tokens[8].trivia.as_ref().unwrap();
+        assert_eq!(
+            kinds(&eof.leading),
+            vec![TriviaKind::Skipped, TriviaKind::Newline]
+        );
+    }
+
+    #[test]
+    pub fn makes_comment_tokens_when_asked() {
+        let reporter = ErrorReporter::new();
+        let src = "a / b // c\n/* d */";
+        let tokens: Vec<_> = Scanner::new(src, &reporter)
+            .with_comments()
+            .map(|t| (t.token_type, t.lexeme))
+            .collect();
+        assert_eq!(
+            tokens,
+            vec![
+                (TokenType::Identifier, "a".to_string()),
+                (TokenType::Slash, "/".to_string()),
+                (TokenType::Identifier, "b".to_string()),
+                (TokenType::Comment, "// c".to_string()),
+                (TokenType::Comment, "/* d */".to_string()),
+                (TokenType::Eof, "".to_string()),
+            ]
+        );
+    }
+
+    #[test]
+    pub fn knows_when_more_lines_are_needed() {
+        assert!(is_incomplete("fun f() {"));
+        assert!(is_incomplete("print (1 +"));
+        assert!(is_incomplete("print \"two\nlines"));
+        assert!(is_incomplete("/* still going"));
+        assert!(!is_incomplete("fun f() {\n  return 1;\n}"));
+        assert!(!is_incomplete("print \"{\"; // ("));
+        // Too many closing braces won't be fixed by more lines, so they're left to the parser
+        assert!(!is_incomplete("}}{"));
+    }
+
+    #[test]
+    pub fn rejects_symbols_outside_identifiers() {
+        let reporter = ErrorReporter::new();
+        Scanner::new("var a = 1 € 2;", &reporter).scan_tokens();
+        assert!(reporter.had_error());
+    }
 }
@@ -0,0 +1,513 @@
+//! Saving the globals a program has defined, along with everything they refer to, so
+//! they can be restored into another interpreter later. Functions are saved as their
+//! source and the environments they close over, and are parsed again when restored.
+
+use std::{cell::RefCell, collections::HashMap, fs, io, path::Path, rc::Rc};
+
+use serde::{Deserialize, Serialize};
+use thiserror::Error;
+
+use crate::{
+    ast::{Ast, SourceEmitter, Stmt},
+    env::Environment,
+    errors::ErrorReporter,
+    gc::{addr, Heap},
+    loxvalue::{Function, LoxCallable, LoxClass, LoxInstance, LoxRef, LoxValue},
+    parser::Parser,
+    resolver::{ResolvedProgram, Resolver},
+    scanner::Scanner,
+    symbol::{self, Symbol},
+};
+
+#[derive(Debug, Error)]
+pub enum SnapshotError {
+    #[error("Can't save {0}")]
+    Unsupported(&'static str),
+
+    #[error("There's no native function called {0} to restore")]
+    MissingNative(String),
+
+    #[error("Snapshot is corrupt: {0}")]
+    Corrupt(String),
+
+    #[error("{0}")]
+    Io(#[from] io::Error),
+
+    #[error("{0}")]
+    Json(#[from] serde_json::Error),
+}
+
+// Objects and environments are numbered, so that ones shared between values, or that
+// refer to themselves, are still shared once restored
+#[derive(Debug, Default, Deserialize, PartialEq, Serialize)]
+pub struct Snapshot {
+    globals: Vec<(String, Value)>,
+    objects: Vec<Object>,
+    // Those that functions close over, apart from the globals
+    envs: Vec<Env>,
+}
+
+#[derive(Debug, Deserialize, PartialEq, Serialize)]
+enum Value {
+    Nil,
+    Boolean(bool),
+    Number(f64),
+    String(String),
+    Object(usize),
+}
+
+#[derive(Debug, Deserialize, PartialEq, Serialize)]
+enum Object {
+    List(Vec<Value>),
+    Channel(Vec<Value>),
+    Instance {
+        class: usize,
+        fields: Vec<(String, Value)>,
+    },
+    Class {
+        name: String,
+        line: usize,
+        superclass: Option<Value>,
+        methods: Vec<(String, usize)>,
+    },
+    // A closure of None is the globals. Methods are saved unbound, so are resolved as
+    // though `this` were defined around them.
+    Function {
+        source: String,
+        closure: Option<usize>,
+        is_initializer: bool,
+        is_method: bool,
+    },
+    // Restored as whichever native has the name then
+    Native(String),
+}
+
+#[derive(Debug, Deserialize, PartialEq, Serialize)]
+struct Env {
+    enclosing: Option<usize>,
+    variables: Vec<(String, Value)>,
+}
+
+impl Snapshot {
+    pub fn save(&self, path: impl AsRef<Path>) -> Result<(), SnapshotError> {
+        fs::write(path, serde_json::to_string(self)?)?;
+        Ok(())
+    }
+
+    pub fn load(path: impl AsRef<Path>) -> Result<Snapshot, SnapshotError> {
+        Ok(serde_json::from_str(&fs::read_to_string(path)?)?)
+    }
+}
+
+// Saves the globals, sorted by name so the same program always saves the same snapshot.
+// Natives are left to whoever restores it to define, unless they're kept under another
+// name.
+pub fn take(ast: &Ast, globals: &Environment) -> Result<Snapshot, SnapshotError> {
+    let mut saver = Saver {
+        ast,
+        snapshot: Snapshot::default(),
+        objects: HashMap::new(),
+        envs: HashMap::new(),
+    };
+    let mut variables = globals.variables();
+    variables.sort_by(|(a, _), (b, _)| a.as_str().cmp(b.as_str()));
+    for (name, value) in variables {
+        if is_native_called(&value, &name) {
+            continue;
+        }
+        let value = saver.value(&value)?;
+        saver.snapshot.globals.push((name.to_string(), value));
+    }
+    Ok(saver.snapshot)
+}
+
+fn is_native_called(value: &LoxValue, name: &Symbol) -> bool {
+    match value {
+        LoxValue::Ref(r) => match &*r.borrow() {
+            LoxRef::Function(Function::Native(native)) => native.name == name.as_str(),
+            _ => false,
+        },
+        _ => false,
+    }
+}
+
+struct Saver<'a> {
+    ast: &'a Ast,
+    snapshot: Snapshot,
+    // The number given to each object and environment, by address
+    objects: HashMap<usize, usize>,
+    envs: HashMap<usize, usize>,
+}
+
+impl<'a> Saver<'a> {
+    fn value(&mut self, value: &LoxValue) -> Result<Value, SnapshotError> {
+        Ok(match value {
+            LoxValue::Nil => Value::Nil,
+            LoxValue::Boolean(b) => Value::Boolean(*b),
+            LoxValue::Number(n) => Value::Number(*n),
+            LoxValue::String(s) => Value::String(s.to_string()),
+            LoxValue::Ref(r) => Value::Object(self.object(r, false)?),
+        })
+    }
+
+    fn values<'v>(
+        &mut self,
+        values: impl Iterator<Item = &'v LoxValue>,
+    ) -> Result<Vec<Value>, SnapshotError> {
+        values.map(|value| self.value(value)).collect()
+    }
+
+    fn fields(
+        &mut self,
+        fields: &HashMap<Symbol, LoxValue>,
+    ) -> Result<Vec<(String, Value)>, SnapshotError> {
+        let mut fields: Vec<_> = fields.iter().collect();
+        fields.sort_by_key(|(name, _)| name.as_str());
+        fields
+            .into_iter()
+            .map(|(name, value)| Ok((name.to_string(), self.value(value)?)))
+            .collect()
+    }
+
+    // The object is numbered before what it refers to is saved, in case that refers
+    // back to it
+    fn object(&mut self, r: &Rc<RefCell<LoxRef>>, is_method: bool) -> Result<usize, SnapshotError> {
+        if let Some(&id) = self.objects.get(&addr(r)) {
+            return Ok(id);
+        }
+        let id = self.snapshot.objects.len();
+        self.objects.insert(addr(r), id);
+        self.snapshot.objects.push(Object::List(Vec::new()));
+        let object = match &*r.borrow() {
+            LoxRef::List(items) => Object::List(self.values(items.iter())?),
+            LoxRef::Channel(items) => Object::Channel(self.values(items.iter())?),
+            LoxRef::Instance(instance) => Object::Instance {
+                class: self.object(instance.class(), false)?,
+                fields: self.fields(instance.fields())?,
+            },
+            LoxRef::Class(class) => {
+                let mut methods: Vec<_> = class.methods().iter().collect();
+                methods.sort_by_key(|(name, _)| name.as_str());
+                let mut saved = Vec::new();
+                for (name, method) in methods {
+                    match method {
+                        LoxValue::Ref(m) => saved.push((name.to_string(), self.object(m, true)?)),
+                        _ => {
+                            return Err(SnapshotError::Unsupported(
+                                "a method that isn't a function",
+                            ))
+                        }
+                    }
+                }
+                Object::Class {
+                    name: class.name(),
+                    line: class.line(),
+                    superclass: class.superclass().map(|sc| self.value(sc)).transpose()?,
+                    methods: saved,
+                }
+            }
+            LoxRef::Function(Function::UserDefined(f)) => Object::Function {
+                source: SourceEmitter::new(self.ast).emit_function(&f.code),
+                closure: self.env(f.closure())?,
+                is_initializer: f.is_initializer(),
+                is_method,
+            },
+            LoxRef::Function(Function::Native(native)) => Object::Native(native.name.clone()),
+            LoxRef::Function(Function::Method(_)) => {
+                return Err(SnapshotError::Unsupported(
+                    "a method of a generator, channel or userdata",
+                ))
+            }
+            LoxRef::Generator(_) => return Err(SnapshotError::Unsupported("a generator")),
+            LoxRef::UserData(_) => return Err(SnapshotError::Unsupported("userdata")),
+        };
+        self.snapshot.objects[id] = object;
+        Ok(id)
+    }
+
+    fn env(&mut self, env: &Rc<RefCell<Environment>>) -> Result<Option<usize>, SnapshotError> {
+        if env.borrow().is_global() {
+            return Ok(None);
+        }
+        if let Some(&id) = self.envs.get(&addr(env)) {
+            return Ok(Some(id));
+        }
+        let id = self.snapshot.envs.len();
+        self.envs.insert(addr(env), id);
+        self.snapshot.envs.push(Env {
+            enclosing: None,
+            variables: Vec::new(),
+        });
+        let enclosing = match env.borrow().enclosing() {
+            Some(enclosing) => self.env(&enclosing)?,
+            None => None,
+        };
+        let mut variables = Vec::new();
+        for (name, value) in env.borrow().variables() {
+            variables.push((name.to_string(), self.value(&value)?));
+        }
+        self.snapshot.envs[id] = Env {
+            enclosing,
+            variables,
+        };
+        Ok(Some(id))
+    }
+}
+
+// Defines the saved globals. Functions are parsed into the AST, and what resolving them
+// found is handed back for the interpreter to keep.
+pub fn restore(
+    snapshot: &Snapshot,
+    ast: &mut Ast,
+    heap: &Heap,
+    globals: &Rc<RefCell<Environment>>,
+) -> Result<ResolvedProgram, SnapshotError> {
+    let corrupt = |what: &str| SnapshotError::Corrupt(what.to_string());
+
+    // Everything is allocated first, so objects can be filled in in any order
+    let mut objects = Vec::new();
+    for object in &snapshot.objects {
+        objects.push(match object {
+            Object::Native(name) => match globals.borrow().get(&Symbol::intern(name)) {
+                Ok(LoxValue::Ref(r)) => r,
+                _ => return Err(SnapshotError::MissingNative(name.clone())),
+            },
+            _ => heap.alloc(LoxRef::List(Vec::new())),
+        });
+    }
+    let mut envs: Vec<Option<Rc<RefCell<Environment>>>> = vec![None; snapshot.envs.len()];
+    for id in 0..snapshot.envs.len() {
+        alloc_env(snapshot, id, heap, globals, &mut envs, 0)?;
+    }
+    let envs: Vec<_> = envs.into_iter().flatten().collect();
+
+    let object = |id: usize| -> Result<Rc<RefCell<LoxRef>>, SnapshotError> {
+        objects
+            .get(id)
+            .cloned()
+            .ok_or_else(|| corrupt("missing object"))
+    };
+    let value = |value: &Value| -> Result<LoxValue, SnapshotError> {
+        Ok(match value {
+            Value::Nil => LoxValue::Nil,
+            Value::Boolean(b) => LoxValue::Boolean(*b),
+            Value::Number(n) => LoxValue::Number(*n),
+            Value::String(s) => LoxValue::String(s.as_str().into()),
+            Value::Object(id) => LoxValue::Ref(object(*id)?),
+        })
+    };
+
+    for (env, saved) in envs.iter().zip(&snapshot.envs) {
+        for (name, saved_value) in &saved.variables {
+            env.borrow_mut()
+                .define(Symbol::intern(name), value(saved_value)?);
+        }
+    }
+
+    let mut resolved = ResolvedProgram::new();
+    for (r, saved) in objects.iter().zip(&snapshot.objects) {
+        let restored = match saved {
+            Object::Native(_) => continue,
+            Object::List(items) => LoxRef::List(items.iter().map(value).collect::<Result<_, _>>()?),
+            Object::Channel(items) => {
+                LoxRef::Channel(items.iter().map(value).collect::<Result<_, _>>()?)
+            }
+            Object::Instance { class, fields } => {
+                let mut instance = LoxInstance::new(object(*class)?);
+                for (name, field) in fields {
+                    instance.set(Symbol::intern(name), value(field)?);
+                }
+                LoxRef::Instance(instance)
+            }
+            Object::Class {
+                name,
+                line,
+                superclass,
+                methods,
+            } => {
+                let superclass = superclass.as_ref().map(value).transpose()?;
+                let mut restored = HashMap::new();
+                for (name, method) in methods {
+                    restored.insert(Symbol::intern(name), LoxValue::Ref(object(*method)?));
+                }
+                LoxRef::Class(LoxClass::new(name.clone(), *line, superclass, restored))
+            }
+            Object::Function {
+                source,
+                closure,
+                is_initializer,
+                is_method,
+            } => {
+                // The names in each environment the function closes over, outermost
+                // first, as the resolver would have had them in scope
+                let mut scopes = Vec::new();
+                let mut next = *closure;
+                while let Some(id) = next {
+                    let env = snapshot
+                        .envs
+                        .get(id)
+                        .ok_or_else(|| corrupt("missing env"))?;
+                    scopes.push(
+                        env.variables
+                            .iter()
+                            .map(|(name, _)| Symbol::intern(name))
+                            .collect(),
+                    );
+                    next = env.enclosing;
+                }
+                scopes.reverse();
+                if *is_method {
+                    scopes.push(vec![symbol::THIS.clone()]);
+                }
+
+                let reporter = ErrorReporter::new();
+                let stmts = Parser::new(Scanner::new(source, &reporter), &mut *ast, &reporter)
+                    .parse_stmts();
+                let declaration = match stmts.as_slice() {
+                    [stmt] if !reporter.had_error() => match &ast[*stmt] {
+                        Stmt::Function(function) => function.clone(),
+                        _ => return Err(corrupt("a function's source isn't a function")),
+                    },
+                    _ => return Err(corrupt("a function's source doesn't parse")),
+                };
+                resolved.extend(Resolver::new(ast, &reporter).resolve_function_in(
+                    &scopes,
+                    &declaration,
+                    *is_initializer,
+                ));
+                let closure = match closure {
+                    Some(id) => envs[*id].clone(),
+                    None => globals.clone(),
+                };
+                LoxRef::Function(Function::new_function(
+                    declaration,
+                    closure,
+                    *is_initializer,
+                ))
+            }
+        };
+        *r.borrow_mut() = restored;
+    }
+
+    for (name, saved_value) in &snapshot.globals {
+        let restored = value(saved_value)?;
+        globals.borrow_mut().define(Symbol::intern(name), restored);
+    }
+    Ok(resolved)
+}
+
+// Environments are allocated after the ones they're inside
+fn alloc_env(
+    snapshot: &Snapshot,
+    id: usize,
+    heap: &Heap,
+    globals: &Rc<RefCell<Environment>>,
+    envs: &mut Vec<Option<Rc<RefCell<Environment>>>>,
+    depth: usize,
+) -> Result<Rc<RefCell<Environment>>, SnapshotError> {
+    if let Some(env) = envs.get(id).cloned().flatten() {
+        return Ok(env);
+    }
+    let saved = snapshot
+        .envs
+        .get(id)
+        .ok_or_else(|| SnapshotError::Corrupt("missing env".to_string()))?;
+    // A saved chain can't loop back on itself
+    if depth > snapshot.envs.len() {
+        return Err(SnapshotError::Corrupt("env inside itself".to_string()));
+    }
+    let enclosing = match saved.enclosing {
+        Some(enclosing) => alloc_env(snapshot, enclosing, heap, globals, envs, depth + 1)?,
+        None => globals.clone(),
+    };
+    let env = heap.alloc_env(Some(enclosing));
+    envs[id] = Some(env.clone());
+    Ok(env)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::interpreter::{Interpreter, InterpreterOptions};
+
+    fn run<'a>(interpreter: &mut Interpreter<'a>, reporter: &'a ErrorReporter, src: &str) {
+        let stmts =
+            Parser::new(Scanner::new(src, reporter), interpreter.ast_mut(), reporter).parse_stmts();
+        let resolved = Resolver::new(&interpreter.ast(), reporter).resolve_stmts(&stmts);
+        interpreter.add_resolution(resolved);
+        interpreter.interpret(&stmts);
+        assert!(!reporter.had_error() && !reporter.had_runtime_error());
+    }
+
+    const SRC: &str = "
+fun makeCounter() {
+    var count = 0;
+    fun increment() { count = count + 1; return count; }
+    return increment;
+}
+var counter = makeCounter();
+counter();
+
+class Shape {
+    init(name) { this.name = name; }
+    describe() { return this.name; }
+}
+class Square < Shape {
+    init(side) { super.init(\"square\"); this.side = side; }
+    describe() { return super.describe() + \" of side \" + str(this.side); }
+}
+fun str(n) { if (n == 2) return \"2\"; return \"?\"; }
+var square = Square(2);
+square.self = square;
+var method = square.describe;
+var items = codePoints(\"ab\");
+";
+
+    #[test]
+    pub fn restores_what_was_saved() {
+        let reporter = ErrorReporter::new();
+        let mut interpreter = Interpreter::new(&reporter, InterpreterOptions::default());
+        run(&mut interpreter, &reporter, SRC);
+        let snapshot = interpreter.snapshot().unwrap();
+        let json = serde_json::to_string(&snapshot).unwrap();
+        let snapshot: Snapshot = serde_json::from_str(&json).unwrap();
+
+        let mut restored = Interpreter::new(&reporter, InterpreterOptions::default());
+        restored.restore(&snapshot).unwrap();
+        run(
+            &mut restored,
+            &reporter,
+            "var count = counter();
+            var described = square.self.describe();
+            var bound = method();
+            var again = Square(3).describe();
+            var length = len(items);",
+        );
+        let global = |name| restored.global(name).unwrap().to_string();
+        assert_eq!(global("count"), "2");
+        assert_eq!(global("described"), "square of side 2");
+        assert_eq!(global("bound"), "square of side 2");
+        assert_eq!(global("again"), "square of side ?");
+        assert_eq!(global("length"), "2");
+        // The counter's variable is kept, not copied
+        assert_eq!(
+            restored.call("counter", &[]).unwrap(),
+            LoxValue::Number(3.0)
+        );
+    }
+
+    #[test]
+    pub fn generators_cant_be_saved() {
+        let reporter = ErrorReporter::new();
+        let mut interpreter = Interpreter::new(&reporter, InterpreterOptions::default());
+        run(
+            &mut interpreter,
+            &reporter,
+            "fun count() { yield 1; } var g = count();",
+        );
+        assert!(matches!(
+            interpreter.snapshot(),
+            Err(SnapshotError::Unsupported("a generator"))
+        ));
+    }
+}
@@ -0,0 +1,93 @@
+use std::{collections::BTreeMap, fmt::Write};
+
+use crate::{
+    ast::{Expr, Stmt},
+    gc::HeapStats,
+};
+
+// Counts what the interpreter does over a run, for working out where time goes and
+// checking that optimizations do what they should
+#[derive(Debug, Default)]
+pub struct Stats {
+    pub exprs: BTreeMap<&'static str, usize>,
+    pub stmts: BTreeMap<&'static str, usize>,
+    pub calls: usize,
+    pub peak_call_depth: usize,
+}
+
+impl Stats {
+    pub fn new() -> Self {
+        Stats::default()
+    }
+
+    pub fn expr(&mut self, expr: &Expr) {
+        *self.exprs.entry(expr_kind(expr)).or_default() += 1;
+    }
+
+    pub fn stmt(&mut self, stmt: &Stmt) {
+        *self.stmts.entry(stmt_kind(stmt)).or_default() += 1;
+    }
+
+    // Called with the depth once the call has been entered
+    pub fn call(&mut self, depth: usize) {
+        self.calls += 1;
+        self.peak_call_depth = self.peak_call_depth.max(depth);
+    }
+
+    pub fn report(&self, heap: &HeapStats) -> String {
+        let mut s = String::new();
+        write_counts(&mut s, "Expressions evaluated", &self.exprs);
+        write_counts(&mut s, "Statements executed", &self.stmts);
+        let _ = writeln!(s, "Function calls: {}", self.calls);
+        let _ = writeln!(s, "Peak call depth: {}", self.peak_call_depth);
+        let _ = writeln!(s, "Environments created: {}", heap.environments_created);
+        let _ = writeln!(s, "Objects allocated: {}", heap.objects_allocated);
+        let _ = writeln!(
+            s,
+            "Garbage collections: {} ({} freed)",
+            heap.collections, heap.freed
+        );
+        s
+    }
+}
+
+fn write_counts(s: &mut String, title: &str, counts: &BTreeMap<&'static str, usize>) {
+    let _ = writeln!(s, "{}: {}", title, counts.values().sum::<usize>());
+    for (kind, count) in counts {
+        let _ = writeln!(s, "{:>12} {}", count, kind);
+    }
+}
+
+fn expr_kind(expr: &Expr) -> &'static str {
+    match expr {
+        Expr::Assign(_) => "assign",
+        Expr::Binary(_) => "binary",
+        Expr::Call(_) => "call",
+        Expr::Get(_) => "get",
+        Expr::Grouping(_) => "grouping",
+        Expr::Literal(_) => "literal",
+        Expr::Logical(_) => "logical",
+        Expr::Set(_) => "set",
+        Expr::Super(_) => "super",
+        Expr::This(_) => "this",
+        Expr::Unary(_) => "unary",
+        Expr::Variable(_) => "variable",
+    }
+}
+
+fn stmt_kind(stmt: &Stmt) -> &'static str {
+    match stmt {
+        Stmt::Block(_) => "block",
+        Stmt::Break(_) => "break",
+        Stmt::Class(_) => "class",
+        Stmt::Debugger => "debugger",
+        Stmt::Expression(_) => "expression",
+        Stmt::Function(_) => "function",
+        Stmt::If(_) => "if",
+        Stmt::Print(_) => "print",
+        Stmt::Return(_) => "return",
+        Stmt::While(_) => "while",
+        Stmt::Var(_) => "var",
+        Stmt::Yield(_) => "yield",
+    }
+}
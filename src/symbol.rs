@@ -0,0 +1,179 @@
+use std::{
+    cell::RefCell,
+    collections::HashMap,
+    fmt,
+    hash::{Hash, Hasher},
+    sync::{Arc, LazyLock, Mutex, MutexGuard, OnceLock, Weak},
+};
+
+// Identifiers are interned as they're scanned, so variables, fields and methods can be
+// looked up by comparing and hashing a pointer rather than a string. A symbol holds its
+// name, and the interner only refers to it weakly, so once nothing uses a name it's
+// freed. That includes names a script builds at runtime, with eval() or setattr().
+#[derive(Clone)]
+pub struct Symbol(Arc<str>);
+
+// Names the interpreter itself needs to look up, kept for as long as the process runs
+pub static INIT: LazyLock<Symbol> = LazyLock::new(|| Symbol::intern("init"));
+pub static THIS: LazyLock<Symbol> = LazyLock::new(|| Symbol::intern("this"));
+pub static SUPER: LazyLock<Symbol> = LazyLock::new(|| Symbol::intern("super"));
+static EMPTY: LazyLock<Symbol> = LazyLock::new(|| Symbol::intern(""));
+
+// Below this many names, ones that are gone aren't worth sweeping out
+const MIN_SWEEP: usize = 64;
+
+// Names by their text. Entries for names that are gone are swept out once there are as
+// many again as there were names left after the last sweep.
+#[derive(Default)]
+struct Interner {
+    names: HashMap<Box<str>, Weak<str>>,
+    live: usize,
+}
+
+impl Interner {
+    fn get(&self, name: &str) -> Option<Symbol> {
+        self.names.get(name).and_then(Weak::upgrade).map(Symbol)
+    }
+
+    fn insert(&mut self, symbol: &Symbol) {
+        if self.names.len() >= 2 * self.live.max(MIN_SWEEP) {
+            self.names.retain(|_, name| name.strong_count() > 0);
+            self.live = self.names.len();
+        }
+        self.names
+            .insert(symbol.as_str().into(), Arc::downgrade(&symbol.0));
+    }
+}
+
+fn interner() -> MutexGuard<'static, Interner> {
+    static INTERNER: OnceLock<Mutex<Interner>> = OnceLock::new();
+    INTERNER
+        .get_or_init(|| Mutex::new(Interner::default()))
+        .lock()
+        .unwrap()
+}
+
+thread_local! {
+    // What this thread has seen of the interner, so that interpreters on different
+    // threads only wait on each other for names that are new to them
+    static SEEN: RefCell<Interner> = RefCell::new(Interner::default());
+}
+
+impl Symbol {
+    pub fn intern(name: &str) -> Symbol {
+        if let Some(symbol) = Symbol::seen(name) {
+            return symbol;
+        }
+        let mut interner = interner();
+        let symbol = interner.get(name).unwrap_or_else(|| {
+            let symbol = Symbol(name.into());
+            interner.insert(&symbol);
+            symbol
+        });
+        drop(interner);
+        SEEN.with(|seen| seen.borrow_mut().insert(&symbol));
+        symbol
+    }
+
+    // The symbol for a name if there is one. A name there isn't one for can't be one
+    // anything's defined with.
+    pub fn lookup(name: &str) -> Option<Symbol> {
+        if let Some(symbol) = Symbol::seen(name) {
+            return Some(symbol);
+        }
+        let symbol = interner().get(name)?;
+        SEEN.with(|seen| seen.borrow_mut().insert(&symbol));
+        Some(symbol)
+    }
+
+    fn seen(name: &str) -> Option<Symbol> {
+        SEEN.with(|seen| seen.borrow().get(name))
+    }
+
+    pub fn as_str(&self) -> &str {
+        &self.0
+    }
+}
+
+impl Default for Symbol {
+    fn default() -> Self {
+        EMPTY.clone()
+    }
+}
+
+// Each name has only one symbol at a time, so they're told apart by where it's kept
+impl PartialEq for Symbol {
+    fn eq(&self, other: &Self) -> bool {
+        Arc::ptr_eq(&self.0, &other.0)
+    }
+}
+
+impl Eq for Symbol {}
+
+impl Hash for Symbol {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        Arc::as_ptr(&self.0).cast::<u8>().hash(state);
+    }
+}
+
+impl fmt::Debug for Symbol {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_tuple("Symbol").field(&self.as_str()).finish()
+    }
+}
+
+impl fmt::Display for Symbol {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    pub fn interning_is_stable() {
+        assert_eq!(Symbol::intern("init"), *INIT);
+        assert_eq!(Symbol::intern("super").as_str(), "super");
+        assert_eq!(Symbol::default().as_str(), "");
+
+        let foo = Symbol::intern("interning_is_stable_foo");
+        assert_eq!(Symbol::intern("interning_is_stable_foo"), foo);
+        assert_ne!(Symbol::intern("interning_is_stable_bar"), foo);
+        assert_eq!(foo.to_string(), "interning_is_stable_foo");
+    }
+
+    #[test]
+    pub fn threads_see_each_others_names() {
+        let foo = std::thread::spawn(|| Symbol::intern("threads_see_each_others_names_foo"))
+            .join()
+            .unwrap();
+        assert_eq!(foo.as_str(), "threads_see_each_others_names_foo");
+        assert_eq!(
+            Symbol::lookup("threads_see_each_others_names_foo"),
+            Some(foo.clone())
+        );
+        assert_eq!(Symbol::intern("threads_see_each_others_names_foo"), foo);
+
+        // Looking a name up doesn't intern it
+        assert_eq!(Symbol::lookup("threads_see_each_others_names_bar"), None);
+        assert_eq!(Symbol::lookup("threads_see_each_others_names_bar"), None);
+    }
+
+    #[test]
+    pub fn names_are_freed_once_unused() {
+        let symbol = Symbol::intern("names_are_freed_once_unused");
+        let name = Arc::downgrade(&symbol.0);
+        drop(symbol);
+        assert!(name.upgrade().is_none());
+        assert_eq!(Symbol::lookup("names_are_freed_once_unused"), None);
+
+        // Nor do the interner's entries for them build up
+        for i in 0..10_000 {
+            Symbol::intern(&format!("names_are_freed_once_unused_{}", i));
+        }
+        assert!(interner().names.len() < 10_000);
+        assert!(SEEN.with(|seen| seen.borrow().names.len()) < 10_000);
+    }
+}
@@ -0,0 +1,154 @@
+use std::{
+    cell::RefCell,
+    collections::VecDeque,
+    future::Future,
+    pin::Pin,
+    rc::Rc,
+    sync::Arc,
+    task::{Context, Poll, Wake, Waker},
+    thread::{self, Thread},
+};
+
+use crate::{
+    interpreter::{Interpreter, RuntimeError},
+    loxvalue::{Function, LoxCallable, LoxRef, LoxValue},
+};
+
+// Tasks take turns on the interpreter's thread rather than running in parallel, which
+// keeps values free to be shared between them. A task made from a generator function
+// gives up its turn at each yield. Any other function runs to the end in one turn.
+//
+// Other tasks get a turn whenever a receive() finds its channel empty, and once the
+// script has run to the end.
+//
+// An async native hands back a future, which is kept pending until it's finished, when
+// the callback the script gave the native is queued to run with the result. With no
+// other task to run, the interpreter waits for one to finish, blocking its thread unless
+// it's running through Interpreter::interpret_async.
+pub enum Task {
+    Start(Function),
+    Resume(Rc<RefCell<LoxRef>>),
+    Callback(Function, Result<LoxValue, RuntimeError>),
+}
+
+// Shared with the spawn() native, which adds to the back
+pub type TaskQueue = Rc<RefCell<VecDeque<Task>>>;
+
+pub type NativeFuture = Pin<Box<dyn Future<Output = Result<LoxValue, RuntimeError>>>>;
+
+// Futures from async natives, each with the callback its result goes to. Shared with the
+// natives, which add to it.
+pub type PendingQueue = Rc<RefCell<Vec<(NativeFuture, Function)>>>;
+
+pub fn receive(
+    interpreter: &mut Interpreter<'_>,
+    channel: &Rc<RefCell<LoxRef>>,
+) -> Result<LoxValue, RuntimeError> {
+    loop {
+        if let LoxRef::Channel(items) = &mut *channel.borrow_mut() {
+            if let Some(item) = items.pop_front() {
+                return Ok(item);
+            }
+        }
+        if !run_next(interpreter)? {
+            let error = RuntimeError::Deadlock;
+            interpreter.native_error(&error);
+            return Err(error);
+        }
+    }
+}
+
+pub fn run_all(interpreter: &mut Interpreter<'_>) -> Result<(), RuntimeError> {
+    while run_next(interpreter)? {}
+    Ok(())
+}
+
+// Runs tasks for as long as there are some ready, without waiting on any futures
+pub fn run_ready(interpreter: &mut Interpreter<'_>) -> Result<(), RuntimeError> {
+    while !interpreter.tasks().borrow().is_empty() {
+        run_next(interpreter)?;
+    }
+    Ok(())
+}
+
+// Polls every pending future, queueing the callbacks of those that have finished.
+// Returns true if any had.
+pub fn poll_pending(interpreter: &Interpreter<'_>, cx: &mut Context<'_>) -> bool {
+    let pending: Vec<_> = interpreter.pending().borrow_mut().drain(..).collect();
+    let mut finished = false;
+    for (mut future, callback) in pending {
+        match future.as_mut().poll(cx) {
+            Poll::Ready(result) => {
+                finished = true;
+                interpreter
+                    .tasks()
+                    .borrow_mut()
+                    .push_back(Task::Callback(callback, result));
+            }
+            Poll::Pending => interpreter.pending().borrow_mut().push((future, callback)),
+        }
+    }
+    finished
+}
+
+struct ThreadWaker(Thread);
+
+impl Wake for ThreadWaker {
+    fn wake(self: Arc<Self>) {
+        self.0.unpark();
+    }
+}
+
+// Blocks until a pending future finishes. Returns false if there weren't any.
+fn wait_for_pending(interpreter: &Interpreter<'_>) -> bool {
+    if interpreter.pending().borrow().is_empty() {
+        return false;
+    }
+    let waker = Waker::from(Arc::new(ThreadWaker(thread::current())));
+    let mut cx = Context::from_waker(&waker);
+    while !poll_pending(interpreter, &mut cx) {
+        thread::park();
+    }
+    true
+}
+
+// Gives the task at the front of the queue a turn, waiting for a future to finish first
+// if that's all there is. Returns false if there weren't any.
+fn run_next(interpreter: &mut Interpreter<'_>) -> Result<bool, RuntimeError> {
+    if interpreter.tasks().borrow().is_empty() && !wait_for_pending(interpreter) {
+        return Ok(false);
+    }
+    let task = match interpreter.tasks().borrow_mut().pop_front() {
+        Some(task) => task,
+        None => return Ok(false),
+    };
+    let generator = match task {
+        Task::Start(function) => match function.call(None, interpreter, None, &[])? {
+            LoxValue::Ref(r) if matches!(&*r.borrow(), LoxRef::Generator(_)) => r.clone(),
+            _ => return Ok(true),
+        },
+        Task::Resume(generator) => {
+            interpreter.resume_generator(&generator)?;
+            generator
+        }
+        Task::Callback(callback, Ok(value)) => {
+            callback.call(None, interpreter, None, &[value])?;
+            return Ok(true);
+        }
+        Task::Callback(_, Err(error)) => {
+            interpreter.native_error(&error);
+            return Err(error);
+        }
+    };
+    let done = match &*generator.borrow() {
+        LoxRef::Generator(g) => g.is_done(),
+        _ => true,
+    };
+    if !done {
+        interpreter
+            .tasks()
+            .borrow_mut()
+            .push_back(Task::Resume(generator));
+    }
+    Ok(true)
+}
@@ -0,0 +1,306 @@
+use std::{
+    path::{Path, PathBuf},
+    process::{Command, Stdio},
+};
+
+// What a test script says it should do, in comments as in Crafting Interpreters' tests:
+//   print 1 + 2; // expect: 3
+//   print nil + 1; // expect runtime error: Operands for '+' must be numbers, ...
+//   print (; // Error at ';': Expect expression
+//   // [line 3] Error at end: Expect '}' after block
+#[derive(Debug, Default, PartialEq)]
+pub struct Expectations {
+    output: Vec<String>,
+    // Line and message, where the message starts at `Error`
+    errors: Vec<(usize, String)>,
+    runtime_error: Option<(usize, String)>,
+}
+
+impl Expectations {
+    pub fn parse(src: &str) -> Expectations {
+        let mut expectations = Expectations::default();
+        for (i, line) in src.lines().enumerate() {
+            let comment = match line.find("//") {
+                Some(start) => line[start + 2..].trim(),
+                None => continue,
+            };
+            let line = i + 1;
+            if let Some(output) = comment.strip_prefix("expect: ") {
+                expectations.output.push(output.to_string());
+            } else if let Some(message) = comment.strip_prefix("expect runtime error: ") {
+                expectations.runtime_error = Some((line, message.to_string()));
+            } else if comment.starts_with("Error") {
+                expectations.errors.push((line, normalize(comment)));
+            } else if let Some(rest) = comment.strip_prefix("[line ") {
+                let (number, error) = rest.split_once("] ").unwrap_or_default();
+                if let (Ok(line), true) = (number.parse(), error.starts_with("Error")) {
+                    expectations.errors.push((line, normalize(error)));
+                }
+            }
+        }
+        expectations
+    }
+
+    // How rlox exits when the script does what's expected
+    fn exit_code(&self) -> i32 {
+        if !self.errors.is_empty() {
+            65
+        } else if self.runtime_error.is_some() {
+            70
+        } else {
+            0
+        }
+    }
+
+    // Compares a run of the script with what was expected, returning what was wrong.
+    // Errors are printed after the output, so lines that look like errors are taken out
+    // of the output before comparing it.
+    pub fn check(&self, stdout: &str, exit_code: Option<i32>) -> Vec<String> {
+        let mut failures = Vec::new();
+        let mut output = Vec::new();
+        let mut errors = Vec::new();
+        let mut runtime_error = None;
+        for line in stdout.lines() {
+            if let Some((line, message)) = error_line(line, "[line ", "] Error") {
+                errors.push((line, normalize(&format!("Error{}", message))));
+            } else if let Some((line, message)) = error_line(line, "[Line ", "] Runtime Error: ") {
+                runtime_error.get_or_insert((line, message.to_string()));
+            } else if !(runtime_error.is_some() && line.starts_with("    ")) {
+                // Anything indented after a runtime error is its backtrace
+                output.push(line.to_string());
+            }
+        }
+
+        if output != self.output {
+            failures.push("Output differs from what was expected:".to_string());
+            failures.extend(diff(&self.output, &output));
+        }
+        for expected in &self.errors {
+            if !errors.contains(expected) {
+                failures.push(format!(
+                    "Missing error on line {}: {}",
+                    expected.0, expected.1
+                ));
+            }
+        }
+        for actual in &errors {
+            if !self.errors.contains(actual) {
+                failures.push(format!(
+                    "Unexpected error on line {}: {}",
+                    actual.0, actual.1
+                ));
+            }
+        }
+        match (&self.runtime_error, &runtime_error) {
+            (expected, actual) if expected == actual => {}
+            (Some((line, message)), actual) => {
+                failures.push(format!(
+                    "Missing runtime error on line {}: {}",
+                    line, message
+                ));
+                if let Some((line, message)) = actual {
+                    failures.push(format!("  got, on line {}: {}", line, message));
+                }
+            }
+            (None, Some((line, message))) => failures.push(format!(
+                "Unexpected runtime error on line {}: {}",
+                line, message
+            )),
+            (None, None) => {}
+        }
+        if failures.is_empty() && exit_code != Some(self.exit_code()) {
+            failures.push(format!(
+                "Expected exit code {}, got {}",
+                self.exit_code(),
+                exit_code.map_or("none".to_string(), |code| code.to_string())
+            ));
+        }
+        failures
+    }
+}
+
+// The line and message of an error, whose position can have a column, as in
+// `[line 3:7] Error at 'x': ...`
+fn error_line<'a>(line: &'a str, prefix: &str, separator: &str) -> Option<(usize, &'a str)> {
+    let rest = line.strip_prefix(prefix)?;
+    let (position, message) = rest.split_once(separator)?;
+    let line = position.split(':').next()?.parse().ok()?;
+    Some((line, message))
+}
+
+// Errors with no location are printed as `Error : ...`, and ones with a location with
+// two spaces before `at`
+fn normalize(error: &str) -> String {
+    error
+        .split_whitespace()
+        .collect::<Vec<_>>()
+        .join(" ")
+        .replacen("Error :", "Error:", 1)
+}
+
+// The lines that differ, marked with - for expected and + for actual, going by the
+// longest run of lines they have in common
+fn diff(expected: &[String], actual: &[String]) -> Vec<String> {
+    let (n, m) = (expected.len(), actual.len());
+    let mut common = vec![vec![0; m + 1]; n + 1];
+    for i in (0..n).rev() {
+        for j in (0..m).rev() {
+            common[i][j] = if expected[i] == actual[j] {
+                common[i + 1][j + 1] + 1
+            } else {
+                common[i + 1][j].max(common[i][j + 1])
+            };
+        }
+    }
+    let mut lines = Vec::new();
+    let (mut i, mut j) = (0, 0);
+    while i < n || j < m {
+        if i < n && j < m && expected[i] == actual[j] {
+            i += 1;
+            j += 1;
+        } else if j == m || (i < n && common[i + 1][j] >= common[i][j + 1]) {
+            lines.push(format!("  - {}", expected[i]));
+            i += 1;
+        } else {
+            lines.push(format!("  + {}", actual[j]));
+            j += 1;
+        }
+    }
+    lines
+}
+
+// The .lox files at the paths, looking through directories, in order
+fn test_files(paths: &[&str]) -> Vec<PathBuf> {
+    fn collect(path: &Path, files: &mut Vec<PathBuf>) {
+        if path.is_dir() {
+            let mut entries: Vec<PathBuf> = match std::fs::read_dir(path) {
+                Ok(entries) => entries.filter_map(|e| e.ok().map(|e| e.path())).collect(),
+                Err(_) => return,
+            };
+            entries.sort();
+            for entry in entries {
+                if entry.is_dir() || entry.extension().is_some_and(|ext| ext == "lox") {
+                    collect(&entry, files);
+                }
+            }
+        } else {
+            files.push(path.to_path_buf());
+        }
+    }
+    let mut files = Vec::new();
+    for path in paths {
+        collect(Path::new(path), &mut files);
+    }
+    files
+}
+
+// Runs each script with this same rlox, in a process of its own so one that crashes or
+// exits doesn't take the rest with it, and returns whether they all passed
+pub fn run_tests(paths: &[&str]) -> bool {
+    let rlox = std::env::current_exe().expect("Could not find the rlox executable");
+    let files = test_files(paths);
+    let mut failed = 0;
+    for file in &files {
+        let failures = match std::fs::read_to_string(file) {
+            Ok(src) => run_test(&rlox, file, &src),
+            Err(e) => vec![format!("Could not read it: {}", e)],
+        };
+        if failures.is_empty() {
+            println!("PASS {}", file.display());
+        } else {
+            failed += 1;
+            println!("FAIL {}", file.display());
+            for failure in failures {
+                println!("  {}", failure);
+            }
+        }
+    }
+    println!(
+        "\n{} tests, {} passed, {} failed",
+        files.len(),
+        files.len() - failed,
+        failed
+    );
+    failed == 0
+}
+
+fn run_test(rlox: &Path, file: &Path, src: &str) -> Vec<String> {
+    let output = Command::new(rlox)
+        .arg("--no-snippets")
+        .arg(file)
+        .stdin(Stdio::null())
+        .stderr(Stdio::null())
+        .output();
+    match output {
+        Ok(output) => Expectations::parse(src).check(
+            &String::from_utf8_lossy(&output.stdout),
+            output.status.code(),
+        ),
+        Err(e) => vec![format!("Could not run it: {}", e)],
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    const SRC: &str = "print 1 + 2; // expect: 3
+print \"a\"; // expect: a
+fun f() {
+  print nil + 1; // expect runtime error: Operands must be numbers
+}
+f();
+";
+
+    #[test]
+    pub fn reads_expectations_from_comments() {
+        let src =
+            "print (; // Error at ';': Expect expression\n// [line 3] Error at end: Expect '}'\n";
+        assert_eq!(
+            Expectations::parse(src),
+            Expectations {
+                output: vec![],
+                errors: vec![
+                    (1, "Error at ';': Expect expression".to_string()),
+                    (3, "Error at end: Expect '}'".to_string()),
+                ],
+                runtime_error: None,
+            }
+        );
+        assert!(Expectations::parse(src)
+            .check(
+                "[line 1:8] Error  at ';': Expect expression\n[line 3:1] Error  at end: Expect '}'\n",
+                Some(65)
+            )
+            .is_empty());
+    }
+
+    #[test]
+    pub fn checks_output_and_runtime_errors() {
+        let expectations = Expectations::parse(SRC);
+        let run = "3\na\n[Line 4:13] Runtime Error: Operands must be numbers\n    at f() called from line 6:2\n";
+        assert!(expectations.check(run, Some(70)).is_empty());
+
+        assert_eq!(
+            expectations.check("3\nb\nc\n", Some(0)),
+            vec![
+                "Output differs from what was expected:",
+                "  - a",
+                "  + b",
+                "  + c",
+                "Missing runtime error on line 4: Operands must be numbers",
+            ]
+        );
+        assert_eq!(
+            expectations.check("3\na\n[Line 5:1] Runtime Error: Oops\n", Some(70)),
+            vec![
+                "Missing runtime error on line 4: Operands must be numbers",
+                "  got, on line 5: Oops",
+            ]
+        );
+        assert_eq!(
+            Expectations::parse("print 1; // expect: 1").check("1\n", Some(70)),
+            vec!["Expected exit code 0, got 70"]
+        );
+    }
+}
@@ -1,6 +1,10 @@
-use std::fmt;
+use std::{fmt, sync::Arc};
 
-#[derive(Clone, Debug, Eq, PartialEq, strum_macros::ToString)]
+use serde::{Serialize, Serializer};
+
+use crate::symbol::Symbol;
+
+#[derive(Clone, Debug, Eq, PartialEq, Serialize, strum_macros::Display)]
 pub enum TokenType {
     // Single-character tokens
     LeftParen,
@@ -18,6 +22,7 @@ pub enum TokenType {
     Star,
 
     // One or two character tokens
+    Arrow,
     Bang,
     BangEqual,
     Equal,
@@ -36,6 +41,7 @@ pub enum TokenType {
     And,
     Break,
     Class,
+    Debugger,
     Else,
     False,
     Fun,
@@ -50,6 +56,10 @@ pub enum TokenType {
     True,
     Var,
     While,
+    Yield,
+
+    // Only made when the scanner is asked for them
+    Comment,
 
     Eof,
 }
@@ -60,25 +70,125 @@ pub enum TokenLiteral {
     True,
     False,
     Nil,
-    String(String),
+    String(Arc<str>),
     Number(f64),
 }
 
-#[derive(Clone, Debug)]
+// Literals are written as the JSON value they stand for
+impl Serialize for TokenLiteral {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        match self {
+            TokenLiteral::None | TokenLiteral::Nil => serializer.serialize_none(),
+            TokenLiteral::True => serializer.serialize_bool(true),
+            TokenLiteral::False => serializer.serialize_bool(false),
+            TokenLiteral::String(s) => serializer.serialize_str(s),
+            TokenLiteral::Number(n) => serializer.serialize_f64(*n),
+        }
+    }
+}
+
+// Byte offsets of a token in the source, end exclusive
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq, Serialize)]
+pub struct Span {
+    pub start: usize,
+    pub end: usize,
+}
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq, Serialize)]
+pub enum TriviaKind {
+    Whitespace,
+    Newline,
+    LineComment,
+    BlockComment,
+    Shebang,
+    // Characters that couldn't be scanned, which have been reported as errors
+    Skipped,
+}
+
+#[derive(Clone, Debug, Eq, PartialEq, Serialize)]
+pub struct TriviaPiece {
+    pub kind: TriviaKind,
+    pub text: String,
+}
+
+// The source around a token that isn't part of any token. Trailing trivia goes up to
+// the end of the token's line, and leading trivia is everything else since the token
+// before, so each bit of source belongs to exactly one token.
+#[derive(Clone, Debug, Default, Eq, PartialEq, Serialize)]
+pub struct Trivia {
+    pub leading: Vec<TriviaPiece>,
+    pub trailing: Vec<TriviaPiece>,
+}
+
+impl Trivia {
+    // The `///` comment lines just before the token, without the slashes, if there are
+    // any. A blank line or any other comment in between ends them.
+    pub fn doc_comment(&self) -> Option<String> {
+        let mut lines = Vec::new();
+        let mut newlines = 0;
+        for piece in self.leading.iter().rev() {
+            match piece.kind {
+                TriviaKind::Whitespace => {}
+                TriviaKind::Newline if newlines == 0 => newlines += 1,
+                TriviaKind::LineComment if piece.text.starts_with("///") => {
+                    if piece.text.starts_with("////") {
+                        break;
+                    }
+                    let text = &piece.text[3..];
+                    lines.push(text.strip_prefix(' ').unwrap_or(text).trim_end());
+                    newlines = 0;
+                }
+                _ => break,
+            }
+        }
+        if lines.is_empty() {
+            return None;
+        }
+        lines.reverse();
+        Some(lines.join("\n"))
+    }
+}
+
+#[derive(Clone, Debug, Serialize)]
 pub struct Token {
+    #[serde(rename = "type")]
     pub token_type: TokenType,
     pub lexeme: String,
     pub literal: TokenLiteral,
     pub line: usize,
+    // 1-based, counted in characters from the start of the line
+    pub column: usize,
+    pub span: Span,
+    // The interned name for identifiers, `this` and `super`, and the empty symbol otherwise
+    #[serde(skip)]
+    pub symbol: Symbol,
+    // Only kept when the scanner is asked for it
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub trivia: Option<Box<Trivia>>,
 }
 
 impl Token {
-    pub fn new(token_type: TokenType, lexeme: String, literal: TokenLiteral, line: usize) -> Self {
+    pub fn new(
+        token_type: TokenType,
+        lexeme: String,
+        literal: TokenLiteral,
+        line: usize,
+        column: usize,
+        span: Span,
+    ) -> Self {
+        let symbol = match token_type {
+            TokenType::Identifier | TokenType::This | TokenType::Super => Symbol::intern(&lexeme),
+            _ => Symbol::default(),
+        };
         Token {
             token_type,
             lexeme,
             literal,
             line,
+            column,
+            span,
+            symbol,
+            trivia: None,
         }
     }
 }
@@ -90,6 +200,6 @@ impl fmt::Display for Token {
         f.write_str(&self.lexeme)?;
         f.write_str(" ")?;
         // f.write_str(&self.literal);
-        Ok(())
+        f.write_fmt(format_args!("{}..{}", self.span.start, self.span.end))
     }
 }
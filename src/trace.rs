@@ -0,0 +1,69 @@
+use std::io::{self, Write};
+
+use crate::loxvalue::LoxValue;
+
+/// Receives the output of tracing, one line at a time.
+pub trait TraceSink {
+    fn write_line(&mut self, line: &str);
+}
+
+pub struct StderrSink;
+
+impl TraceSink for StderrSink {
+    fn write_line(&mut self, line: &str) {
+        let _ = writeln!(io::stderr(), "{}", line);
+    }
+}
+
+// Statements are written as they start, indented by how deep in function calls they
+// are. Once a statement finishes, the variables it read or wrote are written below it.
+// Those touched by statements nested inside it (in a function it calls, say) are only
+// listed against the nested statement.
+pub struct Tracer {
+    sink: Box<dyn TraceSink>,
+    touched: Vec<(String, LoxValue)>,
+}
+
+impl Tracer {
+    pub fn new(sink: Box<dyn TraceSink>) -> Self {
+        Tracer {
+            sink,
+            touched: Vec::new(),
+        }
+    }
+
+    // Returns the variables touched so far by the enclosing statement, to be handed back
+    // to end_statement
+    pub fn start_statement(&mut self, depth: usize, text: &str) -> Vec<(String, LoxValue)> {
+        self.sink.write_line(&format!("{}{}", indent(depth), text));
+        std::mem::take(&mut self.touched)
+    }
+
+    pub fn end_statement(&mut self, depth: usize, enclosing: Vec<(String, LoxValue)>) {
+        let touched = std::mem::replace(&mut self.touched, enclosing);
+        if touched.is_empty() {
+            return;
+        }
+        let values: Vec<String> = touched
+            .iter()
+            .map(|(name, value)| match value {
+                LoxValue::String(s) => format!("{} = \"{}\"", name, s),
+                _ => format!("{} = {}", name, value),
+            })
+            .collect();
+        self.sink
+            .write_line(&format!("{}  -- {}", indent(depth), values.join(", ")));
+    }
+
+    // Only the latest value of each variable is kept
+    pub fn touch(&mut self, name: &str, value: &LoxValue) {
+        match self.touched.iter_mut().find(|(n, _)| n == name) {
+            Some(entry) => entry.1 = value.clone(),
+            None => self.touched.push((name.to_string(), value.clone())),
+        }
+    }
+}
+
+fn indent(depth: usize) -> String {
+    "  ".repeat(depth)
+}
@@ -0,0 +1,449 @@
+use std::{collections::HashMap, fmt, rc::Rc};
+
+use crate::{
+    ast::{Ast, ClassStmt, Expr, ExprId, FunctionStmt, Stmt, StmtId},
+    errors::ErrorReporter,
+    symbol::Symbol,
+    tokens::{Token, TokenLiteral, TokenType},
+};
+
+// Types are only known for literals, the results of operators, and variables and
+// functions with annotations. Everything else is Any, which matches every type, so code
+// without annotations is never reported.
+#[derive(Clone, Debug, PartialEq)]
+pub enum Type {
+    Any,
+    Nil,
+    Bool,
+    Number,
+    String,
+    // The signature, where the function's declaration can be seen
+    Function(Option<Rc<Signature>>),
+    Class(String),
+    Instance(String),
+}
+
+#[derive(Debug, PartialEq)]
+pub struct Signature {
+    params: Vec<Type>,
+    ret: Type,
+}
+
+impl Type {
+    fn matches(&self, expected: &Type) -> bool {
+        match (self, expected) {
+            (Type::Any, _) | (_, Type::Any) => true,
+            (Type::Function(_), Type::Function(_)) => true,
+            // Objects can be missing, as in the links of a list
+            (Type::Nil, Type::Instance(_)) => true,
+            _ => self == expected,
+        }
+    }
+
+    fn is_known(&self) -> bool {
+        *self != Type::Any
+    }
+}
+
+impl fmt::Display for Type {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Type::Any => write!(f, "Any"),
+            Type::Nil => write!(f, "nil"),
+            Type::Bool => write!(f, "Bool"),
+            Type::Number => write!(f, "Number"),
+            Type::String => write!(f, "String"),
+            Type::Function(_) => write!(f, "Function"),
+            Type::Class(name) => write!(f, "class {}", name),
+            Type::Instance(name) => write!(f, "{}", name),
+        }
+    }
+}
+
+struct Binding {
+    ty: Type,
+    // Only annotated variables are checked when assigned to
+    declared: bool,
+}
+
+// Runs after the resolver, when asked for. Mismatches are reported the way the
+// resolver's errors are, so the program doesn't run.
+pub struct TypeChecker<'a> {
+    ast: &'a Ast,
+    error_reporter: &'a ErrorReporter,
+    scopes: Vec<HashMap<Symbol, Binding>>,
+    // Every class in the program, so annotations can name classes declared later
+    classes: Vec<String>,
+    // The declared return type of each function being checked, innermost last
+    returns: Vec<Type>,
+    current_class: Option<String>,
+}
+
+impl<'a> TypeChecker<'a> {
+    pub fn new(ast: &'a Ast, error_reporter: &'a ErrorReporter) -> Self {
+        let classes = ast
+            .stmt_ids()
+            .filter_map(|id| match &ast[id] {
+                Stmt::Class(class) => Some(class.name.lexeme.clone()),
+                _ => None,
+            })
+            .collect();
+        TypeChecker {
+            ast,
+            error_reporter,
+            scopes: vec![HashMap::new()],
+            classes,
+            returns: Vec::new(),
+            current_class: None,
+        }
+    }
+
+    pub fn check_stmts(&mut self, stmts: &[StmtId]) {
+        for stmt in stmts {
+            self.check_stmt(*stmt);
+        }
+    }
+
+    fn check_stmt(&mut self, id: StmtId) {
+        let ast = self.ast;
+        match &ast[id] {
+            Stmt::Block(stmts) => {
+                self.scopes.push(HashMap::new());
+                self.check_stmts(stmts);
+                self.scopes.pop();
+            }
+            Stmt::Break(_) | Stmt::Debugger => {}
+            Stmt::Class(class) => self.check_class(class),
+            Stmt::Expression(e) | Stmt::Print(e) => {
+                self.check_expr(*e);
+            }
+            Stmt::Function(function) => {
+                let signature = self.signature(function);
+                self.define(
+                    &function.name,
+                    Type::Function(Some(signature.clone())),
+                    false,
+                );
+                self.check_function(function, &signature);
+            }
+            Stmt::If(e) => {
+                self.check_expr(e.condition);
+                self.check_stmt(e.then_branch);
+                if let Some(else_branch) = e.else_branch {
+                    self.check_stmt(else_branch);
+                }
+            }
+            Stmt::Return(r) => {
+                let ty = self.check_expr(r.value);
+                if let Some(expected) = self.returns.last() {
+                    if !ty.matches(expected) {
+                        let msg = format!("Expected to return {} but got {}", expected, ty);
+                        self.error(&r.keyword, &msg);
+                    }
+                }
+            }
+            Stmt::While(e) => {
+                self.check_expr(e.condition);
+                self.check_stmt(e.body);
+            }
+            Stmt::Var(v) => {
+                let ty = self.check_expr(v.initializer);
+                // `var x: T;` starts out nil, which is allowed for any type
+                let uninitialized = matches!(ast[v.initializer], Expr::Literal(TokenLiteral::Nil));
+                match &v.type_annotation {
+                    Some(annotation) => {
+                        let declared = self.annotation(annotation);
+                        if !uninitialized && !ty.matches(&declared) {
+                            let msg = format!(
+                                "'{}' is declared as {} but initialized with {}",
+                                v.name.lexeme, declared, ty
+                            );
+                            self.error(&v.name, &msg);
+                        }
+                        self.define(&v.name, declared, true);
+                    }
+                    None => self.define(&v.name, Type::Any, false),
+                }
+            }
+            Stmt::Yield(y) => {
+                self.check_expr(y.value);
+            }
+        }
+    }
+
+    fn check_class(&mut self, class: &ClassStmt) {
+        let name = class.name.lexeme.clone();
+        self.define(&class.name, Type::Class(name.clone()), false);
+        if let Some(superclass) = class.superclass {
+            self.check_expr(superclass);
+        }
+        let enclosing = self.current_class.replace(name);
+        for method in &class.methods {
+            let signature = self.signature(method);
+            self.check_function(method, &signature);
+        }
+        self.current_class = enclosing;
+    }
+
+    fn check_function(&mut self, function: &FunctionStmt, signature: &Signature) {
+        let mut scope = HashMap::new();
+        for (param, ty) in function.params.iter().zip(&signature.params) {
+            scope.insert(
+                param.symbol.clone(),
+                Binding {
+                    ty: ty.clone(),
+                    declared: ty.is_known(),
+                },
+            );
+        }
+        self.scopes.push(scope);
+        self.returns.push(signature.ret.clone());
+        self.check_stmts(&function.body);
+        self.returns.pop();
+        self.scopes.pop();
+    }
+
+    fn check_expr(&mut self, id: ExprId) -> Type {
+        let ast = self.ast;
+        match &ast[id] {
+            Expr::Assign(e) => {
+                let ty = self.check_expr(e.value);
+                if let Some(Binding {
+                    ty: declared,
+                    declared: true,
+                }) = self.lookup(&e.name.symbol)
+                {
+                    if !ty.matches(declared) {
+                        let msg = format!(
+                            "Can't assign {} to '{}', which is declared as {}",
+                            ty, e.name.lexeme, declared
+                        );
+                        self.error(&e.name, &msg);
+                    }
+                }
+                ty
+            }
+            Expr::Binary(e) => {
+                let left = self.check_expr(e.left);
+                let right = self.check_expr(e.right);
+                self.binary(&e.operator, left, right)
+            }
+            Expr::Call(e) => {
+                let callee = self.check_expr(e.callee);
+                let args: Vec<Type> = e.arguments.iter().map(|a| self.check_expr(*a)).collect();
+                match callee {
+                    Type::Function(Some(signature)) => {
+                        self.check_args(&e.paren, &signature, &args);
+                        signature.ret.clone()
+                    }
+                    Type::Class(name) => Type::Instance(name),
+                    _ => Type::Any,
+                }
+            }
+            Expr::Get(e) => {
+                self.check_expr(e.object);
+                Type::Any
+            }
+            Expr::Grouping(e) => self.check_expr(*e),
+            Expr::Literal(l) => match l {
+                TokenLiteral::None | TokenLiteral::Nil => Type::Nil,
+                TokenLiteral::True | TokenLiteral::False => Type::Bool,
+                TokenLiteral::Number(_) => Type::Number,
+                TokenLiteral::String(_) => Type::String,
+            },
+            Expr::Logical(e) => {
+                let left = self.check_expr(e.left);
+                let right = self.check_expr(e.right);
+                if left == right {
+                    left
+                } else {
+                    Type::Any
+                }
+            }
+            Expr::Set(e) => {
+                self.check_expr(e.object);
+                self.check_expr(e.value)
+            }
+            Expr::Super(_) => Type::Any,
+            Expr::This(_) => match &self.current_class {
+                Some(name) => Type::Instance(name.clone()),
+                None => Type::Any,
+            },
+            Expr::Unary(e) => {
+                let right = self.check_expr(e.right);
+                match e.operator.token_type {
+                    TokenType::Minus => {
+                        if !right.matches(&Type::Number) {
+                            let msg = format!("Operand of '-' must be a Number, not {}", right);
+                            self.error(&e.operator, &msg);
+                        }
+                        Type::Number
+                    }
+                    _ => Type::Bool,
+                }
+            }
+            Expr::Variable(v) => self
+                .lookup(&v.name.symbol)
+                .map_or(Type::Any, |binding| binding.ty.clone()),
+        }
+    }
+
+    // Mirrors Interpreter::evaluate_binary
+    fn binary(&mut self, operator: &Token, left: Type, right: Type) -> Type {
+        match operator.token_type {
+            TokenType::Plus => match (&left, &right) {
+                (Type::String, _) => Type::String,
+                (Type::Number, Type::Number) => Type::Number,
+                (Type::Number, Type::Any) | (Type::Any, _) => Type::Any,
+                _ => {
+                    let msg = format!("Can't add {} and {}", left, right);
+                    self.error(operator, &msg);
+                    Type::Any
+                }
+            },
+            TokenType::Minus | TokenType::Slash | TokenType::Star => {
+                self.numbers(operator, &left, &right);
+                Type::Number
+            }
+            TokenType::Greater
+            | TokenType::GreaterEqual
+            | TokenType::Less
+            | TokenType::LessEqual => {
+                self.numbers(operator, &left, &right);
+                Type::Bool
+            }
+            TokenType::BangEqual | TokenType::EqualEqual => Type::Bool,
+            TokenType::Comma | TokenType::QuestionMark => right,
+            // The two branches of a ternary
+            TokenType::Colon if left == right => left,
+            _ => Type::Any,
+        }
+    }
+
+    fn numbers(&mut self, operator: &Token, left: &Type, right: &Type) {
+        if !left.matches(&Type::Number) || !right.matches(&Type::Number) {
+            let msg = format!(
+                "Operands of '{}' must be Numbers, not {} and {}",
+                operator.lexeme, left, right
+            );
+            self.error(operator, &msg);
+        }
+    }
+
+    fn check_args(&mut self, paren: &Token, signature: &Signature, args: &[Type]) {
+        if args.len() != signature.params.len() {
+            let msg = format!(
+                "Expected {} arguments but got {}",
+                signature.params.len(),
+                args.len()
+            );
+            self.error(paren, &msg);
+            return;
+        }
+        for (i, (arg, param)) in args.iter().zip(&signature.params).enumerate() {
+            if !arg.matches(param) {
+                let msg = format!("Argument {} should be {} but is {}", i + 1, param, arg);
+                self.error(paren, &msg);
+            }
+        }
+    }
+
+    fn signature(&mut self, function: &FunctionStmt) -> Rc<Signature> {
+        let params = function
+            .param_types
+            .iter()
+            .map(|t| t.as_ref().map_or(Type::Any, |t| self.annotation(t)))
+            .collect();
+        let ret = function
+            .return_type
+            .as_ref()
+            .map_or(Type::Any, |t| self.annotation(t));
+        Rc::new(Signature { params, ret })
+    }
+
+    fn annotation(&mut self, name: &Token) -> Type {
+        match name.lexeme.as_str() {
+            "Any" => Type::Any,
+            "nil" => Type::Nil,
+            "Bool" => Type::Bool,
+            "Number" => Type::Number,
+            "String" => Type::String,
+            "Function" => Type::Function(None),
+            class if self.classes.iter().any(|c| c == class) => Type::Instance(class.to_string()),
+            _ => {
+                self.error(name, &format!("Unknown type '{}'", name.lexeme));
+                Type::Any
+            }
+        }
+    }
+
+    fn define(&mut self, name: &Token, ty: Type, declared: bool) {
+        if let Some(scope) = self.scopes.last_mut() {
+            scope.insert(name.symbol.clone(), Binding { ty, declared });
+        }
+    }
+
+    fn lookup(&self, name: &Symbol) -> Option<&Binding> {
+        self.scopes.iter().rev().find_map(|scope| scope.get(name))
+    }
+
+    fn error(&self, token: &Token, msg: &str) {
+        self.error_reporter.runtime_error(token, msg);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::{parser::Parser, scanner::Scanner};
+
+    fn check(src: &str) -> Vec<String> {
+        let reporter = ErrorReporter::new();
+        let mut ast = Ast::new();
+        let stmts = Parser::new(Scanner::new(src, &reporter), &mut ast, &reporter).parse_stmts();
+        assert!(!reporter.had_error());
+        TypeChecker::new(&ast, &reporter).check_stmts(&stmts);
+        reporter.take_errors()
+    }
+
+    #[test]
+    pub fn reports_mismatched_annotations() {
+        let src = "fun greet(name: String, times: Number) -> String {
+  return name + times;
+}
+var n: Number = greet(\"a\", 2);
+var s: String = greet(1, 2);
+fun half(x: Number) -> Number { return x / 2; }
+var h = half(\"4\") - true;
+class Point {}
+var p: Point = Point();
+p = nil;
+var q: Point;
+q = 1;
+var u: Unknown = 1;";
+        assert_eq!(
+            check(src),
+            vec![
+                "[Line 4:5] Runtime Error: 'n' is declared as Number but initialized with String",
+                "[Line 5:27] Runtime Error: Argument 1 should be String but is Number",
+                "[Line 7:17] Runtime Error: Argument 1 should be Number but is String",
+                "[Line 7:19] Runtime Error: Operands of '-' must be Numbers, not Number and Bool",
+                "[Line 12:1] Runtime Error: Can't assign Number to 'q', which is declared as Point",
+                "[Line 13:8] Runtime Error: Unknown type 'Unknown'",
+            ]
+        );
+    }
+
+    #[test]
+    pub fn ignores_code_without_annotations() {
+        let src = "fun f(a, b) { return a + b; }
+var x = f(1, 2);
+x = \"now a string\";
+print -x;
+print 1 + \"a\";";
+        assert_eq!(
+            check(src),
+            vec!["[Line 5:9] Runtime Error: Can't add Number and String"]
+        );
+    }
+}
@@ -0,0 +1,131 @@
+//! Bindings for running Lox in a browser, built with the `wasm` feature, as in
+//! `cargo rustc --lib --release --target wasm32-unknown-unknown --features wasm
+//! --crate-type cdylib` followed by `wasm-bindgen`. Scripts run sandboxed, with calls
+//! and expressions kept shallow enough for the browser's stack, and what they print is
+//! handed back rather than written anywhere.
+
+use std::{
+    cell::RefCell,
+    io::{self, Write},
+    rc::Rc,
+};
+
+use wasm_bindgen::prelude::*;
+
+use crate::{
+    errors::{Diagnostic, ErrorReporter, Severity},
+    interpreter::{Interpreter, InterpreterOptions},
+};
+
+#[wasm_bindgen(getter_with_clone)]
+pub struct RunResult {
+    pub output: String,
+    pub diagnostics: Vec<Message>,
+}
+
+// A diagnostic, with where it is for an editor to mark
+#[wasm_bindgen(getter_with_clone)]
+#[derive(Clone)]
+pub struct Message {
+    // "error" or "warning"
+    pub severity: String,
+    pub line: usize,
+    // 0 when the column isn't known
+    pub column: usize,
+    // As the rlox command prints it
+    pub text: String,
+}
+
+impl From<&Diagnostic> for Message {
+    fn from(diagnostic: &Diagnostic) -> Self {
+        Message {
+            severity: match diagnostic.severity {
+                Severity::Error => "error",
+                Severity::Warning => "warning",
+            }
+            .to_string(),
+            line: diagnostic.line,
+            column: diagnostic.column,
+            text: diagnostic.to_string(),
+        }
+    }
+}
+
+#[derive(Clone, Default)]
+struct Output(Rc<RefCell<Vec<u8>>>);
+
+impl Write for Output {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.0.borrow_mut().write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+const MAX_CALL_DEPTH: usize = 200;
+const MAX_EXPR_DEPTH: usize = 100;
+
+#[wasm_bindgen]
+pub fn run(source: &str) -> RunResult {
+    let reporter = ErrorReporter::new();
+    // There's no clock to time out by in the browser, so only the step limit applies. The
+    // stack is about 1MB and can't grow, so calls and expressions can't nest as deeply.
+    let options = InterpreterOptions {
+        timeout: None,
+        max_call_depth: MAX_CALL_DEPTH,
+        max_expr_depth: MAX_EXPR_DEPTH,
+        ..InterpreterOptions::sandboxed()
+    };
+    let output = Output::default();
+    let mut interpreter = Interpreter::new(&reporter, options);
+    interpreter.set_output(Box::new(output.clone()));
+
+    let outcome = interpreter.run(source);
+    let diagnostics = outcome.diagnostics.iter().map(Message::from).collect();
+    let output = String::from_utf8_lossy(&output.0.borrow()).into_owned();
+    RunResult {
+        output,
+        diagnostics,
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    pub fn captures_output_and_diagnostics() {
+        let result = run("print 1 + 2;\nprint \"a\" - 1;");
+        assert_eq!(result.output, "3\n");
+        assert_eq!(result.diagnostics.len(), 1);
+        assert_eq!(result.diagnostics[0].severity, "error");
+        assert_eq!(result.diagnostics[0].line, 2);
+
+        let result = run("print (;");
+        assert_eq!(result.output, "");
+        assert!(result.diagnostics[0].text.starts_with("[line 1:"));
+    }
+
+    #[test]
+    pub fn runs_sandboxed() {
+        let result = run("while (true) {}");
+        assert!(result.diagnostics[0].text.contains("Execution"));
+    }
+
+    #[test]
+    pub fn limits_recursion_to_the_browser_stack() {
+        let result = run("fun f(n) { return f(n + 1); } f(0);");
+        assert_eq!(result.diagnostics.len(), 1);
+        assert!(result.diagnostics[0]
+            .text
+            .contains("max call depth 200 exceeded"));
+        let src = "fun f(n) { if (n > 0) return f(n - 1) + 1; return 0; } print f(199);";
+        assert_eq!(run(src).output, "199\n");
+
+        let result = run(&format!("print {}1;", "-".repeat(150)));
+        assert_eq!(result.diagnostics.len(), 1);
+        assert_eq!(run(&format!("print {}1;", "-".repeat(98))).output, "1\n");
+    }
+}
@@ -0,0 +1,150 @@
+//! Running an interpreter on a thread of its own. Values are reference counted without
+//! atomics, so an [`Interpreter`] can't be moved to another thread, but a [`Worker`] can:
+//! it keeps an interpreter on its own thread and runs whatever it's sent there. A server
+//! can keep workers in a pool, and use them from whichever thread has a request.
+//!
+//! Each worker's thread has an 8MB stack, as much as a program's main thread usually
+//! gets. Deep Lox calls grow it on the heap as they need, up to the options'
+//! `max_call_depth`. The code each run adds is freed once it's finished, apart from
+//! functions and classes it declares, which later runs can use.
+
+use std::{
+    sync::mpsc,
+    thread::{self, JoinHandle},
+};
+
+use crate::{
+    errors::{Diagnostic, ErrorReporter},
+    interpreter::{Interpreter, InterpreterOptions},
+};
+
+// Calls grow the stack themselves, so this only needs room for parsing code nested as
+// deeply as the parser allows, which takes about 3MB in a debug build
+const STACK_SIZE: usize = 8 * 1024 * 1024;
+
+type Job = Box<dyn FnOnce(&mut Interpreter<'_>) + Send>;
+
+pub struct Worker {
+    // Dropped to stop the thread
+    jobs: Option<mpsc::Sender<Job>>,
+    thread: Option<JoinHandle<()>>,
+}
+
+impl Worker {
+    pub fn new(options: InterpreterOptions) -> Worker {
+        Worker::with_setup(options, |_| {})
+    }
+
+    // `setup` runs on the worker's thread before anything else, to register natives or
+    // set the output, which don't have to be sent between threads themselves
+    pub fn with_setup(
+        options: InterpreterOptions,
+        setup: impl FnOnce(&mut Interpreter<'_>) + Send + 'static,
+    ) -> Worker {
+        let (jobs, received) = mpsc::channel::<Job>();
+        let thread = thread::Builder::new()
+            .name("rlox worker".to_string())
+            .stack_size(STACK_SIZE)
+            .spawn(move || {
+                let reporter = ErrorReporter::new();
+                let mut interpreter = Interpreter::new(&reporter, options);
+                setup(&mut interpreter);
+                for job in received {
+                    job(&mut interpreter);
+                }
+            })
+            .expect("Could not start worker thread");
+        Worker {
+            jobs: Some(jobs),
+            thread: Some(thread),
+        }
+    }
+
+    // Runs `f` with the interpreter on the worker's thread, and waits for what it returns.
+    // Panics if an earlier job panicked, which stops the worker.
+    pub fn with<R: Send + 'static>(
+        &self,
+        f: impl FnOnce(&mut Interpreter<'_>) -> R + Send + 'static,
+    ) -> R {
+        let (result, received) = mpsc::sync_channel(1);
+        self.jobs
+            .as_ref()
+            .unwrap()
+            .send(Box::new(move |interpreter| {
+                let _ = result.send(f(interpreter));
+            }))
+            .expect("Worker thread has stopped");
+        received.recv().expect("Worker thread has stopped")
+    }
+
+    // Runs the source in the worker's interpreter, returning the errors and warnings it
+    // had. What it defines stays defined for later runs.
+    pub fn run(&self, source: &str) -> Vec<Diagnostic> {
+        let source = source.to_string();
+        self.with(move |interpreter| interpreter.run(&source).diagnostics)
+    }
+}
+
+impl Drop for Worker {
+    fn drop(&mut self) {
+        self.jobs.take();
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::loxvalue::LoxValue;
+    use std::sync::Arc;
+
+    #[test]
+    pub fn runs_on_other_threads() {
+        let worker = Arc::new(Worker::with_setup(
+            InterpreterOptions::default(),
+            |interpreter| {
+                interpreter.register_native("double", 1, |args| match args[0] {
+                    LoxValue::Number(n) => Ok(LoxValue::Number(n * 2.0)),
+                    _ => Err("double() expects a number"),
+                })
+            },
+        ));
+        assert!(worker.run("var total = 0;").is_empty());
+
+        let threads: Vec<_> = (0..4)
+            .map(|_| {
+                let worker = Arc::clone(&worker);
+                thread::spawn(move || worker.run("total = total + double(1);"))
+            })
+            .collect();
+        for thread in threads {
+            assert!(thread.join().unwrap().is_empty());
+        }
+        let total = worker.with(|interpreter| match interpreter.global("total") {
+            Some(LoxValue::Number(n)) => n,
+            _ => panic!("total isn't a number"),
+        });
+        assert_eq!(total, 8.0);
+
+        let errors = worker.run("print nope;");
+        assert_eq!(errors.len(), 1);
+        assert!(errors[0].runtime);
+        assert!(worker.run("total = 0;").is_empty());
+    }
+
+    #[test]
+    pub fn runs_free_their_code() {
+        let worker = Worker::new(InterpreterOptions::default());
+        assert!(worker.run("fun inc(n) { return n + 1; }").is_empty());
+        let mark = worker.with(|interpreter| interpreter.ast().mark());
+        for _ in 0..100 {
+            assert!(worker.run("var a = inc(1 + 2 * 3);").is_empty());
+        }
+        assert_eq!(worker.with(move |i| i.ast().size_since(mark)), 0);
+
+        let errors = worker.run("fun f(n) { return f(n + 1); } f(0);");
+        assert!(errors[0].message.contains("max call depth 10000 exceeded"));
+    }
+}